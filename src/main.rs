@@ -1,218 +1,4173 @@
 mod utils;
 
+use std::collections::HashMap;
 use std::path::Path;
 
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, SecondsFormat, Utc};
 use confique::Config;
 use dotenv::dotenv;
-use regex::Regex;
-use salvo::http::StatusCode;
+use futures_util::future::join_all;
+use futures_util::StreamExt;
+use http_body_util::LengthLimitError;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use salvo::conn::unix::UnixListener;
+use salvo::conn::Acceptor;
+use salvo::http::{HeaderValue, Method, ParseError, StatusCode};
 use salvo::prelude::*;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::FileTypeExt;
 use std::sync::OnceLock;
-use tracing::warn;
+use std::time::Duration;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
 use urlencoding::decode;
-use utils::azure::{get_azure_object_data, head_azure_object, list_azure_objects, SearchRequest};
-use utils::s3::generate_s3_list_objects_v2_response;
+use utils::allowlist::{client_ip, is_ip_allowed, load_ip_rules};
+use utils::archive::ZipWriter;
+use utils::audit::{
+    flush_audit, is_audit_enabled, record_audit, spawn_audit_writer, AuditRecord, AuditedBody,
+};
+use utils::azure::{
+    check_auth_mode, copy_azure_object, create_azure_sharing_link, create_azure_upload_session,
+    delete_azure_object, get_azure_item, get_azure_item_fields, get_azure_item_key,
+    get_azure_items, get_azure_object_data, get_azure_object_pdf, head_azure_object,
+    is_pdf_convertible, key_in_parent, list_azure_changes, list_azure_objects,
+    list_azure_objects_recursive, list_azure_permissions, put_azure_object, resolve_azure_share,
+    search_azure_content, spawn_token_refresher, update_azure_fields, CopyOutcome, DeltaItem,
+    GetAzureObjectResponse, HeadAzureObjectResponse, Item, SearchRequest, SharePointObjects,
+    ShareRequest,
+};
+use utils::buckets::{buckets, find_bucket, is_multi_bucket, resolve_site, Bucket};
+use utils::cache::{
+    cache_listing, cached_listing, export_final_snapshot, import_snapshot, invalidate_listings,
+    is_listing_cache_enabled, listing_key, spawn_listing_tracker, spawn_snapshot_exporter,
+};
+use utils::changes::{
+    decode_changes_token, encode_changes_token, known_key, resolve_changed_keys, ChangeFeed,
+    ChangedKey, ChangesToken, DeletedKey,
+};
+use utils::compat::{bucket_subresource_response, BUCKET_SUBRESOURCES};
+use utils::conditional::Conditions;
+use utils::cursor::{create_cursor, cursor_prefix, delete_cursor, read_cursor, CursorEntry};
+use utils::dryrun::{
+    evaluate_ip, evaluate_key, evaluate_token, is_dry_run_enabled, load_dry_run_policies,
+};
+use utils::error::AdapterError;
+use utils::health::{readiness, Warning};
+use utils::journal::{merge_writes, recent_write, record_write, Write};
+use utils::libraries::{
+    is_library_mode, library_folders, lists_libraries, resolve_library, spawn_library_loader,
+};
+use utils::lifecycle::spawn_lifecycle_runner;
+use utils::metadata::{fields_from_headers, is_user_metadata_enabled, metadata_headers};
+use utils::metrics::{
+    record_abort, record_token_denial, record_token_request, render_metrics, Abort, MeteredStream,
+};
+use utils::naming::{filename_regex, load_filename_pattern, nfc, sanitize_key, validate_key};
+use utils::notifications::{handle_notifications, spawn_subscription_manager, Notifications};
+use utils::overrides::{has_overrides, parse_overrides, DownloadMode, Overrides};
+use utils::range::{multipart_byteranges, parse_content_range, parse_range, ByteRange};
+use utils::ratelimit::{
+    is_rate_limit_enabled, rate_limit_state, try_download, try_request, PermittedStream,
+    RateLimitState,
+};
+use utils::readahead::{
+    buffered_range, invalidate_read_ahead, is_read_ahead_enabled, record_range_read, RangeRead,
+};
+use utils::request::{canonicalize, new_request_id, split_path, with_request_id, CanonicalRequest};
+use utils::s3::{
+    decode_continuation_token, encode_continuation_token, generate_s3_copy_object_result_response,
+    generate_s3_delete_result_response, generate_s3_error_response,
+    generate_s3_list_buckets_response, generate_s3_object_attributes_response,
+    generate_s3_tagging_response, http_date, listing_scope, normalize_prefix,
+    parse_s3_delete_request, parse_s3_tagging_request, sign_token,
+    stream_s3_list_objects_v2_response, verify_token, DeleteError, ListObjectsPage,
+    ObjectAttributes, S3Error, AFTER_LINK,
+};
+use utils::selftest::run_selftest;
+use utils::shadow::{is_shadow_enabled, shadow_read, ShadowRead, ShadowedStream};
+use utils::shutdown::shutdown_signal;
+use utils::sigv4::{is_sigv4_authorization, verify_sigv4};
+use utils::snapshot::{
+    check_snapshot_views, get_snapshot_object_data, head_snapshot_object, snapshot_items,
+    snapshot_views,
+};
+use utils::tail::{cached_tail, invalidate_tail, is_tail_cache_enabled, record_tail_read};
+use utils::telemetry::{flush_traces, is_tracing_enabled, otel_layer, request_span};
+use utils::tls::{check_tls_config, is_tls_enabled, tls_configs};
+use utils::tokens::{find_api_token, load_api_tokens, tokens_match, ApiToken};
+use utils::watermark::{apply_pdf_watermark, is_watermark_enabled, WatermarkContext};
 
 #[derive(Config)]
 struct Conf {
-    #[config(env = "APP_CLIENT_ID")]
+    #[config(env = "APP_CLIENT_ID", default = "")]
     app_client_id: String,
 
-    #[config(env = "APP_CLIENT_SECRET")]
+    #[config(env = "APP_CLIENT_SECRET", default = "")]
     app_client_secret: String,
 
+    /// `client_secret`, `managed_identity` or `workload_identity`.
+    #[config(env = "AUTH_MODE", default = "client_secret")]
+    auth_mode: String,
+
+    #[config(env = "AZURE_CLIENT_ID")]
+    azure_client_id: Option<String>,
+
+    #[config(env = "AZURE_FEDERATED_TOKEN_FILE")]
+    azure_federated_token_file: Option<String>,
+
+    #[config(env = "READ_APP_CLIENT_ID")]
+    read_app_client_id: Option<String>,
+
+    #[config(env = "READ_APP_CLIENT_SECRET")]
+    read_app_client_secret: Option<String>,
+
     #[config(env = "TENANT")]
     tenant: String,
 
     #[config(env = "SHAREPOINT_SITE_ID")]
-    sharepoint_site_id: String,
+    sharepoint_site_id: Option<String>,
+
+    #[config(env = "SHAREPOINT_SITE_URL")]
+    sharepoint_site_url: Option<String>,
+
+    #[config(env = "DRIVE_ID")]
+    drive_id: Option<String>,
+
+    #[config(env = "FILENAME_PATTERN", default = "")]
+    filename_pattern: String,
+
+    #[config(env = "API_TOKEN")]
+    api_token: Option<String>,
+
+    #[config(env = "API_TOKENS", parse_env = confique::env::parse::list_by_semicolon, default = [])]
+    api_tokens: Vec<String>,
+
+    /// Answered to GetBucketLocation, which clients sign requests for.
+    #[config(env = "BUCKET_REGION", default = "us-east-1")]
+    bucket_region: String,
+
+    #[config(env = "BUCKET_MAPPINGS", parse_env = confique::env::parse::list_by_semicolon, default = [])]
+    bucket_mappings: Vec<String>,
+
+    #[config(env = "SNAPSHOT_VIEWS", parse_env = confique::env::parse::list_by_semicolon, default = [])]
+    snapshot_views: Vec<String>,
+
+    #[config(env = "ACCESS_KEYS", parse_env = confique::env::parse::list_by_comma, default = [])]
+    access_keys: Vec<String>,
+
+    #[config(env = "OVERRIDE_CALLERS", parse_env = confique::env::parse::list_by_comma, default = [])]
+    override_callers: Vec<String>,
+
+    #[config(env = "WHITELISTED_IPS", parse_env = confique::env::parse::list_by_comma, default = [])]
+    whitelisted_ips: Vec<String>,
+
+    #[config(env = "TRUSTED_PROXIES", parse_env = confique::env::parse::list_by_comma, default = [])]
+    trusted_proxies: Vec<String>,
+
+    #[config(env = "ON_BEHALF_OF_CALLERS", parse_env = confique::env::parse::list_by_comma, default = [])]
+    on_behalf_of_callers: Vec<String>,
+
+    #[config(env = "EMPTY_FOLDER_EXISTS", default = true)]
+    empty_folder_exists: bool,
+
+    #[config(env = "INCLUDE_REMOTE_ITEMS", default = false)]
+    include_remote_items: bool,
+
+    #[config(env = "LIBRARIES_AS_PREFIXES", default = false)]
+    libraries_as_prefixes: bool,
+
+    #[config(env = "PDF_WATERMARK_URL")]
+    pdf_watermark_url: Option<String>,
+
+    #[config(env = "SHADOW_ENDPOINT")]
+    shadow_endpoint: Option<String>,
+
+    #[config(env = "SHADOW_AUTHORIZATION")]
+    shadow_authorization: Option<String>,
+
+    #[config(env = "FAULT_INJECTION_RATE", default = 0.0)]
+    fault_injection_rate: f64,
+
+    #[config(env = "FAULT_INJECTION_MAX_LATENCY_MS", default = 0)]
+    fault_injection_max_latency_ms: u64,
+
+    #[config(env = "QUARANTINE_PREFIX")]
+    quarantine_prefix: Option<String>,
+
+    #[config(env = "SANITIZE_KEYS", default = false)]
+    sanitize_keys: bool,
+
+    #[config(env = "KEY_REPLACEMENTS", parse_env = confique::env::parse::list_by_comma, default = [])]
+    key_replacements: Vec<String>,
+
+    #[config(env = "MAX_UPLOAD_SIZE", default = 262144000)]
+    max_upload_size: usize,
+
+    #[config(env = "UPLOAD_CHUNK_SIZE", default = 10485760)]
+    upload_chunk_size: usize,
+
+    /// Signs continuation tokens. Without it a random key is used, and
+    /// tokens do not survive a restart or reach another replica.
+    #[config(env = "TOKEN_SIGNING_SECRET")]
+    token_signing_secret: Option<String>,
+
+    #[config(env = "CURSOR_TTL_SECS", default = 3600)]
+    cursor_ttl_secs: u64,
+
+    #[config(env = "MAX_CURSORS", default = 100)]
+    max_cursors: usize,
+
+    #[config(env = "MAX_RANGES", default = 16)]
+    max_ranges: usize,
+
+    #[config(env = "MAX_PROXY_SIZE", default = 0)]
+    max_proxy_size: u64,
+
+    #[config(env = "READ_AHEAD_BYTES", default = 0)]
+    read_ahead_bytes: u64,
+
+    #[config(env = "READ_AHEAD_MAX_OBJECTS", default = 64)]
+    read_ahead_max_objects: usize,
+
+    #[config(env = "TAIL_CACHE_BYTES", default = 0)]
+    tail_cache_bytes: u64,
+
+    #[config(env = "TAIL_CACHE_MIN_SIZE", default = 16777216)]
+    tail_cache_min_size: u64,
+
+    #[config(env = "TAIL_CACHE_MAX_OBJECTS", default = 256)]
+    tail_cache_max_objects: usize,
+
+    #[config(env = "TAIL_CACHE_TTL_SECS", default = 300)]
+    tail_cache_ttl_secs: u64,
+
+    #[config(env = "LISTING_CACHE_TTL_SECS", default = 0)]
+    listing_cache_ttl_secs: u64,
+
+    #[config(env = "LISTING_CACHE_MAX_ENTRIES", default = 256)]
+    listing_cache_max_entries: usize,
+
+    #[config(env = "LISTING_CACHE_DELTA_INTERVAL_SECS", default = 0)]
+    listing_cache_delta_interval_secs: u64,
+
+    #[config(env = "LISTING_CACHE_SNAPSHOT")]
+    listing_cache_snapshot: Option<String>,
+
+    #[config(env = "LISTING_CACHE_SNAPSHOT_INTERVAL_SECS", default = 300)]
+    listing_cache_snapshot_interval_secs: u64,
+
+    #[config(env = "NOTIFICATION_URL")]
+    notification_url: Option<String>,
+
+    #[config(env = "NOTIFICATION_CLIENT_STATE")]
+    notification_client_state: Option<String>,
+
+    /// listItem columns exposed as S3 object tags, e.g. `Status,Owner`.
+    #[config(env = "TAG_FIELDS", parse_env = confique::env::parse::list_by_comma, default = [])]
+    tag_fields: Vec<String>,
+
+    /// listItem columns sent and accepted as `x-amz-meta-*` headers.
+    #[config(env = "METADATA_FIELDS", parse_env = confique::env::parse::list_by_comma, default = [])]
+    metadata_fields: Vec<String>,
+
+    /// Region of the tenant for content search, e.g. `EUR`, which the Graph
+    /// Search API requires with application tokens.
+    #[config(env = "SEARCH_REGION")]
+    search_region: Option<String>,
+
+    #[config(env = "LIFECYCLE_RULES", parse_env = confique::env::parse::list_by_semicolon, default = [])]
+    lifecycle_rules: Vec<String>,
+
+    #[config(env = "LIFECYCLE_INTERVAL_SECS", default = 3600)]
+    lifecycle_interval_secs: u64,
+
+    #[config(env = "WRITE_JOURNAL_TTL_SECS", default = 0)]
+    write_journal_ttl_secs: u64,
+
+    #[config(env = "TOKEN_REFRESH_MARGIN_SECS", default = 300)]
+    token_refresh_margin_secs: i64,
+
+    #[config(env = "REQUEST_TIMEOUT_SECS", default = 0)]
+    request_timeout_secs: u64,
+
+    #[config(env = "RATE_LIMIT_RPS", default = 0.0)]
+    rate_limit_rps: f64,
+
+    #[config(env = "RATE_LIMIT_BURST", default = 0)]
+    rate_limit_burst: u32,
+
+    #[config(env = "MAX_CONCURRENT_DOWNLOADS", default = 0)]
+    max_concurrent_downloads: usize,
+
+    /// Item and field lookups arriving within this many milliseconds of
+    /// each other share one Graph `$batch` call; 0 sends each on its own.
+    #[config(env = "GRAPH_BATCH_WINDOW_MS", default = 0)]
+    graph_batch_window_ms: u64,
+
+    #[config(env = "RATE_LIMIT_BY", default = "caller")]
+    rate_limit_by: String,
+
+    #[config(env = "BIND_ADDR", default = "0.0.0.0")]
+    bind_addr: String,
+
+    #[config(env = "PORT", default = 3000)]
+    port: u16,
+
+    #[config(env = "UNIX_SOCKET_PATH")]
+    unix_socket_path: Option<String>,
+
+    #[config(env = "SELFTEST_PREFIX")]
+    selftest_prefix: Option<String>,
+
+    #[config(env = "SHUTDOWN_DRAIN_TIMEOUT_SECS", default = 30)]
+    shutdown_drain_timeout_secs: u64,
+
+    #[config(env = "TLS_CERT_PATH")]
+    tls_cert_path: Option<String>,
+
+    #[config(env = "TLS_KEY_PATH")]
+    tls_key_path: Option<String>,
+
+    #[config(env = "LOG_LEVELS", default = "info")]
+    log_levels: String,
+
+    #[config(env = "LOG_FORMAT", default = "text")]
+    log_format: String,
+
+    #[config(env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otel_exporter_otlp_endpoint: Option<String>,
+
+    #[config(env = "OTEL_SERVICE_NAME", default = "s3-sharepoint-adapter")]
+    otel_service_name: String,
+
+    #[config(env = "EGRESS_SIGNING_SECRET")]
+    egress_signing_secret: Option<String>,
+
+    #[config(env = "EGRESS_SIGNATURE_HEADER", default = "X-Adapter-Signature")]
+    egress_signature_header: String,
+
+    #[config(nested)]
+    budgets: BudgetConf,
+
+    #[config(nested)]
+    dns: DnsConf,
+
+    #[config(nested)]
+    events: EventConf,
+
+    #[config(nested)]
+    audit: AuditConf,
+
+    #[config(nested)]
+    dry_run: DryRunConf,
+}
+
+/// Timeout and retry budgets per class of Graph operation.
+#[derive(Config)]
+struct BudgetConf {
+    #[config(env = "LIST_TIMEOUT_SECS", default = 30)]
+    list_timeout_secs: u64,
+
+    #[config(env = "LIST_MAX_RETRIES", default = 3)]
+    list_max_retries: u32,
+
+    #[config(env = "HEAD_TIMEOUT_SECS", default = 15)]
+    head_timeout_secs: u64,
+
+    #[config(env = "HEAD_MAX_RETRIES", default = 2)]
+    head_max_retries: u32,
+
+    #[config(env = "GET_TIMEOUT_SECS", default = 600)]
+    get_timeout_secs: u64,
+
+    #[config(env = "GET_MAX_RETRIES", default = 0)]
+    get_max_retries: u32,
+
+    #[config(env = "WRITE_TIMEOUT_SECS", default = 120)]
+    write_timeout_secs: u64,
+
+    #[config(env = "WRITE_MAX_RETRIES", default = 0)]
+    write_max_retries: u32,
+
+    #[config(env = "TOKEN_TIMEOUT_SECS", default = 10)]
+    token_timeout_secs: u64,
+
+    #[config(env = "TOKEN_MAX_RETRIES", default = 3)]
+    token_max_retries: u32,
+
+    #[config(env = "RETRY_BASE_DELAY_MS", default = 500)]
+    retry_base_delay_ms: u64,
+
+    #[config(env = "RETRY_MAX_DELAY_MS", default = 30000)]
+    retry_max_delay_ms: u64,
+}
+
+/// Destinations for S3-style event notifications about drive changes.
+#[derive(Config)]
+struct EventConf {
+    #[config(env = "EVENT_WEBHOOK_URL")]
+    event_webhook_url: Option<String>,
+
+    #[config(env = "EVENT_SQS_QUEUE_URL")]
+    event_sqs_queue_url: Option<String>,
+
+    #[config(env = "EVENT_SNS_TOPIC_ARN")]
+    event_sns_topic_arn: Option<String>,
+
+    #[config(env = "EVENT_AWS_REGION", default = "us-east-1")]
+    event_aws_region: String,
+
+    #[config(env = "EVENT_AWS_ACCESS_KEY_ID")]
+    event_aws_access_key_id: Option<String>,
+
+    #[config(env = "EVENT_AWS_SECRET_ACCESS_KEY")]
+    event_aws_secret_access_key: Option<String>,
+}
+
+/// Where the audit trail of object access is written.
+#[derive(Config)]
+struct AuditConf {
+    /// `file`, `stdout` or `webhook`; no audit trail when unset.
+    #[config(env = "AUDIT_SINK")]
+    audit_sink: Option<String>,
+
+    #[config(env = "AUDIT_FILE")]
+    audit_file: Option<String>,
+
+    #[config(env = "AUDIT_WEBHOOK_URL")]
+    audit_webhook_url: Option<String>,
+
+    #[config(env = "AUDIT_BUFFER_SIZE", default = 10000)]
+    audit_buffer_size: usize,
+}
+
+/// Candidate policies evaluated and logged next to the live ones without
+/// being enforced. Each takes the format of its live counterpart and
+/// replaces it as a whole; unset options are not evaluated.
+#[derive(Config)]
+struct DryRunConf {
+    #[config(env = "DRY_RUN_FILENAME_PATTERN")]
+    dry_run_filename_pattern: Option<String>,
+
+    #[config(env = "DRY_RUN_WHITELISTED_IPS")]
+    dry_run_whitelisted_ips: Option<String>,
+
+    #[config(env = "DRY_RUN_API_TOKENS")]
+    dry_run_api_tokens: Option<String>,
+}
+
+/// Name resolution and connection settings for Graph and login hosts.
+#[derive(Config)]
+struct DnsConf {
+    #[config(env = "GRAPH_DNS_OVERRIDES", parse_env = confique::env::parse::list_by_comma, default = [])]
+    graph_dns_overrides: Vec<String>,
+
+    #[config(env = "GRAPH_DNS_CACHE_TTL_SECS", default = 60)]
+    graph_dns_cache_ttl_secs: u64,
+
+    #[config(env = "GRAPH_IP_FAMILY", default = "any")]
+    graph_ip_family: String,
+
+    #[config(env = "GRAPH_CONNECT_TIMEOUT_MS", default = 10000)]
+    graph_connect_timeout_ms: u64,
+
+    #[config(env = "GRAPH_POOL_IDLE_TIMEOUT_SECS", default = 90)]
+    graph_pool_idle_timeout_secs: u64,
+
+    #[config(env = "GRAPH_POOL_MAX_IDLE_PER_HOST", default = 32)]
+    graph_pool_max_idle_per_host: usize,
+
+    #[config(env = "GRAPH_HTTP2", default = true)]
+    graph_http2: bool,
+}
+
+/// Loads the configuration from the environment, falling back to the TOML,
+/// YAML or JSON5 file named by `CONFIG_FILE` for options not set there.
+fn config() -> &'static Conf {
+    static CONFIG: OnceLock<Conf> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let builder = Conf::builder().env();
+        let builder = match std::env::var("CONFIG_FILE") {
+            Ok(path) => {
+                let file = confique::File::new(&path)
+                    .unwrap_or_else(|err| panic!("Unsupported CONFIG_FILE {}: {}", path, err));
+                builder.preloaded(
+                    file.required()
+                        .load()
+                        .unwrap_or_else(|err| panic!("Invalid CONFIG_FILE {}: {}", path, err)),
+                )
+            }
+            Err(_) => builder,
+        };
+        builder.load().unwrap()
+    })
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct SearchResult {
+    file_name: String,
+    file_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    web_url: Option<String>,
+    /// The S3 key of the file within the bucket.
+    key: String,
+    size: Option<u64>,
+    last_modified: Option<String>,
+    mime_type: Option<String>,
+    e_tag: Option<String>,
+    /// The passage of a content search hit that matched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<String>,
+}
+
+/// One page of search results. `total` counts the results of this page for
+/// name searches, as Graph does not count drive search hits, and all hits in
+/// the site for content searches.
+#[derive(Serialize, Debug)]
+struct SearchPage {
+    results: Vec<SearchResult>,
+    next_token: Option<String>,
+    total: u64,
+    /// `page` when `sort_by` ordered the results of this page only: pages
+    /// follow Graph's relevance ranking, whatever the order asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sort_scope: Option<&'static str>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ShareResult {
+    web_url: String,
+    scope: Option<String>,
+    expiration_date_time: Option<String>,
+}
+
+/// What the presented credentials resolve to, for debugging 403s.
+#[derive(Serialize, Debug)]
+struct WhoAmI {
+    caller: String,
+    on_behalf_of: Option<String>,
+    may_act_on_behalf: bool,
+    buckets: Vec<String>,
+    filename_pattern: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<TokenScope>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_limit: Option<RateLimitState>,
+}
+
+/// The restrictions of an `API_TOKENS` entry, `None` meaning unrestricted.
+#[derive(Serialize, Debug)]
+struct TokenScope {
+    name: String,
+    methods: Option<Vec<String>>,
+    prefixes: Option<Vec<String>>,
+    expires: Option<DateTime<Utc>>,
+}
+
+#[handler]
+async fn ok_handler(res: &mut Response) {
+    res.status_code(StatusCode::OK).render(Text::Plain("OK"))
+}
+
+#[derive(Serialize, Debug)]
+struct ReadyWarnings {
+    warnings: Vec<Warning>,
+}
+
+/// Answers 503 with a problem document while Graph, the credentials or a
+/// site are unusable. Ready buckets whose drive is missing or empty are
+/// listed as warnings.
+#[handler]
+async fn ready_handler(res: &mut Response) {
+    match readiness().await {
+        Ok(warnings) if warnings.is_empty() => {
+            res.status_code(StatusCode::OK).render(Text::Plain("OK"))
+        }
+        Ok(warnings) => {
+            for warning in &warnings {
+                warn!("Bucket {}: {}", warning.bucket, warning.detail);
+            }
+            res.status_code(StatusCode::OK)
+                .render(Json(ReadyWarnings { warnings }));
+        }
+        Err(problem) => {
+            warn!("Not ready: {} ({})", problem.title, problem.detail);
+            res.status_code(StatusCode::SERVICE_UNAVAILABLE)
+                .render(Json(problem));
+            res.headers_mut()
+                .insert("Content-Type", "application/problem+json".parse().unwrap());
+        }
+    }
+}
+
+#[handler]
+async fn metrics_handler(res: &mut Response) {
+    res.status_code(StatusCode::OK)
+        .render(Text::Plain(render_metrics()))
+}
+
+/// Receives Graph change notifications. Graph cannot authenticate, so
+/// notifications are checked against the subscription's client state.
+#[handler]
+async fn notifications_handler(req: &mut Request, res: &mut Response) {
+    // Graph validates a new subscription by expecting the token echoed back.
+    if let Some(validation_token) = req.query::<String>("validationToken") {
+        res.status_code(StatusCode::OK)
+            .render(Text::Plain(validation_token));
+        return;
+    }
+    match req.parse_json::<Notifications>().await {
+        Ok(notifications) if handle_notifications(&notifications) => {
+            res.status_code(StatusCode::ACCEPTED);
+        }
+        Ok(_) => res.render(S3Error::access_denied()),
+        Err(err) => res.render(S3Error::invalid_argument(err.to_string())),
+    }
+}
+
+/// Answers requests the adapter could not finish within `REQUEST_TIMEOUT_SECS`.
+/// Bodies that are already streaming are not cut off.
+#[handler]
+async fn deadline_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    if config().request_timeout_secs == 0 {
+        return;
+    }
+    let timeout = Duration::from_secs(config().request_timeout_secs);
+    if tokio::time::timeout(timeout, ctrl.call_next(req, depot, res))
+        .await
+        .is_err()
+    {
+        warn!("Adapter timed out on {} {}", req.method(), req.uri());
+        record_abort(Abort::AdapterTimeout);
+        ctrl.skip_rest();
+        res.render(S3Error::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ServiceUnavailable",
+            "The adapter did not answer in time.",
+        ));
+    }
+}
+
+/// Assigns each request an id and runs it in a span carrying the id, so
+/// every log line of the request can be correlated.
+#[handler]
+async fn request_id_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    let request_id = new_request_id();
+    let span = info_span!("request", request_id = %request_id, caller = field::Empty);
+    if let Ok(value) = request_id.parse() {
+        res.headers_mut().insert("x-amz-request-id", value);
+    }
+    depot.insert("request_id", request_id.clone());
+    depot.insert("request_span", span.clone());
+    with_request_id(request_id, ctrl.call_next(req, depot, res).instrument(span)).await;
+}
+
+/// Runs each request in a server span, continuing the caller's trace.
+#[handler]
+async fn trace_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    if !is_tracing_enabled() {
+        return;
+    }
+    let span = request_span(req.method().as_str(), req.uri().path(), req.headers());
+    ctrl.call_next(req, depot, res)
+        .instrument(span.clone())
+        .await;
+    span.record(
+        "http.response.status_code",
+        res.status_code.unwrap_or(StatusCode::OK).as_u16(),
+    );
+}
+
+#[handler]
+async fn bad_request_handler(res: &mut Response) {
+    res.status_code(StatusCode::BAD_REQUEST)
+        .render(Text::Plain("BAD REQUEST"))
+}
+
+/// Sets the validators clients use for conditional reads.
+fn set_validators(
+    res: &mut Response,
+    e_tag: Option<&str>,
+    last_modified: Option<&str>,
+) -> Result<(), AdapterError> {
+    if let Some(e_tag) = e_tag {
+        res.headers_mut().insert("ETag", e_tag.parse()?);
+    }
+    if let Some(last_modified) = last_modified.and_then(http_date) {
+        res.headers_mut()
+            .insert("Last-Modified", last_modified.parse()?);
+    }
+    Ok(())
+}
+
+/// Adds the `METADATA_FIELDS` columns of a file as user metadata. This
+/// costs a Graph call, and a failing one only leaves the headers out.
+/// Snapshot views carry none, as Graph keeps no history of the columns.
+async fn set_user_metadata(res: &mut Response, bucket: &Bucket, key: &str) {
+    if !is_user_metadata_enabled() || bucket.as_of.is_some() {
+        return;
+    }
+    match get_azure_item_fields(bucket.drive_url(), key.to_string()).await {
+        Ok(fields) => {
+            for (name, value) in metadata_headers(&fields) {
+                res.headers_mut().insert(name, value);
+            }
+        }
+        Err(err) => warn!("Reading the fields of {} failed: {}", key, err),
+    }
+}
+
+#[handler]
+async fn head_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AdapterError> {
+    let bucket = current_bucket(depot);
+
+    let key = current_key(depot);
+    let conditions = Conditions::from_headers(req.headers());
+    // Range support is advertised on every answer, errors included, so
+    // mounted filesystems see the same capabilities on each call.
+    res.headers_mut()
+        .insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+    let result = match bucket.as_of {
+        Some(as_of) => head_snapshot_object(bucket.drive_url(), key.clone(), as_of).await,
+        None => head_azure_object(bucket.drive_url(), key.clone()).await,
+    };
+    // Until Graph reflects a recent write, HEAD answers from the journal.
+    let journaled = recent_write(&bucket.drive_url(), &key).filter(|_| bucket.as_of.is_none());
+    let result = match (journaled, result) {
+        (Some(Write::Deleted), _) => Ok(HeadAzureObjectResponse {
+            content_type: "application/xml".to_string(),
+            status_code: 404,
+            size: 0,
+            e_tag: None,
+            last_modified: None,
+        }),
+        (Some(Write::Put(item)), result)
+            if result.as_ref().map_or_else(
+                |err| err.status() == Some(StatusCode::NOT_FOUND),
+                |result| result.status_code == 404,
+            ) =>
+        {
+            Ok(HeadAzureObjectResponse {
+                e_tag: item.object_e_tag(),
+                content_type: item
+                    .file
+                    .map(|file| file.mime_type)
+                    .unwrap_or("application/octet-stream".to_string()),
+                status_code: 200,
+                size: item.size.unwrap_or_default(),
+                last_modified: item.last_modified_date_time,
+            })
+        }
+        (_, result) => result,
+    };
+    match result {
+        Ok(result)
+            if result.status_code == 200
+                && conditions
+                    .is_not_modified(result.e_tag.as_deref(), result.last_modified.as_deref()) =>
+        {
+            res.status_code(StatusCode::NOT_MODIFIED);
+            set_validators(
+                res,
+                result.e_tag.as_deref(),
+                result.last_modified.as_deref(),
+            )?;
+        }
+        Ok(result) => {
+            res.headers_mut()
+                .insert("Content-Type", result.content_type.parse()?);
+            res.headers_mut()
+                .insert("Content-Length", result.size.to_string().parse()?);
+            // Same validators as GET, which serves watermarked documents
+            // without the item's ETag.
+            set_validators(
+                res,
+                result
+                    .e_tag
+                    .as_deref()
+                    .filter(|_| !is_watermark_enabled(&result.content_type)),
+                result.last_modified.as_deref(),
+            )?;
+            res.status_code(
+                StatusCode::from_u16(result.status_code).unwrap_or(StatusCode::BAD_GATEWAY),
+            );
+            if result.status_code == 200 {
+                set_user_metadata(res, &bucket, &key).await;
+            }
+            shadow_read(ShadowRead {
+                method: Method::HEAD,
+                key,
+                status_code: result.status_code,
+                size: result.size,
+                content_hash: None,
+            });
+        }
+        Err(err) => {
+            // HEAD responses carry no body, only the status of the S3 error.
+            res.headers_mut()
+                .insert("Content-Type", HeaderValue::from_static("application/xml"));
+            res.headers_mut()
+                .insert("Content-Length", HeaderValue::from_static("0"));
+            res.status_code(S3Error::from(err).status_code);
+        }
+    }
+    Ok(())
+}
+
+/// Lists every configured bucket, dated by the creation of its drive root.
+#[handler]
+async fn list_buckets(res: &mut Response) {
+    let mut result = Vec::new();
+    for bucket in buckets() {
+        match get_azure_item(bucket.drive_url(), "".to_string()).await {
+            Ok(root) => result.push((bucket.name, root.created_date_time)),
+            Err(err) => {
+                res.render(S3Error::from(err).with_resource(bucket.name));
+                return;
+            }
+        }
+    }
+    // Snapshot views date from the time they show.
+    for view in snapshot_views() {
+        let as_of = view.as_of.unwrap_or_default();
+        result.push((view.name, as_of.to_rfc3339_opts(SecondsFormat::Secs, true)));
+    }
+    res.status_code(StatusCode::OK)
+        .render(Text::Xml(generate_s3_list_buckets_response(result)));
+}
+
+type ModifiedRange = (Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+/// Parses the `modified-after`/`modified-before` listing extensions.
+fn modified_range(req: &Request) -> Result<ModifiedRange, String> {
+    let parse = |name: &str| {
+        req.query::<String>(name)
+            .map(|value| {
+                DateTime::parse_from_rfc3339(&value)
+                    .map(|value| value.with_timezone(&Utc))
+                    .map_err(|_| format!("{} must be an RFC 3339 timestamp", name))
+            })
+            .transpose()
+    };
+    Ok((parse("modified-after")?, parse("modified-before")?))
+}
+
+/// Listings with the `/` delimiter map to one folder level, any other
+/// delimiter or none walks the prefix recursively like S3 does.
+fn is_recursive(delimiter: Option<&str>) -> bool {
+    delimiter != Some("/")
+}
+
+/// Decodes a continuation token, which has to belong to the listing mode:
+/// a key to continue after or a Graph nextLink for folder listings, a
+/// traversal for recursive ones.
+fn decode_listing_token(token: &str, scope: &str, recursive: bool) -> Option<String> {
+    decode_continuation_token(token, scope)
+        .filter(|next_link| next_link.starts_with('{') == recursive)
+}
+
+/// Where a listing starts without a continuation token. Folder listings
+/// continue after a key, the start-after key or the empty one; recursive
+/// listings follow the traversal, which cannot resume after a key.
+fn first_link(recursive: bool, start_after: Option<&str>) -> Result<Option<String>, S3Error> {
+    match (recursive, start_after) {
+        (false, start_after) => Ok(Some(format!(
+            "{}{}",
+            AFTER_LINK,
+            start_after.unwrap_or_default()
+        ))),
+        (true, None) => Ok(None),
+        (true, Some(_)) => Err(S3Error::invalid_argument(
+            "Only listings with the delimiter / can start after a key",
+        )),
+    }
+}
+
+/// Lists one page of a folder in UTF-8 binary key order, with the entries
+/// after `start_after` whose names start with `name_prefix`. Graph pages in
+/// an order of its own, so the whole folder is read for each page and the
+/// next page continues after the last key.
+async fn list_after(
+    bucket: &Bucket,
+    prefix: String,
+    name_prefix: Option<&str>,
+    start_after: &str,
+    max_keys: u16,
+    use_cache: bool,
+) -> Result<SharePointObjects, reqwest::Error> {
+    let key_prefix = normalize_prefix(&prefix);
+    let key = |item: &Item| match item.folder {
+        Some(_) => format!("{}{}/", key_prefix, item.name),
+        None => format!("{}{}", key_prefix, item.name),
+    };
+    let mut items = Vec::new();
+    let mut next_link = None;
+    loop {
+        let objects = list_page(bucket, prefix.clone(), 1000, false, next_link, use_cache).await?;
+        items.extend(objects.items.into_iter().filter(|item| {
+            item.name.starts_with(name_prefix.unwrap_or_default())
+                && key(item).as_str() > start_after
+        }));
+        next_link = objects.next_link;
+        if next_link.is_none() {
+            break;
+        }
+    }
+    items.sort_by_cached_key(key);
+    let max_keys = usize::from(max_keys.max(1));
+    let next_link =
+        (items.len() > max_keys).then(|| format!("{}{}", AFTER_LINK, key(&items[max_keys - 1])));
+    items.truncate(max_keys);
+    Ok(SharePointObjects { items, next_link })
+}
+
+/// Lists the page of a bucket a listing link points to.
+async fn list_link(
+    bucket: &Bucket,
+    prefix: String,
+    name_prefix: Option<&str>,
+    max_keys: u16,
+    recursive: bool,
+    next_link: Option<String>,
+    use_cache: bool,
+) -> Result<SharePointObjects, reqwest::Error> {
+    match next_link
+        .as_deref()
+        .and_then(|next_link| next_link.strip_prefix(AFTER_LINK))
+    {
+        Some(start_after) => {
+            list_after(
+                bucket,
+                prefix,
+                name_prefix,
+                start_after,
+                max_keys,
+                use_cache,
+            )
+            .await
+        }
+        None => list_page(bucket, prefix, max_keys, recursive, next_link, use_cache).await,
+    }
+}
+
+/// Lists one page of a bucket. In library mode the bucket root lists the
+/// libraries and other prefixes are listed within their library.
+async fn list_page(
+    bucket: &Bucket,
+    prefix: String,
+    max_keys: u16,
+    recursive: bool,
+    next_link: Option<String>,
+    use_cache: bool,
+) -> Result<SharePointObjects, reqwest::Error> {
+    if lists_libraries(bucket, &prefix) {
+        return Ok(SharePointObjects {
+            items: library_folders(bucket).await?,
+            next_link: None,
+        });
+    }
+    let Some((bucket, prefix)) = resolve_library(bucket, &prefix).await? else {
+        return Ok(SharePointObjects {
+            items: Vec::new(),
+            next_link: None,
+        });
+    };
+    let drive = bucket.drive_url();
+    let cache_key = listing_key(&drive, &prefix, max_keys, recursive, next_link.as_deref());
+    // Snapshot views share the drive of their bucket but not its state.
+    let use_cache = use_cache && is_listing_cache_enabled() && bucket.as_of.is_none();
+    let cached = if use_cache {
+        cached_listing(&cache_key)
+    } else {
+        None
+    };
+    let mut objects = match cached {
+        Some(objects) => objects,
+        None => {
+            let objects = if recursive {
+                let traversal =
+                    next_link.and_then(|next_link| serde_json::from_str(&next_link).ok());
+                list_azure_objects_recursive(drive.clone(), prefix.clone(), max_keys, traversal)
+                    .await?
+            } else {
+                list_azure_objects(drive.clone(), prefix.clone(), max_keys, None, next_link).await?
+            };
+            if use_cache {
+                cache_listing(cache_key, &drive, &prefix, recursive, &objects);
+            }
+            objects
+        }
+    };
+    match bucket.as_of {
+        Some(as_of) => objects.items = snapshot_items(&drive, objects.items, as_of).await?,
+        None => merge_writes(&drive, &prefix, recursive, &mut objects),
+    }
+    Ok(objects)
+}
+
+/// Whether `prefix` names a folder, a library in library mode included.
+async fn names_folder(bucket: &Bucket, prefix: &str) -> Result<bool, reqwest::Error> {
+    if lists_libraries(bucket, prefix) {
+        return Ok(true);
+    }
+    let Some((bucket, path)) = resolve_library(bucket, prefix).await? else {
+        return Ok(false);
+    };
+    match get_azure_item(bucket.drive_url(), path).await {
+        Ok(item) => Ok(item.folder.is_some()),
+        Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Splits a listing prefix into the folder to list and, when its last
+/// segment names no folder, the start of the names to keep: `reports/2024-0`
+/// lists `reports` and keeps the children starting with `2024-0`, as in S3.
+/// Prefixes ending in `/` are always folders, others naming a folder keep
+/// listing its contents as before.
+async fn split_prefix(
+    bucket: &Bucket,
+    prefix: String,
+) -> Result<(String, Option<String>), reqwest::Error> {
+    if prefix.is_empty() || prefix.ends_with('/') || names_folder(bucket, &prefix).await? {
+        return Ok((prefix.trim_end_matches('/').to_string(), None));
+    }
+    let (parent, name) = prefix.rsplit_once('/').unwrap_or(("", &prefix));
+    Ok((parent.to_string(), Some(name.to_string())))
+}
+
+/// The `prefix` query parameter, composed like the keys it is compared to.
+fn prefix_query(req: &Request) -> Option<String> {
+    req.query::<String>("prefix").map(|prefix| nfc(&prefix))
+}
+
+/// Whether the listing asked for `encoding-type=url`, the only encoding S3
+/// defines.
+fn encoding_type(req: &Request) -> Result<bool, S3Error> {
+    match req.query::<String>("encoding-type").as_deref() {
+        None => Ok(false),
+        Some("url") => Ok(true),
+        Some(_) => Err(S3Error::invalid_argument(
+            "Invalid Encoding Method specified in Request",
+        )),
+    }
+}
+
+#[handler]
+async fn list_objects_v1(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let prefix = prefix_query(req).unwrap_or_default();
+    let max_keys = req.query::<u16>("max-keys").unwrap_or(1000);
+    let delimiter = req.query::<String>("delimiter");
+    let recursive = is_recursive(delimiter.as_deref());
+    let marker = req
+        .query::<String>("marker")
+        .filter(|marker| !marker.is_empty())
+        .map(|marker| nfc(&marker));
+    let (modified_after, modified_before) = match modified_range(req) {
+        Ok(range) => range,
+        Err(err) => {
+            res.render(S3Error::invalid_argument(err));
+            return;
+        }
+    };
+    let web_urls = req.query::<bool>("web-url").unwrap_or_default();
+    let url_encoded = match encoding_type(req) {
+        Ok(url_encoded) => url_encoded,
+        Err(err) => {
+            res.render(err);
+            return;
+        }
+    };
+    let bucket = current_bucket(depot);
+    let (prefix, name_prefix) = match split_prefix(&bucket, prefix).await {
+        Ok(split) => split,
+        Err(err) => {
+            res.render(S3Error::from(err));
+            return;
+        }
+    };
+    // Markers issued by the adapter resume their listing, any other marker
+    // is treated as a key to start after.
+    let scope = listing_scope(&bucket.name, &normalize_prefix(&prefix));
+    let next_link = marker
+        .as_deref()
+        .and_then(|marker| decode_listing_token(marker, &scope, recursive));
+    let page = match next_link {
+        Some(_) => ListObjectsPage {
+            continuation_token: marker,
+            modified_after,
+            modified_before,
+            delimiter,
+            max_keys,
+            name_prefix,
+            url_encoded,
+            web_urls,
+            ..Default::default()
+        },
+        None => ListObjectsPage {
+            start_after: marker,
+            modified_after,
+            modified_before,
+            delimiter,
+            max_keys,
+            name_prefix,
+            url_encoded,
+            web_urls,
+            ..Default::default()
+        },
+    };
+    let next_link = match next_link {
+        Some(next_link) => Some(next_link),
+        None => match first_link(recursive, page.start_after.as_deref()) {
+            Ok(next_link) => next_link,
+            Err(err) => {
+                res.render(err);
+                return;
+            }
+        },
+    };
+    // Libraries are folders, which recursive listings would drop.
+    let files_only = recursive && !lists_libraries(&bucket, &prefix);
+    let use_cache = !current_overrides(depot).cache_bypass;
+    match list_link(
+        &bucket,
+        prefix.clone(),
+        page.name_prefix.as_deref(),
+        max_keys,
+        recursive,
+        next_link,
+        use_cache,
+    )
+    .await
+    {
+        Ok(objects) => {
+            res.status_code(StatusCode::OK);
+            stream_s3_list_objects_v2_response(res, bucket.name, prefix, objects, files_only, page);
+        }
+        Err(err) => {
+            res.render(S3Error::from(err));
+        }
+    }
+}
+
+#[handler]
+async fn list_objects_v2(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let prefix = prefix_query(req).unwrap_or_default();
+    let max_keys = req.query::<u16>("max-keys").unwrap_or(1000);
+    let continuation_token = req.query::<String>("continuation-token");
+    let start_after = req
+        .query::<String>("start-after")
+        .filter(|start_after| !start_after.is_empty())
+        .map(|start_after| nfc(&start_after));
+    let delimiter = req.query::<String>("delimiter");
+    let recursive = is_recursive(delimiter.as_deref());
+    let (modified_after, modified_before) = match modified_range(req) {
+        Ok(range) => range,
+        Err(err) => {
+            res.render(S3Error::invalid_argument(err));
+            return;
+        }
+    };
+    let web_urls = req.query::<bool>("web-url").unwrap_or_default();
+    let url_encoded = match encoding_type(req) {
+        Ok(url_encoded) => url_encoded,
+        Err(err) => {
+            res.render(err);
+            return;
+        }
+    };
+    let bucket = current_bucket(depot);
+    let (prefix, name_prefix) = match split_prefix(&bucket, prefix).await {
+        Ok(split) => split,
+        Err(err) => {
+            res.render(S3Error::from(err));
+            return;
+        }
+    };
+    let scope = listing_scope(&bucket.name, &normalize_prefix(&prefix));
+    let next_link = match &continuation_token {
+        Some(token) => match decode_listing_token(token, &scope, recursive) {
+            Some(next_link) => Some(next_link),
+            None => {
+                res.render(S3Error::invalid_argument(
+                    "The continuation token provided is incorrect",
+                ));
+                return;
+            }
+        },
+        None => match first_link(recursive, start_after.as_deref()) {
+            Ok(next_link) => next_link,
+            Err(err) => {
+                res.render(err);
+                return;
+            }
+        },
+    };
+    // Libraries are folders, which recursive listings would drop.
+    let files_only = recursive && !lists_libraries(&bucket, &prefix);
+    let use_cache = !current_overrides(depot).cache_bypass;
+    match list_link(
+        &bucket,
+        prefix.clone(),
+        name_prefix.as_deref(),
+        max_keys,
+        recursive,
+        next_link,
+        use_cache,
+    )
+    .await
+    {
+        Ok(objects) => {
+            res.status_code(StatusCode::OK);
+            stream_s3_list_objects_v2_response(
+                res,
+                bucket.name,
+                prefix,
+                objects,
+                files_only,
+                ListObjectsPage {
+                    v2: true,
+                    continuation_token,
+                    start_after,
+                    modified_after,
+                    modified_before,
+                    delimiter,
+                    max_keys,
+                    name_prefix,
+                    url_encoded,
+                    web_urls,
+                },
+            );
+        }
+        Err(err) => {
+            res.render(S3Error::from(err));
+        }
+    }
+}
+
+/// Reads a field of a JSON body, `None` when it is absent or null.
+fn body_field<T: DeserializeOwned>(
+    body: &serde_json::Map<String, serde_json::Value>,
+    name: &'static str,
+) -> Result<Option<T>, AdapterError> {
+    match body.get(name) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(value) => T::deserialize(value)
+            .map(Some)
+            .map_err(|err| AdapterError::invalid_field(name, err.to_string())),
+    }
+}
+
+fn required_body_field<T: DeserializeOwned>(
+    body: &serde_json::Map<String, serde_json::Value>,
+    name: &'static str,
+) -> Result<T, AdapterError> {
+    body_field(body, name)?.ok_or_else(|| AdapterError::invalid_field(name, "is required"))
+}
+
+/// Name search reaches a page by following Graph's nextLinks, one call per
+/// page skipped.
+const MAX_SEARCH_PAGE: u32 = 100;
+
+/// Checks a search body field by field, so a client learns which field it
+/// got wrong instead of a generic parse error.
+fn validate_search_request(body: &[u8]) -> Result<SearchRequest, AdapterError> {
+    let body = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(body)) => body,
+        Ok(_) => return Err(AdapterError::invalid_body("The body must be a JSON object")),
+        Err(err) => return Err(AdapterError::invalid_body(err.to_string())),
+    };
+    let request = SearchRequest {
+        query: required_body_field(&body, "query")?,
+        prefix: required_body_field(&body, "prefix")?,
+        max_keys: body_field(&body, "max_keys")?,
+        web_url: body_field(&body, "web_url")?,
+        skip_token: body_field(&body, "skip_token")?,
+        page: body_field(&body, "page")?,
+        sort_by: body_field(&body, "sort_by")?,
+        scope: body_field(&body, "scope")?,
+        extensions: body_field(&body, "extensions")?,
+        modified_after: body_field(&body, "modified_after")?,
+        modified_before: body_field(&body, "modified_before")?,
+        min_size: body_field(&body, "min_size")?,
+        max_size: body_field(&body, "max_size")?,
+    };
+    if request.query.trim().is_empty() {
+        return Err(AdapterError::invalid_field("query", "must not be empty"));
+    }
+    if request
+        .max_keys
+        .is_some_and(|max_keys| !(1..=1000).contains(&max_keys))
+    {
+        return Err(AdapterError::invalid_field(
+            "max_keys",
+            "must be between 1 and 1000",
+        ));
+    }
+    if request
+        .page
+        .is_some_and(|page| !(1..=MAX_SEARCH_PAGE).contains(&page))
+    {
+        return Err(AdapterError::invalid_field(
+            "page",
+            format!("must be between 1 and {}", MAX_SEARCH_PAGE),
+        ));
+    }
+    if request.page.is_some() && request.skip_token.is_some() {
+        return Err(AdapterError::invalid_field(
+            "page",
+            "cannot be combined with skip_token",
+        ));
+    }
+    if !matches!(
+        request.sort_by.as_deref(),
+        None | Some("name" | "last_modified" | "size" | "relevance")
+    ) {
+        return Err(AdapterError::invalid_field(
+            "sort_by",
+            "must be name, last_modified, size or relevance",
+        ));
+    }
+    if !matches!(request.scope.as_deref(), None | Some("name" | "content")) {
+        return Err(AdapterError::invalid_field(
+            "scope",
+            "must be name or content",
+        ));
+    }
+    if let (Some(after), Some(before)) = (request.modified_after, request.modified_before) {
+        if after >= before {
+            return Err(AdapterError::invalid_field(
+                "modified_before",
+                "must be later than modified_after",
+            ));
+        }
+    }
+    if let (Some(min_size), Some(max_size)) = (request.min_size, request.max_size) {
+        if min_size > max_size {
+            return Err(AdapterError::invalid_field(
+                "max_size",
+                "must not be smaller than min_size",
+            ));
+        }
+    }
+    Ok(request)
+}
+
+/// Whether a hit passes the extension, date and size filters of a search.
+/// Graph can filter neither search results nor drive children by these, so
+/// they are applied to each page, which can come back with fewer results.
+fn matches_search_filters(result: &SearchResult, payload: &SearchRequest) -> bool {
+    let extension = Path::new(&result.file_name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let extension_matches = payload.extensions.as_ref().is_none_or(|extensions| {
+        extension.as_ref().is_some_and(|extension| {
+            extensions
+                .iter()
+                .any(|wanted| wanted.trim_start_matches('.').to_lowercase() == *extension)
+        })
+    });
+    // As in listings, results without a timestamp never match a range.
+    let date_matches = (payload.modified_after.is_none() && payload.modified_before.is_none())
+        || result
+            .last_modified
+            .as_deref()
+            .and_then(|modified| DateTime::parse_from_rfc3339(modified).ok())
+            .is_some_and(|modified| {
+                payload.modified_after.is_none_or(|after| modified > after)
+                    && payload
+                        .modified_before
+                        .is_none_or(|before| modified < before)
+            });
+    let size = result.size.unwrap_or_default();
+    extension_matches
+        && date_matches
+        && payload.min_size.is_none_or(|min_size| size >= min_size)
+        && payload.max_size.is_none_or(|max_size| size <= max_size)
+}
+
+/// What a search token is bound to: the bucket, kind of search, prefix and
+/// query it was issued for.
+fn search_scope(bucket: &Bucket, payload: &SearchRequest) -> String {
+    format!(
+        "search\n{}\n{}\n{}\n{}",
+        bucket.name,
+        payload.scope.as_deref().unwrap_or("name"),
+        payload.prefix,
+        payload.query
+    )
+}
+
+/// Name search of the drive, paged by Graph's nextLink.
+async fn search_names(
+    bucket: &Bucket,
+    payload: &SearchRequest,
+) -> Result<SearchPage, AdapterError> {
+    let scope = search_scope(bucket, payload);
+    let mut next_link =
+        match &payload.skip_token {
+            Some(token) => Some(decode_listing_token(token, &scope, false).ok_or_else(|| {
+                AdapterError::invalid_field("skip_token", "is not a search token")
+            })?),
+            None => None,
+        };
+    let search = |next_link| {
+        list_azure_objects(
+            bucket.drive_url(),
+            payload.prefix.clone(),
+            payload.max_keys.unwrap_or(1000),
+            Some(payload.query.clone()),
+            next_link,
+        )
+    };
+    for _ in 1..payload.page.unwrap_or(1) {
+        next_link = search(next_link).await?.next_link;
+        if next_link.is_none() {
+            return Ok(SearchPage {
+                results: Vec::new(),
+                next_token: None,
+                total: 0,
+                sort_scope: None,
+            });
+        }
+    }
+    let objects = search(next_link).await?;
+    let regex = filename_regex();
+    let results = objects
+        .items
+        .iter()
+        .filter(|item| item.folder.is_none() && regex.is_match(&item.name))
+        .map(|item| {
+            let web_url =
+                decode(&item.web_url).map_or(item.web_url.clone(), |web_url| web_url.into_owned());
+            let ending = web_url
+                .rsplit(payload.prefix.as_str())
+                .next()
+                .unwrap_or_default();
+            let full = format!("{}{}", payload.prefix, ending);
+            let path = Path::new(full.as_str());
+            // Search hits may come without the path of their parent, the
+            // key is then derived from the webUrl like the file path.
+            let key = item
+                .parent_reference
+                .as_ref()
+                .and_then(|reference| reference.path.as_deref())
+                .map_or(full.trim_start_matches('/').to_string(), |parent_path| {
+                    key_in_parent(parent_path, &item.name)
+                });
+            SearchResult {
+                file_name: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                file_path: path
+                    .parent()
+                    .map(|parent| parent.display().to_string())
+                    .unwrap_or_default(),
+                web_url: payload
+                    .web_url
+                    .unwrap_or_default()
+                    .then(|| item.web_url.clone()),
+                key,
+                size: item.size,
+                last_modified: item.last_modified_date_time.clone(),
+                mime_type: item.file.as_ref().map(|file| file.mime_type.clone()),
+                e_tag: item.object_e_tag(),
+                summary: None,
+            }
+        })
+        .filter(|result| matches_search_filters(result, payload))
+        .collect::<Vec<SearchResult>>();
+    Ok(SearchPage {
+        total: results.len() as u64,
+        results,
+        next_token: objects
+            .next_link
+            .as_deref()
+            .map(|next_link| encode_continuation_token(next_link, &scope)),
+        sort_scope: None,
+    })
+}
+
+/// Content search of the site, paged by hit offset. Hits outside the drive
+/// of the bucket or the prefix are dropped, the others are keyed by their
+/// webUrl below the drive root, as search hits carry no path.
+async fn search_content(
+    bucket: &Bucket,
+    payload: &SearchRequest,
+) -> Result<SearchPage, AdapterError> {
+    let scope = search_scope(bucket, payload);
+    let size = payload.max_keys.unwrap_or(1000);
+    let from = match (&payload.skip_token, payload.page) {
+        (Some(token), _) => verify_token(token, &scope)
+            .and_then(|offset| offset.parse::<u32>().ok())
+            .ok_or_else(|| AdapterError::invalid_field("skip_token", "is not a search token"))?,
+        (None, Some(page)) => (page - 1) * u32::from(size),
+        (None, None) => 0,
+    };
+    let decoded = |url: &str| nfc(&decode(url).map_or(url.to_string(), |url| url.into_owned()));
+    let root = get_azure_item(bucket.drive_url(), String::new()).await?;
+    let root_url = decoded(&root.web_url);
+    let prefix = normalize_prefix(&payload.prefix);
+    let page = search_azure_content(&bucket.site_id, &payload.query, from, size).await?;
+    let regex = filename_regex();
+    let results = page
+        .hits
+        .iter()
+        .filter_map(|hit| {
+            let resource = &hit.resource;
+            let key = decoded(&resource.web_url)
+                .strip_prefix(&root_url)?
+                .strip_prefix('/')?
+                .to_string();
+            if !key.starts_with(&prefix) || !regex.is_match(&resource.name) {
+                return None;
+            }
+            let full = format!("/{}", key);
+            Some(SearchResult {
+                file_name: resource.name.clone(),
+                file_path: Path::new(full.as_str())
+                    .parent()
+                    .map(|parent| parent.display().to_string())
+                    .unwrap_or_default(),
+                web_url: payload
+                    .web_url
+                    .unwrap_or_default()
+                    .then(|| resource.web_url.clone()),
+                key,
+                size: resource.size,
+                last_modified: resource.last_modified_date_time.clone(),
+                mime_type: resource.file.as_ref().map(|file| file.mime_type.clone()),
+                // Search hits carry neither hashes nor a cTag.
+                e_tag: None,
+                summary: hit.summary.clone(),
+            })
+        })
+        .filter(|result| matches_search_filters(result, payload))
+        .collect::<Vec<SearchResult>>();
+    Ok(SearchPage {
+        results,
+        next_token: page
+            .more_results_available
+            .then(|| sign_token(&(from as usize + page.hits.len()).to_string(), &scope)),
+        total: page.total,
+        sort_scope: None,
+    })
+}
+
+#[handler]
+async fn search_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AdapterError> {
+    let payload = validate_search_request(req.payload().await?)?;
+    if !is_in_token_scope(depot, &payload.prefix) {
+        return Err(S3Error::access_denied().into());
+    }
+    let bucket = current_bucket(depot);
+    let mut page = match payload.scope.as_deref() {
+        Some("content") => search_content(&bucket, &payload).await?,
+        _ => search_names(&bucket, &payload).await?,
+    };
+    let results = &mut page.results;
+    match payload.sort_by.as_deref().unwrap_or_default() {
+        "name" => results.sort_by(|a, b| a.file_name.cmp(&b.file_name)),
+        // Graph timestamps share one format, so they sort as strings.
+        "last_modified" => results.sort_by(|a, b| b.last_modified.cmp(&a.last_modified)),
+        "size" => results.sort_by_key(|result| std::cmp::Reverse(result.size)),
+        _ => {}
+    }
+    if !matches!(payload.sort_by.as_deref(), None | Some("relevance")) {
+        page.sort_scope = Some("page");
+    }
+    res.status_code(StatusCode::OK).render(Json(page));
+    Ok(())
+}
+
+/// Downloads an object, as it was at the time of a snapshot view.
+async fn fetch_object(
+    bucket: &Bucket,
+    key: &str,
+    range: Option<String>,
+    conditions: &Conditions,
+) -> Result<GetAzureObjectResponse, reqwest::Error> {
+    match bucket.as_of {
+        Some(as_of) => {
+            get_snapshot_object_data(
+                bucket.drive_url(),
+                key.to_string(),
+                as_of,
+                range,
+                conditions,
+            )
+            .await
+        }
+        None => get_azure_object_data(bucket.drive_url(), key.to_string(), range, conditions).await,
+    }
+}
+
+#[handler]
+async fn get_object(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AdapterError> {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let key = current_key(depot);
+    // Range support is advertised on every answer, errors included, so
+    // mounted filesystems see the same capabilities on each call.
+    res.headers_mut()
+        .insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+    if !regex.is_match(&key) {
+        res.render(S3Error::access_denied());
+        return Ok(());
+    }
+    // The slot is held until the body has been streamed.
+    let permit = match depot.get::<String>("rate_limit_client") {
+        Ok(client) => match try_download(client) {
+            Some(permit) => Some(permit),
+            None => {
+                warn!("Too many concurrent downloads by {}", client);
+                res.render(S3Error::slow_down("Too many concurrent downloads."));
+                return Ok(());
+            }
+        },
+        Err(_) => None,
+    };
+    // `?format=pdf` answers Office documents converted by Graph, as a whole
+    // and unconditionally. PDFs are served as they are.
+    let as_pdf = req.query::<String>("format").as_deref() == Some("pdf")
+        && !key.to_lowercase().ends_with(".pdf");
+    if as_pdf {
+        if bucket.as_of.is_some() {
+            res.render(S3Error::invalid_argument(
+                "Documents of snapshot views cannot be converted",
+            ));
+            return Ok(());
+        }
+        if !is_pdf_convertible(&key) {
+            res.render(S3Error::invalid_argument(
+                "Only Office documents can be converted to PDF",
+            ));
+            return Ok(());
+        }
+    }
+    let conditions = Conditions::from_headers(req.headers());
+    // Single ranges are forwarded, several ranges are fetched concurrently
+    // and answered as multipart/byteranges. Above MAX_RANGES the full object
+    // is served, which RFC 9110 allows. Conditional multi-range reads take
+    // the full object path so that a current copy is answered with 304.
+    let range = match req
+        .header::<String>("Range")
+        .filter(|_| !as_pdf)
+        .map(|range| parse_range(&range))
+    {
+        Some(Ok(ranges)) if ranges.len() == 1 => Some(ranges[0]),
+        Some(Ok(ranges))
+            if ranges.len() <= config().max_ranges
+                && conditions.is_empty()
+                && bucket.as_of.is_none() =>
+        {
+            match fetch_byteranges(bucket.drive_url(), key.clone(), &ranges).await {
+                Ok(Some((_, parts))) if parts.is_empty() => {
+                    res.render(
+                        S3Error::new(
+                            StatusCode::RANGE_NOT_SATISFIABLE,
+                            "InvalidRange",
+                            "The requested range is not satisfiable",
+                        )
+                        .with_resource(key),
+                    );
+                    return Ok(());
+                }
+                Ok(Some((content_type, parts))) => {
+                    let boundary = rand::thread_rng()
+                        .sample_iter(&Alphanumeric)
+                        .take(24)
+                        .map(char::from)
+                        .collect::<String>();
+                    res.status_code(StatusCode::PARTIAL_CONTENT);
+                    res.headers_mut().insert(
+                        "Content-Type",
+                        format!("multipart/byteranges; boundary={}", boundary).parse()?,
+                    );
+                    let _ = res.write_body(multipart_byteranges(&boundary, &content_type, parts));
+                    return Ok(());
+                }
+                Ok(None) => None,
+                Err(err) => {
+                    res.render(S3Error::from(err).with_resource(key));
+                    return Ok(());
+                }
+            }
+        }
+        Some(Ok(_)) | None => None,
+        // A Range header that does not parse is ignored, as RFC 9110 asks,
+        // and S3 does the same.
+        Some(Err(err)) => {
+            debug!("Ignoring Range header: {}", err);
+            None
+        }
+    };
+    // Tails of large files and bounded ranges of sequential readers may
+    // already be in memory.
+    let object = format!("{}:{}", bucket.drive_url(), key);
+    let overrides = current_overrides(depot);
+    let use_cache = !overrides.cache_bypass && bucket.as_of.is_none() && !as_pdf;
+    let buffered = range
+        .filter(|_| use_cache && conditions.is_empty() && is_tail_cache_enabled())
+        .and_then(|range| cached_tail(&object, range))
+        .or_else(|| {
+            range
+                .and_then(|range| range.start.zip(range.end))
+                .filter(|_| use_cache && conditions.is_empty() && is_read_ahead_enabled())
+                .and_then(|(start, end)| buffered_range(&object, start, end))
+        });
+    let mut response = match buffered {
+        Some(buffered) => Ok(buffered),
+        None if as_pdf => get_azure_object_pdf(bucket.drive_url(), key.clone()).await,
+        None => {
+            fetch_object(
+                &bucket,
+                &key,
+                range.map(|range| range.to_header()),
+                &conditions,
+            )
+            .await
+        }
+    };
+    // Watermarks are stamped on whole documents, so partial PDFs are refetched.
+    if let Ok(partial) = &response {
+        if partial.status_code == 206 && is_watermark_enabled(&partial.content_type) {
+            response = fetch_object(&bucket, &key, None, &conditions).await;
+        }
+    }
+    match response {
+        Ok(result) if result.status_code == 304 => {
+            res.status_code(StatusCode::NOT_MODIFIED);
+            set_validators(
+                res,
+                result.e_tag.as_deref(),
+                result.last_modified.as_deref(),
+            )?;
+        }
+        Ok(result) if result.status_code == 416 => {
+            if let Some(content_range) = result.content_range {
+                res.headers_mut()
+                    .insert("Content-Range", content_range.parse()?);
+            }
+            res.render(
+                S3Error::new(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "InvalidRange",
+                    "The requested range is not satisfiable",
+                )
+                .with_resource(key),
+            );
+        }
+        Ok(result) if result.status_code >= 400 => {
+            res.render(S3Error::from_graph_status(result.status_code).with_resource(key));
+        }
+        Ok(result) => {
+            // Objects above MAX_PROXY_SIZE are handed off to SharePoint
+            // directly, unless they have to pass the watermark service.
+            let object_size = result
+                .content_range
+                .as_deref()
+                .and_then(parse_content_range)
+                .map(|(_, _, total)| total)
+                .or(result.size);
+            let redirect = match overrides.download_mode {
+                Some(DownloadMode::Redirect) => true,
+                Some(DownloadMode::Proxy) => false,
+                None => object_size.is_some_and(|object_size| {
+                    config().max_proxy_size > 0 && object_size > config().max_proxy_size
+                }),
+            };
+            if let Some(download_url) = &result.download_url {
+                if redirect && !is_watermark_enabled(&result.content_type) {
+                    info!(
+                        "Redirecting {} of {:?} bytes to SharePoint",
+                        key, object_size
+                    );
+                    res.render(Redirect::found(download_url));
+                    return Ok(());
+                }
+            }
+            res.status_code(StatusCode::from_u16(result.status_code).unwrap_or(StatusCode::OK));
+            if let Some(content_range) = result
+                .content_range
+                .as_ref()
+                .filter(|_| result.status_code == 206)
+            {
+                res.headers_mut()
+                    .insert("Content-Range", content_range.parse()?);
+            }
+            res.headers_mut()
+                .insert("Content-Type", result.content_type.parse()?);
+            res.headers_mut().insert(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", result.file_name).parse()?,
+            );
+            // The watermarked document differs from the stored one, so it
+            // must not claim the item's ETag.
+            set_validators(
+                res,
+                result
+                    .e_tag
+                    .as_deref()
+                    .filter(|_| !is_watermark_enabled(&result.content_type)),
+                result.last_modified.as_deref(),
+            )?;
+            set_user_metadata(res, &bucket, &key).await;
+            if is_watermark_enabled(&result.content_type) {
+                let context = WatermarkContext {
+                    subject: depot
+                        .get::<String>("on_behalf_of")
+                        .or(depot.get::<String>("caller"))
+                        .cloned()
+                        .unwrap_or_default(),
+                    timestamp: Utc::now(),
+                };
+                let watermarked = match result.collect().await {
+                    Ok(data) => apply_pdf_watermark(data, &context).await,
+                    Err(err) => Err(err),
+                };
+                match watermarked {
+                    Ok(data) => {
+                        let _ = res.write_body(data);
+                    }
+                    Err(err) => {
+                        warn!("Watermarking {} failed: {}", key, err);
+                        res.render(S3Error::new(
+                            StatusCode::BAD_GATEWAY,
+                            "InternalError",
+                            err.to_string(),
+                        ));
+                    }
+                }
+                return Ok(());
+            }
+            if let Some(size) = result.size {
+                res.headers_mut().insert("Content-Length", size.into());
+            }
+            if let Some((start, end, total)) = result
+                .content_range
+                .as_deref()
+                .and_then(parse_content_range)
+                .filter(|_| result.status_code == 206)
+            {
+                let read = RangeRead {
+                    drive: bucket.drive_url(),
+                    key: key.clone(),
+                    start,
+                    end,
+                    total,
+                    content_type: result.content_type.clone(),
+                    file_name: result.file_name.clone(),
+                    e_tag: result.e_tag.clone(),
+                    last_modified: result.last_modified.clone(),
+                };
+                if use_cache && is_tail_cache_enabled() {
+                    record_tail_read(read.clone());
+                }
+                if use_cache
+                    && is_read_ahead_enabled()
+                    && range.is_some_and(|range| range.end.is_some())
+                {
+                    record_range_read(read);
+                }
+            }
+            let stream =
+                MeteredStream::new(PermittedStream::new(result.stream, permit), key.clone());
+            if is_shadow_enabled() && result.status_code == 200 {
+                res.stream(ShadowedStream::new(stream, key.clone()));
+            } else {
+                res.stream(stream);
+            }
+        }
+        Err(err) => {
+            res.render(S3Error::from(err).with_resource(key));
+        }
+    }
+    Ok(())
+}
+
+type ByteRangeParts = (String, Vec<(String, Vec<u8>)>);
+
+/// Fetches several ranges of an object concurrently, returning the content
+/// type and the satisfiable parts. `None` means the object has to be served
+/// whole, because Graph ignored a range or the document gets watermarked.
+async fn fetch_byteranges(
+    drive: String,
+    key: String,
+    ranges: &[ByteRange],
+) -> Result<Option<ByteRangeParts>, reqwest::Error> {
+    let conditions = Conditions::default();
+    let responses = join_all(ranges.iter().map(|range| {
+        get_azure_object_data(
+            drive.clone(),
+            key.clone(),
+            Some(range.to_header()),
+            &conditions,
+        )
+    }))
+    .await;
+    let mut content_type = String::new();
+    let mut parts = Vec::new();
+    for response in responses {
+        let response = response?;
+        match response.status_code {
+            206 if !is_watermark_enabled(&response.content_type) => {}
+            416 => continue,
+            _ => return Ok(None),
+        }
+        content_type = response.content_type.clone();
+        let content_range = response.content_range.clone().unwrap_or_default();
+        parts.push((content_range, response.collect().await?));
+    }
+    Ok(Some((content_type, parts)))
+}
+
+/// Audits a side effect of a request, such as the key a write was
+/// redirected to. The record of the request itself carries the key as sent,
+/// under the same request id.
+fn audit_side_effect(depot: &Depot, operation: &'static str, key: &str, status: u16) {
+    if !is_audit_enabled() {
+        return;
+    }
+    record_audit(AuditRecord {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        request_id: depot
+            .get::<String>("request_id")
+            .cloned()
+            .unwrap_or_default(),
+        caller: depot.get::<String>("caller").ok().cloned(),
+        on_behalf_of: depot.get::<String>("on_behalf_of").ok().cloned(),
+        source_ip: None,
+        operation,
+        bucket: depot
+            .get::<Bucket>("bucket")
+            .ok()
+            .map(|bucket| bucket.name.clone()),
+        key: key.to_string(),
+        status,
+        bytes_received: 0,
+        bytes_sent: 0,
+        complete: true,
+    });
+}
+
+/// Validates the key of a write, sanitizing it when configured to. Keys
+/// violating `FILENAME_PATTERN` are rejected, or moved below
+/// `QUARANTINE_PREFIX` for uploads when one is configured. Renders the
+/// rejection and returns `None` for keys that cannot be written.
+fn destination_key(depot: &Depot, res: &mut Response, quarantine: bool) -> Option<String> {
+    let regex = filename_regex();
+    let mut key = current_key(depot);
+    if let Err(invalid) = validate_key(&key) {
+        let sanitized = sanitize_key(&key);
+        if config().sanitize_keys && validate_key(&sanitized).is_ok() {
+            warn!("Sanitized key {} to {}", key, sanitized);
+            audit_side_effect(depot, "SanitizeKey", &sanitized, 200);
+            key = sanitized;
+        } else {
+            res.status_code(StatusCode::BAD_REQUEST)
+                .render(Text::Xml(generate_s3_error_response(
+                    "InvalidObjectName",
+                    &invalid.reason,
+                    &[
+                        ("Key", key.clone()),
+                        (
+                            "InvalidCharacters",
+                            invalid.invalid_characters.iter().collect::<String>(),
+                        ),
+                    ],
+                )));
+            return None;
+        }
+    }
+    if !regex.is_match(&key) {
+        let quarantine_prefix = config()
+            .quarantine_prefix
+            .as_deref()
+            .map(|prefix| prefix.trim_matches('/'))
+            .filter(|prefix| quarantine && !prefix.is_empty());
+        let Some(quarantine_prefix) = quarantine_prefix else {
+            res.render(S3Error::access_denied());
+            return None;
+        };
+        let quarantined = format!("{}/{}", quarantine_prefix, key);
+        warn!(
+            "Quarantined upload of {} violating the filename pattern to {}",
+            key, quarantined
+        );
+        audit_side_effect(depot, "QuarantineKey", &quarantined, 200);
+        res.headers_mut().insert(
+            "Warning",
+            "199 - \"Key violates the filename policy and was quarantined\""
+                .parse()
+                .unwrap(),
+        );
+        return Some(quarantined);
+    }
+    Some(key)
+}
+
+#[handler]
+async fn put_object(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AdapterError> {
+    let bucket = current_bucket(depot);
+    let Some(key) = destination_key(depot, res, true) else {
+        return Ok(());
+    };
+    let content_type = req
+        .header::<String>("Content-Type")
+        .unwrap_or("application/octet-stream".to_string());
+    let size = req.header::<u64>("Content-Length").unwrap_or_default();
+    let fields = fields_from_headers(req.headers());
+    let result = if size > upload_chunk_size() as u64 {
+        if size > config().max_upload_size as u64 {
+            res.render(entity_too_large(format!(
+                "Body of {} bytes exceeds MAX_UPLOAD_SIZE",
+                size
+            )));
+            return Ok(());
+        }
+        invalidate_caches(&bucket.drive_url(), &key);
+        stream_upload(req, &bucket, &key, size).await
+    } else {
+        let data = match req.payload_with_max_size(config().max_upload_size).await {
+            Ok(data) => data.to_vec(),
+            Err(ParseError::Other(err)) if err.is::<LengthLimitError>() => {
+                res.render(entity_too_large(err.to_string()));
+                return Ok(());
+            }
+            Err(err) => {
+                // Anything else means the body stopped arriving.
+                warn!("Client aborted the upload of {}: {}", key, err);
+                record_abort(Abort::Client);
+                res.render(incomplete_body());
+                return Ok(());
+            }
+        };
+        invalidate_caches(&bucket.drive_url(), &key);
+        put_azure_object(bucket.drive_url(), key.clone(), content_type, data)
+            .await
+            .map_err(S3Error::from)
+    };
+    invalidate_caches(&bucket.drive_url(), &key);
+    // The columns are written once the file exists. The upload stands
+    // when that fails, so the client is not told to retry a stored file.
+    if result.is_ok() && !fields.is_empty() {
+        let items = vec![(bucket.drive_url(), key.clone())];
+        let failure = match update_azure_fields(items, &serde_json::Value::Object(fields)).await {
+            Ok(statuses) => match statuses.first() {
+                Some(status) if (200..300).contains(status) => None,
+                status => {
+                    let status = status.copied().unwrap_or(500);
+                    Some((status, format!("Graph answered {}", status)))
+                }
+            },
+            Err(err) => Some((
+                err.status().map_or(500, |status| status.as_u16()),
+                err.to_string(),
+            )),
+        };
+        if let Some((status, reason)) = failure {
+            error!("Writing the fields of {} failed: {}", key, reason);
+            audit_side_effect(depot, "PutObjectFields", &key, status);
+            res.headers_mut().insert(
+                "Warning",
+                "199 - \"The object was stored but its metadata was not written\"".parse()?,
+            );
+        }
+    }
+    match result {
+        Ok(item) => {
+            record_write(
+                &bucket.drive_url(),
+                &key,
+                Write::Put(Box::new(item.clone())),
+            );
+            if let Some(e_tag) = item.object_e_tag() {
+                res.headers_mut().insert("ETag", e_tag.parse()?);
+            }
+            res.headers_mut()
+                .insert("x-adapter-key", urlencoding::encode(&key).parse()?);
+            res.status_code(StatusCode::OK);
+        }
+        Err(err) => {
+            res.render(err.with_resource(key));
+        }
+    }
+    Ok(())
+}
+
+/// Drops the cached reads and listings of a key. Writes do this before
+/// calling Graph and again once it answered, so a read racing the write
+/// cannot put the old state back into a cache.
+fn invalidate_caches(drive: &str, key: &str) {
+    invalidate_read_ahead(drive, key);
+    invalidate_tail(drive, key);
+    invalidate_listings(drive, key);
+}
+
+fn entity_too_large(message: String) -> S3Error {
+    S3Error::new(StatusCode::BAD_REQUEST, "EntityTooLarge", message)
+}
+
+fn incomplete_body() -> S3Error {
+    S3Error::new(
+        StatusCode::BAD_REQUEST,
+        "IncompleteBody",
+        "You did not provide the number of bytes specified by the Content-Length HTTP header.",
+    )
+}
+
+/// Graph wants upload session chunks in multiples of 320 KiB.
+fn upload_chunk_size() -> usize {
+    const GRANULE: usize = 320 * 1024;
+    (config().upload_chunk_size / GRANULE).max(1) * GRANULE
+}
+
+/// Streams a body larger than one chunk into a Graph upload session. The
+/// next chunk is only read from the client once Graph acknowledged the
+/// previous one, so slow uploads to Graph slow down the client instead of
+/// filling memory.
+async fn stream_upload(
+    req: &mut Request,
+    bucket: &Bucket,
+    key: &str,
+    size: u64,
+) -> Result<Item, S3Error> {
+    let mut upload = create_azure_upload_session(bucket.drive_url(), key.to_string(), size)
+        .await
+        .map_err(S3Error::from)?;
+    let chunk_size = upload_chunk_size();
+    let mut body = req.take_body();
+    let mut buffer = BytesMut::with_capacity(chunk_size);
+    loop {
+        let data = match body.next().await {
+            Some(Ok(frame)) => match frame.into_data() {
+                Ok(data) => Some(data),
+                Err(_) => continue,
+            },
+            Some(Err(err)) => {
+                warn!("Client aborted the upload of {}: {}", key, err);
+                record_abort(Abort::Client);
+                upload.cancel().await;
+                return Err(incomplete_body());
+            }
+            None => None,
+        };
+        let finished = data.is_none();
+        if let Some(data) = data {
+            buffer.extend_from_slice(&data);
+        }
+        while buffer.len() >= chunk_size || (finished && !buffer.is_empty()) {
+            let chunk = buffer.split_to(chunk_size.min(buffer.len())).freeze();
+            match upload.put_chunk(chunk).await {
+                Ok(Some(item)) => return Ok(item),
+                Ok(None) => {}
+                Err(err) => {
+                    upload.cancel().await;
+                    return Err(S3Error::from(err));
+                }
+            }
+        }
+        if finished {
+            // The body ended before the declared size arrived.
+            upload.cancel().await;
+            return Err(incomplete_body());
+        }
+    }
+}
+
+#[handler]
+async fn copy_object(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let Some(key) = destination_key(depot, res, false) else {
+        return;
+    };
+    // x-amz-copy-source is `[/]bucket/key[?versionId=...]`, URL-encoded.
+    let copy_source = req
+        .header::<String>("x-amz-copy-source")
+        .unwrap_or_default();
+    let (source_bucket, source_key) =
+        split_path(copy_source.split('?').next().unwrap_or_default(), true).unwrap_or_default();
+    let Some(source_bucket) = source_bucket.filter(|_| !source_key.is_empty()) else {
+        res.render(S3Error::invalid_argument(
+            "Copy Source must mention the source bucket and key",
+        ));
+        return;
+    };
+    // Without bucket mappings every bucket name addresses the one site.
+    let source_bucket = if is_multi_bucket() {
+        find_bucket(&source_bucket)
+    } else {
+        Some(bucket.clone())
+    };
+    let Some(source_bucket) = source_bucket else {
+        res.render(S3Error::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchBucket",
+            "The specified source bucket does not exist",
+        ));
+        return;
+    };
+    // Graph copies the current state, which a snapshot view must not hand out.
+    if source_bucket.as_of.is_some()
+        || !regex.is_match(&source_key)
+        || !is_in_token_scope(depot, &source_key)
+    {
+        res.render(S3Error::access_denied());
+        return;
+    }
+    invalidate_caches(&bucket.drive_url(), &key);
+    let result = copy_azure_object(
+        source_bucket.drive_url(),
+        source_key,
+        bucket.drive_url(),
+        key.clone(),
+    )
+    .await;
+    invalidate_caches(&bucket.drive_url(), &key);
+    match result {
+        Ok(CopyOutcome::Completed(item)) => {
+            record_write(&bucket.drive_url(), &key, Write::Put(item.clone()));
+            res.status_code(StatusCode::OK).render(Text::Xml(
+                generate_s3_copy_object_result_response(
+                    &item.object_e_tag().unwrap_or_default(),
+                    &item.last_modified_date_time.unwrap_or_default(),
+                ),
+            ));
+        }
+        Ok(CopyOutcome::Failed(reason)) => {
+            res.render(S3Error::internal_error(reason).with_resource(key));
+        }
+        Err(err) => {
+            res.render(S3Error::from(err).with_resource(key));
+        }
+    }
+}
+
+#[handler]
+async fn delete_object(depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let key = current_key(depot);
+    if !regex.is_match(&key) {
+        res.render(S3Error::access_denied());
+        return;
+    }
+    invalidate_caches(&bucket.drive_url(), &key);
+    let result = delete_azure_object(bucket.drive_url(), key.clone()).await;
+    invalidate_caches(&bucket.drive_url(), &key);
+    match result {
+        Ok(()) => {
+            record_write(&bucket.drive_url(), &key, Write::Deleted);
+            res.status_code(StatusCode::NO_CONTENT);
+        }
+        Err(err) => {
+            res.render(S3Error::from(err).with_resource(key));
+        }
+    }
+}
+
+#[handler]
+async fn delete_objects(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let request = match req.payload().await.map_err(|err| err.to_string()) {
+        Ok(body) => parse_s3_delete_request(body),
+        Err(err) => Err(err),
+    };
+    let request = match request {
+        Ok(request) if request.keys.len() <= 1000 => request,
+        Ok(_) => {
+            res.render(S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "MalformedXML",
+                "A maximum of 1000 keys can be deleted per request",
+            ));
+            return;
+        }
+        Err(err) => {
+            res.render(S3Error::new(StatusCode::BAD_REQUEST, "MalformedXML", err));
+            return;
+        }
+    };
+    let mut deleted = Vec::new();
+    let mut errors = Vec::new();
+    for key in request.keys {
+        let error = if !regex.is_match(&key) || !is_in_token_scope(depot, &key) {
+            S3Error::access_denied()
+        } else {
+            invalidate_caches(&bucket.drive_url(), &key);
+            let result = delete_azure_object(bucket.drive_url(), key.clone()).await;
+            invalidate_caches(&bucket.drive_url(), &key);
+            match result {
+                Ok(()) => {
+                    record_write(&bucket.drive_url(), &key, Write::Deleted);
+                    deleted.push(key);
+                    continue;
+                }
+                Err(err) => S3Error::from(err),
+            }
+        };
+        errors.push(DeleteError {
+            key,
+            code: error.code.to_string(),
+            message: error.message,
+        });
+    }
+    res.status_code(StatusCode::OK)
+        .render(Text::Xml(generate_s3_delete_result_response(
+            deleted,
+            errors,
+            request.quiet,
+        )));
+}
+
+const OBJECT_ATTRIBUTES: [&str; 5] = [
+    "ETag",
+    "Checksum",
+    "ObjectParts",
+    "StorageClass",
+    "ObjectSize",
+];
+
+/// Graph hashes are hex, S3 checksums base64 of the digest.
+fn hex_to_base64(hash: &str) -> Option<String> {
+    hex::decode(hash).ok().map(|digest| STANDARD.encode(digest))
+}
+
+/// GetObjectAttributes, answering the attributes named in
+/// `x-amz-object-attributes` from the Graph item. Checksums are the SHA
+/// hashes Graph reports, which SharePoint only computes for some files;
+/// its `quickXorHash` has no S3 counterpart. Files are never uploaded in
+/// S3 parts, so `ObjectParts` is always left out.
+#[handler]
+async fn attributes_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AdapterError> {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let key = current_key(depot);
+    if !regex.is_match(&key) {
+        res.render(S3Error::access_denied());
+        return Ok(());
+    }
+    let requested = req
+        .header::<String>("x-amz-object-attributes")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|attribute| !attribute.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<String>>();
+    if requested.is_empty() {
+        res.render(S3Error::invalid_argument(
+            "The x-amz-object-attributes header specifying the attributes to be retrieved is either missing or empty",
+        ));
+        return Ok(());
+    }
+    if let Some(attribute) = requested
+        .iter()
+        .find(|attribute| !OBJECT_ATTRIBUTES.contains(&attribute.as_str()))
+    {
+        res.render(S3Error::invalid_argument(format!(
+            "Invalid attribute name specified: {}",
+            attribute
+        )));
+        return Ok(());
+    }
+    let wants = |attribute: &str| requested.iter().any(|requested| requested == attribute);
+    let (e_tag, size, last_modified, hashes) = match bucket.as_of {
+        // Views know the size and version of a file, not its hashes.
+        Some(as_of) => match head_snapshot_object(bucket.drive_url(), key.clone(), as_of).await {
+            Ok(head) if head.status_code == 200 => {
+                (head.e_tag, head.size, head.last_modified, None)
+            }
+            Ok(head) => {
+                res.render(S3Error::from_graph_status(head.status_code).with_resource(key));
+                return Ok(());
+            }
+            Err(err) => {
+                res.render(S3Error::from(err).with_resource(key));
+                return Ok(());
+            }
+        },
+        None => match get_azure_item(bucket.drive_url(), key.clone()).await {
+            Ok(item) if item.file.is_some() => (
+                item.object_e_tag(),
+                item.size.unwrap_or_default(),
+                item.last_modified_date_time,
+                item.file.and_then(|file| file.hashes),
+            ),
+            Ok(_) => {
+                res.render(S3Error::no_such_key().with_resource(key));
+                return Ok(());
+            }
+            Err(err) => {
+                res.render(S3Error::from(err).with_resource(key));
+                return Ok(());
+            }
+        },
+    };
+    let mut checksums = Vec::new();
+    if let Some(hashes) = hashes.filter(|_| wants("Checksum")) {
+        if let Some(sha1) = hashes.sha1_hash.as_deref().and_then(hex_to_base64) {
+            checksums.push(("ChecksumSHA1", sha1));
+        }
+        if let Some(sha256) = hashes.sha256_hash.as_deref().and_then(hex_to_base64) {
+            checksums.push(("ChecksumSHA256", sha256));
+        }
+    }
+    if let Some(last_modified) = last_modified.as_deref().and_then(http_date) {
+        res.headers_mut()
+            .insert("Last-Modified", last_modified.parse()?);
+    }
+    let attributes = ObjectAttributes {
+        // Unquoted, unlike the ETag header.
+        e_tag: e_tag
+            .filter(|_| wants("ETag"))
+            .map(|e_tag| e_tag.trim_matches('"').to_string()),
+        checksums,
+        storage_class: wants("StorageClass").then_some("STANDARD"),
+        object_size: wants("ObjectSize").then_some(size),
+    };
+    res.status_code(StatusCode::OK)
+        .render(Text::Xml(generate_s3_object_attributes_response(
+            attributes,
+        )));
+    Ok(())
+}
+
+/// The bucket sub-resource a request asks for, e.g. `location`.
+fn bucket_subresource(req: &Request) -> Option<&'static str> {
+    BUCKET_SUBRESOURCES
+        .into_iter()
+        .find(|subresource| req.queries().contains_key(*subresource))
+}
+
+/// Answers the bucket sub-resources clients probe with static documents.
+#[handler]
+async fn bucket_subresource_handler(req: &mut Request, res: &mut Response) {
+    let subresource = bucket_subresource(req).unwrap_or_default();
+    match bucket_subresource_response(subresource) {
+        Ok(document) => {
+            res.status_code(StatusCode::OK).render(Text::Xml(document));
+        }
+        Err(err) => res.render(err),
+    }
+}
+
+/// S3 allows no more tags on an object.
+const MAX_TAGS: usize = 10;
+
+/// Reads the `TAG_FIELDS` columns of a file's listItem as S3 tags. Empty
+/// columns are left out, as S3 has no tags without a value.
+#[handler]
+async fn get_tagging_handler(depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let key = current_key(depot);
+    if !regex.is_match(&key) {
+        res.render(S3Error::access_denied());
+        return;
+    }
+    match get_azure_item_fields(bucket.drive_url(), key.clone()).await {
+        Ok(fields) => {
+            let tags = config()
+                .tag_fields
+                .iter()
+                .filter_map(|name| {
+                    let value = match fields.get(name)? {
+                        serde_json::Value::Null => return None,
+                        serde_json::Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    };
+                    Some((name.clone(), value))
+                })
+                .collect();
+            res.status_code(StatusCode::OK)
+                .render(Text::Xml(generate_s3_tagging_response(tags)));
+        }
+        Err(err) => {
+            res.render(S3Error::from(err).with_resource(key));
+        }
+    }
+}
+
+/// Replaces the tags of a file by writing the `TAG_FIELDS` columns of its
+/// listItem. Columns missing from the tag set are cleared, as S3 replaces
+/// the whole set; other columns cannot be written as tags.
+#[handler]
+async fn put_tagging_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let key = current_key(depot);
+    if !regex.is_match(&key) {
+        res.render(S3Error::access_denied());
+        return;
+    }
+    let tags = match req.payload().await.map_err(|err| err.to_string()) {
+        Ok(body) => parse_s3_tagging_request(body),
+        Err(err) => Err(err),
+    };
+    let tags = match tags {
+        Ok(tags) => tags,
+        Err(err) => {
+            res.render(S3Error::new(StatusCode::BAD_REQUEST, "MalformedXML", err));
+            return;
+        }
+    };
+    if tags.len() > MAX_TAGS {
+        res.render(S3Error::new(
+            StatusCode::BAD_REQUEST,
+            "BadRequest",
+            "Object tags cannot be greater than 10",
+        ));
+        return;
+    }
+    let mut fields = serde_json::Map::new();
+    for (name, value) in tags {
+        if !config().tag_fields.contains(&name) {
+            res.render(S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidTag",
+                format!("{} is not one of TAG_FIELDS", name),
+            ));
+            return;
+        }
+        if fields.contains_key(&name) {
+            res.render(S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidTag",
+                format!("Cannot provide multiple tags with the same key {}", name),
+            ));
+            return;
+        }
+        fields.insert(name, serde_json::Value::String(value));
+    }
+    for name in &config().tag_fields {
+        fields
+            .entry(name.clone())
+            .or_insert(serde_json::Value::Null);
+    }
+    let items = vec![(bucket.drive_url(), key.clone())];
+    match update_azure_fields(items, &serde_json::Value::Object(fields)).await {
+        Ok(statuses) => match statuses.first() {
+            Some(status) if (200..300).contains(status) => {
+                res.status_code(StatusCode::OK);
+            }
+            status => {
+                res.render(
+                    S3Error::from_graph_status(status.copied().unwrap_or(500)).with_resource(key),
+                );
+            }
+        },
+        Err(err) => {
+            res.render(S3Error::from(err).with_resource(key));
+        }
+    }
+}
+
+#[handler]
+async fn sharing_handler(depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let key = current_key(depot);
+    if !regex.is_match(&key) {
+        res.render(S3Error::access_denied());
+        return;
+    }
+    match list_azure_permissions(bucket.drive_url(), key.clone()).await {
+        Ok(result) => {
+            res.status_code(StatusCode::OK)
+                .render(Json(result.permissions));
+        }
+        Err(err) => {
+            res.render(S3Error::from(err).with_resource(key));
+        }
+    }
+}
+
+#[handler]
+async fn share_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let key = current_key(depot);
+    if !regex.is_match(&key) {
+        res.render(S3Error::access_denied());
+        return;
+    }
+    let payload = match req.parse_json::<ShareRequest>().await {
+        Ok(payload) => payload,
+        Err(err) => {
+            res.render(S3Error::invalid_argument(err.to_string()));
+            return;
+        }
+    };
+    if !matches!(payload.scope.as_str(), "organization" | "anonymous")
+        || !matches!(payload.link_type.as_str(), "view" | "edit")
+    {
+        res.render(S3Error::invalid_argument(
+            "scope must be organization or anonymous, type must be view or edit",
+        ));
+        return;
+    }
+    match create_azure_sharing_link(bucket.drive_url(), key.clone(), payload).await {
+        Ok(permission) => match permission.link.and_then(|link| {
+            link.web_url.map(|web_url| ShareResult {
+                web_url,
+                scope: link.scope,
+                expiration_date_time: permission.expiration_date_time,
+            })
+        }) {
+            Some(result) => {
+                res.status_code(StatusCode::OK).render(Json(result));
+            }
+            None => {
+                res.render(S3Error::new(
+                    StatusCode::BAD_GATEWAY,
+                    "InternalError",
+                    "SharePoint did not return a sharing link",
+                ));
+            }
+        },
+        Err(err) => {
+            res.render(S3Error::from(err).with_resource(key));
+        }
+    }
+}
+
+#[handler]
+async fn create_cursor_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let prefix = prefix_query(req)
+        .unwrap_or("/".to_string())
+        .trim_end_matches("/")
+        .to_string();
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let key_prefix = normalize_prefix(&prefix);
+    let use_cache = !current_overrides(depot).cache_bypass;
+    let mut entries = Vec::new();
+    let mut next_link = None;
+    loop {
+        match list_page(&bucket, prefix.clone(), 1000, false, next_link, use_cache).await {
+            Ok(objects) => {
+                entries.extend(objects.items.into_iter().filter_map(|item| {
+                    if item.folder.is_some() {
+                        Some(CursorEntry {
+                            key: format!("{}{}/", key_prefix, item.name),
+                            size: 0,
+                            last_modified: item.last_modified_date_time,
+                            e_tag: None,
+                        })
+                    } else if item.file.is_some() && regex.is_match(&item.name.to_lowercase()) {
+                        Some(CursorEntry {
+                            key: format!("{}{}", key_prefix, item.name),
+                            size: item.size.unwrap_or(0),
+                            e_tag: item.object_e_tag(),
+                            last_modified: item.last_modified_date_time,
+                        })
+                    } else {
+                        None
+                    }
+                }));
+                next_link = objects.next_link;
+                if next_link.is_none() {
+                    break;
+                }
+            }
+            Err(err) => {
+                res.render(S3Error::from(err));
+                return;
+            }
+        }
+    }
+    match create_cursor(prefix, entries) {
+        Some(cursor) => {
+            res.status_code(StatusCode::CREATED).render(Json(cursor));
+        }
+        None => {
+            res.render(S3Error::slow_down("Too many open cursors"));
+        }
+    }
+}
+
+#[handler]
+async fn read_cursor_handler(req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    let offset = req.query::<usize>("offset").unwrap_or(0);
+    let limit = req.query::<usize>("limit").unwrap_or(1000).min(10000);
+    match read_cursor(&id, offset, limit) {
+        Some(page) => {
+            res.status_code(StatusCode::OK).render(Json(page));
+        }
+        None => {
+            res.render(S3Error::new(
+                StatusCode::NOT_FOUND,
+                "NoSuchCursor",
+                "Cursor not found or expired",
+            ));
+        }
+    }
+}
 
-    #[config(env = "FILENAME_PATTERN", default = "")]
-    filename_pattern: String,
+#[handler]
+async fn delete_cursor_handler(req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    if delete_cursor(&id) {
+        res.status_code(StatusCode::NO_CONTENT);
+    } else {
+        res.status_code(StatusCode::NOT_FOUND);
+    }
+}
 
-    #[config(env = "API_TOKEN")]
-    api_token: Option<String>,
+#[derive(Deserialize, Debug)]
+struct MetadataRequest {
+    keys: Vec<String>,
+    fields: serde_json::Map<String, serde_json::Value>,
 }
 
-fn config() -> &'static Conf {
-    static CONFIG: OnceLock<Conf> = OnceLock::new();
-    CONFIG.get_or_init(|| Conf::builder().env().load().unwrap())
+#[derive(Serialize, Debug)]
+struct MetadataError {
+    key: String,
+    code: String,
+    message: String,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-struct SearchResult {
-    file_name: String,
-    file_path: String,
+#[derive(Serialize, Debug)]
+struct MetadataResult {
+    updated: Vec<String>,
+    errors: Vec<MetadataError>,
 }
 
+/// Sets the same listItem fields on many keys at once, e.g. to mark files
+/// as processed, using Graph `$batch` instead of one call per key.
 #[handler]
-async fn ok_handler(res: &mut Response) {
-    res.status_code(StatusCode::OK).render(Text::Plain("OK"))
+async fn metadata_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let request = match req.parse_json::<MetadataRequest>().await {
+        Ok(request)
+            if !request.keys.is_empty()
+                && request.keys.len() <= 1000
+                && !request.fields.is_empty() =>
+        {
+            request
+        }
+        Ok(_) => {
+            res.render(S3Error::invalid_argument(
+                "Between 1 and 1000 keys and at least one field are required",
+            ));
+            return;
+        }
+        Err(err) => {
+            res.render(S3Error::invalid_argument(err.to_string()));
+            return;
+        }
+    };
+    let mut errors = Vec::new();
+    let mut targets = Vec::new();
+    for key in request.keys {
+        let error = if !regex.is_match(&key) || !is_in_token_scope(depot, &key) {
+            S3Error::access_denied()
+        } else {
+            match resolve_library(&bucket, &key).await {
+                Ok(Some((bucket, path))) => {
+                    targets.push((key, bucket.drive_url(), path));
+                    continue;
+                }
+                Ok(None) => S3Error::no_such_key(),
+                Err(err) => S3Error::from(err),
+            }
+        };
+        errors.push(MetadataError {
+            key,
+            code: error.code.to_string(),
+            message: error.message,
+        });
+    }
+    let fields = serde_json::Value::Object(request.fields);
+    let items = targets
+        .iter()
+        .map(|(_, drive, path)| (drive.clone(), path.clone()))
+        .collect();
+    let statuses = match update_azure_fields(items, &fields).await {
+        Ok(statuses) => statuses,
+        Err(err) => {
+            res.render(S3Error::from(err));
+            return;
+        }
+    };
+    let mut updated = Vec::new();
+    for ((key, _, _), status) in targets.into_iter().zip(statuses) {
+        if (200..300).contains(&status) {
+            updated.push(key);
+        } else {
+            let error = S3Error::from_graph_status(status);
+            errors.push(MetadataError {
+                key,
+                code: error.code.to_string(),
+                message: error.message,
+            });
+        }
+    }
+    res.render(Json(MetadataResult { updated, errors }));
 }
 
-#[handler]
-async fn bad_request_handler(res: &mut Response) {
-    res.status_code(StatusCode::BAD_REQUEST)
-        .render(Text::Plain("BAD REQUEST"))
+/// What a HEAD would tell about one key of a metadata lookup.
+#[derive(Serialize, Debug)]
+struct KeyMetadata {
+    key: String,
+    exists: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e_tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+    /// The S3 error code when the key could not be looked up, e.g.
+    /// `AccessDenied`. Missing keys only have `exists` unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+impl KeyMetadata {
+    fn missing(key: String, error: Option<&'static str>) -> Self {
+        KeyMetadata {
+            key,
+            exists: false,
+            size: None,
+            e_tag: None,
+            last_modified: None,
+            error,
+        }
+    }
 }
 
+/// Looks up size, ETag and modification time of many keys at once, in the
+/// order given, with Graph `$batch` instead of one HEAD per key. Folders
+/// do not exist as objects, as for HEAD.
 #[handler]
-async fn head_handler(req: &mut Request, res: &mut Response) {
-    let site_id = config().sharepoint_site_id.clone();
+async fn lookup_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AdapterError> {
+    let keys = match serde_json::from_slice::<Vec<String>>(req.payload().await?) {
+        Ok(keys) if !keys.is_empty() && keys.len() <= 1000 => keys,
+        Ok(_) => {
+            return Err(AdapterError::invalid_body(
+                "Between 1 and 1000 keys are required",
+            ))
+        }
+        Err(err) => return Err(AdapterError::invalid_body(err.to_string())),
+    };
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let mut results = Vec::with_capacity(keys.len());
+    let mut targets = Vec::new();
+    for key in keys.into_iter().map(|key| nfc(&key)) {
+        if !regex.is_match(&key) || !is_in_token_scope(depot, &key) {
+            results.push(KeyMetadata::missing(key, Some("AccessDenied")));
+            continue;
+        }
+        match resolve_library(&bucket, &key).await? {
+            Some((bucket, path)) => {
+                targets.push((results.len(), bucket.drive_url(), path));
+                results.push(KeyMetadata::missing(key, None));
+            }
+            None => results.push(KeyMetadata::missing(key, None)),
+        }
+    }
+    let items = targets
+        .iter()
+        .map(|(_, drive, path)| (drive.clone(), path.clone()))
+        .collect();
+    for ((index, _, _), item) in targets.iter().zip(get_azure_items(items).await?) {
+        let result = &mut results[*index];
+        match item {
+            Ok(item) if item.file.is_some() => {
+                result.exists = true;
+                result.e_tag = item.object_e_tag();
+                result.size = item.size;
+                result.last_modified = item.last_modified_date_time;
+            }
+            Ok(_) | Err(404) => {}
+            Err(status) => result.error = Some(S3Error::from_graph_status(status).code),
+        }
+    }
+    res.render(Json(results));
+    Ok(())
+}
 
-    let key = req.params().get("**path").cloned().unwrap_or_default();
-    match head_azure_object(site_id.clone(), key.clone()).await {
-        Ok(result) => {
-            res.headers_mut()
-                .insert("Content-Type", result.content_type.parse().unwrap());
-            res.headers_mut()
-                .insert("Content-Length", result.size.to_string().parse().unwrap());
-            res.status_code(StatusCode::from_u16(result.status_code).unwrap());
+/// Upper bound of files scanned for one duplicate report.
+const MAX_DUPLICATE_SCAN: usize = 100_000;
+
+#[derive(Serialize, Debug)]
+struct DuplicateGroup {
+    hash: String,
+    size: u64,
+    keys: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct DuplicateReport {
+    duplicates: Vec<DuplicateGroup>,
+    scanned: usize,
+    wasted_bytes: u64,
+    is_truncated: bool,
+}
+
+/// Reports files below a prefix sharing the same content hash and size,
+/// largest waste first.
+#[handler]
+async fn duplicates_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let prefix = normalize_prefix(&prefix_query(req).unwrap_or_default());
+    if lists_libraries(&bucket, &prefix) {
+        res.render(S3Error::invalid_argument(
+            "The prefix has to name a document library",
+        ));
+        return;
+    }
+    let (bucket, path) = match resolve_library(&bucket, &prefix).await {
+        Ok(Some(resolved)) => resolved,
+        Ok(None) => {
+            res.render(Json(DuplicateReport {
+                duplicates: Vec::new(),
+                scanned: 0,
+                wasted_bytes: 0,
+                is_truncated: false,
+            }));
+            return;
         }
-        Err(_) => {
-            res.headers_mut()
-                .insert("Content-Type", "application/xml".parse().unwrap());
-            res.headers_mut()
-                .insert("Content-Length", "0".parse().unwrap());
-            res.status_code(StatusCode::NOT_FOUND);
+        Err(err) => {
+            res.render(S3Error::from(err));
+            return;
+        }
+    };
+    let mut groups: HashMap<(String, u64), Vec<String>> = HashMap::new();
+    let mut scanned = 0;
+    let mut traversal = None;
+    loop {
+        let page =
+            match list_azure_objects_recursive(bucket.drive_url(), path.clone(), 1000, traversal)
+                .await
+            {
+                Ok(page) => page,
+                Err(err) => {
+                    res.render(S3Error::from(err));
+                    return;
+                }
+            };
+        for item in page.items {
+            let key = format!("{}{}", prefix, item.name);
+            let hash = item
+                .file
+                .and_then(|file| file.hashes)
+                .and_then(|hashes| hashes.quick_xor_hash);
+            scanned += 1;
+            if let (Some(hash), true) = (hash, regex.is_match(&key)) {
+                groups
+                    .entry((hash, item.size.unwrap_or_default()))
+                    .or_default()
+                    .push(key);
+            }
+        }
+        traversal = page
+            .next_link
+            .and_then(|next_link| serde_json::from_str(&next_link).ok());
+        if traversal.is_none() || scanned >= MAX_DUPLICATE_SCAN {
+            break;
+        }
+    }
+    let mut duplicates = groups
+        .into_iter()
+        .filter(|(_, keys)| keys.len() > 1)
+        .map(|((hash, size), mut keys)| {
+            keys.sort();
+            DuplicateGroup { hash, size, keys }
+        })
+        .collect::<Vec<DuplicateGroup>>();
+    let waste = |group: &DuplicateGroup| group.size * (group.keys.len() as u64 - 1);
+    duplicates.sort_by_key(|group| std::cmp::Reverse(waste(group)));
+    res.render(Json(DuplicateReport {
+        wasted_bytes: duplicates.iter().map(waste).sum(),
+        duplicates,
+        scanned,
+        is_truncated: traversal.is_some(),
+    }));
+}
+
+type ArchiveSender = tokio::sync::mpsc::Sender<Result<Bytes, std::io::Error>>;
+
+/// Hands a part of the archive to the response, false once the client went
+/// away.
+async fn send_archive_part(sender: &ArchiveSender, part: Bytes) -> bool {
+    sender.send(Ok(part)).await.is_ok()
+}
+
+/// Downloads the files of the listing pages one after another into the
+/// archive, continuing the traversal until the prefix is exhausted. PDFs
+/// are watermarked like single downloads, which needs them as a whole.
+async fn write_archive(
+    sender: &ArchiveSender,
+    drive: String,
+    path: String,
+    prefix: String,
+    mut page: SharePointObjects,
+    api_token: Option<&'static ApiToken>,
+    context: WatermarkContext,
+) -> Result<(), AdapterError> {
+    let regex = filename_regex();
+    let conditions = Conditions::default();
+    let mut zip = ZipWriter::default();
+    loop {
+        for item in page.items {
+            let key = format!("{}{}", prefix, item.name);
+            if !regex.is_match(&key)
+                || !api_token.is_none_or(|api_token| api_token.allows_key(&key))
+            {
+                continue;
+            }
+            let file_path = if path.is_empty() {
+                item.name.clone()
+            } else {
+                format!("{}/{}", path, item.name)
+            };
+            let mut object =
+                get_azure_object_data(drive.clone(), file_path, None, &conditions).await?;
+            match object.status_code {
+                200 => {}
+                // Deleted since it was listed.
+                404 => continue,
+                status => return Err(S3Error::from_graph_status(status).into()),
+            }
+            let modified = item
+                .last_modified_date_time
+                .as_deref()
+                .and_then(|modified| DateTime::parse_from_rfc3339(modified).ok())
+                .map(|modified| modified.with_timezone(&Utc));
+            if is_watermark_enabled(&object.content_type) {
+                let data = apply_pdf_watermark(object.collect().await?, &context).await?;
+                let header = zip.begin_entry(&item.name, modified, data.len() as u64);
+                let data = Bytes::from(data);
+                zip.data(&data);
+                if !send_archive_part(sender, header).await
+                    || !send_archive_part(sender, data).await
+                    || !send_archive_part(sender, zip.end_entry()).await
+                {
+                    return Ok(());
+                }
+                continue;
+            }
+            let header = zip.begin_entry(&item.name, modified, item.size.unwrap_or_default());
+            if !send_archive_part(sender, header).await {
+                return Ok(());
+            }
+            while let Some(chunk) = object.stream.next().await {
+                let chunk = chunk?;
+                zip.data(&chunk);
+                if !send_archive_part(sender, chunk).await {
+                    return Ok(());
+                }
+            }
+            if !send_archive_part(sender, zip.end_entry()).await {
+                return Ok(());
+            }
         }
+        let Some(traversal) = page
+            .next_link
+            .and_then(|next_link| serde_json::from_str(&next_link).ok())
+        else {
+            break;
+        };
+        page = list_azure_objects_recursive(drive.clone(), path.clone(), 1000, Some(traversal))
+            .await?;
     }
+    send_archive_part(sender, zip.finish()).await;
+    Ok(())
+}
+
+/// A `Content-Disposition` offering a download as `file_name`: an ASCII
+/// `filename` for older clients and the exact name as RFC 6266 `filename*`.
+fn attachment_disposition(file_name: &str) -> String {
+    let fallback = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        urlencoding::encode(file_name)
+    )
 }
 
+/// Streams the files below a prefix as a ZIP archive, built while the
+/// files are downloaded so neither the archive nor a file is ever held as
+/// a whole, save PDFs to watermark. Files hidden by `FILENAME_PATTERN` or
+/// outside the token's prefixes are left out. The archive takes one of the
+/// client's download slots until it has been streamed. Once the first byte is sent a failure can only
+/// cut the archive short, which unzip reports as a missing central
+/// directory.
 #[handler]
-async fn list_objects_v1(req: &mut Request, res: &mut Response) {
-    let prefix = req
-        .query::<String>("prefix")
-        .unwrap_or("/".to_string())
-        .trim_end_matches("/")
-        .to_string();
-    let max_keys = req.query::<u16>("max-keys").unwrap_or(1000);
-    let site_id = config().sharepoint_site_id.clone();
-    match list_azure_objects(site_id.clone(), prefix.clone(), max_keys, None).await {
-        Ok(objects) => {
-            res.status_code(StatusCode::OK).render(Text::Xml(
-                generate_s3_list_objects_v2_response(site_id, prefix, objects, false),
-            ));
+async fn archive_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AdapterError> {
+    let bucket = current_bucket(depot);
+    let prefix = normalize_prefix(&prefix_query(req).unwrap_or_default());
+    if lists_libraries(&bucket, &prefix) {
+        return Err(S3Error::invalid_argument("The prefix has to name a document library").into());
+    }
+    let name = match prefix.trim_end_matches('/').rsplit('/').next() {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => bucket.name.clone(),
+    };
+    let (drive, path, page) = match resolve_library(&bucket, &prefix).await? {
+        Some((bucket, path)) => {
+            // The first page is listed up front, so a missing prefix is
+            // still answered with an error status.
+            let page =
+                list_azure_objects_recursive(bucket.drive_url(), path.clone(), 1000, None).await?;
+            (bucket.drive_url(), path, page)
+        }
+        None => (
+            bucket.drive_url(),
+            String::new(),
+            SharePointObjects {
+                items: Vec::new(),
+                next_link: None,
+            },
+        ),
+    };
+    // The slot is held until the archive has been streamed.
+    let permit = match depot.get::<String>("rate_limit_client") {
+        Ok(client) => match try_download(client) {
+            Some(permit) => Some(permit),
+            None => {
+                warn!("Too many concurrent downloads by {}", client);
+                return Err(S3Error::slow_down("Too many concurrent downloads.").into());
+            }
+        },
+        Err(_) => None,
+    };
+    let api_token = depot.get::<&ApiToken>("api_token").ok().copied();
+    let context = WatermarkContext {
+        subject: depot
+            .get::<String>("on_behalf_of")
+            .or(depot.get::<String>("caller"))
+            .cloned()
+            .unwrap_or_default(),
+        timestamp: Utc::now(),
+    };
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(async move {
+        if let Err(err) =
+            write_archive(&sender, drive, path, prefix, page, api_token, context).await
+        {
+            warn!("Archive stopped: {}", err);
+            let _ = sender
+                .send(Err(std::io::Error::other(err.to_string())))
+                .await;
+        }
+    });
+    res.headers_mut()
+        .insert("Content-Type", HeaderValue::from_static("application/zip"));
+    res.headers_mut().insert(
+        "Content-Disposition",
+        attachment_disposition(&format!("{}.zip", name)).parse()?,
+    );
+    res.stream(futures_util::stream::poll_fn(move |cx| {
+        let _permit = &permit;
+        receiver.poll_recv(cx)
+    }));
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct ResolveRequest {
+    url: Option<String>,
+    id: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ResolveResult {
+    bucket: String,
+    key: String,
+}
+
+/// Translates a SharePoint web URL or item id into the S3 key of the item.
+/// Items outside the bucket's drive or hidden by `FILENAME_PATTERN` are
+/// reported as missing.
+#[handler]
+async fn resolve_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let bucket = current_bucket(depot);
+    let request = match req.parse_json::<ResolveRequest>().await {
+        Ok(request) => request,
+        Err(err) => {
+            res.render(S3Error::invalid_argument(err.to_string()));
+            return;
         }
+    };
+    let item_id = match (request.id, request.url) {
+        // Ids end up in the Graph path and must not be able to leave it.
+        (Some(id), _)
+            if !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!-_".contains(c)) =>
+        {
+            res.render(S3Error::invalid_argument("Invalid item id"));
+            return;
+        }
+        (Some(id), _) => id,
+        (None, Some(url)) => match resolve_azure_share(url).await {
+            Ok(id) => id,
+            Err(err) => {
+                res.render(S3Error::from(err));
+                return;
+            }
+        },
+        (None, None) => {
+            res.render(S3Error::invalid_argument("Either url or id is required"));
+            return;
+        }
+    };
+    let key = match get_azure_item_key(bucket.drive_url(), item_id).await {
+        Ok(key) => key,
         Err(err) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR)
-                .render(Text::Plain(err.to_string()));
+            res.render(S3Error::from(err));
+            return;
         }
+    };
+    let regex = filename_regex();
+    if !regex.is_match(&key) || !is_in_token_scope(depot, &key) {
+        res.render(S3Error::no_such_key());
+        return;
     }
+    res.render(Json(ResolveResult {
+        bucket: bucket.name,
+        key,
+    }));
 }
 
 #[handler]
-async fn search_handler(req: &mut Request, res: &mut Response) {
-    let payload = req.parse_json::<SearchRequest>().await.unwrap();
-    let site_id = config().sharepoint_site_id.clone();
-    match list_azure_objects(
-        site_id.clone(),
-        payload.prefix.clone(),
-        payload.max_keys.unwrap_or(1000),
-        Some(payload.query),
+async fn changes_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let regex = filename_regex();
+    let bucket = current_bucket(depot);
+    let since = match req.query::<String>("since") {
+        Some(since) => match decode_changes_token(&since, &bucket.name) {
+            Some(token) => Some(token),
+            None => {
+                res.render(S3Error::invalid_argument(
+                    "The since token provided is incorrect",
+                ));
+                return;
+            }
+        },
+        None => None,
+    };
+    let issued_at = Utc::now();
+    let changes = match list_azure_changes(
+        bucket.drive_url(),
+        since.as_ref().map(|token| token.delta_link.clone()),
     )
     .await
     {
-        Ok(objects) => {
-            let filename_pattern = config().filename_pattern.clone();
-            let regex = Regex::new(&filename_pattern).unwrap();
-            let search_results = objects
-                .items
-                .iter()
-                .filter(|item| item.folder.is_none() && regex.is_match(&item.name))
-                .map(|item| {
-                    let web_url = decode(&item.web_url).expect("UTF-8").to_string();
-                    let ending = web_url.split(&payload.prefix).last().unwrap_or_default();
-                    let full = format!("{}{}", payload.prefix, ending);
-                    let path = Path::new(full.as_str());
-                    SearchResult {
-                        file_name: path.file_name().unwrap().to_string_lossy().into_owned(),
-                        file_path: path.parent().unwrap().display().to_string(),
-                    }
-                })
-                .collect::<Vec<SearchResult>>();
-            res.status_code(StatusCode::OK).render(Json(search_results));
+        Ok(changes) => changes,
+        Err(err) => {
+            res.render(S3Error::from(err));
+            return;
         }
+    };
+    // Without a new link the client has to ask again from where it was.
+    let next_token = match (changes.delta_link, &since) {
+        (Some(delta_link), _) => ChangesToken {
+            delta_link,
+            issued_at,
+        },
+        (None, Some(since)) => ChangesToken {
+            delta_link: since.delta_link.clone(),
+            issued_at: since.issued_at,
+        },
+        (None, None) => {
+            res.render(S3Error::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailable",
+                "The change feed returned no delta link, please try again",
+            ));
+            return;
+        }
+    };
+    let mut feed = ChangeFeed {
+        next_token: encode_changes_token(&next_token, &bucket.name),
+        ..Default::default()
+    };
+    let drive = bucket.drive_url();
+    let (deleted, changed): (Vec<_>, Vec<_>) = changes
+        .items
+        .into_iter()
+        .partition(|item| item.deleted.is_some());
+    for item in deleted {
+        feed.deleted.push(DeletedKey {
+            key: known_key(&drive, &item.id),
+            id: item.id,
+        });
+    }
+    let changed = changed
+        .into_iter()
+        .filter(|item| {
+            item.file.is_some() && regex.is_match(&item.name.clone().unwrap_or_default())
+        })
+        .collect::<Vec<DeltaItem>>();
+    let keys = match resolve_changed_keys(&drive, &changed).await {
+        Ok(keys) => keys,
         Err(err) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR)
-                .render(Text::Plain(err.to_string()));
+            res.render(S3Error::from(err));
+            return;
+        }
+    };
+    for (item, key) in changed.into_iter().zip(keys) {
+        let Some(key) = key else {
+            warn!("Resolving key of changed item {} failed", item.id);
+            continue;
+        };
+        let created = item
+            .created_date_time
+            .as_deref()
+            .and_then(|created| DateTime::parse_from_rfc3339(created).ok());
+        let changed = ChangedKey {
+            key,
+            size: item.size.unwrap_or(0),
+            last_modified: item.last_modified_date_time,
+        };
+        match (&since, created) {
+            (Some(since), Some(created)) if created <= since.issued_at => {
+                feed.modified.push(changed)
+            }
+            _ => feed.added.push(changed),
         }
     }
+    res.status_code(StatusCode::OK).render(Json(feed));
+}
+
+fn current_request(depot: &Depot) -> &CanonicalRequest {
+    depot
+        .get::<CanonicalRequest>("request")
+        .expect("normalize_handler canonicalizes the request")
+}
+
+fn current_key(depot: &Depot) -> String {
+    current_request(depot).key.clone()
 }
 
+/// Derives bucket and key from the raw URI once, before auth and routing.
 #[handler]
-async fn get_object(req: &mut Request, res: &mut Response) {
-    let filename_pattern = config().filename_pattern.clone();
-    let regex = Regex::new(&filename_pattern).unwrap();
-    let site_id = config().sharepoint_site_id.clone();
-    let key = req.params().get("**path").cloned().unwrap_or_default();
-    if !regex.is_match(&key) {
-        res.status_code(StatusCode::FORBIDDEN);
+async fn normalize_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    match canonicalize(
+        req.method().as_str(),
+        req.uri().path(),
+        req.uri().query().unwrap_or(""),
+    ) {
+        Ok(request) => {
+            depot.insert("request", request);
+        }
+        Err(err) => {
+            res.render(S3Error::new(StatusCode::BAD_REQUEST, "InvalidURI", err));
+        }
+    }
+}
+
+fn current_bucket(depot: &Depot) -> Bucket {
+    depot
+        .get::<Bucket>("bucket")
+        .cloned()
+        .expect("bucket_handler resolves the bucket")
+}
+
+/// Resolves the bucket of a request, the first path segment in multi-bucket
+/// mode and the configured site otherwise.
+#[handler]
+async fn bucket_handler(depot: &mut Depot, res: &mut Response) {
+    let bucket = match &current_request(depot).bucket {
+        Some(name) => find_bucket(name),
+        None => buckets().into_iter().next(),
+    };
+    match bucket {
+        Some(bucket) => {
+            depot.insert("bucket", bucket);
+        }
+        None => {
+            res.render(S3Error::new(
+                StatusCode::NOT_FOUND,
+                "NoSuchBucket",
+                "The specified bucket does not exist",
+            ));
+        }
+    }
+}
+
+/// The bucket endpoint a request is routed to, matched like `bucket_routes`
+/// by method and the whole key, so objects such as `archive/report.pdf`
+/// stay objects.
+fn bucket_endpoint(req: &Request, key: &str) -> Option<&'static str> {
+    let is_cursor = key
+        .strip_prefix("_cursors/")
+        .is_some_and(|id| !id.is_empty() && !id.contains('/'));
+    match (req.method().clone(), key) {
+        (Method::POST, "search") => Some("search"),
+        (Method::POST, "metadata") => Some("metadata"),
+        (Method::GET, "archive") => Some("archive"),
+        (Method::GET, "_changes") => Some("_changes"),
+        (Method::POST, "_resolve") => Some("_resolve"),
+        (Method::POST, "_metadata") => Some("_metadata"),
+        (Method::GET, "_duplicates") => Some("_duplicates"),
+        (Method::POST, "_cursors") => Some("_cursors"),
+        (Method::GET | Method::DELETE, _) if is_cursor => Some("_cursors"),
+        _ => None,
+    }
+}
+
+/// Keeps snapshot views read-only and limited to listings, HEAD and GET.
+/// Sharing, tags, attributes and the bucket endpoints would reveal the
+/// current state.
+#[handler]
+async fn snapshot_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    if current_bucket(depot).as_of.is_none() {
         return;
     }
-    match get_azure_object_data(site_id.clone(), key.clone()).await {
-        Ok(result) => {
-            res.headers_mut()
-                .insert("Content-Type", result.content_type.parse().unwrap());
-            res.headers_mut().insert(
-                "Content-Disposition",
-                format!("attachment; filename=\"{}\"", result.file_name)
-                    .parse()
-                    .unwrap(),
-            );
-            let _ = res.write_body(result.data);
+    let key = current_key(depot);
+    let readable = matches!(*req.method(), Method::GET | Method::HEAD)
+        && bucket_endpoint(req, &key).is_none()
+        && !["sharing", "tagging", "attributes"]
+            .iter()
+            .any(|query| req.queries().contains_key(*query));
+    if !readable {
+        res.render(S3Error::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "MethodNotAllowed",
+            "Snapshot views are read-only",
+        ));
+    }
+}
+
+/// Routes object requests to the library named by the first key segment
+/// when libraries are exposed as prefixes.
+#[handler]
+async fn library_handler(depot: &mut Depot, res: &mut Response) {
+    if !is_library_mode() {
+        return;
+    }
+    let key = current_key(depot);
+    match resolve_library(&current_bucket(depot), &key).await {
+        Ok(Some((bucket, key))) => {
+            depot.insert("bucket", bucket);
+            if let Ok(request) = depot.get_mut::<CanonicalRequest>("request") {
+                request.key = key;
+            }
+        }
+        Ok(None) => res.render(S3Error::no_such_key().with_resource(key)),
+        Err(err) => res.render(S3Error::from(err).with_resource(key)),
+    }
+}
+
+/// Object routes, resolving the library of the key first.
+fn object_router() -> Router {
+    Router::with_path("<**path>").hoop(library_handler)
+}
+
+/// The behavior overrides of the current request.
+fn current_overrides(depot: &Depot) -> Overrides {
+    depot
+        .get::<Overrides>("overrides")
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Accepts `x-adapter-cache` and `x-adapter-download-mode` from callers
+/// listed in `OVERRIDE_CALLERS`. Returns false when they must be rejected.
+fn resolve_overrides(req: &Request, depot: &mut Depot, trust_key: &str) -> bool {
+    if !has_overrides(req.headers()) {
+        return true;
+    }
+    if !config()
+        .override_callers
+        .iter()
+        .any(|caller| caller == trust_key)
+    {
+        warn!("Untrusted caller {} sent behavior overrides", trust_key);
+        return false;
+    }
+    match parse_overrides(req.headers()) {
+        Ok(overrides) => {
+            info!("{} overrides {:?}", trust_key, overrides);
+            depot.insert("overrides", overrides);
+            true
         }
         Err(err) => {
-            res.status_code(StatusCode::INTERNAL_SERVER_ERROR)
-                .render(Text::Plain(err.to_string()));
+            warn!("{} from {}", err, trust_key);
+            false
         }
     }
 }
 
+/// Accepts `x-adapter-on-behalf-of` from callers listed in
+/// `ON_BEHALF_OF_CALLERS` (`api-token`, `API_TOKENS` names or access key
+/// ids) and records the end user for attribution. Returns false when the header must be rejected.
+fn resolve_on_behalf_of(req: &Request, depot: &mut Depot, trust_key: &str) -> bool {
+    let Some(on_behalf_of) = req.header::<String>("x-adapter-on-behalf-of") else {
+        return true;
+    };
+    if !config()
+        .on_behalf_of_callers
+        .iter()
+        .any(|caller| caller == trust_key)
+    {
+        warn!("Untrusted caller {} sent x-adapter-on-behalf-of", trust_key);
+        return false;
+    }
+    let valid = !on_behalf_of.is_empty()
+        && on_behalf_of.len() <= 256
+        && on_behalf_of
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@._+-".contains(c));
+    if !valid {
+        warn!("Invalid x-adapter-on-behalf-of value from {}", trust_key);
+        return false;
+    }
+    info!(
+        "{} {} by {} on behalf of {}",
+        req.method(),
+        req.uri().path(),
+        depot.get::<String>("caller").cloned().unwrap_or_default(),
+        on_behalf_of
+    );
+    depot.insert("on_behalf_of", on_behalf_of);
+    true
+}
+
+/// Records the authenticated caller, also on the request span so access
+/// logs name it.
+fn set_caller(depot: &mut Depot, caller: String) {
+    if let Ok(span) = depot.get::<Span>("request_span") {
+        span.record("caller", caller.as_str());
+    }
+    depot.insert("caller", caller);
+}
+
+/// Whether the `API_TOKENS` entry the request authenticated with, if any,
+/// may access a key.
+fn is_in_token_scope(depot: &Depot, key: &str) -> bool {
+    depot
+        .get::<&ApiToken>("api_token")
+        .map_or(true, |api_token| api_token.allows_key(key))
+}
+
+/// Confines `API_TOKENS` entries to their prefixes. Listings are checked by
+/// their prefix, so a restricted token cannot list the whole bucket; the
+/// keys of multi-object deletes and copy sources are checked by their
+/// handlers. Bucket endpoints are checked by the prefix they work on, the
+/// change feed covering the whole bucket; those taking keys or a prefix in
+/// their body check them in their handlers.
+#[handler]
+async fn token_scope_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let Ok(api_token) = depot.get::<&ApiToken>("api_token").copied() else {
+        return;
+    };
+    let key = current_key(depot);
+    // The static sub-resource answers reveal nothing about the content.
+    if key.is_empty() && bucket_subresource(req).is_some() {
+        return;
+    }
+    let target = match bucket_endpoint(req, &key) {
+        Some("archive" | "_duplicates") => prefix_query(req).unwrap_or_default(),
+        Some("_cursors") if key == "_cursors" => prefix_query(req).unwrap_or_default(),
+        // Unknown cursors are answered by their handlers.
+        Some("_cursors") => match cursor_prefix(key.trim_start_matches("_cursors/")) {
+            Some(prefix) => prefix,
+            None => return,
+        },
+        Some("_changes") => String::new(),
+        Some(_) => return,
+        None if key.is_empty() => prefix_query(req).unwrap_or_default(),
+        None => key,
+    };
+    if !api_token.allows_key(&target) {
+        record_token_denial(&api_token.name);
+        warn!("Api token {} may not access {}", api_token.name, target);
+        res.render(S3Error::access_denied());
+    }
+}
+
+/// The S3 operation or adapter endpoint a request addresses, for the audit
+/// trail.
+fn s3_operation(req: &Request, key: &str) -> &'static str {
+    let queries = req.queries();
+    if let Some(endpoint) = bucket_endpoint(req, key) {
+        return endpoint;
+    }
+    match req.uri().path() {
+        "/_whoami" => return "_whoami",
+        "/_selftest" => return "_selftest",
+        _ => {}
+    }
+    match *req.method() {
+        Method::GET if req.uri().path() == "/" && queries.is_empty() => "ListBuckets",
+        Method::GET if key.is_empty() => match bucket_subresource(req) {
+            Some("location") => "GetBucketLocation",
+            Some("versioning") => "GetBucketVersioning",
+            Some("acl") => "GetBucketAcl",
+            Some("lifecycle") => "GetBucketLifecycleConfiguration",
+            Some("policy") => "GetBucketPolicy",
+            Some("encryption") => "GetBucketEncryption",
+            _ if req.query::<i8>("list-type") == Some(2) => "ListObjectsV2",
+            _ => "ListObjects",
+        },
+        Method::GET if queries.contains_key("sharing") => "GetSharing",
+        Method::GET if queries.contains_key("attributes") => "GetObjectAttributes",
+        Method::GET if queries.contains_key("tagging") => "GetObjectTagging",
+        Method::PUT if queries.contains_key("tagging") => "PutObjectTagging",
+        Method::GET => "GetObject",
+        Method::HEAD if key.is_empty() => "HeadBucket",
+        Method::HEAD => "HeadObject",
+        Method::PUT if req.headers().contains_key("x-amz-copy-source") => "CopyObject",
+        Method::PUT => "PutObject",
+        Method::DELETE => "DeleteObject",
+        Method::POST if queries.contains_key("delete") => "DeleteObjects",
+        Method::POST if queries.contains_key("share") => "CreateShare",
+        _ => "Unknown",
+    }
+}
+
+/// Records who accessed what with which result in the audit trail. Bodies
+/// are counted while they stream, so the record is written once the
+/// response has been sent.
+#[handler]
+async fn audit_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    if !is_audit_enabled() {
+        return;
+    }
+    // The key as the client sent it, before library routing rewrites it.
+    let key = current_key(depot);
+    ctrl.call_next(req, depot, res).await;
+    let peer = req.remote_addr().clone().into_std().map(|addr| addr.ip());
+    let mut record = AuditRecord {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        request_id: depot
+            .get::<String>("request_id")
+            .cloned()
+            .unwrap_or_default(),
+        caller: depot.get::<String>("caller").ok().cloned(),
+        on_behalf_of: depot.get::<String>("on_behalf_of").ok().cloned(),
+        source_ip: client_ip(peer, req.headers()).map(|ip| ip.to_string()),
+        operation: s3_operation(req, &key),
+        bucket: depot
+            .get::<Bucket>("bucket")
+            .ok()
+            .map(|bucket| bucket.name.clone()),
+        key,
+        status: res.status_code.unwrap_or(StatusCode::OK).as_u16(),
+        bytes_received: req.header::<u64>("Content-Length").unwrap_or_default(),
+        bytes_sent: 0,
+        complete: true,
+    };
+    if res.body_mut().is_stream() {
+        let body = res.take_body();
+        res.stream(AuditedBody::new(body, record));
+    } else {
+        record.bytes_sent = res.body_mut().size().unwrap_or_default();
+        record_audit(record);
+    }
+}
+
+/// Limits each client to `RATE_LIMIT_RPS`, protecting the Graph quota shared
+/// by all consumers. Clients are told apart by their caller identity, or by
+/// address with `RATE_LIMIT_BY=ip`, e.g. when all share one `API_TOKEN`.
+#[handler]
+async fn rate_limit_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    if !is_rate_limit_enabled() {
+        return;
+    }
+    let client = if config().rate_limit_by == "ip" {
+        let peer = req.remote_addr().clone().into_std().map(|addr| addr.ip());
+        client_ip(peer, req.headers())
+            .map(|ip| ip.to_string())
+            .unwrap_or("local".to_string())
+    } else {
+        depot.get::<String>("caller").cloned().unwrap_or_default()
+    };
+    if !try_request(&client) {
+        warn!("Rate limited {}", client);
+        res.render(S3Error::slow_down("Please reduce your request rate."));
+        return;
+    }
+    depot.insert("rate_limit_client", client);
+}
+
+/// Evaluates the dry-run policies for every request before the live ones
+/// decide, so denied requests are compared as well.
+#[handler]
+async fn dry_run_handler(req: &mut Request, depot: &mut Depot) {
+    if !is_dry_run_enabled() {
+        return;
+    }
+    let peer = req.remote_addr().clone().into_std().map(|addr| addr.ip());
+    evaluate_ip(client_ip(peer, req.headers()));
+    let key = current_key(depot);
+    if !key.is_empty() && !key.ends_with('/') {
+        evaluate_key(&key);
+    }
+    let authorization = req
+        .header::<String>("Authorization")
+        .unwrap_or("".to_string());
+    if !is_sigv4_authorization(&authorization) {
+        let target = if key.is_empty() {
+            prefix_query(req).unwrap_or_default()
+        } else {
+            key
+        };
+        let token = authorization.split(' ').next_back().unwrap_or("");
+        evaluate_token(token, req.method().as_str(), &target);
+    }
+}
+
+/// Rejects clients outside `WHITELISTED_IPS`.
+#[handler]
+async fn allowlist_handler(req: &mut Request, res: &mut Response) {
+    let peer = req.remote_addr().clone().into_std().map(|addr| addr.ip());
+    let client = client_ip(peer, req.headers());
+    if !is_ip_allowed(client) {
+        warn!("Rejected request from {:?}", client);
+        res.render(S3Error::access_denied());
+    }
+}
+
 #[handler]
-async fn auth_handler(req: &mut Request, res: &mut Response) {
-    let api_token = config().api_token.clone().expect("API Token not set");
-    let req_token = req
+async fn auth_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let authorization = req
         .header::<String>("Authorization")
-        .unwrap_or("".to_string())
+        .unwrap_or("".to_string());
+    if is_sigv4_authorization(&authorization) {
+        let request = current_request(depot);
+        match verify_sigv4(
+            &request.method,
+            &request.raw_path,
+            &request.raw_query,
+            req.headers(),
+        ) {
+            Ok(access_key) => {
+                set_caller(depot, format!("access-key:{}", access_key));
+                if !resolve_on_behalf_of(req, depot, &access_key)
+                    || !resolve_overrides(req, depot, &access_key)
+                {
+                    res.render(S3Error::access_denied());
+                }
+            }
+            Err(err) => {
+                warn!("Invalid signature: {}", err);
+                res.render(S3Error::access_denied());
+            }
+        }
+        return;
+    }
+
+    let req_token = authorization
         .split(' ')
-        .last()
+        .next_back()
         .unwrap_or("")
         .to_string();
+    if let Some(api_token) = find_api_token(&req_token) {
+        set_caller(depot, format!("token:{}", api_token.name));
+        record_token_request(&api_token.name);
+        let method = req.method().as_str();
+        if api_token.is_expired() {
+            warn!("Expired api token {}", api_token.name);
+            record_token_denial(&api_token.name);
+            res.render(S3Error::access_denied());
+            return;
+        }
+        if !api_token.allows_method(method) {
+            warn!("Api token {} may not {}", api_token.name, method);
+            record_token_denial(&api_token.name);
+            res.render(S3Error::access_denied());
+            return;
+        }
+        depot.insert("api_token", api_token);
+        if !resolve_on_behalf_of(req, depot, &api_token.name)
+            || !resolve_overrides(req, depot, &api_token.name)
+        {
+            record_token_denial(&api_token.name);
+            res.render(S3Error::access_denied());
+        }
+        return;
+    }
 
-    if api_token.clone().ne(&req_token) {
-        warn!("Invalid api token {}: {}", api_token, req_token);
-        res.status_code(StatusCode::FORBIDDEN);
+    let Some(api_token) = config().api_token.clone() else {
+        warn!("Bearer token used but API_TOKEN is not set");
+        res.render(S3Error::access_denied());
         return;
+    };
+    // Identify the caller without exposing the token itself.
+    let fingerprint = format!("{:x}", Sha256::digest(req_token.as_bytes()));
+    if !tokens_match(&api_token, &req_token) {
+        warn!("Invalid api token {}", &fingerprint[..12]);
+        res.render(S3Error::access_denied());
+        return;
+    }
+    set_caller(depot, format!("token:{}", &fingerprint[..12]));
+    if !resolve_on_behalf_of(req, depot, "api-token") || !resolve_overrides(req, depot, "api-token")
+    {
+        res.render(S3Error::access_denied());
+    }
+}
+
+#[handler]
+async fn whoami_handler(depot: &mut Depot, res: &mut Response) {
+    let caller = depot.get::<String>("caller").cloned().unwrap_or_default();
+    let trust_key = match depot.get::<&ApiToken>("api_token") {
+        Ok(api_token) => api_token.name.as_str(),
+        Err(_) => caller.strip_prefix("access-key:").unwrap_or("api-token"),
+    };
+    let may_act_on_behalf = config()
+        .on_behalf_of_callers
+        .iter()
+        .any(|caller| caller == trust_key);
+    let token = depot
+        .get::<&ApiToken>("api_token")
+        .ok()
+        .map(|api_token| TokenScope {
+            name: api_token.name.clone(),
+            methods: api_token.methods().map(<[String]>::to_vec),
+            prefixes: api_token.prefixes().map(<[String]>::to_vec),
+            expires: api_token.expires(),
+        });
+    let rate_limit = depot
+        .get::<String>("rate_limit_client")
+        .ok()
+        .map(|client| rate_limit_state(client));
+    res.render(Json(WhoAmI {
+        on_behalf_of: depot.get::<String>("on_behalf_of").ok().cloned(),
+        may_act_on_behalf,
+        caller,
+        buckets: buckets().into_iter().map(|bucket| bucket.name).collect(),
+        filename_pattern: config().filename_pattern.clone(),
+        token,
+        rate_limit,
+    }));
+}
+
+/// Runs the self-test against SharePoint, answering 503 when a step fails
+/// so deployment pipelines can gate on the status code.
+#[handler]
+async fn selftest_handler(res: &mut Response) {
+    let report = run_selftest().await;
+    if report.passed {
+        res.status_code(StatusCode::OK);
+    } else {
+        warn!("Self-test failed: {:?}", report);
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
     }
+    res.render(Json(report));
+}
+
+fn bucket_routes(router: Router) -> Router {
+    router
+        .hoop(bucket_handler)
+        .hoop(snapshot_handler)
+        .hoop(token_scope_handler)
+        .push(Router::with_path("search").post(search_handler))
+        .push(Router::with_path("_changes").get(changes_handler))
+        .push(Router::with_path("_resolve").post(resolve_handler))
+        .push(Router::with_path("_metadata").post(metadata_handler))
+        .push(Router::with_path("metadata").post(lookup_handler))
+        .push(Router::with_path("_duplicates").get(duplicates_handler))
+        .push(Router::with_path("archive").get(archive_handler))
+        .push(
+            Router::with_path("_cursors")
+                .post(create_cursor_handler)
+                .push(
+                    Router::with_path("<id>")
+                        .get(read_cursor_handler)
+                        .delete(delete_cursor_handler),
+                ),
+        )
+        .push(
+            Router::with_filter_fn(|req, _| bucket_subresource(req).is_some())
+                .get(bucket_subresource_handler),
+        )
+        .push(object_router().head(head_handler))
+        .push(
+            Router::with_filter_fn(|req, _| {
+                req.query::<i8>("list-type").is_none()
+                    && (req.query::<String>("prefix").is_some()
+                        || (req.query::<String>("delimiter").is_some()
+                            || req.query::<String>("max-keys").is_some()
+                            || req.query::<String>("marker").is_some()
+                            || req.query::<String>("encoding-type").is_some()))
+            })
+            .get(list_objects_v1),
+        )
+        .push(
+            Router::with_filter_fn(|req, _| req.query::<i8>("list-type") == Some(2))
+                .get(list_objects_v2),
+        )
+        .push(
+            Router::with_filter_fn(|req, _| req.queries().contains_key("delete"))
+                .post(delete_objects),
+        )
+        .push(
+            object_router()
+                .filter_fn(|req, _| req.queries().contains_key("sharing"))
+                .get(sharing_handler),
+        )
+        .push(
+            object_router()
+                .filter_fn(|req, _| req.queries().contains_key("attributes"))
+                .get(attributes_handler),
+        )
+        .push(
+            object_router()
+                .filter_fn(|req, _| req.queries().contains_key("tagging"))
+                .get(get_tagging_handler)
+                .put(put_tagging_handler),
+        )
+        .push(
+            object_router()
+                .filter_fn(|req, _| req.queries().contains_key("share"))
+                .post(share_handler),
+        )
+        .push(object_router().get(get_object))
+        .push(
+            object_router()
+                .filter_fn(|req, _| req.headers().contains_key("x-amz-copy-source"))
+                .put(copy_object),
+        )
+        .push(object_router().put(put_object))
+        .push(object_router().delete(delete_object))
+}
+
+/// Parses `LOG_LEVELS`, e.g. `info,utils::azure=debug,salvo=warn`. Targets
+/// starting with `utils` are the adapter's own modules.
+fn log_targets() -> Result<Targets, String> {
+    let directives = config()
+        .log_levels
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| {
+            if directive.starts_with("utils") {
+                format!("{}::{}", env!("CARGO_CRATE_NAME"), directive)
+            } else {
+                directive.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+    directives
+        .parse()
+        .map_err(|err| format!("Invalid LOG_LEVELS {}: {}", config().log_levels, err))
+}
+
+/// Serves until SIGTERM or SIGINT, then stops accepting connections and
+/// gives requests in flight `SHUTDOWN_DRAIN_TIMEOUT_SECS` to complete.
+async fn serve(acceptor: impl Acceptor + Send, service: Service) {
+    let server = Server::new(acceptor);
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        handle.stop_graceful(Duration::from_secs(config().shutdown_drain_timeout_secs));
+    });
+    server.serve(service).await;
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    tracing_subscriber::fmt().init();
+    let log_format = match config().log_format.as_str() {
+        "json" => fmt::layer().json().with_span_list(true).boxed(),
+        _ => fmt::layer().boxed(),
+    };
+    // An invalid LOG_LEVELS falls back to INFO and is reported once logging
+    // is up.
+    let targets = log_targets();
+    let (otel, otel_error) = match otel_layer() {
+        Ok(otel) => (otel, None),
+        Err(err) => (None, Some(err)),
+    };
+    tracing_subscriber::registry()
+        .with(log_format)
+        .with(
+            targets
+                .clone()
+                .unwrap_or_else(|_| Targets::new().with_default(LevelFilter::INFO)),
+        )
+        .with(otel)
+        .init();
+    if let Err(err) = targets {
+        warn!("{}", err);
+    }
+    if let Some(err) = otel_error {
+        error!("{}", err);
+    }
+    if let Err(err) = check_auth_mode() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    spawn_token_refresher();
+    if let Err(err) = load_filename_pattern() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = load_ip_rules() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = load_api_tokens() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = load_dry_run_policies() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = check_tls_config() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = spawn_audit_writer().await {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = resolve_site().await {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = check_snapshot_views() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    spawn_library_loader();
+    spawn_lifecycle_runner();
+    import_snapshot();
+    spawn_listing_tracker();
+    spawn_snapshot_exporter();
+    spawn_subscription_manager();
 
+    // Path-style `/bucket/key` requests in multi-bucket mode.
+    let bucket_router = if is_multi_bucket() {
+        Router::with_path("<bucket>")
+    } else {
+        Router::new()
+    };
     let router = Router::new()
-        .push(Router::with_path("status").get(ok_handler))
+        .hoop(normalize_handler)
+        .push(
+            Router::with_path("status")
+                .get(ok_handler)
+                .push(Router::with_path("ready").get(ready_handler)),
+        )
+        .push(Router::with_path("metrics").get(metrics_handler))
+        .push(Router::with_path("notifications").post(notifications_handler))
         .push(
             Router::new()
+                .hoop(audit_handler)
+                .hoop(dry_run_handler)
+                .hoop(deadline_handler)
+                .hoop(allowlist_handler)
                 .hoop(auth_handler)
-                .push(Router::with_path("search").post(search_handler))
-                .push(Router::with_path("<**path>").head(head_handler))
+                .hoop(rate_limit_handler)
+                .push(Router::with_path("_whoami").get(whoami_handler))
+                .push(Router::with_path("_selftest").post(selftest_handler))
                 .push(
                     Router::with_filter_fn(|req, _| {
-                        req.query::<i8>("list-type").is_none()
-                            && (req.query::<String>("prefix").is_some()
-                                || (req.query::<String>("delimiter").is_some()
-                                    || req.query::<String>("max-keys").is_some()))
+                        req.uri().path() == "/" && req.queries().is_empty()
                     })
-                    .get(list_objects_v1),
+                    .get(list_buckets),
                 )
-                .push(Router::with_path("<**path>").get(get_object)),
+                .push(bucket_routes(bucket_router)),
         )
         .goal(bad_request_handler);
-    let service = Service::new(router).hoop(Logger::new());
-    let acceptor = TcpListener::new("0.0.0.0:3000").bind().await;
-    Server::new(acceptor).serve(service).await;
+    let service = Service::new(router)
+        .hoop(trace_handler)
+        .hoop(request_id_handler)
+        .hoop(Logger::new());
+    // A Unix socket replaces the TCP listener, e.g. behind a local proxy.
+    if let Some(path) = &config().unix_socket_path {
+        // Remove a socket left behind by a previous run.
+        if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            let _ = std::fs::remove_file(path);
+        }
+        info!("Listening on unix socket {}", path);
+        let acceptor = UnixListener::new(path.clone()).bind().await;
+        serve(acceptor, service).await;
+    } else {
+        let address = format!("{}:{}", config().bind_addr, config().port);
+        if is_tls_enabled() {
+            let configs = tls_configs().unwrap_or_else(|err| {
+                error!("{}", err);
+                std::process::exit(1);
+            });
+            let acceptor = TcpListener::new(address).rustls(configs).bind().await;
+            serve(acceptor, service).await;
+        } else {
+            let acceptor = TcpListener::new(address).bind().await;
+            serve(acceptor, service).await;
+        }
+    }
+    export_final_snapshot().await;
+    flush_audit().await;
+    flush_traces().await;
+    info!("Shut down");
 }
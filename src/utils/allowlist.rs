@@ -0,0 +1,94 @@
+use ipnet::IpNet;
+use salvo::http::HeaderMap;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use crate::config;
+
+struct IpRules {
+    allowed: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+}
+
+static IP_RULES: OnceLock<IpRules> = OnceLock::new();
+
+/// Parses an address or CIDR range; a bare address is a single-host range.
+fn parse_network(entry: &str) -> Option<IpNet> {
+    let entry = entry.trim();
+    entry
+        .parse::<IpNet>()
+        .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+pub fn parse_networks(name: &str, entries: &[String]) -> Result<Vec<IpNet>, String> {
+    entries
+        .iter()
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            parse_network(entry).ok_or(format!("Invalid address or range {} in {}", entry, name))
+        })
+        .collect()
+}
+
+/// Parses `WHITELISTED_IPS` and `TRUSTED_PROXIES` at startup, so a typo
+/// stops the adapter instead of silently widening or narrowing access.
+pub fn load_ip_rules() -> Result<(), String> {
+    let rules = IpRules {
+        allowed: parse_networks("WHITELISTED_IPS", &config().whitelisted_ips)?,
+        trusted_proxies: parse_networks("TRUSTED_PROXIES", &config().trusted_proxies)?,
+    };
+    let _ = IP_RULES.set(rules);
+    Ok(())
+}
+
+fn ip_rules() -> &'static IpRules {
+    IP_RULES.get_or_init(|| IpRules {
+        allowed: Vec::new(),
+        trusted_proxies: Vec::new(),
+    })
+}
+
+fn is_trusted_proxy(ip: &IpAddr) -> bool {
+    ip_rules()
+        .trusted_proxies
+        .iter()
+        .any(|network| network.contains(ip))
+}
+
+/// The address of the client. `X-Forwarded-For` is only honored when the
+/// peer is a trusted proxy, and then walked from the right: each proxy
+/// appends the address it received the request from, so the first entry
+/// not belonging to a trusted proxy is the client. Anything left of it could
+/// have been sent by the client itself. `peer` is `None` for Unix socket
+/// connections, which come from a local proxy and are trusted.
+pub fn client_ip(peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+    let peer = peer.map(|peer| peer.to_canonical());
+    if peer.is_some_and(|peer| !is_trusted_proxy(&peer)) {
+        return peer;
+    }
+    let hops = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<Option<IpAddr>>>();
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        // The client cannot be told from an unparsable chain.
+        let hop = hop?.to_canonical();
+        client = Some(hop);
+        if !is_trusted_proxy(&hop) {
+            break;
+        }
+    }
+    client
+}
+
+/// Whether `WHITELISTED_IPS` admits the client. An empty list admits all.
+pub fn is_ip_allowed(client: Option<IpAddr>) -> bool {
+    let allowed = &ip_rules().allowed;
+    allowed.is_empty()
+        || client.is_some_and(|client| allowed.iter().any(|network| network.contains(&client)))
+}
@@ -0,0 +1,218 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use crc32fast::Hasher;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END: u32 = 0x0605_4b50;
+
+/// Sizes and CRC follow the data in a descriptor; names are UTF-8.
+const FLAGS: u16 = 0x0808;
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Unix, so readers apply the permissions of the external attributes.
+const MADE_BY: u16 = 3 << 8 | VERSION_ZIP64;
+const FILE_MODE: u32 = 0o100644 << 16;
+const ZIP64_EXTRA: u16 = 0x0001;
+
+struct Entry {
+    name: String,
+    modified: (u16, u16),
+    crc: u32,
+    size: u64,
+    offset: u64,
+    zip64: bool,
+}
+
+/// Writes a ZIP archive while its entries are streamed, without knowing
+/// sizes or checksums up front and without seeking. Entries are stored
+/// uncompressed: Office documents and PDFs are compressed already. ZIP64
+/// records are written where sizes or offsets need them.
+#[derive(Default)]
+pub struct ZipWriter {
+    entries: Vec<Entry>,
+    offset: u64,
+    current: Option<(Entry, Hasher)>,
+}
+
+impl ZipWriter {
+    /// The local header of the next entry. `expected_size` decides whether
+    /// the entry is written as ZIP64, as that has to be declared before the
+    /// data.
+    pub fn begin_entry(
+        &mut self,
+        name: &str,
+        modified: Option<DateTime<Utc>>,
+        expected_size: u64,
+    ) -> Bytes {
+        let entry = Entry {
+            name: name.to_string(),
+            modified: dos_date_time(modified),
+            crc: 0,
+            size: 0,
+            offset: self.offset,
+            zip64: expected_size >= u64::from(u32::MAX),
+        };
+        let mut header = BytesMut::with_capacity(30 + name.len() + 20);
+        header.put_u32_le(LOCAL_HEADER);
+        header.put_u16_le(if entry.zip64 { VERSION_ZIP64 } else { VERSION });
+        header.put_u16_le(FLAGS);
+        header.put_u16_le(0); // Stored
+        header.put_u16_le(entry.modified.1);
+        header.put_u16_le(entry.modified.0);
+        header.put_u32_le(0); // CRC, in the descriptor
+        if entry.zip64 {
+            header.put_u32_le(u32::MAX);
+            header.put_u32_le(u32::MAX);
+        } else {
+            header.put_u32_le(0);
+            header.put_u32_le(0);
+        }
+        header.put_u16_le(name.len() as u16);
+        header.put_u16_le(if entry.zip64 { 20 } else { 0 });
+        header.put_slice(name.as_bytes());
+        if entry.zip64 {
+            header.put_u16_le(ZIP64_EXTRA);
+            header.put_u16_le(16);
+            header.put_u64_le(0);
+            header.put_u64_le(0);
+        }
+        self.offset += header.len() as u64;
+        self.current = Some((entry, Hasher::new()));
+        header.freeze()
+    }
+
+    /// Accounts for data of the current entry, which is sent as is.
+    pub fn data(&mut self, chunk: &[u8]) {
+        if let Some((entry, hasher)) = self.current.as_mut() {
+            hasher.update(chunk);
+            entry.size += chunk.len() as u64;
+            self.offset += chunk.len() as u64;
+        }
+    }
+
+    /// The data descriptor closing the current entry.
+    pub fn end_entry(&mut self) -> Bytes {
+        let Some((mut entry, hasher)) = self.current.take() else {
+            return Bytes::new();
+        };
+        entry.crc = hasher.finalize();
+        let mut descriptor = BytesMut::with_capacity(24);
+        descriptor.put_u32_le(DATA_DESCRIPTOR);
+        descriptor.put_u32_le(entry.crc);
+        if entry.zip64 {
+            descriptor.put_u64_le(entry.size);
+            descriptor.put_u64_le(entry.size);
+        } else {
+            // Readers take the sizes from the central directory, which has
+            // room for them if the file grew past the expected size.
+            descriptor.put_u32_le(entry.size as u32);
+            descriptor.put_u32_le(entry.size as u32);
+        }
+        self.offset += descriptor.len() as u64;
+        self.entries.push(entry);
+        descriptor.freeze()
+    }
+
+    /// The central directory and end records, closing the archive.
+    pub fn finish(self) -> Bytes {
+        let start = self.offset;
+        let mut directory = BytesMut::new();
+        for entry in &self.entries {
+            let mut extra = BytesMut::new();
+            if entry.size >= u64::from(u32::MAX) {
+                extra.put_u64_le(entry.size);
+                extra.put_u64_le(entry.size);
+            }
+            if entry.offset >= u64::from(u32::MAX) {
+                extra.put_u64_le(entry.offset);
+            }
+            let size = clamp32(entry.size);
+            directory.put_u32_le(CENTRAL_HEADER);
+            directory.put_u16_le(MADE_BY);
+            directory.put_u16_le(if entry.zip64 || !extra.is_empty() {
+                VERSION_ZIP64
+            } else {
+                VERSION
+            });
+            directory.put_u16_le(FLAGS);
+            directory.put_u16_le(0);
+            directory.put_u16_le(entry.modified.1);
+            directory.put_u16_le(entry.modified.0);
+            directory.put_u32_le(entry.crc);
+            directory.put_u32_le(size);
+            directory.put_u32_le(size);
+            directory.put_u16_le(entry.name.len() as u16);
+            directory.put_u16_le(if extra.is_empty() {
+                0
+            } else {
+                extra.len() as u16 + 4
+            });
+            directory.put_u16_le(0); // Comment
+            directory.put_u16_le(0); // Disk
+            directory.put_u16_le(0); // Internal attributes
+            directory.put_u32_le(FILE_MODE);
+            directory.put_u32_le(clamp32(entry.offset));
+            directory.put_slice(entry.name.as_bytes());
+            if !extra.is_empty() {
+                directory.put_u16_le(ZIP64_EXTRA);
+                directory.put_u16_le(extra.len() as u16);
+                directory.put_slice(&extra);
+            }
+        }
+        let length = directory.len() as u64;
+        let count = self.entries.len() as u64;
+        let zip64 = count >= u64::from(u16::MAX)
+            || start >= u64::from(u32::MAX)
+            || length >= u64::from(u32::MAX);
+        if zip64 {
+            let end = start + length;
+            directory.put_u32_le(ZIP64_END);
+            directory.put_u64_le(44);
+            directory.put_u16_le(MADE_BY);
+            directory.put_u16_le(VERSION_ZIP64);
+            directory.put_u32_le(0);
+            directory.put_u32_le(0);
+            directory.put_u64_le(count);
+            directory.put_u64_le(count);
+            directory.put_u64_le(length);
+            directory.put_u64_le(start);
+            directory.put_u32_le(ZIP64_LOCATOR);
+            directory.put_u32_le(0);
+            directory.put_u64_le(end);
+            directory.put_u32_le(1);
+        }
+        directory.put_u32_le(END);
+        directory.put_u16_le(0);
+        directory.put_u16_le(0);
+        let count = if zip64 { u16::MAX } else { count as u16 };
+        directory.put_u16_le(count);
+        directory.put_u16_le(count);
+        directory.put_u32_le(clamp32(length));
+        directory.put_u32_le(clamp32(start));
+        directory.put_u16_le(0); // Comment
+        directory.freeze()
+    }
+}
+
+fn clamp32(value: u64) -> u32 {
+    value.min(u64::from(u32::MAX)) as u32
+}
+
+/// DOS date and time, which only cover 1980 to 2107.
+fn dos_date_time(modified: Option<DateTime<Utc>>) -> (u16, u16) {
+    let Some(modified) = modified.filter(|modified| (1980..=2107).contains(&modified.year()))
+    else {
+        return (0x21, 0); // 1980-01-01
+    };
+    let date = ((modified.year() - 1980) as u16) << 9
+        | (modified.month() as u16) << 5
+        | modified.day() as u16;
+    let time = (modified.hour() as u16) << 11
+        | (modified.minute() as u16) << 5
+        | (modified.second() as u16 / 2);
+    (date, time)
+}
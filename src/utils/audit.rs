@@ -0,0 +1,226 @@
+use bytes::Bytes;
+use futures_util::Stream;
+use once_cell::sync::Lazy;
+use reqwest::Client;
+use salvo::http::body::{BytesFrame, ResBody};
+use serde::Serialize;
+use std::io::Error as IoError;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
+use tracing::warn;
+
+use crate::config;
+
+/// Records taken from the channel and written at once.
+const BATCH_SIZE: usize = 256;
+
+/// One access to the adapter, written as a JSON line or webhook element.
+#[derive(Serialize, Debug)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caller: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_behalf_of: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<String>,
+    pub operation: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    pub key: String,
+    pub status: u16,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// False when the client went away before the body was sent.
+    pub complete: bool,
+}
+
+enum AuditMessage {
+    Record(AuditRecord),
+    Flush(oneshot::Sender<()>),
+}
+
+type AuditChannel = (
+    mpsc::Sender<AuditMessage>,
+    AsyncMutex<Option<mpsc::Receiver<AuditMessage>>>,
+);
+
+static CHANNEL: Lazy<AuditChannel> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::channel(config().audit.audit_buffer_size.max(1));
+    (sender, AsyncMutex::new(Some(receiver)))
+});
+
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+pub fn is_audit_enabled() -> bool {
+    config().audit.audit_sink.is_some()
+}
+
+/// Queues a record without waiting. When the sink falls behind and the
+/// buffer is full the record is dropped and counted rather than slowing
+/// down requests.
+pub fn record_audit(record: AuditRecord) {
+    if CHANNEL.0.try_send(AuditMessage::Record(record)).is_err() {
+        let dropped = DROPPED.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped.is_power_of_two() {
+            warn!("Audit buffer full, {} records dropped so far", dropped);
+        }
+    }
+}
+
+async fn write_file(path: &str, lines: &[u8]) -> Result<(), IoError> {
+    // Reopened for every batch, so a rotated file is picked up.
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(lines).await?;
+    file.flush().await
+}
+
+async fn write_batch(client: &Client, records: &[AuditRecord]) {
+    let audit = &config().audit;
+    let result = match audit.audit_sink.as_deref() {
+        Some("file") => {
+            let lines = records
+                .iter()
+                .filter_map(|record| serde_json::to_string(record).ok())
+                .map(|line| line + "\n")
+                .collect::<String>();
+            write_file(
+                audit.audit_file.as_deref().unwrap_or_default(),
+                lines.as_bytes(),
+            )
+            .await
+            .map_err(|err| err.to_string())
+        }
+        Some("webhook") => client
+            .post(audit.audit_webhook_url.as_deref().unwrap_or_default())
+            .json(records)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| err.to_string()),
+        _ => {
+            for record in records {
+                if let Ok(line) = serde_json::to_string(record) {
+                    println!("{}", line);
+                }
+            }
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
+        warn!("Writing {} audit records failed: {}", records.len(), err);
+    }
+}
+
+/// Validates `AUDIT_SINK` and starts the writer, which drains the buffer in
+/// batches off the request path.
+pub async fn spawn_audit_writer() -> Result<(), String> {
+    let audit = &config().audit;
+    match audit.audit_sink.as_deref() {
+        None | Some("stdout") => {}
+        Some("file") if audit.audit_file.is_none() => {
+            return Err("AUDIT_SINK=file requires AUDIT_FILE".to_string())
+        }
+        Some("webhook") if audit.audit_webhook_url.is_none() => {
+            return Err("AUDIT_SINK=webhook requires AUDIT_WEBHOOK_URL".to_string())
+        }
+        Some("file" | "webhook") => {}
+        Some(sink) => return Err(format!("Unknown AUDIT_SINK {}", sink)),
+    }
+    if !is_audit_enabled() {
+        return Ok(());
+    }
+    let Some(mut receiver) = CHANNEL.1.lock().await.take() else {
+        return Ok(());
+    };
+    tokio::spawn(async move {
+        let client = Client::new();
+        let mut messages = Vec::with_capacity(BATCH_SIZE);
+        while receiver.recv_many(&mut messages, BATCH_SIZE).await > 0 {
+            let mut records = Vec::with_capacity(messages.len());
+            let mut flushed = Vec::new();
+            for message in messages.drain(..) {
+                match message {
+                    AuditMessage::Record(record) => records.push(record),
+                    AuditMessage::Flush(done) => flushed.push(done),
+                }
+            }
+            if !records.is_empty() {
+                write_batch(&client, &records).await;
+            }
+            for done in flushed {
+                let _ = done.send(());
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Waits until the records queued so far are written, at shutdown.
+pub async fn flush_audit() {
+    if !is_audit_enabled() {
+        return;
+    }
+    let (done, written) = oneshot::channel();
+    if CHANNEL.0.send(AuditMessage::Flush(done)).await.is_ok() {
+        let _ = written.await;
+    }
+}
+
+/// Passes a response body through, counting the bytes sent. The record is
+/// queued when the body ended or was dropped by a client going away.
+pub struct AuditedBody {
+    inner: ResBody,
+    record: Option<AuditRecord>,
+}
+
+impl AuditedBody {
+    pub fn new(inner: ResBody, record: AuditRecord) -> Self {
+        AuditedBody {
+            inner,
+            record: Some(record),
+        }
+    }
+
+    fn finish(&mut self, complete: bool) {
+        if let Some(mut record) = self.record.take() {
+            record.complete = complete;
+            record_audit(record);
+        }
+    }
+}
+
+impl Stream for AuditedBody {
+    type Item = Result<BytesFrame, IoError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                let sent = frame.data_ref().map(Bytes::len).unwrap_or_default();
+                if let Some(record) = self.record.as_mut() {
+                    record.bytes_sent += sent as u64;
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.finish(false),
+            Poll::Ready(None) => self.finish(true),
+            Poll::Pending => {}
+        }
+        poll.map(|frame| frame.map(|frame| frame.map(BytesFrame)))
+    }
+}
+
+impl Drop for AuditedBody {
+    fn drop(&mut self) {
+        self.finish(false);
+    }
+}
@@ -1,13 +1,31 @@
-use chrono::{DateTime, Utc};
-use jsonwebtoken::{decode, errors::Error as JwtError, Algorithm, DecodingKey, Validation};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures_util::{Stream, StreamExt};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 use once_cell::sync::Lazy;
-use regex::Regex;
-use reqwest::{Client, Error};
+use rand::Rng;
+use reqwest::{Error, RequestBuilder, Response, Url};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tokio::sync::Mutex as AsyncMutex;
-use tracing::{debug, info};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
+use tracing::{debug, info, info_span, warn, Instrument};
 
+use super::conditional::Conditions;
+use super::dns::graph_client;
+use super::egress::sign_egress;
+use super::faults::{inject_latency, inject_response_fault};
+use super::metrics::{record_abort, record_empty_drive, Abort, EmptyDrive};
+use super::naming::{deserialize_nfc, filename_regex};
+use super::request::current_request_id;
+use super::s3::synthetic_e_tag;
+use super::shutdown::sleep_until_shutdown;
+use super::telemetry::inject_trace_context;
 use crate::config;
 
 #[derive(Debug, Clone)]
@@ -19,23 +37,219 @@ struct TokenData {
 static TOKEN_DATA: Lazy<Arc<AsyncMutex<Option<TokenData>>>> =
     Lazy::new(|| Arc::new(AsyncMutex::new(None)));
 
+static READ_TOKEN_DATA: Lazy<Arc<AsyncMutex<Option<TokenData>>>> =
+    Lazy::new(|| Arc::new(AsyncMutex::new(None)));
+
+/// What a Graph call needs to be allowed to do. Reads use the token of the
+/// read-only app registration when one is configured, so read paths never
+/// carry a write-capable token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
+}
+
+impl Access {
+    fn uses_read_app(self) -> bool {
+        self == Access::Read && config().read_app_client_id.is_some()
+    }
+
+    fn token_data(self) -> &'static AsyncMutex<Option<TokenData>> {
+        if self.uses_read_app() {
+            &READ_TOKEN_DATA
+        } else {
+            &TOKEN_DATA
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct SearchRequest {
     pub query: String,
     pub prefix: String,
     pub max_keys: Option<u16>,
+    /// Includes the SharePoint `webUrl` of each result.
+    pub web_url: Option<bool>,
+    /// Continues a search from the `next_token` of the previous page.
+    pub skip_token: Option<String>,
+    /// Jumps to a page of `max_keys` results, counting from 1, instead of
+    /// following `next_token`.
+    pub page: Option<u32>,
+    /// `name`, `last_modified` (newest first), `size` (largest first) or
+    /// `relevance`, the order Graph ranks hits in. Graph pages by relevance,
+    /// so the other orders only sort the results of each page, which the
+    /// response marks with `sort_scope: "page"`.
+    pub sort_by: Option<String>,
+    /// `name` matches file names, `content` searches inside documents with
+    /// the Graph Search API.
+    pub scope: Option<String>,
+    /// Keeps files with one of these extensions, e.g. `["pdf", "docx"]`.
+    pub extensions: Option<Vec<String>>,
+    pub modified_after: Option<DateTime<Utc>>,
+    pub modified_before: Option<DateTime<Utc>>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+/// A document found by the Graph Search API, with the highlighted passage
+/// that matched.
+#[derive(Deserialize, Debug)]
+pub struct ContentHit {
+    pub summary: Option<String>,
+    pub resource: ContentHitResource,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ContentHitResource {
+    #[serde(deserialize_with = "deserialize_nfc")]
+    pub name: String,
+    #[serde(rename = "webUrl")]
+    pub web_url: String,
+    pub size: Option<u64>,
+    #[serde(rename = "lastModifiedDateTime")]
+    pub last_modified_date_time: Option<String>,
+    pub file: Option<File>,
+}
+
+/// One page of hits, `total` counting all of them.
+#[derive(Deserialize, Debug, Default)]
+pub struct ContentHits {
+    #[serde(default)]
+    pub hits: Vec<ContentHit>,
+    #[serde(default)]
+    pub total: u64,
+    #[serde(rename = "moreResultsAvailable", default)]
+    pub more_results_available: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResponse {
+    value: Vec<SearchResponseValue>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResponseValue {
+    #[serde(rename = "hitsContainers", default)]
+    hits_containers: Vec<ContentHits>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ShareRequest {
+    pub scope: String,
+    #[serde(rename = "type", default = "default_share_type")]
+    pub link_type: String,
+    pub expiration: Option<DateTime<Utc>>,
+}
+
+fn default_share_type() -> String {
+    "view".to_string()
 }
 
 #[derive(Deserialize, Debug)]
 struct TokenResponse {
     access_token: String,
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    expires_in: Option<i64>,
 }
 
+/// Azure AD answers `expires_in` as a number, IMDS as a string.
+fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(seconds) => seconds.as_i64(),
+        serde_json::Value::String(seconds) => seconds.parse().ok(),
+        _ => None,
+    })
+}
+
+/// The OAuth error body of Azure AD, whose `AADSTS` codes tell throttling
+/// and outages apart from configuration errors.
 #[derive(Deserialize, Debug)]
+struct AadError {
+    error: String,
+    #[serde(default)]
+    error_description: String,
+}
+
+/// Consecutive failed token requests. Once Azure AD failed this often,
+/// token requests are tried once instead of retried until one succeeds, so
+/// requests do not queue up behind backoffs while Azure AD is down.
+static TOKEN_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+const TOKEN_BREAKER_THRESHOLD: u32 = 3;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, Error>> + Send>>;
+
 pub struct GetAzureObjectResponse {
     pub content_type: String,
-    pub data: Vec<u8>,
+    pub stream: ByteStream,
+    pub size: Option<u64>,
     pub file_name: String,
+    pub status_code: u16,
+    pub content_range: Option<String>,
+    /// Pre-authenticated download URL of the item.
+    pub download_url: Option<String>,
+    pub e_tag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl GetAzureObjectResponse {
+    /// A 206 response for `data` starting at byte `start`, served from memory
+    /// with the metadata recorded when the range was first read from Graph.
+    pub fn partial(
+        data: Bytes,
+        start: u64,
+        total: u64,
+        content_type: String,
+        file_name: String,
+        e_tag: Option<String>,
+        last_modified: Option<String>,
+    ) -> Self {
+        GetAzureObjectResponse {
+            content_type,
+            size: Some(data.len() as u64),
+            file_name,
+            status_code: 206,
+            download_url: None,
+            e_tag,
+            last_modified,
+            content_range: Some(format!(
+                "bytes {}-{}/{}",
+                start,
+                start + data.len() as u64 - 1,
+                total
+            )),
+            stream: Box::pin(futures_util::stream::once(async move { Ok(data) })),
+        }
+    }
+
+    /// A bodyless response carrying only the status of a failed lookup.
+    pub fn status(status_code: u16, file_name: String) -> Self {
+        GetAzureObjectResponse {
+            content_type: "application/xml".to_string(),
+            stream: Box::pin(futures_util::stream::empty()),
+            size: None,
+            file_name,
+            status_code,
+            content_range: None,
+            download_url: None,
+            e_tag: None,
+            last_modified: None,
+        }
+    }
+
+    /// Buffers the whole body, only for consumers that need the complete
+    /// document such as the watermark service.
+    pub async fn collect(self) -> Result<Vec<u8>, Error> {
+        let mut stream = self.stream;
+        let mut data = Vec::with_capacity(self.size.unwrap_or_default() as usize);
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -43,27 +257,40 @@ pub struct HeadAzureObjectResponse {
     pub content_type: String,
     pub status_code: u16,
     pub size: u64,
+    pub e_tag: Option<String>,
+    pub last_modified: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct SharePointObjects {
-    #[serde(rename = "value")]
+    /// Missing in some answers about drives without content, which list as
+    /// empty rather than failing.
+    #[serde(rename = "value", default)]
     pub items: Vec<Item>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "@odata.nextLink")]
+    pub next_link: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Item {
     #[serde(rename = "createdDateTime")]
     pub created_date_time: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "eTag")]
     pub e_tag: Option<String>,
+    /// Changes with the content only, unlike `eTag`, which also changes
+    /// with metadata such as the columns.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "cTag")]
+    pub c_tag: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
     pub id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "lastModifiedDateTime")]
     pub last_modified_date_time: Option<String>,
+    #[serde(deserialize_with = "deserialize_nfc")]
     pub name: String,
     #[serde(rename = "webUrl")]
     pub web_url: String,
@@ -73,18 +300,205 @@ pub struct Item {
     pub file: Option<File>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "parentReference")]
+    pub parent_reference: Option<ItemReference>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "@microsoft.graph.downloadUrl")]
+    pub download_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "remoteItem")]
+    pub remote_item: Option<RemoteItem>,
 }
 
+impl Item {
+    /// The ETag the adapter answers for the item in listings, GET, HEAD and
+    /// conditional requests alike: a quoted digest of the content hash, or
+    /// of the cTag where SharePoint computed no hash, so it only changes
+    /// with the content. Folders are tagged by their id, as in listings.
+    pub fn object_e_tag(&self) -> Option<String> {
+        if self.folder.is_some() {
+            return Some(synthetic_e_tag(&self.id));
+        }
+        let hash = self
+            .file
+            .as_ref()
+            .and_then(|file| file.hashes.as_ref())
+            .and_then(|hashes| hashes.quick_xor_hash.as_deref());
+        hash.or(self.c_tag.as_deref())
+            .or(self.e_tag.as_deref())
+            .map(synthetic_e_tag)
+    }
+}
+
+/// An item shared into the site from another drive, e.g. a shortcut added
+/// with "Add shortcut to My files". Its content lives in the owning drive.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RemoteItem {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "lastModifiedDateTime")]
+    pub last_modified_date_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<File>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "parentReference")]
+    pub parent_reference: Option<ItemReference>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ItemReference {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "driveId")]
+    pub drive_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+/// Item as returned by the delta feed, where deleted items carry few fields.
 #[derive(Deserialize, Serialize, Debug)]
+pub struct DeltaItem {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "createdDateTime")]
+    pub created_date_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "lastModifiedDateTime")]
+    pub last_modified_date_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<File>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "parentReference")]
+    pub parent_reference: Option<ItemReference>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeltaPage {
+    value: Vec<DeltaItem>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+    #[serde(rename = "@odata.deltaLink")]
+    delta_link: Option<String>,
+}
+
+/// All changes of a delta round and the link to resume from next time.
+pub struct DriveChanges {
+    pub items: Vec<DeltaItem>,
+    /// Missing when Graph ended the feed without one.
+    pub delta_link: Option<String>,
+}
+
+/// A stored version of a file. SharePoint lists the current version too.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ItemVersion {
+    pub id: String,
+    #[serde(rename = "lastModifiedDateTime")]
+    pub last_modified_date_time: String,
+    pub size: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ItemVersions {
+    value: Vec<ItemVersion>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CopyMonitor {
+    status: String,
+    #[serde(rename = "errorCode")]
+    error_code: Option<String>,
+}
+
+/// Result of the asynchronous Graph copy action.
+pub enum CopyOutcome {
+    Completed(Box<Item>),
+    Failed(String),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Folder {
     #[serde(rename = "childCount")]
     pub child_count: u32,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct File {
     #[serde(rename = "mimeType")]
     pub mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hashes: Option<Hashes>,
+}
+
+/// Content hashes SharePoint computes for a file; `quickXorHash` is the one
+/// it reliably provides. The SHA hashes are hex encoded.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Hashes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "quickXorHash")]
+    pub quick_xor_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "sha1Hash")]
+    pub sha1_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "sha256Hash")]
+    pub sha256_hash: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SharePointPermissions {
+    #[serde(rename = "value")]
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Permission {
+    pub id: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<SharingLink>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "grantedToV2")]
+    pub granted_to: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "grantedToIdentitiesV2")]
+    pub granted_to_identities: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "inheritedFrom")]
+    pub inherited_from: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "expirationDateTime")]
+    pub expiration_date_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "hasPassword")]
+    pub has_password: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SharingLink {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "type")]
+    pub link_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "webUrl")]
+    pub web_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "preventsDownload")]
+    pub prevents_download: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -93,9 +507,10 @@ struct Claims {
 }
 
 fn prepare_prefix(prefix: String, search_query: String) -> String {
+    let search_query = urlencoding::encode(&search_query.replace('\'', "''")).into_owned();
     if prefix == "/" || prefix.is_empty() {
         if search_query.is_empty() {
-            format!("/children")
+            "/children".to_string()
         } else {
             format!("/search(q='{}')", search_query)
         }
@@ -103,68 +518,208 @@ fn prepare_prefix(prefix: String, search_query: String) -> String {
         if search_query.is_empty() {
             format!(
                 ":/{}:/children",
-                prefix.trim_start_matches("/").trim_end_matches("/")
+                encode_path(prefix.trim_start_matches("/").trim_end_matches("/"))
             )
         } else {
             format!(
                 ":/{}:/search(q='{}')",
-                prefix.trim_start_matches("/").trim_end_matches("/"),
+                encode_path(prefix.trim_start_matches("/").trim_end_matches("/")),
                 search_query
             )
         }
     }
 }
 
-fn decode_no_verify(token: &str) -> Result<DateTime<Utc>, JwtError> {
+fn decode_no_verify(token: &str) -> Option<DateTime<Utc>> {
     let mut no_verify = Validation::new(Algorithm::RS256);
     no_verify.insecure_disable_signature_validation();
     no_verify.set_audience(&["https://graph.microsoft.com".to_string()]);
-    match decode::<Claims>(
+    decode::<Claims>(
         token,
         &DecodingKey::from_secret("noverify".as_bytes()),
         &no_verify,
-    ) {
-        Ok(token_data) => Ok(DateTime::from_timestamp(token_data.claims.exp, 0).unwrap()),
-        Err(err) => Err(err),
+    )
+    .ok()
+    .and_then(|token_data| DateTime::from_timestamp(token_data.claims.exp, 0))
+}
+
+/// How the adapter's own app obtains Graph tokens, from `AUTH_MODE`. The
+/// read-only app registration always uses its client secret.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AuthMode {
+    ClientSecret,
+    /// The identity of the Azure VM or node, from the instance metadata
+    /// service. `APP_CLIENT_ID` selects a user-assigned identity.
+    ManagedIdentity,
+    /// A Kubernetes service account token federated with the app, read
+    /// from `AZURE_FEDERATED_TOKEN_FILE`.
+    WorkloadIdentity,
+}
+
+fn auth_mode() -> Option<AuthMode> {
+    match config().auth_mode.as_str() {
+        "client_secret" => Some(AuthMode::ClientSecret),
+        "managed_identity" => Some(AuthMode::ManagedIdentity),
+        "workload_identity" => Some(AuthMode::WorkloadIdentity),
+        _ => None,
+    }
+}
+
+/// Validates `AUTH_MODE` and the credentials it needs at startup.
+pub fn check_auth_mode() -> Result<(), String> {
+    match auth_mode() {
+        None => Err(format!("Unknown AUTH_MODE {}", config().auth_mode)),
+        Some(AuthMode::ClientSecret)
+            if config().app_client_id.is_empty() || config().app_client_secret.is_empty() =>
+        {
+            Err("AUTH_MODE=client_secret requires APP_CLIENT_ID and APP_CLIENT_SECRET".to_string())
+        }
+        Some(AuthMode::WorkloadIdentity) => {
+            if workload_client_id().is_empty() {
+                return Err(
+                    "AUTH_MODE=workload_identity requires APP_CLIENT_ID or AZURE_CLIENT_ID"
+                        .to_string(),
+                );
+            }
+            let Some(path) = config().azure_federated_token_file.as_deref() else {
+                return Err(
+                    "AUTH_MODE=workload_identity requires AZURE_FEDERATED_TOKEN_FILE".to_string(),
+                );
+            };
+            std::fs::metadata(path)
+                .map(|_| ())
+                .map_err(|err| format!("Cannot read AZURE_FEDERATED_TOKEN_FILE {}: {}", path, err))
+        }
+        Some(_) => Ok(()),
     }
 }
 
-async fn fetch_token() -> Result<TokenData, Error> {
-    let tenant = config().tenant.clone();
-    let client_id = config().app_client_id.clone();
-    let client_secret = config().app_client_secret.clone();
+/// The app federated with the service account; the workload identity
+/// webhook injects it as `AZURE_CLIENT_ID`.
+fn workload_client_id() -> String {
+    match config().app_client_id.as_str() {
+        "" => config().azure_client_id.clone().unwrap_or_default(),
+        client_id => client_id.to_string(),
+    }
+}
 
+/// Talks to the instance metadata service directly, never through a proxy.
+static IMDS_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .no_proxy()
+        .build()
+        .unwrap_or_default()
+});
+
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+fn token_request(access: Access) -> RequestBuilder {
     let url = format!(
         "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
-        tenant
+        config().tenant
     );
+    let scope = ("scope", "https://graph.microsoft.com/.default".to_owned());
+    let grant_type = ("grant_type", "client_credentials".to_owned());
+    let request = match auth_mode() {
+        _ if access.uses_read_app() => graph_client().post(url).form(&[
+            (
+                "client_id",
+                config().read_app_client_id.clone().unwrap_or_default(),
+            ),
+            scope,
+            (
+                "client_secret",
+                config().read_app_client_secret.clone().unwrap_or_default(),
+            ),
+            grant_type,
+        ]),
+        Some(AuthMode::ManagedIdentity) => {
+            let mut query = vec![
+                ("api-version", "2018-02-01"),
+                ("resource", "https://graph.microsoft.com/"),
+            ];
+            if !config().app_client_id.is_empty() {
+                query.push(("client_id", config().app_client_id.as_str()));
+            }
+            return IMDS_CLIENT
+                .get(IMDS_TOKEN_URL)
+                .header("Metadata", "true")
+                .query(&query);
+        }
+        Some(AuthMode::WorkloadIdentity) => {
+            // Read for every request, as the kubelet rotates the file.
+            let path = config()
+                .azure_federated_token_file
+                .as_deref()
+                .unwrap_or_default();
+            let assertion = std::fs::read_to_string(path)
+                .inspect_err(|err| warn!("Reading {} failed: {}", path, err))
+                .unwrap_or_default();
+            graph_client().post(url).form(&[
+                ("client_id", workload_client_id()),
+                scope,
+                (
+                    "client_assertion_type",
+                    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer".to_owned(),
+                ),
+                ("client_assertion", assertion.trim().to_string()),
+                grant_type,
+            ])
+        }
+        _ => graph_client().post(url).form(&[
+            ("client_id", config().app_client_id.clone()),
+            scope,
+            ("client_secret", config().app_client_secret.clone()),
+            grant_type,
+        ]),
+    };
+    request.header("Content-Type", "application/x-www-form-urlencoded")
+}
 
-    let client = Client::new();
-    match client
-        .post(url)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .form(&[
-            ("client_id", client_id),
-            ("scope", "https://graph.microsoft.com/.default".to_owned()),
-            ("client_secret", client_secret),
-            ("grant_type", "client_credentials".to_owned()),
-        ])
-        .send()
-        .await
-        .unwrap()
-        .json::<TokenResponse>()
-        .await
-    {
-        Ok(response) => Ok(TokenData {
-            access_token: response.access_token.clone(),
-            expires_at: decode_no_verify(&response.access_token).unwrap(),
-        }),
-        Err(err) => Err(err),
+async fn fetch_token(access: Access) -> Result<TokenData, Error> {
+    let (timeout, mut max_retries) = GraphOperation::Token.budget();
+    if TOKEN_FAILURES.load(Ordering::Relaxed) >= TOKEN_BREAKER_THRESHOLD {
+        max_retries = 0;
+    }
+    let response = send_with_retries(
+        GraphOperation::Token,
+        token_request(access).timeout(timeout),
+        max_retries,
+    )
+    .await
+    .inspect_err(|_| {
+        TOKEN_FAILURES.fetch_add(1, Ordering::Relaxed);
+    })?;
+    if let Some(err) = response.error_for_status_ref().err() {
+        TOKEN_FAILURES.fetch_add(1, Ordering::Relaxed);
+        match response.json::<AadError>().await {
+            Ok(aad) => warn!(
+                "Azure AD answered {}: {} {}",
+                err.status().unwrap_or_default(),
+                aad.error,
+                aad.error_description
+            ),
+            Err(_) => warn!("Azure AD answered {}", err.status().unwrap_or_default()),
+        }
+        return Err(err);
     }
+    let response = response.json::<TokenResponse>().await?;
+    TOKEN_FAILURES.store(0, Ordering::Relaxed);
+    // The token's own expiry is authoritative, `expires_in` covers tokens
+    // that are not JWTs.
+    let expires_at = decode_no_verify(&response.access_token)
+        .unwrap_or_else(|| Utc::now() + TimeDelta::seconds(response.expires_in.unwrap_or(300)));
+    Ok(TokenData {
+        access_token: response.access_token,
+        expires_at,
+    })
 }
 
-async fn get_token() -> Result<String, Error> {
-    let token_data = TOKEN_DATA.lock().await;
+/// Returns the cached token, fetching a new one once it expired. The lock is
+/// held across the fetch, so concurrent requests wait for a single refresh
+/// instead of each asking Azure AD.
+async fn get_token(access: Access) -> Result<String, Error> {
+    let mut token_data = access.token_data().lock().await;
     if let Some(ref data) = *token_data {
         if data.expires_at > Utc::now() {
             info!(
@@ -175,54 +730,442 @@ async fn get_token() -> Result<String, Error> {
             return Ok(data.access_token.clone());
         }
     }
-    drop(token_data); // Explicitly drop to release the lock before fetching new token
-    let new_token_data = fetch_token().await.unwrap();
-
-    let mut token_data = TOKEN_DATA.lock().await;
+    let new_token_data = fetch_token(access).await?;
     *token_data = Some(new_token_data.clone());
     debug!("New token fetched and stored");
 
     Ok(new_token_data.access_token)
 }
 
+/// Acquires the tokens at startup and renews them ahead of their expiry in
+/// the background, so no request has to wait for a token round trip.
+pub fn spawn_token_refresher() {
+    spawn_refresher(Access::Write);
+    if Access::Read.uses_read_app() {
+        spawn_refresher(Access::Read);
+    }
+}
+
+fn spawn_refresher(access: Access) {
+    tokio::spawn(async move {
+        loop {
+            let margin = TimeDelta::seconds(config().token_refresh_margin_secs);
+            let wait = match fetch_token(access).await {
+                Ok(new_token_data) => {
+                    let refresh_at = new_token_data.expires_at - margin;
+                    *access.token_data().lock().await = Some(new_token_data);
+                    debug!("Token refreshed, next refresh at {}", refresh_at);
+                    (refresh_at - Utc::now())
+                        .to_std()
+                        .unwrap_or_default()
+                        .max(Duration::from_secs(30))
+                }
+                Err(err) => {
+                    warn!("{:?} token refresh failed: {}", access, err);
+                    Duration::from_secs(30)
+                }
+            };
+            if !sleep_until_shutdown(wait).await {
+                return;
+            }
+        }
+    });
+}
+
+/// Percent-encodes each segment of a key for use in a Graph item path.
+fn encode_path(file_path: &str) -> String {
+    file_path
+        .split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Rewrites the `$top` of a Graph nextLink so a follow-up page never returns
+/// more items than still fit into the requested max-keys.
+fn with_page_size(next_link: &str, page_size: usize) -> String {
+    match Url::parse(next_link) {
+        Ok(mut url) => {
+            let pairs = url
+                .query_pairs()
+                .filter(|(name, _)| name != "$top")
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect::<Vec<(String, String)>>();
+            url.query_pairs_mut()
+                .clear()
+                .extend_pairs(pairs)
+                .append_pair("$top", &page_size.to_string());
+            url.to_string()
+        }
+        Err(_) => next_link.to_string(),
+    }
+}
+
+/// Class of a Graph call, selecting its timeout and retry budget. `Token`
+/// covers the Azure AD token endpoint, which is retried the same way.
+#[derive(Debug, Clone, Copy)]
+pub enum GraphOperation {
+    List,
+    Head,
+    Get,
+    Write,
+    Token,
+}
+
+impl GraphOperation {
+    fn budget(self) -> (Duration, u32) {
+        let budgets = &config().budgets;
+        let (timeout_secs, max_retries) = match self {
+            GraphOperation::List => (budgets.list_timeout_secs, budgets.list_max_retries),
+            GraphOperation::Head => (budgets.head_timeout_secs, budgets.head_max_retries),
+            GraphOperation::Get => (budgets.get_timeout_secs, budgets.get_max_retries),
+            GraphOperation::Write => (budgets.write_timeout_secs, budgets.write_max_retries),
+            GraphOperation::Token => (budgets.token_timeout_secs, budgets.token_max_retries),
+        };
+        (Duration::from_secs(timeout_secs), max_retries)
+    }
+}
+
+fn record_graph_timeout(err: &Error) {
+    if err.is_timeout() {
+        record_abort(Abort::GraphTimeout);
+    }
+}
+
+/// Single exit point for all Graph calls, enforcing the operation budget.
+async fn send_graph_request(
+    operation: GraphOperation,
+    request: RequestBuilder,
+) -> Result<Response, Error> {
+    let (timeout, max_retries) = operation.budget();
+    let span = info_span!("graph_request", otel.kind = "client", operation = ?operation);
+    let request = match current_request_id() {
+        Some(request_id) => request.header("client-request-id", request_id),
+        None => request,
+    };
+    let request = inject_trace_context(request.timeout(timeout), &span);
+    send_with_retries(operation, request, max_retries)
+        .instrument(span)
+        .await
+}
+
+async fn send_with_retries(
+    operation: GraphOperation,
+    request: RequestBuilder,
+    max_retries: u32,
+) -> Result<Response, Error> {
+    let mut attempt = 0;
+    loop {
+        // Requests with streaming bodies cannot be cloned and are sent once.
+        let Some(current) = request.try_clone() else {
+            inject_latency().await;
+            let response = sign_egress(request)?
+                .send()
+                .await
+                .inspect_err(record_graph_timeout)?;
+            return Ok(inject_response_fault(response).await);
+        };
+        // Every attempt is signed with its own timestamp.
+        let current = sign_egress(current)?;
+        inject_latency().await;
+        let result = match current.send().await {
+            Ok(response) => Ok(inject_response_fault(response).await),
+            Err(err) => Err(err),
+        };
+        let retryable = match &result {
+            Ok(response) => {
+                response.status().is_server_error() || response.status().as_u16() == 429
+            }
+            Err(err) => err.is_timeout() || err.is_connect(),
+        };
+        if !retryable || attempt >= max_retries {
+            return result.inspect_err(record_graph_timeout);
+        }
+        attempt += 1;
+        let delay = match result.as_ref().ok().and_then(retry_after) {
+            // Waiting longer than the backoff cap would hold the client
+            // hostage, so the throttled response is handed back instead.
+            Some(delay) if delay > max_retry_delay() => return result,
+            Some(delay) => delay,
+            None => backoff(attempt),
+        };
+        warn!(
+            "Retrying {:?} Graph call in {:?}, attempt {} of {}",
+            operation, delay, attempt, max_retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn max_retry_delay() -> Duration {
+    Duration::from_millis(config().budgets.retry_max_delay_ms)
+}
+
+/// Delay requested by a throttled Graph response. Graph sends seconds, but
+/// HTTP dates are accepted as well.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get("Retry-After")?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Exponential backoff with full jitter, capped at `RETRY_MAX_DELAY_MS`.
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = config()
+        .budgets
+        .retry_base_delay_ms
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(config().budgets.retry_max_delay_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling))
+}
+
+/// Lists remote files like local ones when `INCLUDE_REMOTE_ITEMS` is set.
+/// Otherwise they carry neither a file nor a folder facet and are skipped
+/// like before. Remote folders stay hidden, Graph does not address paths
+/// through them.
+fn adopt_remote_items(items: &mut [Item]) {
+    if !config().include_remote_items {
+        return;
+    }
+    for item in items.iter_mut().filter(|item| item.file.is_none()) {
+        let Some(remote) = item
+            .remote_item
+            .as_ref()
+            .filter(|remote| remote.file.is_some())
+        else {
+            continue;
+        };
+        item.file = remote.file.clone();
+        item.size = remote.size;
+        item.last_modified_date_time = remote
+            .last_modified_date_time
+            .clone()
+            .or(item.last_modified_date_time.take());
+    }
+}
+
+/// Replaces a remote file by the item in its owning drive, which carries the
+/// download URL, keeping the local name the key refers to.
+async fn resolve_remote_item(token: &str, item: Item) -> Result<Item, Error> {
+    if !config().include_remote_items || item.file.is_some() {
+        return Ok(item);
+    }
+    let Some(remote) = item
+        .remote_item
+        .as_ref()
+        .filter(|remote| remote.file.is_some())
+    else {
+        return Ok(item);
+    };
+    let Some(drive_id) = remote
+        .parent_reference
+        .as_ref()
+        .and_then(|reference| reference.drive_id.as_ref())
+    else {
+        return Ok(item);
+    };
+    let url = format!(
+        "https://graph.microsoft.com/v1.0/drives/{}/items/{}",
+        drive_id, remote.id
+    );
+    let owned = send_graph_request(
+        GraphOperation::Head,
+        graph_client()
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?
+    .error_for_status()?
+    .json::<Item>()
+    .await?;
+    Ok(Item {
+        name: item.name,
+        ..owned
+    })
+}
+
 pub async fn list_azure_objects(
-    site_id: String,
+    drive: String,
     prefix: String,
     max_keys: u16,
     search_query: Option<String>,
+    next_link: Option<String>,
 ) -> Result<SharePointObjects, Error> {
     let search_query = search_query.unwrap_or("".to_string());
-    match get_token().await {
-        Ok(token) => {
-            let relative_path = prepare_prefix(prefix, search_query.clone());
-            let url = format!(
-                "https://graph.microsoft.com/v1.0/sites/{}/drive/root{}?$top={}",
-                site_id, relative_path, max_keys
-            );
-            let client = Client::new();
-            match client
-                .get(url)
-                .header("Authorization", format!("Bearer {}", token))
-                .send()
-                .await
-                .unwrap()
-                .json::<SharePointObjects>()
-                .await
-            {
-                Ok(objects) => Ok(objects),
-                Err(err) => Err(err),
+    let lists_root = prefix.trim_matches('/').is_empty() && search_query.is_empty();
+    let first_page = next_link.is_none();
+    let token = get_token(Access::Read).await?;
+    let relative_path = prepare_prefix(prefix, search_query.clone());
+    let max_keys = usize::from(max_keys.max(1));
+    let mut url = Some(next_link.map_or(
+        format!("{}/root{}?$top={}", drive, relative_path, max_keys),
+        |next_link| with_page_size(&next_link, max_keys),
+    ));
+    let mut objects = SharePointObjects {
+        items: Vec::new(),
+        next_link: None,
+    };
+    let client = graph_client();
+    // Graph caps its page size, so keep following nextLink until
+    // max_keys items are collected or the listing is exhausted.
+    while let Some(page_url) = url.take() {
+        let response = send_graph_request(
+            GraphOperation::List,
+            client
+                .get(page_url)
+                .header("Authorization", format!("Bearer {}", token)),
+        )
+        .await?;
+        // A prefix that does not exist lists as empty, like in S3.
+        if response.status() == 404 && objects.items.is_empty() {
+            if lists_root {
+                warn!("Drive {} does not exist, listing it as empty", drive);
+                record_empty_drive(EmptyDrive::Missing);
             }
+            return Ok(objects);
         }
-        Err(err) => Err(err),
+        let page = response
+            .error_for_status()?
+            .json::<SharePointObjects>()
+            .await?;
+        let mut items = page.items;
+        adopt_remote_items(&mut items);
+        objects.items.extend(items);
+        objects.next_link = page.next_link;
+        let remaining = max_keys.saturating_sub(objects.items.len());
+        if remaining > 0 {
+            url = objects
+                .next_link
+                .as_ref()
+                .map(|next_link| with_page_size(next_link, remaining));
+        }
+    }
+    if lists_root && first_page && objects.items.is_empty() {
+        debug!("Drive {} is empty", drive);
+        record_empty_drive(EmptyDrive::Empty);
+    }
+    debug!(
+        "Listed {} items, more available: {}",
+        objects.items.len(),
+        objects.next_link.is_some()
+    );
+    Ok(objects)
+}
+
+/// Position of a recursive listing: the folder being paged, its Graph
+/// nextLink and the folders still to visit, relative to the drive root.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Traversal {
+    #[serde(default)]
+    pub drive: String,
+    pub pending: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_link: Option<String>,
+}
+
+impl Traversal {
+    /// Whether the traversal stays in `drive` and below `root`, so a
+    /// position cannot be pointed at other folders or drives.
+    fn is_within(&self, drive: &str, root: &str) -> bool {
+        let is_below_root = |folder: &String| {
+            (root.is_empty() || folder == root || folder.starts_with(&format!("{}/", root)))
+                && !folder
+                    .split('/')
+                    .any(|segment| segment == "." || segment == "..")
+        };
+        self.drive == drive
+            && self.pending.iter().all(is_below_root)
+            && self.current.as_ref().is_none_or(is_below_root)
+            && self
+                .next_link
+                .as_deref()
+                .is_none_or(|next_link| next_link.starts_with("https://graph.microsoft.com/"))
+    }
+}
+
+/// Lists all files below `prefix` across folder levels, up to `max_keys`.
+/// Item names are returned relative to `prefix` and `next_link` holds the
+/// serialized `Traversal` to resume from while folders remain.
+pub async fn list_azure_objects_recursive(
+    drive: String,
+    prefix: String,
+    max_keys: u16,
+    traversal: Option<Traversal>,
+) -> Result<SharePointObjects, Error> {
+    let root = prefix.trim_matches('/').to_string();
+    let mut objects = SharePointObjects {
+        items: Vec::new(),
+        next_link: None,
+    };
+    if traversal
+        .as_ref()
+        .is_some_and(|traversal| !traversal.is_within(&drive, &root))
+    {
+        warn!("Ignoring a traversal outside of {} in {}", root, drive);
+        return Ok(objects);
+    }
+    let mut traversal = traversal.unwrap_or(Traversal {
+        drive: drive.clone(),
+        pending: vec![root.clone()],
+        ..Default::default()
+    });
+    let max_keys = usize::from(max_keys.max(1));
+    while objects.items.len() < max_keys {
+        let (folder, next_link) = match traversal.next_link.take() {
+            Some(next_link) => (
+                traversal.current.clone().unwrap_or_default(),
+                Some(next_link),
+            ),
+            None => match traversal.pending.pop() {
+                Some(folder) => (folder, None),
+                None => break,
+            },
+        };
+        let remaining = (max_keys - objects.items.len()).min(usize::from(u16::MAX)) as u16;
+        let page =
+            list_azure_objects(drive.clone(), folder.clone(), remaining, None, next_link).await?;
+        let relative = folder
+            .strip_prefix(root.as_str())
+            .unwrap_or(&folder)
+            .trim_start_matches('/');
+        for mut item in page.items {
+            let path = if folder.is_empty() {
+                item.name.clone()
+            } else {
+                format!("{}/{}", folder, item.name)
+            };
+            if item.folder.is_some() {
+                traversal.pending.push(path);
+            } else {
+                if !relative.is_empty() {
+                    item.name = format!("{}/{}", relative, item.name);
+                }
+                objects.items.push(item);
+            }
+        }
+        traversal.current = Some(folder);
+        traversal.next_link = page.next_link;
+    }
+    if !traversal.pending.is_empty() || traversal.next_link.is_some() {
+        objects.next_link = serde_json::to_string(&traversal).ok();
     }
+    Ok(objects)
 }
 
 pub async fn head_azure_object(
-    site_id: String,
+    drive: String,
     file_path: String,
 ) -> Result<HeadAzureObjectResponse, Error> {
-    let filename_pattern = config().filename_pattern.clone();
-    let regex = Regex::new(&filename_pattern).unwrap();
+    let regex = filename_regex();
     let part = if file_path.is_empty() || file_path.eq("/") {
         ""
     } else {
@@ -231,101 +1174,1254 @@ pub async fn head_azure_object(
     let key = if file_path.is_empty() {
         "/".to_string()
     } else {
-        file_path.clone()
+        encode_path(&file_path)
     };
-    match get_token().await {
-        Ok(token) => {
-            let url = format!(
-                "https://graph.microsoft.com/v1.0/sites/{}/drive/root{}{}",
-                site_id, part, key
-            );
-            let client = Client::new();
-            match client
-                .get(url)
-                .header("Authorization", format!("Bearer {}", token))
-                .send()
-                .await
-                .unwrap()
-                .json::<Item>()
-                .await
-            {
-                Ok(result) => {
-                    if key.ends_with('/') {
-                        if result.folder.is_some() {
-                            Ok(HeadAzureObjectResponse {
-                                content_type: "application/xml".to_string(),
-                                status_code: 200,
-                                size: 0,
-                            })
-                        } else {
-                            Ok(HeadAzureObjectResponse {
-                                content_type: "application/xml".to_string(),
-                                status_code: 404,
-                                size: 0,
-                            })
-                        }
-                    } else {
-                        if result.file.is_some() {
-                            if !regex.is_match(&result.name) {
-                                return Ok(HeadAzureObjectResponse {
-                                    content_type: "application/xml".to_string(),
-                                    status_code: 403,
-                                    size: 0,
-                                });
-                            }
-                            Ok(HeadAzureObjectResponse {
-                                content_type: result.file.unwrap().mime_type,
-                                status_code: 200,
-                                size: result.size.unwrap_or(0),
-                            })
-                        } else {
-                            Ok(HeadAzureObjectResponse {
-                                content_type: "application/xml".to_string(),
-                                status_code: 404,
-                                size: 0,
-                            })
-                        }
-                    }
+    let token = get_token(Access::Read).await?;
+    let url = format!("{}/root{}{}", drive, part, key);
+    match graph_batch(GraphOperation::Head, &token, url)
+        .await?
+        .error_for_status()?
+        .json::<Item>()
+        .await
+    {
+        Ok(result) => {
+            let result = resolve_remote_item(&token, result).await?;
+            let e_tag = result.object_e_tag();
+            if key.ends_with('/') {
+                let folder_exists = result
+                    .folder
+                    .as_ref()
+                    .is_some_and(|folder| folder.child_count > 0 || config().empty_folder_exists);
+                if folder_exists {
+                    Ok(HeadAzureObjectResponse {
+                        content_type: "application/xml".to_string(),
+                        status_code: 200,
+                        size: 0,
+                        e_tag: Some(synthetic_e_tag(&result.id)),
+                        last_modified: result.last_modified_date_time.clone(),
+                    })
+                } else {
+                    Ok(HeadAzureObjectResponse {
+                        content_type: "application/xml".to_string(),
+                        status_code: 404,
+                        size: 0,
+                        e_tag: None,
+                        last_modified: None,
+                    })
                 }
-                Err(err) => Err(err),
+            } else if let Some(file) = result.file {
+                if !regex.is_match(&result.name) {
+                    return Ok(HeadAzureObjectResponse {
+                        content_type: "application/xml".to_string(),
+                        status_code: 403,
+                        size: 0,
+                        e_tag: None,
+                        last_modified: None,
+                    });
+                }
+                Ok(HeadAzureObjectResponse {
+                    e_tag,
+                    content_type: file.mime_type,
+                    status_code: 200,
+                    size: result.size.unwrap_or(0),
+                    last_modified: result.last_modified_date_time,
+                })
+            } else {
+                Ok(HeadAzureObjectResponse {
+                    content_type: "application/xml".to_string(),
+                    status_code: 404,
+                    size: 0,
+                    e_tag: None,
+                    last_modified: None,
+                })
             }
         }
         Err(err) => Err(err),
     }
 }
 
+/// Looks up the item for its metadata and downloads the content from its
+/// pre-authenticated download URL, forwarding the range if any.
 pub async fn get_azure_object_data(
-    site_id: String,
+    drive: String,
     file_path: String,
+    range: Option<String>,
+    conditions: &Conditions,
 ) -> Result<GetAzureObjectResponse, Error> {
-    match get_token().await {
-        Ok(token) => {
-            let url = format!(
-                "https://graph.microsoft.com/v1.0/sites/{}/drive/root:/{}:/content",
-                site_id, file_path
-            );
-            let file_name = file_path.split('/').last().unwrap_or_default();
-            let client = Client::new();
-            match client
-                .get(url)
-                .header("Authorization", format!("Bearer {}", token))
-                .send()
-                .await
-            {
-                Ok(objects) => Ok(GetAzureObjectResponse {
-                    content_type: objects
-                        .headers()
-                        .get("Content-Type")
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_string(),
-                    data: objects.bytes().await.unwrap().to_vec(),
-                    file_name: file_name.to_string(),
-                }),
-                Err(err) => Err(err),
+    let token = get_token(Access::Read).await?;
+    let url = format!("{}/root:/{}", drive, encode_path(&file_path));
+    let file_name = file_path
+        .split('/')
+        .next_back()
+        .unwrap_or_default()
+        .to_string();
+    let client = graph_client();
+    let item = send_graph_request(
+        GraphOperation::Head,
+        client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?;
+    if !item.status().is_success() {
+        return Ok(GetAzureObjectResponse::status(
+            item.status().as_u16(),
+            file_name,
+        ));
+    }
+    let item = resolve_remote_item(&token, item.json::<Item>().await?).await?;
+    let e_tag = item.object_e_tag();
+    let (Some(file), Some(download_url)) = (item.file, item.download_url) else {
+        return Ok(GetAzureObjectResponse::status(404, file_name));
+    };
+    if conditions.is_not_modified(e_tag.as_deref(), item.last_modified_date_time.as_deref()) {
+        return Ok(GetAzureObjectResponse {
+            e_tag,
+            last_modified: item.last_modified_date_time,
+            ..GetAzureObjectResponse::status(304, file_name)
+        });
+    }
+    let mut request = client.get(&download_url);
+    if let Some(range) = range {
+        request = request.header("Range", range);
+    }
+    match send_graph_request(GraphOperation::Get, request).await {
+        Ok(objects) => {
+            let header = |name: &str| {
+                objects
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            };
+            let status_code = objects.status().as_u16();
+            Ok(GetAzureObjectResponse {
+                // The item's MIME type, as HEAD reports it, rather
+                // than whatever the download host answers with.
+                content_type: file.mime_type,
+                content_range: header("Content-Range"),
+                status_code,
+                size: objects
+                    .content_length()
+                    .or(item.size.filter(|_| status_code == 200)),
+                download_url: Some(download_url),
+                e_tag,
+                last_modified: item.last_modified_date_time,
+                file_name,
+                stream: Box::pin(objects.bytes_stream()),
+            })
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Extensions of the documents Graph converts with `/content?format=pdf`.
+const PDF_SOURCES: [&str; 18] = [
+    "csv", "doc", "docx", "odp", "ods", "odt", "pot", "potm", "potx", "pps", "ppsx", "ppsxm",
+    "ppt", "pptm", "pptx", "rtf", "xls", "xlsx",
+];
+
+pub fn is_pdf_convertible(file_path: &str) -> bool {
+    file_path
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| PDF_SOURCES.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Downloads a document converted to PDF by Graph. The redirect to the
+/// converted content is followed here, and as the PDF is not the stored
+/// item it carries neither ETag nor modification time.
+pub async fn get_azure_object_pdf(
+    drive: String,
+    file_path: String,
+) -> Result<GetAzureObjectResponse, Error> {
+    let token = get_token(Access::Read).await?;
+    let url = format!(
+        "{}/root:/{}:/content?format=pdf",
+        drive,
+        encode_path(&file_path)
+    );
+    let name = file_path.split('/').next_back().unwrap_or_default();
+    let file_name = match name.rsplit_once('.') {
+        Some((stem, _)) => format!("{}.pdf", stem),
+        None => format!("{}.pdf", name),
+    };
+    let response = send_graph_request(
+        GraphOperation::Get,
+        graph_client()
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?;
+    let status_code = response.status().as_u16();
+    if !response.status().is_success() {
+        return Ok(GetAzureObjectResponse::status(status_code, file_name));
+    }
+    Ok(GetAzureObjectResponse {
+        content_type: "application/pdf".to_string(),
+        size: response.content_length(),
+        file_name,
+        status_code,
+        content_range: None,
+        download_url: None,
+        e_tag: None,
+        last_modified: None,
+        stream: Box::pin(response.bytes_stream()),
+    })
+}
+
+pub async fn list_azure_permissions(
+    drive: String,
+    file_path: String,
+) -> Result<SharePointPermissions, Error> {
+    let token = get_token(Access::Read).await?;
+    let url = format!("{}/root:/{}:/permissions", drive, encode_path(&file_path));
+    let client = graph_client();
+    send_graph_request(
+        GraphOperation::List,
+        client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?
+    .error_for_status()?
+    .json::<SharePointPermissions>()
+    .await
+}
+
+pub async fn create_azure_sharing_link(
+    drive: String,
+    file_path: String,
+    request: ShareRequest,
+) -> Result<Permission, Error> {
+    let mut body = serde_json::json!({
+        "type": request.link_type,
+        "scope": request.scope,
+    });
+    if let Some(expiration) = request.expiration {
+        body["expirationDateTime"] = serde_json::Value::String(expiration.to_rfc3339());
+    }
+    let token = get_token(Access::Write).await?;
+    let url = format!("{}/root:/{}:/createLink", drive, encode_path(&file_path));
+    let client = graph_client();
+    send_graph_request(
+        GraphOperation::Write,
+        client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body),
+    )
+    .await?
+    .error_for_status()?
+    .json::<Permission>()
+    .await
+}
+
+pub async fn put_azure_object(
+    drive: String,
+    file_path: String,
+    content_type: String,
+    data: Vec<u8>,
+) -> Result<Item, Error> {
+    let token = get_token(Access::Write).await?;
+    let url = format!("{}/root:/{}:/content", drive, encode_path(&file_path));
+    let client = graph_client();
+    send_graph_request(
+        GraphOperation::Write,
+        client
+            .put(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", content_type)
+            .body(data),
+    )
+    .await?
+    .error_for_status()?
+    .json::<Item>()
+    .await
+}
+
+#[derive(Deserialize, Debug)]
+struct UploadSession {
+    #[serde(rename = "uploadUrl")]
+    upload_url: String,
+}
+
+/// A Graph upload session for an object of known size. Chunks go up in
+/// order and each is acknowledged before the caller reads the next one, so
+/// an upload holds at most one chunk in memory.
+pub struct Upload {
+    url: String,
+    size: u64,
+    offset: u64,
+}
+
+/// Opens an upload session for `size` bytes, replacing an existing item.
+pub async fn create_azure_upload_session(
+    drive: String,
+    file_path: String,
+    size: u64,
+) -> Result<Upload, Error> {
+    let token = get_token(Access::Write).await?;
+    let url = format!(
+        "{}/root:/{}:/createUploadSession",
+        drive,
+        encode_path(&file_path)
+    );
+    let session = send_graph_request(
+        GraphOperation::Write,
+        graph_client()
+            .post(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&serde_json::json!({
+                "item": { "@microsoft.graph.conflictBehavior": "replace" }
+            })),
+    )
+    .await?
+    .error_for_status()?
+    .json::<UploadSession>()
+    .await?;
+    Ok(Upload {
+        url: session.upload_url,
+        size,
+        offset: 0,
+    })
+}
+
+impl Upload {
+    /// Sends the next chunk and returns the item once the last one is in.
+    /// The upload URL is pre-authenticated and must not carry a token.
+    pub async fn put_chunk(&mut self, chunk: Bytes) -> Result<Option<Item>, Error> {
+        let end = self.offset + chunk.len() as u64;
+        let response = send_graph_request(
+            GraphOperation::Write,
+            graph_client()
+                .put(&self.url)
+                .header("Content-Length", chunk.len())
+                .header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", self.offset, end - 1, self.size),
+                )
+                .body(chunk),
+        )
+        .await?
+        .error_for_status()?;
+        self.offset = end;
+        if response.status().as_u16() == 202 {
+            return Ok(None);
+        }
+        response.json::<Item>().await.map(Some)
+    }
+
+    /// Discards the bytes of an upload that will not complete.
+    pub async fn cancel(self) {
+        if let Err(err) =
+            send_graph_request(GraphOperation::Write, graph_client().delete(&self.url)).await
+        {
+            warn!("Cancelling upload session failed: {}", err);
+        }
+    }
+}
+
+pub async fn delete_azure_object(drive: String, file_path: String) -> Result<(), Error> {
+    let token = get_token(Access::Write).await?;
+    let url = format!(
+        "{}/root:/{}",
+        drive,
+        encode_path(file_path.trim_end_matches('/'))
+    );
+    let client = graph_client();
+    let response = send_graph_request(
+        GraphOperation::Write,
+        client
+            .delete(url)
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?;
+    // Deleting a missing key succeeds in S3 as well.
+    if response.status().as_u16() == 404 {
+        return Ok(());
+    }
+    match response.error_for_status() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+pub async fn get_azure_item(drive: String, file_path: String) -> Result<Item, Error> {
+    let path = file_path.trim_matches('/');
+    let token = get_token(Access::Read).await?;
+    let url = if path.is_empty() {
+        format!("{}/root", drive)
+    } else {
+        format!("{}/root:/{}", drive, encode_path(path))
+    };
+    graph_batch(GraphOperation::Head, &token, url)
+        .await?
+        .error_for_status()?
+        .json::<Item>()
+        .await
+}
+
+/// Lists the stored versions of an item, newest first.
+pub async fn list_azure_versions(
+    drive: String,
+    item_id: String,
+) -> Result<Vec<ItemVersion>, Error> {
+    let token = get_token(Access::Read).await?;
+    let mut url = Some(format!("{}/items/{}/versions", drive, item_id));
+    let mut versions = Vec::new();
+    let client = graph_client();
+    while let Some(page_url) = url.take() {
+        let page = send_graph_request(
+            GraphOperation::List,
+            client
+                .get(page_url)
+                .header("Authorization", format!("Bearer {}", token)),
+        )
+        .await?
+        .error_for_status()?
+        .json::<ItemVersions>()
+        .await?;
+        versions.extend(page.value);
+        url = page.next_link;
+    }
+    Ok(versions)
+}
+
+/// Downloads a stored version of a file, forwarding the range if any. The
+/// item supplies name and MIME type, which versions do not carry.
+pub async fn get_azure_version_data(
+    drive: String,
+    item: Item,
+    version: ItemVersion,
+    range: Option<String>,
+) -> Result<GetAzureObjectResponse, Error> {
+    let token = get_token(Access::Read).await?;
+    let url = format!(
+        "{}/items/{}/versions/{}/content",
+        drive,
+        item.id,
+        urlencoding::encode(&version.id)
+    );
+    let mut request = graph_client()
+        .get(url)
+        .header("Authorization", format!("Bearer {}", token));
+    if let Some(range) = range {
+        request = request.header("Range", range);
+    }
+    let response = send_graph_request(GraphOperation::Get, request).await?;
+    let status_code = response.status().as_u16();
+    if !response.status().is_success() {
+        return Ok(GetAzureObjectResponse::status(status_code, item.name));
+    }
+    Ok(GetAzureObjectResponse {
+        content_type: item
+            .file
+            .map(|file| file.mime_type)
+            .unwrap_or("application/octet-stream".to_string()),
+        content_range: response
+            .headers()
+            .get("Content-Range")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        size: response
+            .content_length()
+            .or(version.size.filter(|_| status_code == 200)),
+        status_code,
+        // The redirect to the content is followed here, so there is no
+        // pre-authenticated URL to hand off.
+        download_url: None,
+        e_tag: Some(synthetic_e_tag(&format!("{}/{}", item.id, version.id))),
+        last_modified: Some(version.last_modified_date_time),
+        file_name: item.name,
+        stream: Box::pin(response.bytes_stream()),
+    })
+}
+
+/// Copies an item with the Graph `copy` action, replacing an existing
+/// destination like S3 does, and polls the monitor URL until it finishes.
+/// The source may live in another drive.
+pub async fn copy_azure_object(
+    source_drive: String,
+    source_path: String,
+    drive: String,
+    destination_path: String,
+) -> Result<CopyOutcome, Error> {
+    let (parent_path, name) = destination_path
+        .rsplit_once('/')
+        .unwrap_or(("", destination_path.as_str()));
+    let parent = get_azure_item(drive.clone(), parent_path.to_string()).await?;
+    let body = serde_json::json!({
+        "parentReference": {
+            "driveId": parent.parent_reference.and_then(|reference| reference.drive_id),
+            "id": parent.id,
+        },
+        "name": name,
+    });
+    let token = get_token(Access::Write).await?;
+    let url = format!(
+        "{}/root:/{}:/copy?@microsoft.graph.conflictBehavior=replace",
+        source_drive,
+        encode_path(&source_path)
+    );
+    let client = graph_client();
+    let response = send_graph_request(
+        GraphOperation::Write,
+        client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body),
+    )
+    .await?
+    .error_for_status()?;
+    let Some(monitor_url) = response
+        .headers()
+        .get("Location")
+        .and_then(|location| location.to_str().ok())
+        .map(str::to_string)
+    else {
+        return Ok(CopyOutcome::Failed(
+            "Graph did not return a copy monitor".to_string(),
+        ));
+    };
+
+    let deadline =
+        Utc::now() + chrono::TimeDelta::seconds(config().budgets.write_timeout_secs as i64);
+    while Utc::now() < deadline {
+        // The monitor URL is pre-authenticated and must not get a bearer token.
+        let monitor = send_graph_request(GraphOperation::Head, client.get(&monitor_url))
+            .await?
+            .error_for_status()?
+            .json::<CopyMonitor>()
+            .await?;
+        match monitor.status.as_str() {
+            "completed" => {
+                let item = get_azure_item(drive, destination_path).await?;
+                return Ok(CopyOutcome::Completed(Box::new(item)));
+            }
+            "failed" => {
+                return Ok(CopyOutcome::Failed(
+                    monitor.error_code.unwrap_or(monitor.status),
+                ))
             }
+            _ => tokio::time::sleep(Duration::from_millis(500)).await,
+        }
+    }
+    Ok(CopyOutcome::Failed(
+        "Copy did not complete in time".to_string(),
+    ))
+}
+
+/// Creates a folder along with its missing parents and returns it.
+pub async fn create_azure_folder(drive: String, folder_path: String) -> Result<Item, Error> {
+    let token = get_token(Access::Write).await?;
+    let client = graph_client();
+    let mut parent = String::new();
+    for name in folder_path.split('/').filter(|name| !name.is_empty()) {
+        let url = if parent.is_empty() {
+            format!("{}/root/children", drive)
+        } else {
+            format!("{}/root:/{}:/children", drive, encode_path(&parent))
+        };
+        let body = serde_json::json!({
+            "name": name,
+            "folder": {},
+            "@microsoft.graph.conflictBehavior": "fail",
+        });
+        let response = send_graph_request(
+            GraphOperation::Write,
+            client
+                .post(url)
+                .header("Authorization", format!("Bearer {}", token))
+                .json(&body),
+        )
+        .await?;
+        // Folders that exist already answer 409.
+        if response.status() != 409 {
+            response.error_for_status()?;
         }
+        if !parent.is_empty() {
+            parent.push('/');
+        }
+        parent.push_str(name);
+    }
+    get_azure_item(drive, parent).await
+}
+
+/// Moves an item within its drive, replacing an existing destination and
+/// creating the destination folder when it is missing.
+pub async fn move_azure_object(
+    drive: String,
+    source_path: String,
+    destination_path: String,
+) -> Result<Item, Error> {
+    let (parent_path, name) = destination_path
+        .rsplit_once('/')
+        .unwrap_or(("", destination_path.as_str()));
+    let parent = match get_azure_item(drive.clone(), parent_path.to_string()).await {
+        Err(err) if err.status().is_some_and(|status| status == 404) => {
+            create_azure_folder(drive.clone(), parent_path.to_string()).await?
+        }
+        parent => parent?,
+    };
+    let body = serde_json::json!({
+        "parentReference": { "id": parent.id },
+        "name": name,
+    });
+    let token = get_token(Access::Write).await?;
+    let url = format!(
+        "{}/root:/{}?@microsoft.graph.conflictBehavior=replace",
+        drive,
+        encode_path(source_path.trim_matches('/'))
+    );
+    let client = graph_client();
+    send_graph_request(
+        GraphOperation::Write,
+        client
+            .patch(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body),
+    )
+    .await?
+    .error_for_status()?
+    .json::<Item>()
+    .await
+}
+
+/// Reads the listItem fields of an item, the columns of its library.
+pub async fn get_azure_item_fields(
+    drive: String,
+    file_path: String,
+) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
+    let token = get_token(Access::Read).await?;
+    let url = format!(
+        "{}/root:/{}:/listItem/fields",
+        drive,
+        encode_path(file_path.trim_matches('/'))
+    );
+    graph_batch(GraphOperation::Head, &token, url)
+        .await?
+        .error_for_status()?
+        .json::<serde_json::Map<String, serde_json::Value>>()
+        .await
+}
+
+/// Collects the drive delta since `delta_link`, or only a fresh link when none
+/// is given, so a first call does not enumerate the whole drive.
+pub async fn list_azure_changes(
+    drive: String,
+    delta_link: Option<String>,
+) -> Result<DriveChanges, Error> {
+    let token = get_token(Access::Read).await?;
+    let mut url = Some(delta_link.unwrap_or(format!("{}/root/delta?token=latest", drive)));
+    let mut items = Vec::new();
+    let client = graph_client();
+    while let Some(page_url) = url.take() {
+        let page = send_graph_request(
+            GraphOperation::List,
+            client
+                .get(page_url)
+                .header("Authorization", format!("Bearer {}", token)),
+        )
+        .await?
+        .error_for_status()?
+        .json::<DeltaPage>()
+        .await?;
+        items.extend(page.value);
+        if page.delta_link.is_some() {
+            return Ok(DriveChanges {
+                items,
+                delta_link: page.delta_link,
+            });
+        }
+        url = page.next_link;
+    }
+    Ok(DriveChanges {
+        items,
+        delta_link: None,
+    })
+}
+
+/// Looks up the key of an item by id, the delta feed itself omits paths.
+pub async fn get_azure_item_key(drive: String, item_id: String) -> Result<String, Error> {
+    let token = get_token(Access::Read).await?;
+    let url = format!(
+        "{}/items/{}?$select=id,name,parentReference",
+        drive, item_id
+    );
+    let client = graph_client();
+    let item = send_graph_request(
+        GraphOperation::Head,
+        client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?
+    .error_for_status()?
+    .json::<DeltaItem>()
+    .await?;
+    let parent_path = item
+        .parent_reference
+        .and_then(|reference| reference.path)
+        .unwrap_or_default();
+    Ok(key_in_parent(&parent_path, &item.name.unwrap_or_default()))
+}
+
+/// The key of an item named `name` whose parent has the Graph path
+/// `parent_path`, such as `/drives/{id}/root:/folder`.
+pub fn key_in_parent(parent_path: &str, name: &str) -> String {
+    let parent = parent_path
+        .split_once("root:")
+        .map(|(_, path)| path.trim_matches('/'))
+        .unwrap_or_default();
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+/// Searches the contents of the documents of a site with the Graph Search
+/// API, returning `size` hits starting at `from`. Application tokens need
+/// `SEARCH_REGION`.
+pub async fn search_azure_content(
+    site_id: &str,
+    query: &str,
+    from: u32,
+    size: u16,
+) -> Result<ContentHits, Error> {
+    let token = get_token(Access::Read).await?;
+    // Site ids are `hostname,site-guid,web-guid`, KQL matches the GUID.
+    let site_guid = site_id.split(',').nth(1).unwrap_or(site_id);
+    let mut request = serde_json::json!({
+        "entityTypes": ["driveItem"],
+        "query": { "queryString": format!("{} SiteId:{}", query, site_guid) },
+        "from": from,
+        // Graph returns at most 500 driveItem hits per request.
+        "size": size.clamp(1, 500),
+    });
+    if let Some(region) = &config().search_region {
+        request["region"] = serde_json::Value::String(region.clone());
+    }
+    let client = graph_client();
+    let response = send_graph_request(
+        GraphOperation::List,
+        client
+            .post("https://graph.microsoft.com/v1.0/search/query")
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&serde_json::json!({ "requests": [request] })),
+    )
+    .await?
+    .error_for_status()?
+    .json::<SearchResponse>()
+    .await?;
+    Ok(response
+        .value
+        .into_iter()
+        .next()
+        .and_then(|value| value.hits_containers.into_iter().next())
+        .unwrap_or_default())
+}
+
+/// Lists the document libraries of a site. Drives carry the same id, name
+/// and timestamps as items, so they are returned as such.
+pub async fn list_azure_drives(site_id: String) -> Result<Vec<Item>, Error> {
+    let token = get_token(Access::Read).await?;
+    let url = format!(
+        "https://graph.microsoft.com/v1.0/sites/{}/drives?$select=id,name,webUrl,createdDateTime,lastModifiedDateTime",
+        site_id
+    );
+    let client = graph_client();
+    let drives = send_graph_request(
+        GraphOperation::List,
+        client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?
+    .error_for_status()?
+    .json::<SharePointObjects>()
+    .await?;
+    Ok(drives.items)
+}
+
+/// Looks up a site by its `hostname:/server-relative-path` address and
+/// returns the site id together with the id of its default drive, `None`
+/// for sites without one. Those serve a missing drive, which readiness
+/// reports as a warning.
+pub async fn get_azure_site(address: String) -> Result<(String, Option<String>), Error> {
+    let token = get_token(Access::Read).await?;
+    let client = graph_client();
+    let site = send_graph_request(
+        GraphOperation::Head,
+        client
+            .get(format!(
+                "https://graph.microsoft.com/v1.0/sites/{}?$select=id",
+                address
+            ))
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?
+    .error_for_status()?
+    .json::<DeltaItem>()
+    .await?;
+    let response = send_graph_request(
+        GraphOperation::Head,
+        client
+            .get(format!(
+                "https://graph.microsoft.com/v1.0/sites/{}/drive?$select=id",
+                site.id
+            ))
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?;
+    if response.status() == 404 {
+        warn!("Site {} has no default drive", site.id);
+        return Ok((site.id, None));
+    }
+    let drive = response.error_for_status()?.json::<DeltaItem>().await?;
+    Ok((site.id, Some(drive.id)))
+}
+
+/// Acquires the tokens of every configured app registration.
+pub async fn check_azure_tokens() -> Result<(), Error> {
+    get_token(Access::Write).await?;
+    if Access::Read.uses_read_app() {
+        get_token(Access::Read).await?;
+    }
+    Ok(())
+}
+
+/// Whether a drive exists and holds anything.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriveState {
+    Missing,
+    Empty,
+    Populated,
+}
+
+/// Looks at the root folder of a drive, which every existing drive has.
+pub async fn check_azure_drive(drive: String) -> Result<DriveState, Error> {
+    let token = get_token(Access::Read).await?;
+    let response = send_graph_request(
+        GraphOperation::Head,
+        graph_client()
+            .get(format!("{}/root?$select=id,folder", drive))
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?;
+    if response.status() == 404 {
+        return Ok(DriveState::Missing);
+    }
+    let root = response
+        .error_for_status()?
+        .json::<serde_json::Value>()
+        .await?;
+    let child_count = root["folder"]["childCount"].as_u64().unwrap_or_default();
+    Ok(if child_count == 0 {
+        DriveState::Empty
+    } else {
+        DriveState::Populated
+    })
+}
+
+/// Fetches the id of a site, proving the site exists and is readable.
+pub async fn check_azure_site(site_id: String) -> Result<(), Error> {
+    let token = get_token(Access::Read).await?;
+    send_graph_request(
+        GraphOperation::Head,
+        graph_client()
+            .get(format!(
+                "https://graph.microsoft.com/v1.0/sites/{}?$select=id",
+                site_id
+            ))
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?
+    .error_for_status()?;
+    Ok(())
+}
+
+/// Looks up the item behind a SharePoint web or sharing URL and returns its id.
+pub async fn resolve_azure_share(web_url: String) -> Result<String, Error> {
+    let token = get_token(Access::Read).await?;
+    let share_id = format!("u!{}", URL_SAFE_NO_PAD.encode(web_url.trim()));
+    let url = format!(
+        "https://graph.microsoft.com/v1.0/shares/{}/driveItem?$select=id",
+        share_id
+    );
+    let client = graph_client();
+    let item = send_graph_request(
+        GraphOperation::Head,
+        client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?
+    .error_for_status()?
+    .json::<DeltaItem>()
+    .await?;
+    Ok(item.id)
+}
+
+/// Graph accepts at most 20 requests per `$batch`.
+const MAX_BATCH_SIZE: usize = 20;
+
+#[derive(Serialize, Debug)]
+struct BatchRequest {
+    id: String,
+    method: &'static str,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchResponse {
+    responses: Vec<BatchItemResponse>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchItemResponse {
+    id: String,
+    status: u16,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+}
+
+/// A GET waiting to share a `$batch` call with others.
+struct BatchedGet {
+    url: String,
+    request_id: Option<String>,
+    reply: oneshot::Sender<Option<BatchItemResponse>>,
+}
+
+static GRAPH_BATCHER: OnceLock<mpsc::Sender<BatchedGet>> = OnceLock::new();
+
+/// Collects the GETs arriving within `GRAPH_BATCH_WINDOW_MS` of the first,
+/// up to `MAX_BATCH_SIZE`, and sends them as one `$batch` call while the
+/// next ones are collected.
+fn graph_batcher() -> &'static mpsc::Sender<BatchedGet> {
+    GRAPH_BATCHER.get_or_init(|| {
+        let (sender, mut receiver) = mpsc::channel::<BatchedGet>(1024);
+        tokio::spawn(async move {
+            let window = Duration::from_millis(config().graph_batch_window_ms);
+            while let Some(first) = receiver.recv().await {
+                let deadline = tokio::time::Instant::now() + window;
+                let mut pending = vec![first];
+                while pending.len() < MAX_BATCH_SIZE {
+                    match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                        Ok(Some(get)) => pending.push(get),
+                        _ => break,
+                    }
+                }
+                tokio::spawn(send_batched_gets(pending));
+            }
+        });
+        sender
+    })
+}
+
+async fn send_batched_gets(pending: Vec<BatchedGet>) {
+    let requests = pending
+        .iter()
+        .enumerate()
+        .map(|(index, get)| BatchRequest {
+            id: index.to_string(),
+            method: "GET",
+            url: get.url.clone(),
+            headers: get
+                .request_id
+                .as_ref()
+                .map(|request_id| serde_json::json!({ "client-request-id": request_id })),
+            body: None,
+        })
+        .collect::<Vec<BatchRequest>>();
+    let responses = match get_token(Access::Read).await {
+        Ok(token) => send_batch(GraphOperation::Head, &token, requests).await,
         Err(err) => Err(err),
+    };
+    let mut responses = responses
+        .inspect_err(|err| warn!("Batching {} Graph lookups failed: {}", pending.len(), err))
+        .unwrap_or_default()
+        .into_iter();
+    // Callers left without a response send their request on their own.
+    for get in pending {
+        let _ = get.reply.send(responses.next().flatten());
+    }
+}
+
+/// Queues a GET for the batcher and turns its sub-response into a regular
+/// response. Throttled and failed sub-requests answer `None`, so they are
+/// retried on their own like any other request.
+async fn batched_get(url: &str) -> Option<Response> {
+    let url = Url::parse(url).ok()?;
+    let relative = url
+        .as_str()
+        .strip_prefix("https://graph.microsoft.com/v1.0")?
+        .to_string();
+    let (reply, response) = oneshot::channel();
+    graph_batcher()
+        .send(BatchedGet {
+            url: relative,
+            request_id: current_request_id(),
+            reply,
+        })
+        .await
+        .ok()?;
+    let item = response.await.ok()??;
+    if item.status == 429 || item.status >= 500 {
+        return None;
+    }
+    let mut builder = http::Response::builder().status(item.status);
+    for (name, value) in &item.headers {
+        builder = builder.header(name, value);
     }
+    let body = item
+        .body
+        .and_then(|body| serde_json::to_vec(&body).ok())
+        .unwrap_or_default();
+    builder.body(body).ok().map(Response::from)
+}
+
+/// GETs a Graph resource such as an item or its fields. With
+/// `GRAPH_BATCH_WINDOW_MS` set, concurrent GETs are coalesced into one
+/// `$batch` call, which saves round trips and counts once against the
+/// throttling limits.
+pub async fn graph_batch(
+    operation: GraphOperation,
+    token: &str,
+    url: String,
+) -> Result<Response, Error> {
+    if config().graph_batch_window_ms > 0 {
+        if let Some(response) = batched_get(&url).await {
+            return Ok(response);
+        }
+    }
+    send_graph_request(
+        operation,
+        graph_client()
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await
+}
+
+/// Sends up to `MAX_BATCH_SIZE` requests, whose ids are their indexes, as
+/// one `$batch` call. The responses are returned in request order.
+async fn send_batch(
+    operation: GraphOperation,
+    token: &str,
+    requests: Vec<BatchRequest>,
+) -> Result<Vec<Option<BatchItemResponse>>, Error> {
+    let mut responses = requests.iter().map(|_| None).collect::<Vec<_>>();
+    let batch = send_graph_request(
+        operation,
+        graph_client()
+            .post("https://graph.microsoft.com/v1.0/$batch")
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&serde_json::json!({ "requests": requests })),
+    )
+    .await?
+    .error_for_status()?
+    .json::<BatchResponse>()
+    .await?;
+    for response in batch.responses {
+        if let Some(slot) = response
+            .id
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| responses.get_mut(index))
+        {
+            *slot = Some(response);
+        }
+    }
+    Ok(responses)
+}
+
+/// Looks up several items, each given as drive URL and path, with `$batch`
+/// instead of one call per item. Each result is the item or the status
+/// Graph answered for it. Shortcuts resolve to the file they point to, as
+/// for HEAD.
+pub async fn get_azure_items(
+    items: Vec<(String, String)>,
+) -> Result<Vec<Result<Item, u16>>, Error> {
+    let token = get_token(Access::Read).await?;
+    let mut results = Vec::with_capacity(items.len());
+    for chunk in items.chunks(MAX_BATCH_SIZE) {
+        let requests = chunk
+            .iter()
+            .enumerate()
+            .map(|(index, (drive, path))| BatchRequest {
+                id: index.to_string(),
+                method: "GET",
+                url: format!(
+                    "{}/root:/{}",
+                    drive.trim_start_matches("https://graph.microsoft.com/v1.0"),
+                    encode_path(path.trim_matches('/'))
+                ),
+                headers: None,
+                body: None,
+            })
+            .collect::<Vec<BatchRequest>>();
+        for response in send_batch(GraphOperation::Head, &token, requests).await? {
+            let item = match response {
+                Some(response) if response.status == 200 => response
+                    .body
+                    .and_then(|body| serde_json::from_value::<Item>(body).ok())
+                    .ok_or(500),
+                Some(response) => Err(response.status),
+                None => Err(500),
+            };
+            results.push(match item {
+                Ok(item) => resolve_remote_item(&token, item)
+                    .await
+                    .map_err(|err| err.status().map_or(500, |status| status.as_u16())),
+                Err(status) => Err(status),
+            });
+        }
+    }
+    Ok(results)
+}
+
+/// Looks up the keys of several items of a drive by id with `$batch`, as
+/// the delta feed omits paths. Items Graph did not answer for are `None`.
+pub async fn get_azure_item_keys(
+    drive: &str,
+    ids: &[String],
+) -> Result<Vec<Option<String>>, Error> {
+    let token = get_token(Access::Read).await?;
+    let drive = drive.trim_start_matches("https://graph.microsoft.com/v1.0");
+    let mut keys = Vec::with_capacity(ids.len());
+    for chunk in ids.chunks(MAX_BATCH_SIZE) {
+        let requests = chunk
+            .iter()
+            .enumerate()
+            .map(|(index, id)| BatchRequest {
+                id: index.to_string(),
+                method: "GET",
+                url: format!(
+                    "{}/items/{}?$select=id,name,parentReference",
+                    drive,
+                    urlencoding::encode(id)
+                ),
+                headers: None,
+                body: None,
+            })
+            .collect::<Vec<BatchRequest>>();
+        for response in send_batch(GraphOperation::Head, &token, requests).await? {
+            keys.push(
+                response
+                    .filter(|response| response.status == 200)
+                    .and_then(|response| response.body)
+                    .and_then(|body| serde_json::from_value::<DeltaItem>(body).ok())
+                    .map(|item| {
+                        let parent_path = item
+                            .parent_reference
+                            .and_then(|reference| reference.path)
+                            .unwrap_or_default();
+                        key_in_parent(&parent_path, &item.name.unwrap_or_default())
+                    }),
+            );
+        }
+    }
+    Ok(keys)
+}
+
+/// Sets listItem fields of several items, each given as drive URL and path,
+/// with one `$batch` call per 20 items. Returns the Graph status per item.
+pub async fn update_azure_fields(
+    items: Vec<(String, String)>,
+    fields: &serde_json::Value,
+) -> Result<Vec<u16>, Error> {
+    let token = get_token(Access::Write).await?;
+    let mut statuses = Vec::with_capacity(items.len());
+    for chunk in items.chunks(MAX_BATCH_SIZE) {
+        let requests = chunk
+            .iter()
+            .enumerate()
+            .map(|(index, (drive, path))| BatchRequest {
+                id: index.to_string(),
+                method: "PATCH",
+                url: format!(
+                    "{}/root:/{}:/listItem/fields",
+                    drive.trim_start_matches("https://graph.microsoft.com/v1.0"),
+                    encode_path(path.trim_matches('/'))
+                ),
+                headers: Some(serde_json::json!({ "Content-Type": "application/json" })),
+                body: Some(fields.clone()),
+            })
+            .collect::<Vec<BatchRequest>>();
+        let responses = send_batch(GraphOperation::Write, &token, requests).await?;
+        statuses.extend(
+            responses
+                .into_iter()
+                .map(|response| response.map_or(500, |response| response.status)),
+        );
+    }
+    Ok(statuses)
+}
+
+/// A Graph change notification subscription.
+#[derive(Deserialize, Debug)]
+pub struct Subscription {
+    pub id: String,
+    #[serde(rename = "expirationDateTime")]
+    pub expiration_date_time: DateTime<Utc>,
+}
+
+/// Subscribes `notification_url` to changes of a drive resource such as
+/// `/drives/{id}/root`.
+pub async fn create_azure_subscription(
+    resource: String,
+    notification_url: String,
+    client_state: String,
+    expiration: DateTime<Utc>,
+) -> Result<Subscription, Error> {
+    let token = get_token(Access::Write).await?;
+    let body = serde_json::json!({
+        "changeType": "updated",
+        "notificationUrl": notification_url,
+        "resource": resource,
+        "expirationDateTime": expiration.to_rfc3339(),
+        "clientState": client_state,
+    });
+    let client = graph_client();
+    send_graph_request(
+        GraphOperation::Write,
+        client
+            .post("https://graph.microsoft.com/v1.0/subscriptions")
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body),
+    )
+    .await?
+    .error_for_status()?
+    .json::<Subscription>()
+    .await
+}
+
+pub async fn renew_azure_subscription(
+    id: String,
+    expiration: DateTime<Utc>,
+) -> Result<Subscription, Error> {
+    let token = get_token(Access::Write).await?;
+    let body = serde_json::json!({ "expirationDateTime": expiration.to_rfc3339() });
+    let client = graph_client();
+    send_graph_request(
+        GraphOperation::Write,
+        client
+            .patch(format!(
+                "https://graph.microsoft.com/v1.0/subscriptions/{}",
+                id
+            ))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body),
+    )
+    .await?
+    .error_for_status()?
+    .json::<Subscription>()
+    .await
 }
@@ -0,0 +1,123 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+
+use super::azure::get_azure_site;
+use super::snapshot::snapshot_views;
+use crate::config;
+
+/// Site and default drive id discovered from `SHAREPOINT_SITE_URL`.
+static RESOLVED_SITE: OnceLock<(String, Option<String>)> = OnceLock::new();
+
+/// A bucket exposed by the adapter and the SharePoint drive serving it.
+#[derive(Clone, Debug)]
+pub struct Bucket {
+    pub name: String,
+    pub site_id: String,
+    pub drive_id: Option<String>,
+    /// Set for snapshot views, which serve the drive read-only as it was at
+    /// this time.
+    pub as_of: Option<DateTime<Utc>>,
+}
+
+impl Bucket {
+    /// Graph URL of the drive, the default drive of the site unless a drive
+    /// id is mapped.
+    pub fn drive_url(&self) -> String {
+        match &self.drive_id {
+            Some(drive_id) => format!("https://graph.microsoft.com/v1.0/drives/{}", drive_id),
+            None => format!(
+                "https://graph.microsoft.com/v1.0/sites/{}/drive",
+                self.site_id
+            ),
+        }
+    }
+}
+
+/// Resolves `SHAREPOINT_SITE_URL` into a site id once at startup, unless
+/// `SHAREPOINT_SITE_ID` is configured or buckets are mapped explicitly.
+pub async fn resolve_site() -> Result<(), String> {
+    if is_multi_bucket() || config().sharepoint_site_id.is_some() || RESOLVED_SITE.get().is_some() {
+        return Ok(());
+    }
+    let Some(site_url) = &config().sharepoint_site_url else {
+        return Err("Either SHAREPOINT_SITE_ID or SHAREPOINT_SITE_URL is required".to_string());
+    };
+    let url =
+        Url::parse(site_url).map_err(|err| format!("Invalid SHAREPOINT_SITE_URL: {}", err))?;
+    let hostname = url
+        .host_str()
+        .ok_or("SHAREPOINT_SITE_URL has no hostname")?;
+    let path = url.path().trim_matches('/');
+    let address = if path.is_empty() {
+        hostname.to_string()
+    } else {
+        format!("{}:/{}", hostname, path)
+    };
+    let site = get_azure_site(address)
+        .await
+        .map_err(|err| format!("Cannot resolve {}: {}", site_url, err))?;
+    RESOLVED_SITE.get_or_init(|| site);
+    Ok(())
+}
+
+/// The configured site id, or the one resolved from `SHAREPOINT_SITE_URL`.
+fn site_id() -> String {
+    config()
+        .sharepoint_site_id
+        .clone()
+        .or_else(|| RESOLVED_SITE.get().map(|(site_id, _)| site_id.clone()))
+        .unwrap_or_default()
+}
+
+pub fn is_multi_bucket() -> bool {
+    !config().bucket_mappings.is_empty()
+}
+
+/// Parses `BUCKET_MAPPINGS` entries of the form `bucket=site-id[/drive-id]`,
+/// without mappings the configured site is the only bucket, served from
+/// `DRIVE_ID` or the site's default document library.
+pub fn buckets() -> Vec<Bucket> {
+    if !is_multi_bucket() {
+        return vec![Bucket {
+            name: site_id(),
+            site_id: site_id(),
+            drive_id: config()
+                .drive_id
+                .clone()
+                .filter(|drive_id| !drive_id.is_empty())
+                .or_else(|| {
+                    RESOLVED_SITE
+                        .get()
+                        .and_then(|(_, drive_id)| drive_id.clone())
+                }),
+            as_of: None,
+        }];
+    }
+    config()
+        .bucket_mappings
+        .iter()
+        .filter_map(|mapping| {
+            let (name, target) = mapping.split_once('=')?;
+            let (site_id, drive_id) = match target.split_once('/') {
+                Some((site_id, drive_id)) => (site_id, Some(drive_id.trim().to_string())),
+                None => (target, None),
+            };
+            Some(Bucket {
+                name: name.trim().to_string(),
+                site_id: site_id.trim().to_string(),
+                drive_id,
+                as_of: None,
+            })
+        })
+        .collect()
+}
+
+/// Finds a bucket or snapshot view by name.
+pub fn find_bucket(name: &str) -> Option<Bucket> {
+    buckets()
+        .into_iter()
+        .chain(snapshot_views())
+        .find(|bucket| bucket.name == name)
+}
@@ -0,0 +1,435 @@
+use once_cell::sync::Lazy;
+use reqwest::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{debug, info, warn};
+
+use super::azure::{list_azure_changes, DeltaItem, SharePointObjects};
+use super::buckets::{buckets, Bucket};
+use super::events::{is_events_enabled, publish_changes};
+use super::libraries::{is_library_mode, libraries};
+use super::notifications::is_notifications_enabled;
+use super::shutdown::sleep_until_shutdown;
+use crate::config;
+
+/// A listing page as returned by Graph, before journal writes are merged.
+struct Listing {
+    drive: String,
+    prefix: String,
+    recursive: bool,
+    objects: SharePointObjects,
+    /// Ids of the listed items and of their folders, to match delta changes.
+    ids: HashSet<String>,
+    cached_at: Instant,
+    used_at: Instant,
+}
+
+static LISTINGS: Lazy<Mutex<HashMap<String, Listing>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Drives whose delta feed is followed, only their listings are cached when
+/// delta tracking is on.
+static TRACKED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// The delta link to resume each tracked drive from.
+static DELTA_LINKS: Lazy<AsyncMutex<HashMap<String, String>>> =
+    Lazy::new(|| AsyncMutex::new(HashMap::new()));
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+pub fn is_listing_cache_enabled() -> bool {
+    config().listing_cache_ttl_secs > 0 || is_delta_tracking()
+}
+
+fn is_delta_tracking() -> bool {
+    config().listing_cache_delta_interval_secs > 0
+        || is_notifications_enabled()
+        || is_events_enabled()
+}
+
+/// Tracked listings stay valid until a change touches them, the TTL still
+/// bounds their age if one is configured.
+fn ttl() -> Duration {
+    match config().listing_cache_ttl_secs {
+        0 => Duration::MAX,
+        secs => Duration::from_secs(secs),
+    }
+}
+
+/// Identifies a page by drive, prefix and the query shaping it.
+pub fn listing_key(
+    drive: &str,
+    prefix: &str,
+    max_keys: u16,
+    recursive: bool,
+    next_link: Option<&str>,
+) -> String {
+    format!(
+        "{}:{}:{}:{}:{}",
+        drive,
+        prefix,
+        max_keys,
+        recursive,
+        next_link.unwrap_or_default()
+    )
+}
+
+pub fn cached_listing(key: &str) -> Option<SharePointObjects> {
+    let mut listings = LISTINGS.lock().unwrap();
+    if listings
+        .get(key)
+        .is_some_and(|listing| listing.cached_at.elapsed() > ttl())
+    {
+        listings.remove(key);
+    }
+    match listings.get_mut(key) {
+        Some(listing) => {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            listing.used_at = Instant::now();
+            debug!("Serving listing {} from cache", key);
+            Some(listing.objects.clone())
+        }
+        None => {
+            MISSES.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+/// Stores a page, evicting the least recently used one when the cache is full.
+pub fn cache_listing(
+    key: String,
+    drive: &str,
+    prefix: &str,
+    recursive: bool,
+    objects: &SharePointObjects,
+) {
+    if is_delta_tracking() && !TRACKED.lock().unwrap().contains(drive) {
+        return;
+    }
+    let ids = objects
+        .items
+        .iter()
+        .flat_map(|item| {
+            [
+                Some(item.id.clone()),
+                item.parent_reference
+                    .as_ref()
+                    .and_then(|reference| reference.id.clone()),
+            ]
+        })
+        .flatten()
+        .collect();
+    let mut listings = LISTINGS.lock().unwrap();
+    listings.retain(|_, listing| listing.cached_at.elapsed() <= ttl());
+    if listings.len() >= config().listing_cache_max_entries && !listings.contains_key(&key) {
+        let oldest = listings
+            .iter()
+            .min_by_key(|(_, listing)| listing.used_at)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            listings.remove(&oldest);
+        }
+    }
+    let now = Instant::now();
+    listings.insert(
+        key,
+        Listing {
+            drive: drive.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            recursive,
+            objects: objects.clone(),
+            ids,
+            cached_at: now,
+            used_at: now,
+        },
+    );
+}
+
+/// Drops every cached page of the drive that could contain the key, i.e.
+/// listings of any folder above it.
+pub fn invalidate_listings(drive: &str, key: &str) {
+    let key = key.trim_matches('/');
+    LISTINGS.lock().unwrap().retain(|_, listing| {
+        listing.drive != drive
+            || !(listing.prefix.is_empty()
+                || key == listing.prefix
+                || key.starts_with(&format!("{}/", listing.prefix)))
+    });
+}
+
+/// Drops the listings a round of delta changes touched: those containing a
+/// changed item or its folder. A change nothing claims may sit in a folder
+/// only a recursive or empty listing covers, so those go as well.
+fn apply_changes(drive: &str, changes: &[DeltaItem]) {
+    let mut listings = LISTINGS.lock().unwrap();
+    let before = listings.len();
+    for change in changes {
+        let parent = change
+            .parent_reference
+            .as_ref()
+            .and_then(|reference| reference.id.as_deref());
+        let touches = |listing: &Listing| {
+            listing.drive == drive
+                && (listing.ids.contains(&change.id)
+                    || parent.is_some_and(|parent| listing.ids.contains(parent)))
+        };
+        if listings.values().any(touches) {
+            listings.retain(|_, listing| !touches(listing));
+        } else {
+            listings.retain(|_, listing| {
+                listing.drive != drive || !(listing.ids.is_empty() || listing.recursive)
+            });
+        }
+    }
+    debug!(
+        "{} changes on {} dropped {} cached listings",
+        changes.len(),
+        drive,
+        before - listings.len()
+    );
+}
+
+fn invalidate_drive(drive: &str) {
+    LISTINGS
+        .lock()
+        .unwrap()
+        .retain(|_, listing| listing.drive != drive);
+}
+
+/// A drive whose changes are followed, with the bucket and key prefix its
+/// items appear under.
+#[derive(Clone, Debug)]
+pub struct TrackedDrive {
+    pub drive: String,
+    pub bucket: String,
+    pub prefix: String,
+}
+
+/// The drives listings are served from: each bucket's drive, or its
+/// libraries when they are exposed as prefixes.
+pub async fn tracked_drives() -> Result<Vec<TrackedDrive>, Error> {
+    let mut drives = Vec::new();
+    for bucket in buckets() {
+        if is_library_mode() && bucket.drive_id.is_none() {
+            for library in libraries(&bucket).await? {
+                drives.push(TrackedDrive {
+                    drive: Bucket {
+                        drive_id: Some(library.id),
+                        ..bucket.clone()
+                    }
+                    .drive_url(),
+                    bucket: bucket.name.clone(),
+                    prefix: format!("{}/", library.name),
+                });
+            }
+        } else {
+            drives.push(TrackedDrive {
+                drive: bucket.drive_url(),
+                bucket: bucket.name.clone(),
+                prefix: String::new(),
+            });
+        }
+    }
+    Ok(drives)
+}
+
+/// Runs one delta round for a drive, dropping the cached listings its
+/// changes touch and publishing them as events. The first round only
+/// fetches a link to start from.
+pub async fn sync_drive(tracked_drive: &TrackedDrive) {
+    let drive = tracked_drive.drive.as_str();
+    let mut delta_links = DELTA_LINKS.lock().await;
+    let delta_link = delta_links.remove(drive);
+    let tracked = delta_link.is_some();
+    match list_azure_changes(drive.to_string(), delta_link).await {
+        Ok(changes) => {
+            if tracked {
+                apply_changes(drive, &changes.items);
+                if is_events_enabled() {
+                    publish_changes(tracked_drive.clone(), changes.items);
+                }
+            }
+            match changes.delta_link {
+                Some(delta_link) => {
+                    delta_links.insert(drive.to_string(), delta_link);
+                    TRACKED.lock().unwrap().insert(drive.to_string());
+                }
+                None => {
+                    warn!("Delta feed of {} ended without a delta link", drive);
+                    TRACKED.lock().unwrap().remove(drive);
+                    invalidate_drive(drive);
+                }
+            }
+        }
+        Err(err) => {
+            // Changes may have been missed, start over.
+            warn!("Tracking changes of {} failed: {}", drive, err);
+            TRACKED.lock().unwrap().remove(drive);
+            invalidate_drive(drive);
+        }
+    }
+}
+
+/// Follows the delta feed of every drive, so listings can be served from
+/// cache until they change. Without a polling interval only the initial
+/// round runs and change notifications trigger the following ones.
+pub fn spawn_listing_tracker() {
+    if !is_delta_tracking() {
+        return;
+    }
+    let interval = config().listing_cache_delta_interval_secs;
+    tokio::spawn(async move {
+        loop {
+            match tracked_drives().await {
+                Ok(drives) => {
+                    for tracked_drive in drives {
+                        sync_drive(&tracked_drive).await;
+                    }
+                }
+                Err(err) => warn!("Listing drives to track failed: {}", err),
+            }
+            if interval == 0 || !sleep_until_shutdown(Duration::from_secs(interval)).await {
+                return;
+            }
+        }
+    });
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotListing {
+    key: String,
+    drive: String,
+    prefix: String,
+    recursive: bool,
+    objects: SharePointObjects,
+    ids: Vec<String>,
+    age_secs: u64,
+}
+
+/// Cached listings plus the delta links they are current with, so a
+/// replica importing them catches up on the changes since.
+#[derive(Serialize, Deserialize, Default)]
+struct Snapshot {
+    listings: Vec<SnapshotListing>,
+    delta_links: HashMap<String, String>,
+}
+
+async fn export_snapshot(path: &str) {
+    let delta_links = DELTA_LINKS.lock().await.clone();
+    let listings = LISTINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, listing)| listing.cached_at.elapsed() <= ttl())
+        .map(|(key, listing)| SnapshotListing {
+            key: key.clone(),
+            drive: listing.drive.clone(),
+            prefix: listing.prefix.clone(),
+            recursive: listing.recursive,
+            objects: listing.objects.clone(),
+            ids: listing.ids.iter().cloned().collect(),
+            age_secs: listing.cached_at.elapsed().as_secs(),
+        })
+        .collect::<Vec<SnapshotListing>>();
+    let count = listings.len();
+    let snapshot = Snapshot {
+        listings,
+        delta_links,
+    };
+    let path = path.to_string();
+    // Written aside and renamed, so a crash never leaves a torn snapshot.
+    let written = tokio::task::spawn_blocking(move || {
+        let temporary = format!("{}.tmp", path);
+        std::fs::write(&temporary, serde_json::to_vec(&snapshot)?)?;
+        std::fs::rename(&temporary, &path)
+    })
+    .await;
+    match written {
+        Ok(Ok(())) => debug!("Exported {} cached listings", count),
+        Ok(Err(err)) => warn!("Exporting the listing cache failed: {}", err),
+        Err(err) => warn!("Exporting the listing cache failed: {}", err),
+    }
+}
+
+/// Seeds the cache from `LISTING_CACHE_SNAPSHOT` at startup, so a fresh
+/// replica does not have to list everything from Graph again.
+pub fn import_snapshot() {
+    let Some(path) = &config().listing_cache_snapshot else {
+        return;
+    };
+    let snapshot = match std::fs::read(path) {
+        Ok(data) => match serde_json::from_slice::<Snapshot>(&data) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                warn!(
+                    "Ignoring unreadable listing cache snapshot {}: {}",
+                    path, err
+                );
+                return;
+            }
+        },
+        Err(err) => {
+            debug!("No listing cache snapshot to import: {}", err);
+            return;
+        }
+    };
+    let now = Instant::now();
+    let mut listings = LISTINGS.lock().unwrap();
+    for listing in snapshot.listings {
+        let age = Duration::from_secs(listing.age_secs);
+        let Some(cached_at) = now.checked_sub(age).filter(|_| age <= ttl()) else {
+            continue;
+        };
+        if listings.len() >= config().listing_cache_max_entries {
+            break;
+        }
+        listings.insert(
+            listing.key,
+            Listing {
+                drive: listing.drive,
+                prefix: listing.prefix,
+                recursive: listing.recursive,
+                objects: listing.objects,
+                ids: listing.ids.into_iter().collect(),
+                cached_at,
+                used_at: cached_at,
+            },
+        );
+    }
+    info!("Imported {} cached listings from {}", listings.len(), path);
+    if let Ok(mut delta_links) = DELTA_LINKS.try_lock() {
+        delta_links.extend(snapshot.delta_links);
+    }
+}
+
+/// Writes the cache to `LISTING_CACHE_SNAPSHOT` periodically.
+pub fn spawn_snapshot_exporter() {
+    let Some(path) = config().listing_cache_snapshot.clone() else {
+        return;
+    };
+    let interval = Duration::from_secs(config().listing_cache_snapshot_interval_secs.max(10));
+    tokio::spawn(async move {
+        while sleep_until_shutdown(interval).await {
+            export_snapshot(&path).await;
+        }
+    });
+}
+
+/// Writes the cache to `LISTING_CACHE_SNAPSHOT` once more on shutdown, so
+/// the next start resumes from the latest state.
+pub async fn export_final_snapshot() {
+    if let Some(path) = &config().listing_cache_snapshot {
+        export_snapshot(path).await;
+    }
+}
+
+/// Hit and miss counts since startup.
+pub fn listing_cache_counts() -> (u64, u64) {
+    (HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed))
+}
@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use reqwest::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::azure::{get_azure_item_keys, key_in_parent, DeltaItem};
+use super::s3::{sign_token, verify_token};
+
+/// Opaque token handed to clients: the Graph deltaLink plus the time it was
+/// issued, which separates added from modified items on the next call.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ChangesToken {
+    pub delta_link: String,
+    pub issued_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChangedKey {
+    pub key: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+/// A deleted item. The delta feed reports deletions by id only, so the key
+/// is known only for items whose key was seen before, and null otherwise.
+#[derive(Serialize, Debug)]
+pub struct DeletedKey {
+    pub key: Option<String>,
+    pub id: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ChangeFeed {
+    pub added: Vec<ChangedKey>,
+    pub modified: Vec<ChangedKey>,
+    pub deleted: Vec<DeletedKey>,
+    pub next_token: String,
+}
+
+fn changes_scope(bucket: &str) -> String {
+    format!("changes\n{}", bucket)
+}
+
+/// Signs a token for the change feed of a bucket.
+pub fn encode_changes_token(token: &ChangesToken, bucket: &str) -> String {
+    sign_token(
+        &serde_json::to_string(token).unwrap_or_default(),
+        &changes_scope(bucket),
+    )
+}
+
+/// Decodes a token issued for the bucket, only accepting delta links back
+/// to Graph.
+pub fn decode_changes_token(token: &str, bucket: &str) -> Option<ChangesToken> {
+    let token =
+        serde_json::from_str::<ChangesToken>(&verify_token(token, &changes_scope(bucket))?).ok()?;
+    if token.delta_link.starts_with("https://graph.microsoft.com/") {
+        Some(token)
+    } else {
+        None
+    }
+}
+
+/// Keys remembered per drive and item id, since deleted items in the delta
+/// feed only carry their id.
+static KNOWN_KEYS: Lazy<Mutex<HashMap<(String, String), String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+const MAX_KNOWN_KEYS: usize = 100_000;
+
+/// Remembers the key of an item seen in a delta feed, so its deletion can
+/// be reported by key later.
+pub fn remember_key(drive: &str, id: &str, key: &str) {
+    let mut known_keys = KNOWN_KEYS.lock().unwrap();
+    if known_keys.len() >= MAX_KNOWN_KEYS {
+        known_keys.clear();
+    }
+    known_keys.insert((drive.to_string(), id.to_string()), key.to_string());
+}
+
+/// The last known key of an item. Kept after a deletion, as both the change
+/// feed and event publishing look it up.
+pub fn known_key(drive: &str, id: &str) -> Option<String> {
+    KNOWN_KEYS
+        .lock()
+        .unwrap()
+        .get(&(drive.to_string(), id.to_string()))
+        .cloned()
+}
+
+/// Resolves and remembers the keys of changed items. Delta items rarely
+/// carry the path of their parent, the others are looked up together.
+/// `None` marks items whose key could not be resolved.
+pub async fn resolve_changed_keys(
+    drive: &str,
+    items: &[DeltaItem],
+) -> Result<Vec<Option<String>>, Error> {
+    let paths = items
+        .iter()
+        .map(|item| {
+            let parent_path = item.parent_reference.as_ref()?.path.as_deref()?;
+            Some(key_in_parent(
+                parent_path,
+                item.name.as_deref().unwrap_or_default(),
+            ))
+        })
+        .collect::<Vec<Option<String>>>();
+    let missing = items
+        .iter()
+        .zip(&paths)
+        .filter(|(_, path)| path.is_none())
+        .map(|(item, _)| item.id.clone())
+        .collect::<Vec<String>>();
+    let mut looked_up = get_azure_item_keys(drive, &missing).await?.into_iter();
+    Ok(items
+        .iter()
+        .zip(paths)
+        .map(|(item, path)| {
+            let key = path.or_else(|| looked_up.next().flatten())?;
+            remember_key(drive, &item.id, &key);
+            Some(key)
+        })
+        .collect())
+}
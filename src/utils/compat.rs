@@ -0,0 +1,151 @@
+use salvo::http::StatusCode;
+use std::io::Cursor;
+use xml::writer::XmlEvent;
+use xml::EmitterConfig;
+
+use super::s3::S3Error;
+use crate::config;
+
+const S3_NAMESPACE: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// The owner reported for every bucket.
+const OWNER_ID: &str = "s3-sharepoint-adapter";
+
+/// Bucket sub-resources that rclone, the MinIO client and the AWS SDKs
+/// read while negotiating with a bucket.
+pub const BUCKET_SUBRESOURCES: [&str; 6] = [
+    "location",
+    "versioning",
+    "acl",
+    "lifecycle",
+    "policy",
+    "encryption",
+];
+
+fn generate_location_response() -> String {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(&mut buffer);
+
+    writer
+        .write(XmlEvent::start_element("LocationConstraint").default_ns(S3_NAMESPACE))
+        .unwrap();
+    // Buckets in us-east-1 answer an empty constraint.
+    let region = config().bucket_region.as_str();
+    if region != "us-east-1" {
+        writer.write(XmlEvent::characters(region)).unwrap();
+    }
+    writer.write(XmlEvent::end_element()).unwrap(); // LocationConstraint
+
+    String::from_utf8(buffer.into_inner()).unwrap()
+}
+
+fn generate_versioning_response() -> String {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(&mut buffer);
+
+    // Without a Status element versioning was never enabled.
+    writer
+        .write(XmlEvent::start_element("VersioningConfiguration").default_ns(S3_NAMESPACE))
+        .unwrap();
+    writer.write(XmlEvent::end_element()).unwrap(); // VersioningConfiguration
+
+    String::from_utf8(buffer.into_inner()).unwrap()
+}
+
+fn generate_acl_response() -> String {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(&mut buffer);
+
+    writer
+        .write(XmlEvent::start_element("AccessControlPolicy").default_ns(S3_NAMESPACE))
+        .unwrap();
+
+    writer.write(XmlEvent::start_element("Owner")).unwrap();
+    writer.write(XmlEvent::start_element("ID")).unwrap();
+    writer.write(XmlEvent::characters(OWNER_ID)).unwrap();
+    writer.write(XmlEvent::end_element()).unwrap(); // ID
+    writer.write(XmlEvent::end_element()).unwrap(); // Owner
+
+    writer
+        .write(XmlEvent::start_element("AccessControlList"))
+        .unwrap();
+    writer.write(XmlEvent::start_element("Grant")).unwrap();
+    writer
+        .write(
+            XmlEvent::start_element("Grantee")
+                .ns("xsi", "http://www.w3.org/2001/XMLSchema-instance")
+                .attr("xsi:type", "CanonicalUser"),
+        )
+        .unwrap();
+    writer.write(XmlEvent::start_element("ID")).unwrap();
+    writer.write(XmlEvent::characters(OWNER_ID)).unwrap();
+    writer.write(XmlEvent::end_element()).unwrap(); // ID
+    writer.write(XmlEvent::end_element()).unwrap(); // Grantee
+    writer.write(XmlEvent::start_element("Permission")).unwrap();
+    writer.write(XmlEvent::characters("FULL_CONTROL")).unwrap();
+    writer.write(XmlEvent::end_element()).unwrap(); // Permission
+    writer.write(XmlEvent::end_element()).unwrap(); // Grant
+    writer.write(XmlEvent::end_element()).unwrap(); // AccessControlList
+
+    writer.write(XmlEvent::end_element()).unwrap(); // AccessControlPolicy
+
+    String::from_utf8(buffer.into_inner()).unwrap()
+}
+
+fn generate_encryption_response() -> String {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(&mut buffer);
+
+    writer
+        .write(
+            XmlEvent::start_element("ServerSideEncryptionConfiguration").default_ns(S3_NAMESPACE),
+        )
+        .unwrap();
+    writer.write(XmlEvent::start_element("Rule")).unwrap();
+    writer
+        .write(XmlEvent::start_element(
+            "ApplyServerSideEncryptionByDefault",
+        ))
+        .unwrap();
+    writer
+        .write(XmlEvent::start_element("SSEAlgorithm"))
+        .unwrap();
+    writer.write(XmlEvent::characters("AES256")).unwrap();
+    writer.write(XmlEvent::end_element()).unwrap(); // SSEAlgorithm
+    writer.write(XmlEvent::end_element()).unwrap(); // ApplyServerSideEncryptionByDefault
+    writer.write(XmlEvent::end_element()).unwrap(); // Rule
+    writer.write(XmlEvent::end_element()).unwrap(); // ServerSideEncryptionConfiguration
+
+    String::from_utf8(buffer.into_inner()).unwrap()
+}
+
+/// Answers a bucket sub-resource the way a bucket with nothing configured
+/// does: unversioned, owned by the adapter, without lifecycle configuration
+/// or policy, and encrypted at rest like all SharePoint content.
+/// `LIFECYCLE_RULES` are not S3 lifecycle rules and are not exposed.
+pub fn bucket_subresource_response(subresource: &str) -> Result<String, S3Error> {
+    match subresource {
+        "location" => Ok(generate_location_response()),
+        "versioning" => Ok(generate_versioning_response()),
+        "acl" => Ok(generate_acl_response()),
+        "encryption" => Ok(generate_encryption_response()),
+        "lifecycle" => Err(S3Error::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchLifecycleConfiguration",
+            "The lifecycle configuration does not exist",
+        )),
+        _ => Err(S3Error::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchBucketPolicy",
+            "The bucket policy does not exist",
+        )),
+    }
+}
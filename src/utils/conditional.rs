@@ -0,0 +1,59 @@
+use chrono::{DateTime, Utc};
+use salvo::http::HeaderMap;
+
+/// `If-None-Match` and `If-Modified-Since` of a read.
+#[derive(Default, Debug)]
+pub struct Conditions {
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<DateTime<Utc>>,
+}
+
+/// Compares entity tags weakly, ignoring `W/` prefixes and quotes.
+fn e_tag_matches(candidates: &str, e_tag: &str) -> bool {
+    let normalize = |value: &str| {
+        value
+            .trim()
+            .trim_start_matches("W/")
+            .trim_matches('"')
+            .to_string()
+    };
+    let e_tag = normalize(e_tag);
+    candidates
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || normalize(candidate) == e_tag)
+}
+
+impl Conditions {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Conditions {
+            if_none_match: header("If-None-Match"),
+            if_modified_since: header("If-Modified-Since")
+                .and_then(|since| DateTime::parse_from_rfc2822(&since).ok())
+                .map(|since| since.with_timezone(&Utc)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.if_none_match.is_none() && self.if_modified_since.is_none()
+    }
+
+    /// Whether the client's copy is current, in which case a read is answered
+    /// with 304. `If-Modified-Since` only applies without `If-None-Match`.
+    pub fn is_not_modified(&self, e_tag: Option<&str>, last_modified: Option<&str>) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            return e_tag.is_some_and(|e_tag| e_tag_matches(if_none_match, e_tag));
+        }
+        let last_modified = last_modified
+            .and_then(|last_modified| DateTime::parse_from_rfc3339(last_modified).ok());
+        match (self.if_modified_since, last_modified) {
+            (Some(since), Some(last_modified)) => last_modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use once_cell::sync::Lazy;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::config;
+
+/// One key of a materialized listing.
+#[derive(Serialize, Debug, Clone)]
+pub struct CursorEntry {
+    pub key: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e_tag: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CursorInfo {
+    pub id: String,
+    pub prefix: String,
+    pub total: usize,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A sorted, immutable snapshot of a prefix that clients page through by offset.
+struct CursorSnapshot {
+    info: CursorInfo,
+    entries: Vec<CursorEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CursorPage {
+    pub id: String,
+    pub offset: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_offset: Option<usize>,
+    pub entries: Vec<CursorEntry>,
+}
+
+static CURSORS: Lazy<Mutex<HashMap<String, CursorSnapshot>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn purge_expired(cursors: &mut HashMap<String, CursorSnapshot>) {
+    let now = Utc::now();
+    cursors.retain(|_, cursor| cursor.info.expires_at > now);
+}
+
+/// Stores a snapshot, returning `None` when the maximum number of live
+/// cursors is reached.
+pub fn create_cursor(prefix: String, mut entries: Vec<CursorEntry>) -> Option<CursorInfo> {
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    let mut cursors = CURSORS.lock().unwrap();
+    purge_expired(&mut cursors);
+    if cursors.len() >= config().max_cursors {
+        return None;
+    }
+    let id = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect::<String>();
+    let created_at = Utc::now();
+    let info = CursorInfo {
+        id: id.clone(),
+        prefix,
+        total: entries.len(),
+        created_at,
+        expires_at: created_at + TimeDelta::seconds(config().cursor_ttl_secs as i64),
+    };
+    cursors.insert(
+        id,
+        CursorSnapshot {
+            info: info.clone(),
+            entries,
+        },
+    );
+    Some(info)
+}
+
+pub fn read_cursor(id: &str, offset: usize, limit: usize) -> Option<CursorPage> {
+    let mut cursors = CURSORS.lock().unwrap();
+    purge_expired(&mut cursors);
+    let cursor = cursors.get(id)?;
+    let entries = cursor
+        .entries
+        .iter()
+        .skip(offset)
+        .take(limit)
+        .cloned()
+        .collect::<Vec<CursorEntry>>();
+    let next_offset = Some(offset + entries.len()).filter(|next| *next < cursor.info.total);
+    Some(CursorPage {
+        id: cursor.info.id.clone(),
+        offset,
+        next_offset,
+        entries,
+    })
+}
+
+/// The prefix a cursor was created for.
+pub fn cursor_prefix(id: &str) -> Option<String> {
+    let mut cursors = CURSORS.lock().unwrap();
+    purge_expired(&mut cursors);
+    cursors.get(id).map(|cursor| cursor.info.prefix.clone())
+}
+
+pub fn delete_cursor(id: &str) -> bool {
+    CURSORS.lock().unwrap().remove(id).is_some()
+}
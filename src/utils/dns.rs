@@ -0,0 +1,130 @@
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Client, ClientBuilder};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::config;
+
+struct CachedAddrs {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+}
+
+static DNS_CACHE: Lazy<Mutex<HashMap<String, CachedAddrs>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Resolver for Graph and login hosts that honors static overrides, caches
+/// lookups and keeps serving the last known addresses when DNS fails.
+struct GraphResolver;
+
+/// Parses `GRAPH_DNS_OVERRIDES` entries of the form `host=ip`, a host may be
+/// listed several times to pin more than one address.
+fn overrides_for(host: &str) -> Vec<SocketAddr> {
+    config()
+        .dns
+        .graph_dns_overrides
+        .iter()
+        .filter_map(|entry| entry.split_once('='))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case(host))
+        .filter_map(|(_, ip)| match ip.trim().parse::<IpAddr>() {
+            Ok(ip) => Some(SocketAddr::new(ip, 0)),
+            Err(_) => {
+                warn!("Ignoring invalid DNS override {}={}", host, ip);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Applies `GRAPH_IP_FAMILY`, `ipv4` or `ipv6` drop the other family so the
+/// connector never waits on a broken stack, `any` leaves fallback to it.
+fn filter_family(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    match config().dns.graph_ip_family.as_str() {
+        "ipv4" => addrs.into_iter().filter(SocketAddr::is_ipv4).collect(),
+        "ipv6" => addrs.into_iter().filter(SocketAddr::is_ipv6).collect(),
+        _ => addrs,
+    }
+}
+
+async fn resolve_host(host: String) -> Result<Vec<SocketAddr>, std::io::Error> {
+    let overrides = overrides_for(&host);
+    if !overrides.is_empty() {
+        return Ok(overrides);
+    }
+    let ttl = Duration::from_secs(config().dns.graph_dns_cache_ttl_secs);
+    let cached = DNS_CACHE
+        .lock()
+        .unwrap()
+        .get(&host)
+        .map(|cached| (cached.addrs.clone(), cached.resolved_at.elapsed() < ttl));
+    if let Some((addrs, true)) = &cached {
+        return Ok(addrs.clone());
+    }
+    let lookup = tokio::net::lookup_host((host.as_str(), 0))
+        .await
+        .map(|addrs| filter_family(addrs.collect()));
+    match lookup {
+        Ok(addrs) => {
+            debug!("Resolved {} to {:?}", host, addrs);
+            if !ttl.is_zero() {
+                DNS_CACHE.lock().unwrap().insert(
+                    host,
+                    CachedAddrs {
+                        addrs: addrs.clone(),
+                        resolved_at: Instant::now(),
+                    },
+                );
+            }
+            Ok(addrs)
+        }
+        Err(err) => match cached {
+            Some((addrs, _)) => {
+                warn!("Resolving {} failed, using stale addresses: {}", host, err);
+                Ok(addrs)
+            }
+            None => Err(err),
+        },
+    }
+}
+
+impl Resolve for GraphResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = resolve_host(host).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Client builder for requests to Graph and the login endpoint. HTTP/2 is
+/// negotiated through ALPN unless disabled with `GRAPH_HTTP2`.
+pub fn graph_client_builder() -> ClientBuilder {
+    let dns = &config().dns;
+    let builder = Client::builder()
+        .dns_resolver(Arc::new(GraphResolver))
+        .connect_timeout(Duration::from_millis(dns.graph_connect_timeout_ms))
+        .pool_idle_timeout(Duration::from_secs(dns.graph_pool_idle_timeout_secs))
+        .pool_max_idle_per_host(dns.graph_pool_max_idle_per_host);
+    if dns.graph_http2 {
+        builder
+    } else {
+        builder.http1_only()
+    }
+}
+
+static GRAPH_CLIENT: Lazy<Client> = Lazy::new(|| {
+    graph_client_builder()
+        .build()
+        .expect("Graph client configuration is valid")
+});
+
+/// The shared Graph client. Clones share one connection pool, so TLS
+/// sessions to Graph and the download hosts are reused across requests.
+pub fn graph_client() -> Client {
+    GRAPH_CLIENT.clone()
+}
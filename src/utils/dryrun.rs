@@ -0,0 +1,135 @@
+use ipnet::IpNet;
+use regex::Regex;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use tracing::info;
+
+use super::allowlist::{is_ip_allowed, parse_networks};
+use super::metrics::record_dry_run;
+use super::naming::filename_regex;
+use super::tokens::{find_api_token, find_token, parse_api_tokens, ApiToken};
+use crate::config;
+
+/// Candidate policies from the `DRY_RUN_*` options. They are evaluated
+/// against real traffic next to the live ones but never enforced, so a
+/// policy change can be validated before it is flipped live.
+struct DryRunPolicies {
+    filename_pattern: Option<Regex>,
+    whitelisted_ips: Option<Vec<IpNet>>,
+    api_tokens: Option<Vec<ApiToken>>,
+}
+
+static POLICIES: OnceLock<DryRunPolicies> = OnceLock::new();
+
+fn split_list(list: &str, separator: char) -> Vec<String> {
+    list.split(separator).map(str::to_string).collect()
+}
+
+/// Parses the candidate policies at startup, failing the same way the live
+/// options would.
+pub fn load_dry_run_policies() -> Result<(), String> {
+    let dry_run = &config().dry_run;
+    let filename_pattern = match &dry_run.dry_run_filename_pattern {
+        Some(pattern) => Some(
+            Regex::new(pattern)
+                .map_err(|err| format!("Invalid DRY_RUN_FILENAME_PATTERN: {}", err))?,
+        ),
+        None => None,
+    };
+    let whitelisted_ips = match &dry_run.dry_run_whitelisted_ips {
+        Some(list) => Some(parse_networks(
+            "DRY_RUN_WHITELISTED_IPS",
+            &split_list(list, ','),
+        )?),
+        None => None,
+    };
+    let api_tokens = match &dry_run.dry_run_api_tokens {
+        Some(list) => Some(parse_api_tokens("DRY_RUN_API_TOKENS", list.split(';'))?),
+        None => None,
+    };
+    let _ = POLICIES.set(DryRunPolicies {
+        filename_pattern,
+        whitelisted_ips,
+        api_tokens,
+    });
+    Ok(())
+}
+
+fn policies() -> Option<&'static DryRunPolicies> {
+    POLICIES.get()
+}
+
+pub fn is_dry_run_enabled() -> bool {
+    policies().is_some_and(|policies| {
+        policies.filename_pattern.is_some()
+            || policies.whitelisted_ips.is_some()
+            || policies.api_tokens.is_some()
+    })
+}
+
+/// Counts a decision and logs it when the candidate disagrees with the live
+/// policy; agreeing decisions would only drown out the interesting ones.
+fn compare(policy: &'static str, subject: &str, live: bool, candidate: bool) {
+    record_dry_run(policy, live, candidate);
+    if live != candidate {
+        info!(
+            "Dry-run {} would {} {} (live: {})",
+            policy,
+            if candidate { "allow" } else { "deny" },
+            subject,
+            if live { "allowed" } else { "denied" }
+        );
+    }
+}
+
+/// Evaluates `DRY_RUN_WHITELISTED_IPS` for a client.
+pub fn evaluate_ip(client: Option<IpAddr>) {
+    let Some(allowed) = policies().and_then(|policies| policies.whitelisted_ips.as_ref()) else {
+        return;
+    };
+    let candidate = allowed.is_empty()
+        || client.is_some_and(|client| allowed.iter().any(|network| network.contains(&client)));
+    let subject = client.map_or("unknown client".to_string(), |client| client.to_string());
+    compare(
+        "whitelisted_ips",
+        &subject,
+        is_ip_allowed(client),
+        candidate,
+    );
+}
+
+/// Evaluates `DRY_RUN_FILENAME_PATTERN` for the key of an object request.
+pub fn evaluate_key(key: &str) {
+    let Some(pattern) = policies().and_then(|policies| policies.filename_pattern.as_ref()) else {
+        return;
+    };
+    let live = filename_regex();
+    compare(
+        "filename_pattern",
+        key,
+        live.is_match(key),
+        pattern.is_match(key),
+    );
+}
+
+/// Evaluates `DRY_RUN_API_TOKENS` for a bearer token, `target` being the
+/// key or the prefix of a listing. `API_TOKEN` is accepted by both tables,
+/// as it is not part of either.
+pub fn evaluate_token(token: &str, method: &str, target: &str) {
+    let Some(tokens) = policies().and_then(|policies| policies.api_tokens.as_ref()) else {
+        return;
+    };
+    let legacy = config().api_token.as_deref() == Some(token);
+    let live = find_api_token(token);
+    let candidate = find_token(tokens, token);
+    let Some(name) = candidate.or(live).map(|api_token| api_token.name.as_str()) else {
+        // Unknown to both tables, nothing to compare.
+        return;
+    };
+    compare(
+        "api_tokens",
+        &format!("token {} to {} {}", name, method, target),
+        live.map_or(legacy, |api_token| api_token.allows(method, target)),
+        candidate.map_or(legacy, |api_token| api_token.allows(method, target)),
+    );
+}
@@ -0,0 +1,61 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::{Error, RequestBuilder};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::config;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Sent along with the signature so the gateway can reject replays.
+const TIMESTAMP_HEADER: &str = "X-Adapter-Timestamp";
+
+const IMDS_HOST: &str = "169.254.169.254";
+
+/// Adds a hex HMAC-SHA256 signature over the newline-joined timestamp,
+/// method, URL and body hash to an outgoing request, for an egress gateway
+/// sharing `EGRESS_SIGNING_SECRET` to verify. Streamed bodies are signed as
+/// `UNSIGNED-PAYLOAD`. The managed identity endpoint is reached directly,
+/// not through the gateway, and is left unsigned.
+pub fn sign_egress(request: RequestBuilder) -> Result<RequestBuilder, Error> {
+    let Some(secret) = config().egress_signing_secret.as_deref() else {
+        return Ok(request);
+    };
+    let (client, request) = request.build_split();
+    let mut request = request?;
+    if request.url().host_str() == Some(IMDS_HOST) {
+        return Ok(RequestBuilder::from_parts(client, request));
+    }
+    let payload_hash = match request.body() {
+        Some(body) => match body.as_bytes() {
+            Some(bytes) => format!("{:x}", Sha256::digest(bytes)),
+            None => "UNSIGNED-PAYLOAD".to_string(),
+        },
+        None => format!("{:x}", Sha256::digest([])),
+    };
+    let timestamp = Utc::now().timestamp().to_string();
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        timestamp,
+        request.method(),
+        request.url(),
+        payload_hash
+    );
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(string_to_sign.as_bytes());
+    let signature = hex::encode(mac.finalize().into_bytes());
+
+    let header = &config().egress_signature_header;
+    match HeaderName::from_bytes(header.as_bytes()) {
+        Ok(name) => {
+            let headers = request.headers_mut();
+            headers.insert(name, HeaderValue::from_str(&signature).unwrap());
+            headers.insert(TIMESTAMP_HEADER, HeaderValue::from_str(&timestamp).unwrap());
+        }
+        Err(_) => warn!("Invalid EGRESS_SIGNATURE_HEADER {}", header),
+    }
+    Ok(RequestBuilder::from_parts(client, request))
+}
@@ -0,0 +1,94 @@
+use salvo::async_trait;
+use salvo::http::header::InvalidHeaderValue;
+use salvo::http::ParseError;
+use salvo::prelude::*;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::warn;
+
+use super::s3::S3Error;
+
+/// Failures a handler propagates with `?`, rendered as the matching S3
+/// error instead of panicking the request task.
+#[derive(Error, Debug)]
+pub enum AdapterError {
+    #[error("Graph request failed: {0}")]
+    Graph(#[from] reqwest::Error),
+    #[error("Malformed request body: {0}")]
+    Body(#[from] ParseError),
+    #[error("Invalid header value: {0}")]
+    Header(#[from] InvalidHeaderValue),
+    /// A JSON request body failing validation, answered as JSON naming
+    /// the field, or no field when the body itself is unusable.
+    #[error("Invalid field {field:?}: {reason}")]
+    InvalidField {
+        field: Option<&'static str>,
+        reason: String,
+    },
+    #[error("{}: {}", .0.code, .0.message)]
+    S3(S3Error),
+}
+
+impl AdapterError {
+    pub fn invalid_field(field: &'static str, reason: impl Into<String>) -> Self {
+        AdapterError::InvalidField {
+            field: Some(field),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn invalid_body(reason: impl Into<String>) -> Self {
+        AdapterError::InvalidField {
+            field: None,
+            reason: reason.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct InvalidFieldBody {
+    field: Option<&'static str>,
+    reason: String,
+}
+
+impl From<S3Error> for AdapterError {
+    fn from(err: S3Error) -> Self {
+        AdapterError::S3(err)
+    }
+}
+
+impl From<AdapterError> for S3Error {
+    fn from(err: AdapterError) -> Self {
+        match err {
+            AdapterError::Graph(err) => S3Error::from(err),
+            AdapterError::Body(err) => S3Error::invalid_argument(err.to_string()),
+            AdapterError::Header(_) => {
+                S3Error::internal_error("We encountered an internal error. Please try again.")
+            }
+            AdapterError::InvalidField { field, reason } => {
+                S3Error::invalid_argument(match field {
+                    Some(field) => format!("{}: {}", field, reason),
+                    None => reason,
+                })
+            }
+            AdapterError::S3(err) => err,
+        }
+    }
+}
+
+#[async_trait]
+impl Writer for AdapterError {
+    async fn write(self, _req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+        match self {
+            AdapterError::InvalidField { field, reason } => {
+                res.status_code(StatusCode::BAD_REQUEST)
+                    .render(Json(InvalidFieldBody { field, reason }));
+            }
+            AdapterError::Header(_) => {
+                warn!("{}", self);
+                res.render(S3Error::from(self));
+            }
+            err => res.render(S3Error::from(err)),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use chrono::{SecondsFormat, Utc};
+use reqwest::{Client, Url};
+use serde_json::json;
+use tracing::{debug, warn};
+
+use super::azure::DeltaItem;
+use super::cache::TrackedDrive;
+use super::changes::{known_key, resolve_changed_keys};
+use super::naming::filename_regex;
+use super::sigv4::{sign_sigv4, AwsCredentials};
+use crate::config;
+
+/// Whether changes are published to a webhook, SQS queue or SNS topic.
+pub fn is_events_enabled() -> bool {
+    let events = &config().events;
+    events.event_webhook_url.is_some()
+        || events.event_sqs_queue_url.is_some()
+        || events.event_sns_topic_arn.is_some()
+}
+
+fn credentials() -> AwsCredentials {
+    let events = &config().events;
+    AwsCredentials {
+        access_key: events.event_aws_access_key_id.clone().unwrap_or_default(),
+        secret: events
+            .event_aws_secret_access_key
+            .clone()
+            .unwrap_or_default(),
+        region: events.event_aws_region.clone(),
+    }
+}
+
+/// An S3 event notification record for one key, `sharepointItemId`
+/// identifying items whose key is unknown.
+fn record(
+    event_name: &str,
+    bucket: &str,
+    key: Option<&str>,
+    item: &DeltaItem,
+) -> serde_json::Value {
+    let now = Utc::now();
+    json!({
+        "Records": [{
+            "eventVersion": "2.1",
+            "eventSource": "aws:s3",
+            "awsRegion": config().events.event_aws_region,
+            "eventTime": now.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "eventName": event_name,
+            "s3": {
+                "s3SchemaVersion": "1.0",
+                "bucket": {
+                    "name": bucket,
+                    "arn": format!("arn:aws:s3:::{}", bucket),
+                },
+                "object": {
+                    "key": key.map(urlencoding::encode),
+                    "sharepointItemId": item.id,
+                    "size": item.size.unwrap_or_default(),
+                    "sequencer": format!("{:016X}", now.timestamp_nanos_opt().unwrap_or_default()),
+                },
+            },
+        }],
+    })
+}
+
+/// Sends a form-encoded AWS query API call, signed for the service.
+async fn send_aws(client: &Client, url: &str, service: &str, form: &[(&str, &str)]) {
+    let Ok(url) = Url::parse(url) else {
+        warn!("Invalid {} endpoint {}", service, url);
+        return;
+    };
+    let content_type = "application/x-www-form-urlencoded";
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form)
+        .finish();
+    let mut request = client
+        .post(url.clone())
+        .header("Content-Type", content_type);
+    for (name, value) in sign_sigv4(
+        "POST",
+        &url,
+        content_type,
+        body.as_bytes(),
+        service,
+        &credentials(),
+    ) {
+        request = request.header(name, value);
+    }
+    match request.body(body).send().await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => warn!("Publishing to {} failed: {}", service, response.status()),
+        Err(err) => warn!("Publishing to {} failed: {}", service, err),
+    }
+}
+
+async fn publish(client: &Client, event: serde_json::Value) {
+    let events = &config().events;
+    let message = event.to_string();
+    if let Some(webhook_url) = &events.event_webhook_url {
+        match client.post(webhook_url).json(&event).send().await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!("Event webhook answered {}", response.status()),
+            Err(err) => warn!("Event webhook failed: {}", err),
+        }
+    }
+    if let Some(queue_url) = &events.event_sqs_queue_url {
+        send_aws(
+            client,
+            queue_url,
+            "sqs",
+            &[
+                ("Action", "SendMessage"),
+                ("MessageBody", &message),
+                ("Version", "2012-11-05"),
+            ],
+        )
+        .await;
+    }
+    if let Some(topic_arn) = &events.event_sns_topic_arn {
+        let endpoint = format!("https://sns.{}.amazonaws.com/", events.event_aws_region);
+        send_aws(
+            client,
+            &endpoint,
+            "sns",
+            &[
+                ("Action", "Publish"),
+                ("TopicArn", topic_arn),
+                ("Message", &message),
+                ("Version", "2010-03-31"),
+            ],
+        )
+        .await;
+    }
+}
+
+/// Publishes a round of delta changes as `s3:ObjectCreated:Put` and
+/// `s3:ObjectRemoved:Delete` events, in the background. Deletions of items
+/// whose key was never seen carry a null key along with the item id.
+pub fn publish_changes(drive: TrackedDrive, changes: Vec<DeltaItem>) {
+    tokio::spawn(async move {
+        let regex = filename_regex();
+        let client = Client::new();
+        let (deleted, changed): (Vec<_>, Vec<_>) =
+            changes.into_iter().partition(|item| item.deleted.is_some());
+        for item in deleted {
+            let key =
+                known_key(&drive.drive, &item.id).map(|key| format!("{}{}", drive.prefix, key));
+            if key.as_ref().is_some_and(|key| !regex.is_match(key)) {
+                continue;
+            }
+            debug!("Publishing ObjectRemoved:Delete of {}", item.id);
+            let event = record("ObjectRemoved:Delete", &drive.bucket, key.as_deref(), &item);
+            publish(&client, event).await;
+        }
+        let changed = changed
+            .into_iter()
+            .filter(|item| item.file.is_some())
+            .collect::<Vec<DeltaItem>>();
+        let keys = match resolve_changed_keys(&drive.drive, &changed).await {
+            Ok(keys) => keys,
+            Err(err) => {
+                warn!("Resolving keys of changed items failed: {}", err);
+                return;
+            }
+        };
+        for (item, key) in changed.iter().zip(keys) {
+            let Some(key) = key else {
+                warn!("Resolving key of changed item {} failed", item.id);
+                continue;
+            };
+            let key = format!("{}{}", drive.prefix, key);
+            if !regex.is_match(&key) {
+                continue;
+            }
+            debug!("Publishing ObjectCreated:Put of {}", key);
+            let event = record("ObjectCreated:Put", &drive.bucket, Some(&key), item);
+            publish(&client, event).await;
+        }
+    });
+}
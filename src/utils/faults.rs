@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::Response;
+use tracing::warn;
+
+use crate::config;
+
+fn should_inject() -> bool {
+    let rate = config().fault_injection_rate;
+    rate > 0.0 && rand::thread_rng().gen_bool(rate.min(1.0))
+}
+
+/// Delays a Graph call by a random duration up to the configured maximum.
+pub async fn inject_latency() {
+    let max_latency_ms = config().fault_injection_max_latency_ms;
+    if max_latency_ms == 0 || !should_inject() {
+        return;
+    }
+    let latency = rand::thread_rng().gen_range(0..=max_latency_ms);
+    warn!("Fault injection: delaying Graph call by {}ms", latency);
+    tokio::time::sleep(Duration::from_millis(latency)).await;
+}
+
+/// Replaces a Graph response with a throttling response or a truncated body.
+pub async fn inject_response_fault(response: Response) -> Response {
+    if !should_inject() {
+        return response;
+    }
+    if rand::thread_rng().gen_bool(0.5) {
+        warn!("Fault injection: throttling {}", response.url());
+        let throttled = http::Response::builder()
+            .status(429)
+            .header("Retry-After", "1")
+            .header("Content-Type", "application/json")
+            .body(
+                r#"{"error":{"code":"activityLimitReached","message":"Injected throttling"}}"#
+                    .to_string(),
+            )
+            .unwrap();
+        return Response::from(throttled);
+    }
+    warn!("Fault injection: truncating body of {}", response.url());
+    let status = response.status();
+    let headers = response.headers().clone();
+    let data = response.bytes().await.unwrap_or_default();
+    let mut truncated = http::Response::builder().status(status);
+    for (name, value) in headers.iter().filter(|(name, _)| *name != "content-length") {
+        truncated = truncated.header(name, value);
+    }
+    Response::from(truncated.body(data.slice(..data.len() / 2)).unwrap())
+}
@@ -0,0 +1,102 @@
+use once_cell::sync::Lazy;
+use reqwest::Error;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::azure::{check_azure_drive, check_azure_site, check_azure_tokens, DriveState};
+use super::buckets::buckets;
+
+/// Readiness probes hit the adapter every few seconds, Graph is asked at
+/// most this often.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An RFC 7807 problem explaining why the adapter is not ready. `check`
+/// names what failed: `credentials` or `site`.
+#[derive(Serialize, Clone, Debug)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub check: &'static str,
+}
+
+/// Something that does not stop the adapter from serving but is likely a
+/// misconfiguration, such as a bucket whose drive is missing or empty.
+#[derive(Serialize, Clone, Debug)]
+pub struct Warning {
+    pub check: &'static str,
+    pub bucket: String,
+    pub detail: String,
+}
+
+fn problem(check: &'static str, title: String, err: Error) -> Problem {
+    Problem {
+        problem_type: "about:blank",
+        title,
+        status: 503,
+        detail: err.to_string(),
+        check,
+    }
+}
+
+type Check = (Instant, Result<Vec<Warning>, Problem>);
+
+static LAST_CHECK: Lazy<AsyncMutex<Option<Check>>> = Lazy::new(|| AsyncMutex::new(None));
+
+async fn check() -> Result<Vec<Warning>, Problem> {
+    check_azure_tokens().await.map_err(|err| {
+        problem(
+            "credentials",
+            "Acquiring a Graph token failed, check the app registration and tenant".to_string(),
+            err,
+        )
+    })?;
+    let mut site_ids = buckets()
+        .into_iter()
+        .map(|bucket| bucket.site_id)
+        .collect::<Vec<String>>();
+    site_ids.sort();
+    site_ids.dedup();
+    for site_id in site_ids {
+        check_azure_site(site_id.clone())
+            .await
+            .map_err(|err| problem("site", format!("Site {} is not accessible", site_id), err))?;
+    }
+    // Missing and empty drives list as empty buckets, which is easily
+    // mistaken for a broken consumer.
+    let mut warnings = Vec::new();
+    for bucket in buckets() {
+        let detail = match check_azure_drive(bucket.drive_url()).await {
+            Ok(DriveState::Populated) => continue,
+            Ok(DriveState::Missing) => {
+                "The drive does not exist, check DRIVE_ID or the site's default library".to_string()
+            }
+            Ok(DriveState::Empty) => "The drive holds no items".to_string(),
+            Err(err) => format!("The drive is not accessible: {}", err),
+        };
+        warnings.push(Warning {
+            check: "drive",
+            bucket: bucket.name,
+            detail,
+        });
+    }
+    Ok(warnings)
+}
+
+/// Whether Graph is reachable with the configured credentials and sites,
+/// with warnings about drives that serve nothing. Concurrent probes share
+/// one check, results are reused for 30 seconds.
+pub async fn readiness() -> Result<Vec<Warning>, Problem> {
+    let mut last_check = LAST_CHECK.lock().await;
+    if let Some((checked_at, result)) = last_check.as_ref() {
+        if checked_at.elapsed() < CHECK_INTERVAL {
+            return result.clone();
+        }
+    }
+    let result = check().await;
+    *last_check = Some((Instant::now(), result.clone()));
+    result
+}
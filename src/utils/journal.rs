@@ -0,0 +1,121 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use super::azure::{Folder, Item, SharePointObjects};
+use crate::config;
+
+/// A write acknowledged to a client that Graph may not reflect yet.
+#[derive(Clone, Debug)]
+pub enum Write {
+    Put(Box<Item>),
+    Deleted,
+}
+
+struct Entry {
+    write: Write,
+    written_at: Instant,
+}
+
+/// Recent writes by `{drive}:{key}`.
+static JOURNAL: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn is_journal_enabled() -> bool {
+    config().write_journal_ttl_secs > 0
+}
+
+fn ttl() -> Duration {
+    Duration::from_secs(config().write_journal_ttl_secs)
+}
+
+pub fn record_write(drive: &str, key: &str, write: Write) {
+    if !is_journal_enabled() {
+        return;
+    }
+    let mut journal = JOURNAL.lock().unwrap();
+    journal.retain(|_, entry| entry.written_at.elapsed() <= ttl());
+    journal.insert(
+        format!("{}:{}", drive, key),
+        Entry {
+            write,
+            written_at: Instant::now(),
+        },
+    );
+}
+
+/// The journaled write of a key, if it is recent enough to still matter.
+pub fn recent_write(drive: &str, key: &str) -> Option<Write> {
+    if !is_journal_enabled() {
+        return None;
+    }
+    let journal = JOURNAL.lock().unwrap();
+    journal
+        .get(&format!("{}:{}", drive, key))
+        .filter(|entry| entry.written_at.elapsed() <= ttl())
+        .map(|entry| entry.write.clone())
+}
+
+/// Merges recent writes below `prefix` into a listing page: deleted items
+/// are dropped from every page, written items that Graph does not list yet
+/// are added to the last page. Writes Graph lists with the written ETag are
+/// forgotten, deletes expire with the journal TTL.
+pub fn merge_writes(drive: &str, prefix: &str, recursive: bool, objects: &mut SharePointObjects) {
+    if !is_journal_enabled() {
+        return;
+    }
+    let prefix = prefix.trim_matches('/');
+    let object_prefix = if prefix.is_empty() {
+        format!("{}:", drive)
+    } else {
+        format!("{}:{}/", drive, prefix)
+    };
+    let mut journal = JOURNAL.lock().unwrap();
+    journal.retain(|_, entry| entry.written_at.elapsed() <= ttl());
+    let mut reflected = Vec::new();
+    for (object, entry) in journal.iter() {
+        let Some(name) = object.strip_prefix(&object_prefix) else {
+            continue;
+        };
+        let (listed_name, in_folder) = match name.split_once('/') {
+            Some((folder, _)) if !recursive => (folder, true),
+            _ => (name, false),
+        };
+        let listed = objects
+            .items
+            .iter()
+            .position(|item| item.name == listed_name);
+        match (&entry.write, listed) {
+            (Write::Deleted, Some(index)) if !in_folder => {
+                objects.items.remove(index);
+            }
+            (Write::Put(item), Some(index))
+                if !in_folder && objects.items[index].e_tag == item.e_tag =>
+            {
+                reflected.push(object.clone());
+            }
+            (Write::Put(item), None) if objects.next_link.is_none() => {
+                debug!("Listing journaled write {}", object);
+                objects.items.push(if in_folder {
+                    Item {
+                        name: listed_name.to_string(),
+                        file: None,
+                        folder: Some(Folder { child_count: 1 }),
+                        size: None,
+                        ..*item.clone()
+                    }
+                } else {
+                    Item {
+                        name: listed_name.to_string(),
+                        ..*item.clone()
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+    for object in reflected {
+        journal.remove(&object);
+    }
+}
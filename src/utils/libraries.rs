@@ -0,0 +1,104 @@
+use once_cell::sync::Lazy;
+use reqwest::Error;
+use std::collections::HashMap;
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{info, warn};
+
+use super::azure::{list_azure_drives, Folder, Item};
+use super::buckets::{buckets, Bucket};
+use crate::config;
+
+/// Document libraries per site, enumerated once and kept for the lifetime
+/// of the process.
+static LIBRARIES: Lazy<AsyncMutex<HashMap<String, Vec<Item>>>> =
+    Lazy::new(|| AsyncMutex::new(HashMap::new()));
+
+/// Whether buckets without a mapped drive present each document library of
+/// their site as a top-level prefix.
+pub fn is_library_mode() -> bool {
+    config().libraries_as_prefixes
+}
+
+fn uses_libraries(bucket: &Bucket) -> bool {
+    is_library_mode() && bucket.drive_id.is_none()
+}
+
+/// The document libraries of the bucket's site.
+pub async fn libraries(bucket: &Bucket) -> Result<Vec<Item>, Error> {
+    let mut libraries = LIBRARIES.lock().await;
+    if let Some(drives) = libraries.get(&bucket.site_id) {
+        return Ok(drives.clone());
+    }
+    let drives = list_azure_drives(bucket.site_id.clone()).await?;
+    info!(
+        "Site {} has libraries {}",
+        bucket.site_id,
+        drives
+            .iter()
+            .map(|drive| drive.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    );
+    libraries.insert(bucket.site_id.clone(), drives.clone());
+    Ok(drives)
+}
+
+/// Enumerates the libraries of every bucket at startup, so the first
+/// requests do not pay for it.
+pub fn spawn_library_loader() {
+    if !is_library_mode() {
+        return;
+    }
+    tokio::spawn(async {
+        for bucket in buckets().iter().filter(|bucket| uses_libraries(bucket)) {
+            if let Err(err) = libraries(bucket).await {
+                warn!("Listing libraries of {} failed: {}", bucket.site_id, err);
+            }
+        }
+    });
+}
+
+/// Whether a listing of `prefix` is the bucket root, which lists the
+/// libraries themselves.
+pub fn lists_libraries(bucket: &Bucket, prefix: &str) -> bool {
+    uses_libraries(bucket) && prefix.trim_matches('/').is_empty()
+}
+
+/// The libraries as folder items for a listing of the bucket root.
+pub async fn library_folders(bucket: &Bucket) -> Result<Vec<Item>, Error> {
+    Ok(libraries(bucket)
+        .await?
+        .into_iter()
+        .map(|library| Item {
+            folder: Some(Folder { child_count: 1 }),
+            ..library
+        })
+        .collect())
+}
+
+/// Routes a key or prefix to the library named by its first segment,
+/// returning the bucket bound to that drive and the path within it. Buckets
+/// with a mapped drive, or outside library mode, are returned unchanged.
+pub async fn resolve_library(
+    bucket: &Bucket,
+    path: &str,
+) -> Result<Option<(Bucket, String)>, Error> {
+    if !uses_libraries(bucket) {
+        return Ok(Some((bucket.clone(), path.to_string())));
+    }
+    let path = path.trim_start_matches('/');
+    let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+    Ok(libraries(bucket)
+        .await?
+        .into_iter()
+        .find(|library| library.name == name)
+        .map(|library| {
+            (
+                Bucket {
+                    drive_id: Some(library.id),
+                    ..bucket.clone()
+                },
+                rest.to_string(),
+            )
+        }))
+}
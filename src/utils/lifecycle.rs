@@ -0,0 +1,351 @@
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use regex::Regex;
+use reqwest::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use super::audit::{is_audit_enabled, record_audit, AuditRecord};
+use super::azure::{
+    delete_azure_object, get_azure_item_fields, list_azure_objects_recursive, move_azure_object,
+    Item, Traversal,
+};
+use super::buckets::{buckets, Bucket};
+use super::cache::invalidate_listings;
+use super::journal::{record_write, Write};
+use super::naming::filename_regex;
+use super::readahead::invalidate_read_ahead;
+use super::request::new_request_id;
+use super::shutdown::sleep_until_shutdown;
+use super::tail::invalidate_tail;
+use crate::config;
+
+#[derive(Debug, Clone)]
+enum Action {
+    Delete,
+    /// Moves matching keys below the given prefix, keeping their path
+    /// relative to the rule's base folder.
+    Move(String),
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    /// Last modified more than this long ago.
+    OlderThan(TimeDelta),
+    /// A listItem field has this value, e.g. one set through `/_metadata`.
+    Field(String, String),
+}
+
+/// A rule from `LIFECYCLE_RULES`, written as `action|glob|condition[|target]`,
+/// e.g. `delete|tmp/**|age=7d` or `move|inbox/**|Processed=true|archive/`.
+#[derive(Debug, Clone)]
+struct Rule {
+    source: String,
+    action: Action,
+    base: String,
+    pattern: Regex,
+    condition: Condition,
+}
+
+impl Rule {
+    fn label(&self) -> &'static str {
+        match self.action {
+            Action::Delete => "delete",
+            Action::Move(_) => "move",
+        }
+    }
+}
+
+/// Translates a key glob into a regex: `**` matches across folders, `*` and
+/// `?` within one path segment.
+fn glob_to_regex(glob: &str) -> Option<Regex> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).ok()
+}
+
+fn parse_condition(condition: &str) -> Option<Condition> {
+    let (name, value) = condition.split_once('=')?;
+    let (name, value) = (name.trim(), value.trim());
+    if name == "age" {
+        let days = value
+            .strip_suffix('d')
+            .unwrap_or(value)
+            .parse::<i64>()
+            .ok()?;
+        return Some(Condition::OlderThan(TimeDelta::try_days(days)?));
+    }
+    Some(Condition::Field(name.to_string(), value.to_string()))
+}
+
+fn parse_rule(rule: &str) -> Option<Rule> {
+    let parts = rule.split('|').map(str::trim).collect::<Vec<&str>>();
+    let glob = parts.get(1)?.trim_start_matches('/');
+    let action = match (parts.first()?.to_lowercase().as_str(), parts.get(3)) {
+        ("delete", None) => Action::Delete,
+        ("move", Some(target)) => {
+            Action::Move(target.trim_end_matches("**").trim_matches('/').to_string())
+        }
+        _ => return None,
+    };
+    // The folder to list: everything up to the last `/` before a wildcard.
+    let literal = &glob[..glob.find(['*', '?']).unwrap_or(glob.len())];
+    let base = literal
+        .rsplit_once('/')
+        .map(|(base, _)| base)
+        .unwrap_or_default();
+    Some(Rule {
+        source: rule.to_string(),
+        action,
+        base: base.to_string(),
+        pattern: glob_to_regex(glob)?,
+        condition: parse_condition(parts.get(2)?)?,
+    })
+}
+
+fn lifecycle_rules() -> Vec<Rule> {
+    config()
+        .lifecycle_rules
+        .iter()
+        .filter(|rule| !rule.trim().is_empty())
+        .filter_map(|rule| {
+            let parsed = parse_rule(rule);
+            if parsed.is_none() {
+                warn!("Ignoring malformed lifecycle rule {}", rule);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Applied and failed actions, by `delete` and `move`.
+static APPLIED: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+static FAILED: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+fn record_outcome(rule: &Rule, ok: bool) {
+    let index = match rule.action {
+        Action::Delete => 0,
+        Action::Move(_) => 1,
+    };
+    let counts = if ok { &APPLIED } else { &FAILED };
+    counts[index].fetch_add(1, Ordering::Relaxed);
+}
+
+/// `(action, applied, failed)` counts since startup.
+pub fn lifecycle_counts() -> [(&'static str, u64, u64); 2] {
+    [
+        (
+            "delete",
+            APPLIED[0].load(Ordering::Relaxed),
+            FAILED[0].load(Ordering::Relaxed),
+        ),
+        (
+            "move",
+            APPLIED[1].load(Ordering::Relaxed),
+            FAILED[1].load(Ordering::Relaxed),
+        ),
+    ]
+}
+
+fn key_of(rule: &Rule, item: &Item) -> String {
+    if rule.base.is_empty() {
+        item.name.clone()
+    } else {
+        format!("{}/{}", rule.base, item.name)
+    }
+}
+
+async fn is_due(rule: &Rule, drive: &str, key: &str, item: &Item) -> Result<bool, Error> {
+    match &rule.condition {
+        Condition::OlderThan(age) => Ok(item
+            .last_modified_date_time
+            .as_deref()
+            .and_then(|modified| DateTime::parse_from_rfc3339(modified).ok())
+            .is_some_and(|modified| Utc::now() - modified.with_timezone(&Utc) > *age)),
+        Condition::Field(name, value) => {
+            let fields = get_azure_item_fields(drive.to_string(), key.to_string()).await?;
+            Ok(fields.get(name).is_some_and(|field| match field {
+                serde_json::Value::String(field) => field == value,
+                field => {
+                    serde_json::from_str::<serde_json::Value>(value)
+                        .ok()
+                        .as_ref()
+                        == Some(field)
+                }
+            }))
+        }
+    }
+}
+
+/// Lists the keys below the rule's base folder that match its glob and the
+/// adapter's `FILENAME_PATTERN`.
+async fn matching_items(rule: &Rule, drive: &str) -> Result<Vec<(String, Item)>, Error> {
+    let filename_pattern = filename_regex();
+    let mut matches = Vec::new();
+    let mut traversal: Option<Traversal> = None;
+    loop {
+        let page =
+            list_azure_objects_recursive(drive.to_string(), rule.base.clone(), 1000, traversal)
+                .await?;
+        for item in page.items {
+            let key = key_of(rule, &item);
+            if rule.pattern.is_match(&key) && filename_pattern.is_match(&key) {
+                matches.push((key, item));
+            }
+        }
+        traversal = match page.next_link {
+            Some(next_link) => serde_json::from_str(&next_link).ok(),
+            None => None,
+        };
+        if traversal.is_none() {
+            return Ok(matches);
+        }
+    }
+}
+
+fn invalidate_caches(drive: &str, key: &str) {
+    invalidate_read_ahead(drive, key);
+    invalidate_tail(drive, key);
+    invalidate_listings(drive, key);
+}
+
+async fn apply(rule: &Rule, bucket: &Bucket, key: &str) -> Result<(), Error> {
+    let drive = bucket.drive_url();
+    // Before and again after the change, so a read racing it cannot cache
+    // the old state.
+    invalidate_caches(&drive, key);
+    let outcome = apply_action(rule, &drive, key).await;
+    invalidate_caches(&drive, key);
+    audit(rule, bucket, key, &outcome);
+    outcome?;
+    record_write(&drive, key, Write::Deleted);
+    Ok(())
+}
+
+/// Audits a transition like a request of the adapter. A move is recorded
+/// for its source and its destination under one request id.
+fn audit(rule: &Rule, bucket: &Bucket, key: &str, outcome: &Result<Option<String>, Error>) {
+    if !is_audit_enabled() {
+        return;
+    }
+    let (operation, status) = match rule.action {
+        Action::Delete => ("LifecycleDelete", 204),
+        Action::Move(_) => ("LifecycleMove", 200),
+    };
+    let status = match outcome {
+        Ok(_) => status,
+        Err(err) => err.status().map_or(500, |status| status.as_u16()),
+    };
+    let request_id = new_request_id();
+    let destination = outcome.as_ref().ok().cloned().flatten();
+    for key in std::iter::once(key.to_string()).chain(destination) {
+        record_audit(AuditRecord {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            request_id: request_id.clone(),
+            caller: Some(format!("lifecycle:{}", rule.label())),
+            on_behalf_of: None,
+            source_ip: None,
+            operation,
+            bucket: Some(bucket.name.clone()),
+            key,
+            status,
+            bytes_received: 0,
+            bytes_sent: 0,
+            complete: true,
+        });
+    }
+}
+
+/// Deletes or moves a key, returning where a moved key went.
+async fn apply_action(rule: &Rule, drive: &str, key: &str) -> Result<Option<String>, Error> {
+    match &rule.action {
+        Action::Delete => {
+            delete_azure_object(drive.to_string(), key.to_string()).await?;
+            info!("Lifecycle rule {} deleted {}", rule.source, key);
+            Ok(None)
+        }
+        Action::Move(target) => {
+            let relative = key
+                .strip_prefix(rule.base.as_str())
+                .unwrap_or(key)
+                .trim_start_matches('/');
+            let destination = if target.is_empty() {
+                relative.to_string()
+            } else {
+                format!("{}/{}", target, relative)
+            };
+            invalidate_listings(drive, &destination);
+            let moved =
+                move_azure_object(drive.to_string(), key.to_string(), destination.clone()).await;
+            invalidate_listings(drive, &destination);
+            record_write(drive, &destination, Write::Put(Box::new(moved?)));
+            info!(
+                "Lifecycle rule {} moved {} to {}",
+                rule.source, key, destination
+            );
+            Ok(Some(destination))
+        }
+    }
+}
+
+async fn run_rule(rule: &Rule, bucket: &Bucket) -> Result<(), Error> {
+    let drive = bucket.drive_url();
+    for (key, item) in matching_items(rule, &drive).await? {
+        let outcome = match is_due(rule, &drive, &key, &item).await {
+            Ok(true) => apply(rule, bucket, &key).await,
+            Ok(false) => continue,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &outcome {
+            warn!(
+                "Lifecycle rule {} failed to {} {}: {}",
+                rule.source,
+                rule.label(),
+                key,
+                err
+            );
+        }
+        record_outcome(rule, outcome.is_ok());
+    }
+    Ok(())
+}
+
+/// Runs the configured lifecycle rules against every bucket on a schedule.
+pub fn spawn_lifecycle_runner() {
+    let rules = lifecycle_rules();
+    if rules.is_empty() {
+        return;
+    }
+    let interval = Duration::from_secs(config().lifecycle_interval_secs.max(60));
+    tokio::spawn(async move {
+        loop {
+            for bucket in buckets() {
+                for rule in &rules {
+                    debug!("Running lifecycle rule {} on {}", rule.source, bucket.name);
+                    if let Err(err) = run_rule(rule, &bucket).await {
+                        warn!(
+                            "Lifecycle rule {} could not list {}: {}",
+                            rule.source, bucket.name, err
+                        );
+                    }
+                }
+            }
+            if !sleep_until_shutdown(interval).await {
+                return;
+            }
+        }
+    });
+}
@@ -0,0 +1,79 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use salvo::http::header::{HeaderName, HeaderValue};
+use salvo::http::HeaderMap;
+
+use crate::config;
+
+const PREFIX: &str = "x-amz-meta-";
+
+/// Whether listItem columns are exposed as user metadata, i.e.
+/// `METADATA_FIELDS` names any.
+pub fn is_user_metadata_enabled() -> bool {
+    !config().metadata_fields.is_empty()
+}
+
+/// Non-ASCII values are sent as RFC 2047 encoded words, as S3 does.
+fn encode_value(value: &str) -> Option<HeaderValue> {
+    if value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+        return HeaderValue::from_str(value).ok();
+    }
+    HeaderValue::from_str(&format!("=?UTF-8?B?{}?=", STANDARD.encode(value))).ok()
+}
+
+fn decode_value(value: &str) -> Option<String> {
+    match value
+        .strip_prefix("=?UTF-8?B?")
+        .and_then(|encoded| encoded.strip_suffix("?="))
+    {
+        Some(encoded) => String::from_utf8(STANDARD.decode(encoded).ok()?).ok(),
+        None => Some(value.to_string()),
+    }
+}
+
+/// The `x-amz-meta-<column>` headers of the `METADATA_FIELDS` columns of a
+/// listItem. Header names are lowercase, as HTTP/2 requires; empty columns
+/// are left out.
+pub fn metadata_headers(
+    fields: &serde_json::Map<String, serde_json::Value>,
+) -> Vec<(HeaderName, HeaderValue)> {
+    config()
+        .metadata_fields
+        .iter()
+        .filter_map(|name| {
+            let value = match fields.get(name)? {
+                serde_json::Value::Null => return None,
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            let header = HeaderName::from_bytes(
+                format!("{}{}", PREFIX, name.to_ascii_lowercase()).as_bytes(),
+            )
+            .ok()?;
+            Some((header, encode_value(&value)?))
+        })
+        .collect()
+}
+
+/// The listItem fields to write for the `x-amz-meta-*` headers of an
+/// upload. Headers not naming one of `METADATA_FIELDS` are ignored, as
+/// clients such as rclone send their own metadata.
+pub fn fields_from_headers(headers: &HeaderMap) -> serde_json::Map<String, serde_json::Value> {
+    let mut fields = serde_json::Map::new();
+    for (header, value) in headers {
+        let Some(column) = header.as_str().strip_prefix(PREFIX) else {
+            continue;
+        };
+        let Some(name) = config()
+            .metadata_fields
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(column))
+        else {
+            continue;
+        };
+        if let Some(value) = value.to_str().ok().and_then(decode_value) {
+            fields.insert(name.clone(), serde_json::Value::String(value));
+        }
+    }
+    fields
+}
@@ -0,0 +1,238 @@
+use bytes::Bytes;
+use futures_util::Stream;
+use once_cell::sync::Lazy;
+use reqwest::Error;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use tracing::warn;
+
+use super::cache::listing_cache_counts;
+use super::lifecycle::lifecycle_counts;
+
+/// Why a request or transfer ended before it completed. Counted separately
+/// to tell a consumer's network apart from a slow SharePoint.
+#[derive(Debug, Clone, Copy)]
+pub enum Abort {
+    /// The client went away while sending or receiving a body.
+    Client,
+    /// A Graph call ran out of its operation budget.
+    GraphTimeout,
+    /// The adapter did not answer within `REQUEST_TIMEOUT_SECS`.
+    AdapterTimeout,
+}
+
+impl Abort {
+    fn label(self) -> &'static str {
+        match self {
+            Abort::Client => "client_abort",
+            Abort::GraphTimeout => "graph_timeout",
+            Abort::AdapterTimeout => "adapter_timeout",
+        }
+    }
+}
+
+const ABORTS: [Abort; 3] = [Abort::Client, Abort::GraphTimeout, Abort::AdapterTimeout];
+
+static ABORT_COUNTS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+pub fn record_abort(abort: Abort) {
+    ABORT_COUNTS[abort as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Why a bucket listed as empty without an error, to tell a misconfigured
+/// drive apart from one that has no content yet.
+#[derive(Debug, Clone, Copy)]
+pub enum EmptyDrive {
+    /// The drive does not exist, e.g. a site without a default library.
+    Missing,
+    /// The drive exists but holds no items.
+    Empty,
+}
+
+impl EmptyDrive {
+    fn label(self) -> &'static str {
+        match self {
+            EmptyDrive::Missing => "missing",
+            EmptyDrive::Empty => "empty",
+        }
+    }
+}
+
+const EMPTY_DRIVES: [EmptyDrive; 2] = [EmptyDrive::Missing, EmptyDrive::Empty];
+
+static EMPTY_DRIVE_COUNTS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+pub fn record_empty_drive(empty_drive: EmptyDrive) {
+    EMPTY_DRIVE_COUNTS[empty_drive as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Requests and denials per `API_TOKENS` entry.
+static TOKEN_COUNTS: Lazy<Mutex<BTreeMap<String, (u64, u64)>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn record_token_request(name: &str) {
+    TOKEN_COUNTS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .0 += 1;
+}
+
+/// Counts a request the token was not allowed to make, e.g. outside its
+/// prefixes or after its expiry.
+pub fn record_token_denial(name: &str) {
+    TOKEN_COUNTS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .1 += 1;
+}
+
+/// Decisions of the dry-run policies, by policy and outcome.
+static DRY_RUN_COUNTS: Lazy<Mutex<BTreeMap<(&'static str, &'static str), u64>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn record_dry_run(policy: &'static str, live: bool, candidate: bool) {
+    let outcome = match (live, candidate) {
+        (true, false) => "would_deny",
+        (false, true) => "would_allow",
+        _ => "agree",
+    };
+    *DRY_RUN_COUNTS
+        .lock()
+        .unwrap()
+        .entry((policy, outcome))
+        .or_default() += 1;
+}
+
+/// Renders the counters in the Prometheus text format.
+pub fn render_metrics() -> String {
+    let mut metrics = String::from(
+        "# HELP s3_adapter_aborts_total Requests and transfers that ended early, by cause.\n\
+         # TYPE s3_adapter_aborts_total counter\n",
+    );
+    for abort in ABORTS {
+        metrics.push_str(&format!(
+            "s3_adapter_aborts_total{{cause=\"{}\"}} {}\n",
+            abort.label(),
+            ABORT_COUNTS[abort as usize].load(Ordering::Relaxed)
+        ));
+    }
+    metrics.push_str(
+        "# HELP s3_adapter_empty_drive_listings_total Root listings answered empty, by drive state.\n\
+         # TYPE s3_adapter_empty_drive_listings_total counter\n",
+    );
+    for empty_drive in EMPTY_DRIVES {
+        metrics.push_str(&format!(
+            "s3_adapter_empty_drive_listings_total{{reason=\"{}\"}} {}\n",
+            empty_drive.label(),
+            EMPTY_DRIVE_COUNTS[empty_drive as usize].load(Ordering::Relaxed)
+        ));
+    }
+    let (hits, misses) = listing_cache_counts();
+    metrics.push_str(&format!(
+        "# HELP s3_adapter_listing_cache_total Listing cache lookups, by result.\n\
+         # TYPE s3_adapter_listing_cache_total counter\n\
+         s3_adapter_listing_cache_total{{result=\"hit\"}} {}\n\
+         s3_adapter_listing_cache_total{{result=\"miss\"}} {}\n",
+        hits, misses
+    ));
+    metrics.push_str(
+        "# HELP s3_adapter_lifecycle_actions_total Lifecycle rule actions, by action and result.\n\
+         # TYPE s3_adapter_lifecycle_actions_total counter\n",
+    );
+    for (action, applied, failed) in lifecycle_counts() {
+        metrics.push_str(&format!(
+            "s3_adapter_lifecycle_actions_total{{action=\"{}\",result=\"applied\"}} {}\n\
+             s3_adapter_lifecycle_actions_total{{action=\"{}\",result=\"failed\"}} {}\n",
+            action, applied, action, failed
+        ));
+    }
+    let token_counts = TOKEN_COUNTS.lock().unwrap();
+    metrics.push_str(
+        "# HELP s3_adapter_token_requests_total Requests authenticated by each API token.\n\
+         # TYPE s3_adapter_token_requests_total counter\n",
+    );
+    for (name, (requests, _)) in token_counts.iter() {
+        metrics.push_str(&format!(
+            "s3_adapter_token_requests_total{{token=\"{}\"}} {}\n",
+            name, requests
+        ));
+    }
+    metrics.push_str(
+        "# HELP s3_adapter_token_denials_total Requests each API token was not allowed to make.\n\
+         # TYPE s3_adapter_token_denials_total counter\n",
+    );
+    for (name, (_, denials)) in token_counts.iter() {
+        metrics.push_str(&format!(
+            "s3_adapter_token_denials_total{{token=\"{}\"}} {}\n",
+            name, denials
+        ));
+    }
+    metrics.push_str(
+        "# HELP s3_adapter_dry_run_decisions_total Dry-run policy decisions compared to the live policy.\n\
+         # TYPE s3_adapter_dry_run_decisions_total counter\n",
+    );
+    for ((policy, outcome), count) in DRY_RUN_COUNTS.lock().unwrap().iter() {
+        metrics.push_str(&format!(
+            "s3_adapter_dry_run_decisions_total{{policy=\"{}\",outcome=\"{}\"}} {}\n",
+            policy, outcome, count
+        ));
+    }
+    metrics
+}
+
+/// Wraps a download body, counting Graph timeouts while it streams and
+/// client aborts when the response is dropped before the body ended.
+pub struct MeteredStream<S> {
+    inner: S,
+    key: String,
+    done: bool,
+}
+
+impl<S> MeteredStream<S> {
+    pub fn new(inner: S, key: String) -> Self {
+        MeteredStream {
+            inner,
+            key,
+            done: false,
+        }
+    }
+}
+
+impl<S> Stream for MeteredStream<S>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Unpin,
+{
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Err(err))) => {
+                self.done = true;
+                if err.is_timeout() {
+                    warn!("Graph timed out while streaming {}", self.key);
+                    record_abort(Abort::GraphTimeout);
+                }
+            }
+            Poll::Ready(None) => self.done = true,
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl<S> Drop for MeteredStream<S> {
+    fn drop(&mut self) {
+        if !self.done {
+            warn!("Client aborted the download of {}", self.key);
+            record_abort(Abort::Client);
+        }
+    }
+}
@@ -1,2 +1,40 @@
+pub mod allowlist;
+pub mod archive;
+pub mod audit;
 pub mod azure;
+pub mod buckets;
+pub mod cache;
+pub mod changes;
+pub mod compat;
+pub mod conditional;
+pub mod cursor;
+pub mod dns;
+pub mod dryrun;
+pub mod egress;
+pub mod error;
+pub mod events;
+pub mod faults;
+pub mod health;
+pub mod journal;
+pub mod libraries;
+pub mod lifecycle;
+pub mod metadata;
+pub mod metrics;
+pub mod naming;
+pub mod notifications;
+pub mod overrides;
+pub mod range;
+pub mod ratelimit;
+pub mod readahead;
+pub mod request;
 pub mod s3;
+pub mod selftest;
+pub mod shadow;
+pub mod shutdown;
+pub mod sigv4;
+pub mod snapshot;
+pub mod tail;
+pub mod telemetry;
+pub mod tls;
+pub mod tokens;
+pub mod watermark;
@@ -0,0 +1,140 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::sync::OnceLock;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config;
+
+/// Characters SharePoint Online rejects anywhere in a file or folder name.
+const INVALID_CHARACTERS: [char; 9] = ['"', '*', ':', '<', '>', '?', '\\', '|', '\u{7f}'];
+
+const RESERVED_NAMES: [&str; 6] = [".lock", "con", "prn", "aux", "nul", "desktop.ini"];
+
+/// SharePoint limits the decoded path of an item to 400 characters.
+const MAX_PATH_LENGTH: usize = 400;
+
+const MAX_NAME_LENGTH: usize = 255;
+
+static FILENAME_PATTERN: OnceLock<Regex> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct InvalidKey {
+    pub reason: String,
+    pub invalid_characters: Vec<char>,
+}
+
+/// Compiles `FILENAME_PATTERN` at startup, so an invalid pattern stops the
+/// adapter instead of failing every request.
+pub fn load_filename_pattern() -> Result<(), String> {
+    let regex = Regex::new(&config().filename_pattern)
+        .map_err(|err| format!("Invalid FILENAME_PATTERN: {}", err))?;
+    let _ = FILENAME_PATTERN.set(regex);
+    Ok(())
+}
+
+/// The compiled `FILENAME_PATTERN`.
+pub fn filename_regex() -> &'static Regex {
+    FILENAME_PATTERN.get().expect("FILENAME_PATTERN not loaded")
+}
+
+/// Composes a key or name into Unicode NFC. SharePoint hands out composed
+/// names while macOS clients send decomposed (NFD) ones, so keys are
+/// compared in NFC on both sides.
+pub fn nfc(value: &str) -> String {
+    value.nfc().collect()
+}
+
+/// Deserializes a name from Graph in NFC.
+pub fn deserialize_nfc<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    String::deserialize(deserializer).map(|value| nfc(&value))
+}
+
+fn is_invalid_character(c: char) -> bool {
+    c.is_control() || INVALID_CHARACTERS.contains(&c)
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    let lower = name.to_lowercase();
+    let stem = lower.split('.').next().unwrap_or_default();
+    RESERVED_NAMES.contains(&lower.as_str())
+        || lower.starts_with("~$")
+        || lower.contains("_vti_")
+        || (stem.len() == 4
+            && (stem.starts_with("com") || stem.starts_with("lpt"))
+            && stem.ends_with(|c: char| c.is_ascii_digit()))
+}
+
+/// Checks a key against the SharePoint naming rules.
+pub fn validate_key(key: &str) -> Result<(), InvalidKey> {
+    let mut invalid_characters = key
+        .chars()
+        .filter(|c| is_invalid_character(*c))
+        .collect::<Vec<char>>();
+    invalid_characters.sort();
+    invalid_characters.dedup();
+    if !invalid_characters.is_empty() {
+        return Err(InvalidKey {
+            reason: "Key contains characters SharePoint does not allow".to_string(),
+            invalid_characters,
+        });
+    }
+    if key.chars().count() > MAX_PATH_LENGTH {
+        return Err(InvalidKey {
+            reason: format!("Key is longer than {} characters", MAX_PATH_LENGTH),
+            invalid_characters,
+        });
+    }
+    for name in key.split('/').filter(|name| !name.is_empty()) {
+        let reason = if name.chars().count() > MAX_NAME_LENGTH {
+            format!(
+                "Name {} is longer than {} characters",
+                name, MAX_NAME_LENGTH
+            )
+        } else if name.trim() != name || name.ends_with('.') {
+            format!(
+                "Name {} starts or ends with a space or ends with a dot",
+                name
+            )
+        } else if is_reserved_name(name) {
+            format!("Name {} is reserved by SharePoint", name)
+        } else {
+            continue;
+        };
+        return Err(InvalidKey {
+            reason,
+            invalid_characters,
+        });
+    }
+    Ok(())
+}
+
+/// Rewrites a key into one SharePoint accepts, using `KEY_REPLACEMENTS`
+/// (`from=to` pairs) and falling back to `_` for other invalid characters.
+pub fn sanitize_key(key: &str) -> String {
+    let replacements = config()
+        .key_replacements
+        .iter()
+        .filter_map(|pair| pair.split_once('='))
+        .filter_map(|(from, to)| from.chars().next().map(|from| (from, to)))
+        .collect::<Vec<(char, &str)>>();
+    let replaced = key
+        .chars()
+        .map(|c| match replacements.iter().find(|(from, _)| *from == c) {
+            Some((_, to)) => to.to_string(),
+            None if is_invalid_character(c) => "_".to_string(),
+            None => c.to_string(),
+        })
+        .collect::<String>();
+    replaced
+        .split('/')
+        .map(|name| {
+            let name = name.trim().trim_end_matches('.');
+            if is_reserved_name(name) {
+                format!("_{}", name)
+            } else {
+                name.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
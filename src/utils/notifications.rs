@@ -0,0 +1,193 @@
+use chrono::{TimeDelta, Utc};
+use once_cell::sync::Lazy;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use super::azure::{create_azure_subscription, renew_azure_subscription, Subscription};
+use super::cache::{sync_drive, tracked_drives, TrackedDrive};
+use super::shutdown::sleep_until_shutdown;
+use crate::config;
+
+/// How long a subscription is requested for; Graph allows a bit under 30
+/// days for drive items.
+const SUBSCRIPTION_LIFETIME_HOURS: i64 = 72;
+
+/// How often subscriptions are checked and renewed ahead of expiry.
+const RENEWAL_INTERVAL_SECS: u64 = 3600;
+
+/// Drive per subscription id, to route incoming notifications.
+static SUBSCRIPTIONS: Lazy<Mutex<HashMap<String, TrackedDrive>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Deserialize, Debug)]
+pub struct Notifications {
+    pub value: Vec<Notification>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Notification {
+    #[serde(rename = "subscriptionId")]
+    pub subscription_id: String,
+    #[serde(rename = "clientState")]
+    pub client_state: Option<String>,
+    pub resource: Option<String>,
+}
+
+/// Whether Graph pushes drive changes to `NOTIFICATION_URL`.
+pub fn is_notifications_enabled() -> bool {
+    config()
+        .notification_url
+        .as_deref()
+        .is_some_and(|url| !url.is_empty())
+}
+
+/// The secret Graph echoes in every notification, generated per process
+/// unless configured.
+fn client_state() -> &'static str {
+    static CLIENT_STATE: OnceLock<String> = OnceLock::new();
+    CLIENT_STATE.get_or_init(|| {
+        config()
+            .notification_client_state
+            .clone()
+            .unwrap_or_else(|| {
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(32)
+                    .map(char::from)
+                    .collect()
+            })
+    })
+}
+
+/// The subscription resource of a drive, e.g. `/drives/{id}/root`.
+fn resource(drive: &str) -> String {
+    format!(
+        "{}/root",
+        drive.trim_start_matches("https://graph.microsoft.com/v1.0")
+    )
+}
+
+/// Runs a delta round for each drive a notification is about. Returns
+/// false when a notification does not carry the expected client state.
+pub fn handle_notifications(notifications: &Notifications) -> bool {
+    let mut drives = Vec::new();
+    for notification in &notifications.value {
+        if notification.client_state.as_deref() != Some(client_state()) {
+            warn!(
+                "Rejected notification for subscription {} with a wrong client state",
+                notification.subscription_id
+            );
+            return false;
+        }
+        let drive = SUBSCRIPTIONS
+            .lock()
+            .unwrap()
+            .get(&notification.subscription_id)
+            .cloned();
+        match drive {
+            Some(drive)
+                if !drives
+                    .iter()
+                    .any(|known: &TrackedDrive| known.drive == drive.drive) =>
+            {
+                drives.push(drive)
+            }
+            Some(_) => {}
+            None => debug!(
+                "Notification for unknown subscription {} on {:?}",
+                notification.subscription_id, notification.resource
+            ),
+        }
+    }
+    // Graph expects an answer within seconds, the delta rounds run after.
+    tokio::spawn(async move {
+        for drive in drives {
+            sync_drive(&drive).await;
+        }
+    });
+    true
+}
+
+async fn subscribe(drive: &TrackedDrive, notification_url: &str) -> Option<Subscription> {
+    let expiration = Utc::now() + TimeDelta::hours(SUBSCRIPTION_LIFETIME_HOURS);
+    match create_azure_subscription(
+        resource(&drive.drive),
+        notification_url.to_string(),
+        client_state().to_string(),
+        expiration,
+    )
+    .await
+    {
+        Ok(subscription) => {
+            info!(
+                "Subscribed to changes of {} as {}",
+                drive.drive, subscription.id
+            );
+            SUBSCRIPTIONS
+                .lock()
+                .unwrap()
+                .insert(subscription.id.clone(), drive.clone());
+            Some(subscription)
+        }
+        Err(err) => {
+            warn!("Subscribing to changes of {} failed: {}", drive.drive, err);
+            None
+        }
+    }
+}
+
+/// Creates a subscription per tracked drive and renews it while the
+/// adapter runs, recreating subscriptions Graph no longer knows.
+pub fn spawn_subscription_manager() {
+    let Some(notification_url) = config()
+        .notification_url
+        .clone()
+        .filter(|_| is_notifications_enabled())
+    else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut subscriptions: HashMap<String, Subscription> = HashMap::new();
+        loop {
+            let drives = match tracked_drives().await {
+                Ok(drives) => drives,
+                Err(err) => {
+                    warn!("Listing drives to subscribe to failed: {}", err);
+                    Vec::new()
+                }
+            };
+            for drive in drives {
+                let renew_before =
+                    Utc::now() + TimeDelta::seconds(2 * RENEWAL_INTERVAL_SECS as i64);
+                let subscription = match subscriptions.remove(&drive.drive) {
+                    Some(subscription) if subscription.expiration_date_time > renew_before => {
+                        Some(subscription)
+                    }
+                    Some(subscription) => {
+                        let expiration = Utc::now() + TimeDelta::hours(SUBSCRIPTION_LIFETIME_HOURS);
+                        match renew_azure_subscription(subscription.id.clone(), expiration).await {
+                            Ok(renewed) => Some(renewed),
+                            Err(err) => {
+                                warn!("Renewing subscription {} failed: {}", subscription.id, err);
+                                SUBSCRIPTIONS.lock().unwrap().remove(&subscription.id);
+                                subscribe(&drive, &notification_url).await
+                            }
+                        }
+                    }
+                    None => subscribe(&drive, &notification_url).await,
+                };
+                if let Some(subscription) = subscription {
+                    subscriptions.insert(drive.drive, subscription);
+                }
+            }
+            if !sleep_until_shutdown(Duration::from_secs(RENEWAL_INTERVAL_SECS)).await {
+                return;
+            }
+        }
+    });
+}
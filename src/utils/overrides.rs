@@ -0,0 +1,51 @@
+use salvo::http::HeaderMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DownloadMode {
+    Proxy,
+    Redirect,
+}
+
+/// Behaviors a trusted caller switched for a single request, to canary a
+/// mode with one consumer before changing it in the configuration.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Overrides {
+    /// `x-adapter-cache: bypass` reads and fills none of the listing,
+    /// read-ahead and tail caches.
+    pub cache_bypass: bool,
+    /// `x-adapter-download-mode: proxy|redirect` streams or redirects
+    /// downloads regardless of `MAX_PROXY_SIZE`.
+    pub download_mode: Option<DownloadMode>,
+}
+
+const OVERRIDE_HEADERS: [&str; 2] = ["x-adapter-cache", "x-adapter-download-mode"];
+
+pub fn has_overrides(headers: &HeaderMap) -> bool {
+    OVERRIDE_HEADERS
+        .iter()
+        .any(|name| headers.contains_key(*name))
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .map(|value| value.to_str().unwrap_or_default())
+}
+
+pub fn parse_overrides(headers: &HeaderMap) -> Result<Overrides, String> {
+    let cache_bypass = match header(headers, "x-adapter-cache") {
+        None => false,
+        Some("bypass") => true,
+        Some(value) => return Err(format!("Invalid x-adapter-cache {}", value)),
+    };
+    let download_mode = match header(headers, "x-adapter-download-mode") {
+        None => None,
+        Some("proxy") => Some(DownloadMode::Proxy),
+        Some("redirect") => Some(DownloadMode::Redirect),
+        Some(value) => return Err(format!("Invalid x-adapter-download-mode {}", value)),
+    };
+    Ok(Overrides {
+        cache_bypass,
+        download_mode,
+    })
+}
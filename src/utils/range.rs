@@ -0,0 +1,96 @@
+/// One `first-last` spec of a `Range: bytes=` header, either bound may be
+/// open (`500-` or the suffix form `-500`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+impl ByteRange {
+    /// First and last byte addressed in an object of `total` bytes, `None`
+    /// when the range cannot be satisfied.
+    pub fn resolve(self, total: u64) -> Option<(u64, u64)> {
+        let last = total.checked_sub(1)?;
+        match (self.start, self.end) {
+            (Some(start), end) if start <= last => Some((start, end.unwrap_or(last).min(last))),
+            (None, Some(suffix)) => Some((total.saturating_sub(suffix), last)),
+            _ => None,
+        }
+    }
+
+    pub fn to_header(self) -> String {
+        format!(
+            "bytes={}-{}",
+            self.start
+                .map(|start| start.to_string())
+                .unwrap_or_default(),
+            self.end.map(|end| end.to_string()).unwrap_or_default()
+        )
+    }
+}
+
+/// Parses a `Range` header into its byte ranges, rejecting anything that is
+/// not a well-formed `bytes` range.
+pub fn parse_range(header: &str) -> Result<Vec<ByteRange>, String> {
+    let specs = header
+        .trim()
+        .strip_prefix("bytes=")
+        .ok_or(format!("Unsupported range unit in {}", header))?;
+    specs
+        .split(',')
+        .map(|spec| {
+            let (start, end) = spec
+                .trim()
+                .split_once('-')
+                .ok_or(format!("Invalid range {}", spec))?;
+            let parse = |bound: &str| {
+                if bound.is_empty() {
+                    Ok(None)
+                } else {
+                    bound
+                        .parse::<u64>()
+                        .map(Some)
+                        .map_err(|_| format!("Invalid range {}", spec))
+                }
+            };
+            let range = ByteRange {
+                start: parse(start)?,
+                end: parse(end)?,
+            };
+            match (range.start, range.end) {
+                (None, None) | (None, Some(0)) => Err(format!("Invalid range {}", spec)),
+                (Some(start), Some(end)) if start > end => Err(format!("Invalid range {}", spec)),
+                _ => Ok(range),
+            }
+        })
+        .collect()
+}
+
+/// Parses a `Content-Range: bytes first-last/total` header.
+pub fn parse_content_range(value: &str) -> Option<(u64, u64, u64)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?, total.parse().ok()?))
+}
+
+/// Assembles a `multipart/byteranges` body from `(Content-Range, data)` parts.
+pub fn multipart_byteranges(
+    boundary: &str,
+    content_type: &str,
+    parts: Vec<(String, Vec<u8>)>,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (content_range, data) in parts {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary, content_type, content_range
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
@@ -0,0 +1,154 @@
+use bytes::Bytes;
+use futures_util::Stream;
+use once_cell::sync::Lazy;
+use reqwest::Error;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// Clients idle this long with a full allowance are forgotten.
+const IDLE_CLIENT: Duration = Duration::from_secs(300);
+
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct ClientState {
+    tokens: f64,
+    refilled: Instant,
+    downloads: usize,
+}
+
+static CLIENTS: Lazy<Mutex<HashMap<String, ClientState>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn is_rate_limit_enabled() -> bool {
+    config().rate_limit_rps > 0.0 || config().max_concurrent_downloads > 0
+}
+
+fn burst() -> f64 {
+    match config().rate_limit_burst {
+        0 => config().rate_limit_rps.max(1.0),
+        burst => f64::from(burst),
+    }
+}
+
+fn with_client<T>(client: &str, action: impl FnOnce(&mut ClientState) -> T) -> T {
+    let mut clients = CLIENTS.lock().unwrap();
+    if !clients.contains_key(client) && clients.len() >= MAX_TRACKED_CLIENTS {
+        clients.retain(|_, state| state.downloads > 0 || state.refilled.elapsed() < IDLE_CLIENT);
+    }
+    let state = clients
+        .entry(client.to_string())
+        .or_insert_with(|| ClientState {
+            tokens: burst(),
+            refilled: Instant::now(),
+            downloads: 0,
+        });
+    action(state)
+}
+
+/// Takes one request from the client's allowance, which refills at
+/// `RATE_LIMIT_RPS` up to `RATE_LIMIT_BURST`.
+pub fn try_request(client: &str) -> bool {
+    let rps = config().rate_limit_rps;
+    if rps <= 0.0 {
+        return true;
+    }
+    with_client(client, |state| {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rps).min(burst());
+        state.refilled = now;
+        if state.tokens < 1.0 {
+            return false;
+        }
+        state.tokens -= 1.0;
+        true
+    })
+}
+
+/// What is left of a client's allowance, for `/whoami`.
+#[derive(Serialize, Debug)]
+pub struct RateLimitState {
+    pub client: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_remaining: Option<f64>,
+    pub downloads: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_downloads: Option<usize>,
+}
+
+/// The client's allowance as of now, without taking from it.
+pub fn rate_limit_state(client: &str) -> RateLimitState {
+    let rps = config().rate_limit_rps;
+    let max = config().max_concurrent_downloads;
+    let (tokens, downloads) = with_client(client, |state| {
+        let elapsed = state.refilled.elapsed().as_secs_f64();
+        ((state.tokens + elapsed * rps).min(burst()), state.downloads)
+    });
+    RateLimitState {
+        client: client.to_string(),
+        requests_remaining: (rps > 0.0).then_some(tokens.floor()),
+        downloads,
+        max_concurrent_downloads: (max > 0).then_some(max),
+    }
+}
+
+/// A download slot of a client, released when dropped.
+pub struct DownloadPermit {
+    client: String,
+}
+
+impl Drop for DownloadPermit {
+    fn drop(&mut self) {
+        if let Some(state) = CLIENTS.lock().unwrap().get_mut(&self.client) {
+            state.downloads = state.downloads.saturating_sub(1);
+        }
+    }
+}
+
+/// Claims one of the client's `MAX_CONCURRENT_DOWNLOADS` slots, `None` when
+/// all are taken.
+pub fn try_download(client: &str) -> Option<DownloadPermit> {
+    let max = config().max_concurrent_downloads;
+    let claimed = with_client(client, |state| {
+        if max > 0 && state.downloads >= max {
+            return false;
+        }
+        state.downloads += 1;
+        true
+    });
+    claimed.then(|| DownloadPermit {
+        client: client.to_string(),
+    })
+}
+
+/// Holds a download slot until the body has been streamed or dropped.
+pub struct PermittedStream<S> {
+    inner: S,
+    _permit: Option<DownloadPermit>,
+}
+
+impl<S> PermittedStream<S> {
+    pub fn new(inner: S, permit: Option<DownloadPermit>) -> Self {
+        PermittedStream {
+            inner,
+            _permit: permit,
+        }
+    }
+}
+
+impl<S> Stream for PermittedStream<S>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Unpin,
+{
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
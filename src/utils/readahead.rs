@@ -0,0 +1,171 @@
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use super::azure::{get_azure_object_data, GetAzureObjectResponse};
+use super::conditional::Conditions;
+use crate::config;
+
+const READ_AHEAD_TTL: Duration = Duration::from_secs(60);
+
+struct Buffer {
+    start: u64,
+    data: Bytes,
+}
+
+/// Ranged reads of one object, used to detect sequential scans.
+struct ReadState {
+    next_offset: u64,
+    total: u64,
+    content_type: String,
+    file_name: String,
+    e_tag: Option<String>,
+    last_modified: Option<String>,
+    buffer: Option<Buffer>,
+    in_flight: bool,
+    touched_at: Instant,
+}
+
+static READS: Lazy<Mutex<HashMap<String, ReadState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn is_read_ahead_enabled() -> bool {
+    config().read_ahead_bytes > 0
+}
+
+/// Serves a range from the prefetched chunk of an object when it is fully
+/// contained in it.
+pub fn buffered_range(object: &str, start: u64, end: u64) -> Option<GetAzureObjectResponse> {
+    let mut reads = READS.lock().unwrap();
+    if reads
+        .get(object)
+        .is_some_and(|state| state.touched_at.elapsed() > READ_AHEAD_TTL)
+    {
+        reads.remove(object);
+    }
+    let state = reads.get(object)?;
+    let buffer = state.buffer.as_ref()?;
+    let end = end.min(state.total.saturating_sub(1));
+    let buffered_end = buffer.start + buffer.data.len() as u64;
+    if start < buffer.start || end >= buffered_end || start > end {
+        return None;
+    }
+    let data = buffer
+        .data
+        .slice((start - buffer.start) as usize..=(end - buffer.start) as usize);
+    debug!("Serving {} bytes {}-{} from read-ahead", object, start, end);
+    Some(GetAzureObjectResponse::partial(
+        data,
+        start,
+        state.total,
+        state.content_type.clone(),
+        state.file_name.clone(),
+        state.e_tag.clone(),
+        state.last_modified.clone(),
+    ))
+}
+
+/// Details of a served range, recorded to detect sequential readers.
+#[derive(Clone)]
+pub struct RangeRead {
+    pub drive: String,
+    pub key: String,
+    pub start: u64,
+    pub end: u64,
+    pub total: u64,
+    pub content_type: String,
+    pub file_name: String,
+    pub e_tag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Records a served range and, once an object is read sequentially and the
+/// read reached the end of what is buffered, prefetches the next chunk.
+pub fn record_range_read(read: RangeRead) {
+    let object = format!("{}:{}", read.drive, read.key);
+    let prefetch_from = {
+        let mut reads = READS.lock().unwrap();
+        if !reads.contains_key(&object) && reads.len() >= config().read_ahead_max_objects {
+            let oldest = reads
+                .iter()
+                .min_by_key(|(_, state)| state.touched_at)
+                .map(|(object, _)| object.clone());
+            if let Some(oldest) = oldest {
+                reads.remove(&oldest);
+            }
+        }
+        let state = reads.entry(object.clone()).or_insert(ReadState {
+            next_offset: u64::MAX,
+            total: read.total,
+            content_type: read.content_type.clone(),
+            file_name: read.file_name.clone(),
+            e_tag: read.e_tag.clone(),
+            last_modified: read.last_modified.clone(),
+            buffer: None,
+            in_flight: false,
+            touched_at: Instant::now(),
+        });
+        // A changed object must not be served from the chunk of its old version.
+        if state.e_tag != read.e_tag {
+            state.buffer = None;
+            state.e_tag = read.e_tag.clone();
+            state.last_modified = read.last_modified.clone();
+        }
+        let sequential = state.next_offset == read.start;
+        state.next_offset = read.end + 1;
+        state.total = read.total;
+        state.touched_at = Instant::now();
+        let buffered_end = state
+            .buffer
+            .as_ref()
+            .map(|buffer| buffer.start + buffer.data.len() as u64)
+            .unwrap_or_default();
+        if sequential
+            && !state.in_flight
+            && buffered_end <= read.end + 1
+            && read.end + 1 < read.total
+        {
+            state.in_flight = true;
+            Some(read.end + 1)
+        } else {
+            None
+        }
+    };
+    let Some(from) = prefetch_from else {
+        return;
+    };
+    let to = (from + config().read_ahead_bytes - 1).min(read.total - 1);
+    tokio::spawn(async move {
+        debug!("Prefetching {} bytes {}-{}", object, from, to);
+        let data = match get_azure_object_data(
+            read.drive,
+            read.key,
+            Some(format!("bytes={}-{}", from, to)),
+            &Conditions::default(),
+        )
+        .await
+        {
+            Ok(response) if response.status_code == 206 && response.e_tag == read.e_tag => {
+                response.collect().await.ok()
+            }
+            _ => None,
+        };
+        let mut reads = READS.lock().unwrap();
+        if let Some(state) = reads.get_mut(&object) {
+            state.in_flight = false;
+            if let Some(data) = data.filter(|_| state.e_tag == read.e_tag) {
+                state.buffer = Some(Buffer {
+                    start: from,
+                    data: Bytes::from(data),
+                });
+            }
+        }
+    });
+}
+
+/// Drops what was prefetched for an object after it was overwritten or deleted.
+pub fn invalidate_read_ahead(drive: &str, key: &str) {
+    READS.lock().unwrap().remove(&format!("{}:{}", drive, key));
+}
@@ -0,0 +1,93 @@
+use rand::Rng;
+use std::future::Future;
+
+use super::buckets::is_multi_bucket;
+use super::naming::nfc;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// A new request id. It is formatted as a GUID, the form Graph expects in
+/// `client-request-id`, so Microsoft support can look up the same id.
+pub fn new_request_id() -> String {
+    let mut bytes = rand::thread_rng().gen::<[u8; 16]>();
+    // Version 4 and RFC 4122 variant bits.
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Runs a request with its id, which Graph calls and error documents made
+/// while handling it pick up.
+pub async fn with_request_id<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+/// The id of the request being handled, `None` in background tasks.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+/// A request reduced to what auth, routing and handlers need. It is derived
+/// once per request so every stage agrees on the bucket and key.
+#[derive(Clone, Debug)]
+pub struct CanonicalRequest {
+    pub method: String,
+    /// Path and query exactly as sent, which SigV4 signatures cover.
+    pub raw_path: String,
+    pub raw_query: String,
+    /// The first path segment in multi-bucket mode.
+    pub bucket: Option<String>,
+    /// Decoded key without a leading slash. A trailing slash is kept, it
+    /// addresses a folder marker.
+    pub key: String,
+}
+
+/// Decodes a path into an optional bucket and a key. Empty segments are
+/// dropped, `.` and `..` are rejected since SharePoint has no such names and
+/// keys end up in Graph URLs.
+pub fn split_path(path: &str, with_bucket: bool) -> Result<(Option<String>, String), String> {
+    let decoded =
+        urlencoding::decode(path).map_err(|_| "The URI is not valid UTF-8".to_string())?;
+    let decoded = nfc(&decoded);
+    let mut segments = decoded
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<&str>>();
+    if segments
+        .iter()
+        .any(|segment| *segment == "." || *segment == "..")
+    {
+        return Err("Relative path segments are not allowed".to_string());
+    }
+    let bucket = if with_bucket && !segments.is_empty() {
+        Some(segments.remove(0).to_string())
+    } else {
+        None
+    };
+    let mut key = segments.join("/");
+    if !key.is_empty() && decoded.ends_with('/') {
+        key.push('/');
+    }
+    Ok((bucket, key))
+}
+
+pub fn canonicalize(method: &str, path: &str, query: &str) -> Result<CanonicalRequest, String> {
+    let (bucket, key) = split_path(path, is_multi_bucket())?;
+    Ok(CanonicalRequest {
+        method: method.to_string(),
+        raw_path: path.to_string(),
+        raw_query: query.to_string(),
+        bucket,
+        key,
+    })
+}
@@ -1,18 +1,144 @@
 use crate::config;
 
-use super::azure::SharePointObjects;
-use regex::Regex;
-use std::io::Cursor;
-use xml::writer::XmlEvent;
-use xml::EmitterConfig;
+use super::azure::{Item, SharePointObjects, Traversal};
+use super::naming::{filename_regex, nfc};
+use super::request::{current_request_id, new_request_id};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use salvo::http::StatusCode;
+use salvo::prelude::{Response, Text};
+use salvo::Scribe;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::io::{self, Cursor, Write};
+use tokio::sync::mpsc;
+use tracing::debug;
+use xml::reader::XmlEvent as ReaderEvent;
+use xml::writer::{Result as EmitterResult, XmlEvent};
+use xml::{EmitterConfig, EventReader};
 
-pub fn generate_s3_list_objects_v2_response(
-    bucket: String,
-    prefix: String,
-    objects: SharePointObjects,
-    files_only: bool,
-) -> String {
-    let prefix = if prefix.is_empty() || prefix == "/" {
+/// Paging state of a listing. V1 listings reuse `continuation_token` for a
+/// marker issued by the adapter and `start_after` for a plain key marker.
+#[derive(Default)]
+pub struct ListObjectsPage {
+    pub v2: bool,
+    pub continuation_token: Option<String>,
+    pub start_after: Option<String>,
+    pub modified_after: Option<DateTime<Utc>>,
+    pub modified_before: Option<DateTime<Utc>>,
+    pub delimiter: Option<String>,
+    pub max_keys: u16,
+    /// The last segment of a prefix naming no folder: the parent folder is
+    /// listed and only children whose names start with it are kept.
+    pub name_prefix: Option<String>,
+    /// `encoding-type=url`: keys, prefixes and markers are URL-encoded.
+    pub url_encoded: bool,
+    /// Adds the SharePoint `webUrl` of each object as a `WebUrl` element.
+    pub web_urls: bool,
+}
+
+/// A stable, quoted ETag derived from an identifier: a folder id, a prefix,
+/// or the content hash of a file. Graph's folder eTags change with every
+/// child, which makes diffing tools see modified directories on each run.
+pub fn synthetic_e_tag(id: &str) -> String {
+    let digest = format!("{:x}", Sha256::digest(id.as_bytes()));
+    format!("\"{}\"", &digest[..32])
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+static TOKEN_KEY: Lazy<Vec<u8>> = Lazy::new(|| match &config().token_signing_secret {
+    Some(secret) => secret.as_bytes().to_vec(),
+    None => {
+        let mut key = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    }
+});
+
+/// The listing a continuation token was issued for.
+pub fn listing_scope(bucket: &str, prefix: &str) -> String {
+    format!("list\n{}\n{}", bucket, prefix)
+}
+
+fn token_mac(scope: &str, next_link: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(&TOKEN_KEY).expect("HMAC accepts any key length");
+    mac.update(scope.as_bytes());
+    mac.update(b"\n");
+    mac.update(next_link.as_bytes());
+    mac
+}
+
+/// Wraps a payload into a token for clients, signed together with the
+/// `scope` it may be used in.
+pub fn sign_token(payload: &str, scope: &str) -> String {
+    let signature = token_mac(scope, payload).finalize().into_bytes();
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(payload),
+        URL_SAFE_NO_PAD.encode(signature)
+    )
+}
+
+/// The payload of a token signed for `scope`.
+pub fn verify_token(token: &str, scope: &str) -> Option<String> {
+    let (payload, signature) = token.split_once('.')?;
+    let payload = String::from_utf8(URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    token_mac(scope, &payload)
+        .verify_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?)
+        .ok()?;
+    Some(payload)
+}
+
+/// Wraps a Graph `@odata.nextLink` into an opaque S3 continuation token,
+/// signed together with the listing it continues.
+pub fn encode_continuation_token(next_link: &str, scope: &str) -> String {
+    sign_token(next_link, scope)
+}
+
+/// Starts the next link of a folder listing resumed after a key, which
+/// continues after the last key of the page instead of at a Graph link.
+pub const AFTER_LINK: &str = "after:";
+
+/// Unwraps a continuation token issued for the same listing. The Graph
+/// link is fetched with the app's token, so a token forged or taken from
+/// another bucket or prefix must not be followed. Links back to Graph are
+/// still all that is accepted; recursive listings carry a serialized
+/// `Traversal` instead, whose link is checked the same way.
+pub fn decode_continuation_token(token: &str, scope: &str) -> Option<String> {
+    let next_link = verify_token(token, scope)?;
+    let is_graph_link = |link: &str| link.starts_with("https://graph.microsoft.com/");
+    if is_graph_link(&next_link) || next_link.starts_with(AFTER_LINK) {
+        return Some(next_link);
+    }
+    match serde_json::from_str::<Traversal>(&next_link) {
+        Ok(traversal) if traversal.next_link.as_deref().is_none_or(is_graph_link) => {
+            Some(next_link)
+        }
+        _ => None,
+    }
+}
+
+/// Formats a Graph timestamp as the HTTP date used by `Last-Modified`.
+pub fn http_date(timestamp: &str) -> Option<String> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|timestamp| {
+            timestamp
+                .with_timezone(&Utc)
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string()
+        })
+}
+
+/// Turns a folder path into the key prefix of its children, e.g. `/a/b` into `a/b/`.
+pub fn normalize_prefix(prefix: &str) -> String {
+    if prefix.is_empty() || prefix == "/" {
         "".to_string()
     } else {
         prefix
@@ -20,129 +146,801 @@ pub fn generate_s3_list_objects_v2_response(
             .trim_end_matches("/")
             .to_string()
             + "/"
+    }
+}
+
+const XML_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Sends what an XML writer produces to the response body in chunks. Writes
+/// fail once the client is gone, which stops the writer.
+struct ChunkWriter {
+    buffer: Vec<u8>,
+    sender: mpsc::Sender<Bytes>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= XML_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        self.sender
+            .blocking_send(chunk)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+/// Runs a synchronous XML writer on the blocking pool and streams its output
+/// as an XML response. The bounded channel keeps at most a few chunks queued.
+fn stream_xml<F>(res: &mut Response, write: F)
+where
+    F: FnOnce(&mut ChunkWriter) -> EmitterResult<()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut chunks = ChunkWriter {
+            buffer: Vec::with_capacity(XML_CHUNK_SIZE),
+            sender,
+        };
+        if let Err(err) = write(&mut chunks) {
+            debug!("Stopped streaming XML: {}", err);
+        }
+    });
+    res.headers_mut().insert(
+        "Content-Type",
+        "application/xml; charset=utf-8".parse().unwrap(),
+    );
+    res.stream(futures_util::stream::unfold(
+        receiver,
+        |mut receiver| async {
+            receiver
+                .recv()
+                .await
+                .map(|chunk| (Ok::<_, io::Error>(chunk), receiver))
+        },
+    ));
+}
+
+/// Streams a `ListBucketResult` into the response while it is written, so
+/// the document of a large listing is never held in memory as a whole.
+pub fn stream_s3_list_objects_v2_response(
+    res: &mut Response,
+    bucket: String,
+    prefix: String,
+    objects: SharePointObjects,
+    files_only: bool,
+    page: ListObjectsPage,
+) {
+    stream_xml(res, move |chunks| {
+        write_s3_list_objects_v2_response(chunks, bucket, prefix, objects, files_only, page)
+    });
+}
+
+/// A CommonPrefixes or Contents element of a listing; an object without an
+/// item is the directory marker of the listed folder.
+enum ListEntry<'a> {
+    Prefix { key: String, e_tag: String },
+    Object { key: String, item: Option<&'a Item> },
+}
+
+impl ListEntry<'_> {
+    fn key(&self) -> &str {
+        match self {
+            ListEntry::Prefix { key, .. } | ListEntry::Object { key, .. } => key,
+        }
+    }
+}
+
+/// URL-encodes a key for `encoding-type=url` listings, leaving `/` as is.
+fn encode_key(key: &str, url_encoded: bool) -> String {
+    if !url_encoded {
+        return key.to_string();
+    }
+    key.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+fn write_s3_list_objects_v2_response(
+    output: impl Write,
+    bucket: String,
+    prefix: String,
+    mut objects: SharePointObjects,
+    files_only: bool,
+    page: ListObjectsPage,
+) -> EmitterResult<()> {
+    let prefix = normalize_prefix(&prefix);
+    let scope = listing_scope(&bucket, &prefix);
+    if let Some(name_prefix) = &page.name_prefix {
+        objects
+            .items
+            .retain(|item| item.name.starts_with(name_prefix.as_str()));
+    }
+    let regex = filename_regex();
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(output);
+
+    writer.write(
+        XmlEvent::start_element("ListBucketResult")
+            .default_ns("http://s3.amazonaws.com/doc/2006-03-01/"),
+    )?;
+
+    writer.write(XmlEvent::start_element("Name"))?;
+    writer.write(XmlEvent::characters(&bucket))?;
+    writer.write(XmlEvent::end_element())?; // Name
+
+    // A listing of the whole bucket has an empty prefix, a partial prefix
+    // is echoed as requested.
+    writer.write(XmlEvent::start_element("Prefix"))?;
+    if let Some(name_prefix) = &page.name_prefix {
+        writer.write(XmlEvent::characters(&encode_key(
+            &format!("{}{}", &prefix, name_prefix),
+            page.url_encoded,
+        )))?;
+    } else if !prefix.is_empty() {
+        writer.write(XmlEvent::characters(&encode_key(
+            &format!("{}/", &prefix.trim_end_matches("/")),
+            page.url_encoded,
+        )))?;
+    }
+    writer.write(XmlEvent::end_element())?; // Prefix
+
+    // StartAfter only applies to the first page of a V2 listing.
+    let start_after = page
+        .start_after
+        .clone()
+        .filter(|_| page.continuation_token.is_none());
+    let encode = |key: &str| encode_key(key, page.url_encoded);
+    let is_after = |key: &str| {
+        start_after
+            .as_ref()
+            .is_none_or(|start_after| key > start_after.as_str())
     };
-    let filename_pattern = config().filename_pattern.clone();
-    let regex = Regex::new(&filename_pattern).unwrap();
+    let marker_key = format!("{}/", &prefix.trim_end_matches("/"));
+    // The listed folder is the parent of its direct children; without any,
+    // the marker falls back to an id derived from bucket and prefix.
+    let marker_e_tag = synthetic_e_tag(
+        &objects
+            .items
+            .iter()
+            .filter(|item| !item.name.contains('/'))
+            .find_map(|item| item.parent_reference.as_ref()?.id.clone())
+            .unwrap_or(format!("{}/{}", bucket, marker_key)),
+    );
+    // The parent of a partial prefix does not match it, and later pages
+    // continue after it.
+    let emit_marker = page.name_prefix.is_none()
+        && page.continuation_token.is_none()
+        && (!objects.items.is_empty() || config().empty_folder_exists)
+        && is_after(&marker_key);
+    let folders = objects
+        .items
+        .iter()
+        .filter(|item| !files_only && item.folder.is_some())
+        .filter(|item| is_after(&format!("{}{}/", &prefix, &item.name)))
+        .collect::<Vec<_>>();
+    let files = objects
+        .items
+        .iter()
+        .filter(|item| item.file.is_some() && regex.is_match(&item.name.to_lowercase()))
+        .filter(|item| is_after(&format!("{}{}", &prefix, &item.name)))
+        .filter(|item| {
+            // Graph cannot $filter drive children by date, so the range is
+            // applied here; items without a timestamp never match a range.
+            if page.modified_after.is_none() && page.modified_before.is_none() {
+                return true;
+            }
+            item.last_modified_date_time
+                .as_deref()
+                .and_then(|modified| DateTime::parse_from_rfc3339(modified).ok())
+                .is_some_and(|modified| {
+                    page.modified_after.is_none_or(|after| modified > after)
+                        && page.modified_before.is_none_or(|before| modified < before)
+                })
+        })
+        .collect::<Vec<_>>();
+    // Keys containing a delimiter other than `/` after the prefix are rolled
+    // up into CommonPrefixes, `/` is already grouped by listing one folder.
+    let delimiter = page
+        .delimiter
+        .clone()
+        .filter(|delimiter| !delimiter.is_empty() && delimiter != "/");
+    let mut grouped = BTreeSet::new();
+    let files = files
+        .into_iter()
+        .filter(|item| {
+            let Some(delimiter) = &delimiter else {
+                return true;
+            };
+            match item.name.find(delimiter.as_str()) {
+                Some(index) => {
+                    grouped.insert(format!(
+                        "{}{}",
+                        &prefix,
+                        &item.name[..index + delimiter.len()]
+                    ));
+                    false
+                }
+                None => true,
+            }
+        })
+        .collect::<Vec<_>>();
+    let key_count = folders.len() + grouped.len() + files.len() + usize::from(emit_marker);
+
+    writer.write(XmlEvent::start_element("IsTruncated"))?;
+    writer.write(XmlEvent::characters(
+        &objects.next_link.is_some().to_string(),
+    ))?;
+    writer.write(XmlEvent::end_element())?; // IsTruncated
+
+    writer.write(XmlEvent::start_element("MaxKeys"))?;
+    writer.write(XmlEvent::characters(&page.max_keys.to_string()))?;
+    writer.write(XmlEvent::end_element())?; // MaxKeys
+
+    if let Some(delimiter) = &page.delimiter {
+        writer.write(XmlEvent::start_element("Delimiter"))?;
+        writer.write(XmlEvent::characters(&encode(delimiter)))?;
+        writer.write(XmlEvent::end_element())?; // Delimiter
+    }
+
+    if page.url_encoded {
+        writer.write(XmlEvent::start_element("EncodingType"))?;
+        writer.write(XmlEvent::characters("url"))?;
+        writer.write(XmlEvent::end_element())?; // EncodingType
+    }
+
+    if page.v2 {
+        writer.write(XmlEvent::start_element("KeyCount"))?;
+        writer.write(XmlEvent::characters(&key_count.to_string()))?;
+        writer.write(XmlEvent::end_element())?; // KeyCount
+
+        if let Some(continuation_token) = &page.continuation_token {
+            writer.write(XmlEvent::start_element("ContinuationToken"))?;
+            writer.write(XmlEvent::characters(continuation_token))?;
+            writer.write(XmlEvent::end_element())?; // ContinuationToken
+        }
+
+        if let Some(next_link) = &objects.next_link {
+            writer.write(XmlEvent::start_element("NextContinuationToken"))?;
+            writer.write(XmlEvent::characters(&encode_continuation_token(
+                next_link, &scope,
+            )))?;
+            writer.write(XmlEvent::end_element())?; // NextContinuationToken
+        }
+
+        if let Some(start_after) = &page.start_after {
+            writer.write(XmlEvent::start_element("StartAfter"))?;
+            writer.write(XmlEvent::characters(&encode(start_after)))?;
+            writer.write(XmlEvent::end_element())?; // StartAfter
+        }
+    } else {
+        // Adapter markers are URL-safe already.
+        writer.write(XmlEvent::start_element("Marker"))?;
+        writer.write(XmlEvent::characters(&match &page.continuation_token {
+            Some(marker) => marker.clone(),
+            None => encode(page.start_after.as_deref().unwrap_or_default()),
+        }))?;
+        writer.write(XmlEvent::end_element())?; // Marker
+
+        if let Some(next_link) = &objects.next_link {
+            writer.write(XmlEvent::start_element("NextMarker"))?;
+            writer.write(XmlEvent::characters(&encode_continuation_token(
+                next_link, &scope,
+            )))?;
+            writer.write(XmlEvent::end_element())?; // NextMarker
+        }
+    }
+
+    // S3 lists keys in UTF-8 binary order, CommonPrefixes in between the
+    // Contents they sort with, so clients resuming after a key see no gaps.
+    let mut entries = folders
+        .into_iter()
+        .map(|folder| ListEntry::Prefix {
+            key: format!("{}{}/", &prefix, &folder.name),
+            e_tag: synthetic_e_tag(&folder.id),
+        })
+        .chain(grouped.into_iter().map(|common_prefix| ListEntry::Prefix {
+            e_tag: synthetic_e_tag(&format!("{}/{}", bucket, common_prefix)),
+            key: common_prefix,
+        }))
+        .chain(files.into_iter().map(|item| ListEntry::Object {
+            key: format!("{}{}", &prefix, &item.name),
+            item: Some(item),
+        }))
+        .collect::<Vec<ListEntry>>();
+    // Empty folders only get a directory marker when configured to exist,
+    // mirroring the HEAD behavior for trailing-slash keys.
+    if emit_marker {
+        entries.push(ListEntry::Object {
+            key: marker_key,
+            item: None,
+        });
+    }
+    entries.sort_by(|a, b| a.key().cmp(b.key()));
+
+    for entry in entries {
+        match entry {
+            ListEntry::Prefix { key, e_tag } => {
+                writer.write(XmlEvent::start_element("CommonPrefixes"))?;
+                writer.write(XmlEvent::start_element("Prefix"))?;
+                writer.write(XmlEvent::characters(&encode(&key)))?;
+                writer.write(XmlEvent::end_element())?; // Prefix
+                writer.write(XmlEvent::start_element("ETag"))?;
+                writer.write(XmlEvent::characters(&e_tag))?;
+                writer.write(XmlEvent::end_element())?; // ETag
+                writer.write(XmlEvent::end_element())?; // CommonPrefixes
+            }
+            ListEntry::Object { key, item: None } => {
+                writer.write(XmlEvent::start_element("Contents"))?;
+
+                writer.write(XmlEvent::start_element("Key"))?;
+                writer.write(XmlEvent::characters(&encode(&key)))?;
+                writer.write(XmlEvent::end_element())?; // Key
+
+                writer.write(XmlEvent::start_element("Size"))?;
+                writer.write(XmlEvent::characters("0"))?;
+                writer.write(XmlEvent::end_element())?; // Size
+
+                writer.write(XmlEvent::start_element("ETag"))?;
+                writer.write(XmlEvent::characters(&marker_e_tag))?;
+                writer.write(XmlEvent::end_element())?; // ETag
+
+                writer.write(XmlEvent::end_element())?; // Contents
+            }
+            ListEntry::Object {
+                key,
+                item: Some(item),
+            } => {
+                writer.write(XmlEvent::start_element("Contents"))?;
+
+                writer.write(XmlEvent::start_element("Key"))?;
+                writer.write(XmlEvent::characters(&encode(&key)))?;
+                writer.write(XmlEvent::end_element())?; // Key
+
+                writer.write(XmlEvent::start_element("Size"))?;
+                writer.write(XmlEvent::characters(&item.size.unwrap_or(0).to_string()))?;
+                writer.write(XmlEvent::end_element())?; // Size
+
+                writer.write(XmlEvent::start_element("LastModified"))?;
+                writer.write(XmlEvent::characters(
+                    &item
+                        .last_modified_date_time
+                        .clone()
+                        .unwrap_or("".to_string()),
+                ))?;
+                writer.write(XmlEvent::end_element())?; // LastModified
+
+                writer.write(XmlEvent::start_element("ETag"))?;
+                writer.write(XmlEvent::characters(
+                    &item.object_e_tag().unwrap_or_default(),
+                ))?;
+                writer.write(XmlEvent::end_element())?; // ETag
+
+                writer.write(XmlEvent::start_element("StorageClass"))?;
+                writer.write(XmlEvent::characters("STANDARD"))?;
+                writer.write(XmlEvent::end_element())?; // StorageClass
+
+                if page.web_urls {
+                    writer.write(XmlEvent::start_element("WebUrl"))?;
+                    writer.write(XmlEvent::characters(&item.web_url))?;
+                    writer.write(XmlEvent::end_element())?; // WebUrl
+                }
+
+                writer.write(XmlEvent::end_element())?; // Contents
+            }
+        }
+    }
+
+    writer.write(XmlEvent::end_element())?; // ListBucketResult
+
+    writer.into_inner().flush()?;
+    Ok(())
+}
+
+pub fn generate_s3_error_response(code: &str, message: &str, details: &[(&str, String)]) -> String {
     let mut buffer = Cursor::new(Vec::new());
     let mut writer = EmitterConfig::new()
         .perform_indent(true)
         .create_writer(&mut buffer);
 
-    writer
-        .write(XmlEvent::start_element("ListBucketResult"))
-        .unwrap();
+    writer.write(XmlEvent::start_element("Error")).unwrap();
 
-    writer.write(XmlEvent::start_element("Name")).unwrap();
-    writer.write(XmlEvent::characters(&bucket)).unwrap();
-    writer.write(XmlEvent::end_element()).unwrap(); // Name
+    writer.write(XmlEvent::start_element("Code")).unwrap();
+    writer.write(XmlEvent::characters(code)).unwrap();
+    writer.write(XmlEvent::end_element()).unwrap(); // Code
 
-    writer.write(XmlEvent::start_element("Prefix")).unwrap();
-    writer
-        .write(XmlEvent::characters(&format!(
-            "{}/",
-            &prefix.trim_end_matches("/")
-        )))
-        .unwrap();
-    writer.write(XmlEvent::end_element()).unwrap(); // Prefix
+    writer.write(XmlEvent::start_element("Message")).unwrap();
+    writer.write(XmlEvent::characters(message)).unwrap();
+    writer.write(XmlEvent::end_element()).unwrap(); // Message
 
-    writer
-        .write(XmlEvent::start_element("IsTruncated"))
-        .unwrap();
-    writer.write(XmlEvent::characters("false")).unwrap();
-    writer.write(XmlEvent::end_element()).unwrap(); // IsTruncated
+    for (name, value) in details {
+        writer.write(XmlEvent::start_element(*name)).unwrap();
+        writer.write(XmlEvent::characters(value)).unwrap();
+        writer.write(XmlEvent::end_element()).unwrap();
+    }
+
+    writer.write(XmlEvent::end_element()).unwrap(); // Error
+
+    String::from_utf8(buffer.into_inner()).unwrap()
+}
+
+/// An S3 error rendered as the standard `<Error>` document.
+#[derive(Debug)]
+pub struct S3Error {
+    pub status_code: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    pub resource: Option<String>,
+}
+
+impl S3Error {
+    pub fn new(status_code: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        S3Error {
+            status_code,
+            code,
+            message: message.into(),
+            resource: None,
+        }
+    }
 
-    writer.write(XmlEvent::start_element("MaxKeys")).unwrap();
-    writer.write(XmlEvent::characters("1000")).unwrap();
-    writer.write(XmlEvent::end_element()).unwrap(); // MaxKeys
+    pub fn with_resource(mut self, resource: impl Into<String>) -> Self {
+        self.resource = Some(resource.into());
+        self
+    }
 
-    writer.write(XmlEvent::start_element("Marker")).unwrap();
-    writer.write(XmlEvent::characters("")).unwrap();
-    writer.write(XmlEvent::end_element()).unwrap(); // Marker
+    pub fn no_such_key() -> Self {
+        S3Error::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchKey",
+            "The specified key does not exist.",
+        )
+    }
+
+    pub fn access_denied() -> Self {
+        S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", "Access Denied")
+    }
+
+    pub fn invalid_argument(message: impl Into<String>) -> Self {
+        S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+    }
+
+    pub fn internal_error(message: impl Into<String>) -> Self {
+        S3Error::new(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", message)
+    }
 
-    if !files_only {
-        for folder in objects.items.iter().filter(|item| item.folder.is_some()) {
-            writer
-                .write(XmlEvent::start_element("CommonPrefixes"))
-                .unwrap();
-            writer.write(XmlEvent::start_element("Prefix")).unwrap();
-            writer
-                .write(XmlEvent::characters(&format!(
-                    "{}{}/",
-                    &prefix, &folder.name
-                )))
-                .unwrap();
-            writer.write(XmlEvent::end_element()).unwrap(); // Prefix
-            writer.write(XmlEvent::end_element()).unwrap(); // CommonPrefixes
+    pub fn slow_down(message: impl Into<String>) -> Self {
+        S3Error::new(StatusCode::SERVICE_UNAVAILABLE, "SlowDown", message)
+    }
+
+    /// Maps the status of a failed Graph call to the matching S3 error.
+    pub fn from_graph_status(status_code: u16) -> Self {
+        match status_code {
+            404 => S3Error::no_such_key(),
+            401 | 403 => S3Error::access_denied(),
+            429 | 502 | 503 | 504 => S3Error::slow_down("Please reduce your request rate."),
+            _ => S3Error::internal_error("We encountered an internal error. Please try again."),
         }
     }
+}
 
-    writer.write(XmlEvent::start_element("Contents")).unwrap();
+impl From<reqwest::Error> for S3Error {
+    fn from(err: reqwest::Error) -> Self {
+        match err.status() {
+            Some(status) => S3Error::from_graph_status(status.as_u16()),
+            None if err.is_timeout() => S3Error::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "GatewayTimeout",
+                "SharePoint did not answer in time.",
+            ),
+            None if err.is_connect() => S3Error::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailable",
+                err.to_string(),
+            ),
+            None => S3Error::internal_error(err.to_string()),
+        }
+    }
+}
+
+impl Scribe for S3Error {
+    fn render(self, res: &mut Response) {
+        let request_id = current_request_id().unwrap_or_else(new_request_id);
+        let mut details = Vec::new();
+        if let Some(resource) = self.resource {
+            details.push(("Resource", resource));
+        }
+        details.push(("RequestId", request_id.clone()));
+        res.headers_mut()
+            .insert("x-amz-request-id", request_id.parse().unwrap());
+        res.status_code(self.status_code)
+            .render(Text::Xml(generate_s3_error_response(
+                self.code,
+                &self.message,
+                &details,
+            )));
+    }
+}
+
+/// Keys and quiet flag of a DeleteObjects request body.
+pub struct DeleteRequest {
+    pub keys: Vec<String>,
+    pub quiet: bool,
+}
+
+pub struct DeleteError {
+    pub key: String,
+    pub code: String,
+    pub message: String,
+}
+
+pub fn parse_s3_delete_request(body: &[u8]) -> Result<DeleteRequest, String> {
+    let mut request = DeleteRequest {
+        keys: Vec::new(),
+        quiet: false,
+    };
+    let mut path = Vec::new();
+    for event in EventReader::new(body) {
+        match event.map_err(|err| err.to_string())? {
+            ReaderEvent::StartElement { name, .. } => path.push(name.local_name),
+            ReaderEvent::EndElement { .. } => {
+                path.pop();
+            }
+            ReaderEvent::Characters(text) => {
+                match path.iter().map(String::as_str).collect::<Vec<&str>>()[..] {
+                    ["Delete", "Object", "Key"] => request.keys.push(nfc(&text)),
+                    ["Delete", "Quiet"] => request.quiet = text.trim() == "true",
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(request)
+}
+
+pub fn generate_s3_delete_result_response(
+    deleted: Vec<String>,
+    errors: Vec<DeleteError>,
+    quiet: bool,
+) -> String {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(&mut buffer);
 
-    writer.write(XmlEvent::start_element("Key")).unwrap();
     writer
-        .write(XmlEvent::characters(&format!(
-            "{}/",
-            &prefix.trim_end_matches("/")
-        )))
+        .write(XmlEvent::start_element("DeleteResult"))
         .unwrap();
-    writer.write(XmlEvent::end_element()).unwrap(); // Key
 
-    writer.write(XmlEvent::start_element("Size")).unwrap();
-    writer.write(XmlEvent::characters("0")).unwrap();
-    writer.write(XmlEvent::end_element()).unwrap(); // Size
+    // Quiet mode only reports the keys that failed.
+    if !quiet {
+        for key in deleted {
+            writer.write(XmlEvent::start_element("Deleted")).unwrap();
+            writer.write(XmlEvent::start_element("Key")).unwrap();
+            writer.write(XmlEvent::characters(&key)).unwrap();
+            writer.write(XmlEvent::end_element()).unwrap(); // Key
+            writer.write(XmlEvent::end_element()).unwrap(); // Deleted
+        }
+    }
 
-    writer.write(XmlEvent::end_element()).unwrap(); // Contents
+    for error in errors {
+        writer.write(XmlEvent::start_element("Error")).unwrap();
 
-    for item in objects
-        .items
-        .iter()
-        .filter(|item| item.file.is_some() && regex.is_match(&item.name.to_lowercase()))
-    {
-        writer.write(XmlEvent::start_element("Contents")).unwrap();
+        writer.write(XmlEvent::start_element("Key")).unwrap();
+        writer.write(XmlEvent::characters(&error.key)).unwrap();
+        writer.write(XmlEvent::end_element()).unwrap(); // Key
+
+        writer.write(XmlEvent::start_element("Code")).unwrap();
+        writer.write(XmlEvent::characters(&error.code)).unwrap();
+        writer.write(XmlEvent::end_element()).unwrap(); // Code
+
+        writer.write(XmlEvent::start_element("Message")).unwrap();
+        writer.write(XmlEvent::characters(&error.message)).unwrap();
+        writer.write(XmlEvent::end_element()).unwrap(); // Message
+
+        writer.write(XmlEvent::end_element()).unwrap(); // Error
+    }
+
+    writer.write(XmlEvent::end_element()).unwrap(); // DeleteResult
+
+    String::from_utf8(buffer.into_inner()).unwrap()
+}
+
+/// Parses the `Tagging` body of PutObjectTagging into key-value pairs.
+pub fn parse_s3_tagging_request(body: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let mut tags: Vec<(String, String)> = Vec::new();
+    let mut path = Vec::new();
+    for event in EventReader::new(body) {
+        match event.map_err(|err| err.to_string())? {
+            ReaderEvent::StartElement { name, .. } => {
+                if name.local_name == "Tag" {
+                    tags.push((String::new(), String::new()));
+                }
+                path.push(name.local_name);
+            }
+            ReaderEvent::EndElement { .. } => {
+                path.pop();
+            }
+            ReaderEvent::Characters(text) => {
+                let Some(tag) = tags.last_mut() else {
+                    continue;
+                };
+                match path.iter().map(String::as_str).collect::<Vec<&str>>()[..] {
+                    ["Tagging", "TagSet", "Tag", "Key"] => tag.0 = text,
+                    ["Tagging", "TagSet", "Tag", "Value"] => tag.1 = text,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(tags)
+}
+
+/// Renders the `Tagging` result of GetObjectTagging.
+pub fn generate_s3_tagging_response(tags: Vec<(String, String)>) -> String {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(&mut buffer);
+
+    writer
+        .write(
+            XmlEvent::start_element("Tagging")
+                .default_ns("http://s3.amazonaws.com/doc/2006-03-01/"),
+        )
+        .unwrap();
+
+    writer.write(XmlEvent::start_element("TagSet")).unwrap();
+    for (key, value) in tags {
+        writer.write(XmlEvent::start_element("Tag")).unwrap();
 
         writer.write(XmlEvent::start_element("Key")).unwrap();
-        writer
-            .write(XmlEvent::characters(&format!("{}{}", &prefix, &item.name)))
-            .unwrap();
+        writer.write(XmlEvent::characters(&key)).unwrap();
         writer.write(XmlEvent::end_element()).unwrap(); // Key
 
-        writer.write(XmlEvent::start_element("Size")).unwrap();
-        writer
-            .write(XmlEvent::characters(&item.size.unwrap_or(0).to_string()))
-            .unwrap();
-        writer.write(XmlEvent::end_element()).unwrap(); // Size
+        writer.write(XmlEvent::start_element("Value")).unwrap();
+        writer.write(XmlEvent::characters(&value)).unwrap();
+        writer.write(XmlEvent::end_element()).unwrap(); // Value
 
-        writer
-            .write(XmlEvent::start_element("LastModified"))
-            .unwrap();
-        writer
-            .write(XmlEvent::characters(
-                &item
-                    .last_modified_date_time
-                    .clone()
-                    .unwrap_or("".to_string()),
-            ))
-            .unwrap();
-        writer.write(XmlEvent::end_element()).unwrap(); // LastModified
+        writer.write(XmlEvent::end_element()).unwrap(); // Tag
+    }
+    writer.write(XmlEvent::end_element()).unwrap(); // TagSet
+
+    writer.write(XmlEvent::end_element()).unwrap(); // Tagging
+
+    String::from_utf8(buffer.into_inner()).unwrap()
+}
+
+/// The attributes GetObjectAttributes was asked for; `None` and empty ones
+/// are left out.
+#[derive(Default)]
+pub struct ObjectAttributes {
+    pub e_tag: Option<String>,
+    /// Checksum element names such as `ChecksumSHA256`, with base64 values.
+    pub checksums: Vec<(&'static str, String)>,
+    pub storage_class: Option<&'static str>,
+    pub object_size: Option<u64>,
+}
+
+pub fn generate_s3_object_attributes_response(attributes: ObjectAttributes) -> String {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(&mut buffer);
+
+    writer
+        .write(
+            XmlEvent::start_element("GetObjectAttributesResponse")
+                .default_ns("http://s3.amazonaws.com/doc/2006-03-01/"),
+        )
+        .unwrap();
 
+    if let Some(e_tag) = attributes.e_tag {
         writer.write(XmlEvent::start_element("ETag")).unwrap();
-        writer
-            .write(XmlEvent::characters(
-                &item.e_tag.clone().unwrap_or("".to_string()),
-            ))
-            .unwrap();
+        writer.write(XmlEvent::characters(&e_tag)).unwrap();
         writer.write(XmlEvent::end_element()).unwrap(); // ETag
+    }
+
+    if !attributes.checksums.is_empty() {
+        writer.write(XmlEvent::start_element("Checksum")).unwrap();
+        for (name, value) in attributes.checksums {
+            writer.write(XmlEvent::start_element(name)).unwrap();
+            writer.write(XmlEvent::characters(&value)).unwrap();
+            writer.write(XmlEvent::end_element()).unwrap();
+        }
+        writer.write(XmlEvent::end_element()).unwrap(); // Checksum
+    }
 
+    if let Some(storage_class) = attributes.storage_class {
         writer
             .write(XmlEvent::start_element("StorageClass"))
             .unwrap();
-        writer.write(XmlEvent::characters("STANDARD")).unwrap();
+        writer.write(XmlEvent::characters(storage_class)).unwrap();
         writer.write(XmlEvent::end_element()).unwrap(); // StorageClass
+    }
+
+    if let Some(object_size) = attributes.object_size {
+        writer.write(XmlEvent::start_element("ObjectSize")).unwrap();
+        writer
+            .write(XmlEvent::characters(&object_size.to_string()))
+            .unwrap();
+        writer.write(XmlEvent::end_element()).unwrap(); // ObjectSize
+    }
+
+    writer.write(XmlEvent::end_element()).unwrap(); // GetObjectAttributesResponse
 
-        writer.write(XmlEvent::end_element()).unwrap(); // Contents
+    String::from_utf8(buffer.into_inner()).unwrap()
+}
+
+/// Renders `ListAllMyBucketsResult` from bucket names and creation dates.
+pub fn generate_s3_list_buckets_response(buckets: Vec<(String, String)>) -> String {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(&mut buffer);
+
+    writer
+        .write(
+            XmlEvent::start_element("ListAllMyBucketsResult")
+                .default_ns("http://s3.amazonaws.com/doc/2006-03-01/"),
+        )
+        .unwrap();
+
+    writer.write(XmlEvent::start_element("Buckets")).unwrap();
+    for (name, creation_date) in buckets {
+        writer.write(XmlEvent::start_element("Bucket")).unwrap();
+
+        writer.write(XmlEvent::start_element("Name")).unwrap();
+        writer.write(XmlEvent::characters(&name)).unwrap();
+        writer.write(XmlEvent::end_element()).unwrap(); // Name
+
+        writer
+            .write(XmlEvent::start_element("CreationDate"))
+            .unwrap();
+        writer.write(XmlEvent::characters(&creation_date)).unwrap();
+        writer.write(XmlEvent::end_element()).unwrap(); // CreationDate
+
+        writer.write(XmlEvent::end_element()).unwrap(); // Bucket
     }
+    writer.write(XmlEvent::end_element()).unwrap(); // Buckets
+
+    writer.write(XmlEvent::end_element()).unwrap(); // ListAllMyBucketsResult
+
+    String::from_utf8(buffer.into_inner()).unwrap()
+}
+
+pub fn generate_s3_copy_object_result_response(e_tag: &str, last_modified: &str) -> String {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(&mut buffer);
+
+    writer
+        .write(XmlEvent::start_element("CopyObjectResult"))
+        .unwrap();
+
+    writer.write(XmlEvent::start_element("ETag")).unwrap();
+    writer.write(XmlEvent::characters(e_tag)).unwrap();
+    writer.write(XmlEvent::end_element()).unwrap(); // ETag
+
+    writer
+        .write(XmlEvent::start_element("LastModified"))
+        .unwrap();
+    writer.write(XmlEvent::characters(last_modified)).unwrap();
+    writer.write(XmlEvent::end_element()).unwrap(); // LastModified
 
-    writer.write(XmlEvent::end_element()).unwrap(); // ListBucketResult
+    writer.write(XmlEvent::end_element()).unwrap(); // CopyObjectResult
 
     String::from_utf8(buffer.into_inner()).unwrap()
 }
@@ -0,0 +1,182 @@
+use futures_util::StreamExt;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use std::future::Future;
+use std::time::Instant;
+
+use super::azure::{
+    delete_azure_object, get_azure_object_data, head_azure_object, list_azure_objects,
+    put_azure_object,
+};
+use super::buckets::{buckets, Bucket};
+use super::cache::invalidate_listings;
+use super::conditional::Conditions;
+use super::naming::filename_regex;
+use crate::config;
+
+const SCRATCH_CONTENT: &[u8] = b"s3-sharepoint-adapter self-test\n";
+
+#[derive(Serialize, Debug)]
+pub struct Step {
+    name: &'static str,
+    passed: bool,
+    duration_ms: u128,
+    detail: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BucketReport {
+    bucket: String,
+    passed: bool,
+    steps: Vec<Step>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub passed: bool,
+    buckets: Vec<BucketReport>,
+}
+
+/// Runs one check, recording its outcome and duration. The value of a
+/// passed check feeds the checks after it.
+async fn run_step<T>(
+    steps: &mut Vec<Step>,
+    name: &'static str,
+    check: impl Future<Output = Result<(T, String), String>>,
+) -> Option<T> {
+    let started = Instant::now();
+    let result = check.await;
+    let (passed, detail) = match &result {
+        Ok((_, detail)) => (true, detail.clone()),
+        Err(err) => (false, err.clone()),
+    };
+    steps.push(Step {
+        name,
+        passed,
+        duration_ms: started.elapsed().as_millis(),
+        detail,
+    });
+    result.ok().map(|(value, _)| value)
+}
+
+/// Reads through the bucket: lists the library root, then heads and reads
+/// the first byte of the first file allowed by `FILENAME_PATTERN`.
+async fn read_checks(bucket: &Bucket, steps: &mut Vec<Step>) {
+    let drive = bucket.drive_url();
+    let regex = filename_regex();
+    let listed = run_step(steps, "list", async {
+        let objects = list_azure_objects(drive.clone(), String::new(), 100, None, None)
+            .await
+            .map_err(|err| err.to_string())?;
+        let file = objects
+            .items
+            .iter()
+            .find(|item| item.file.is_some() && regex.is_match(&item.name))
+            .map(|item| item.name.clone());
+        let detail = match &file {
+            Some(_) => format!("{} items listed", objects.items.len()),
+            None => format!(
+                "{} items listed, none matches FILENAME_PATTERN to read",
+                objects.items.len()
+            ),
+        };
+        Ok((file, detail))
+    })
+    .await;
+    let Some(Some(key)) = listed else {
+        return;
+    };
+    run_step(steps, "head", async {
+        let head = head_azure_object(drive.clone(), key.clone())
+            .await
+            .map_err(|err| err.to_string())?;
+        match head.status_code {
+            200 => Ok(((), format!("{} is {} bytes", key, head.size))),
+            status => Err(format!("{} answered {}", key, status)),
+        }
+    })
+    .await;
+    run_step(steps, "get", async {
+        let mut object = get_azure_object_data(
+            drive.clone(),
+            key.clone(),
+            Some("bytes=0-0".to_string()),
+            &Conditions::default(),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        if !matches!(object.status_code, 200 | 206) {
+            return Err(format!("{} answered {}", key, object.status_code));
+        }
+        match object.stream.next().await {
+            Some(Err(err)) => Err(err.to_string()),
+            _ => Ok(((), format!("Read {}", key))),
+        }
+    })
+    .await;
+}
+
+/// Writes and deletes an object below `SELFTEST_PREFIX`.
+async fn write_checks(bucket: &Bucket, prefix: &str, steps: &mut Vec<Step>) {
+    let drive = bucket.drive_url();
+    let suffix = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect::<String>();
+    let key = format!("{}/selftest-{}.txt", prefix.trim_matches('/'), suffix);
+    let put = run_step(steps, "put", async {
+        let item = put_azure_object(
+            drive.clone(),
+            key.clone(),
+            "text/plain".to_string(),
+            SCRATCH_CONTENT.to_vec(),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        invalidate_listings(&drive, &key);
+        match item.size {
+            Some(size) if size == SCRATCH_CONTENT.len() as u64 => {
+                Ok(((), format!("Wrote {} bytes to {}", size, key)))
+            }
+            size => Err(format!("{} was stored with {:?} bytes", key, size)),
+        }
+    })
+    .await;
+    if put.is_none() {
+        // A partially stored object is still removed.
+        let _ = delete_azure_object(drive.clone(), key.clone()).await;
+        return;
+    }
+    run_step(steps, "delete", async {
+        delete_azure_object(drive.clone(), key.clone())
+            .await
+            .map_err(|err| err.to_string())?;
+        invalidate_listings(&drive, &key);
+        Ok(((), format!("Deleted {}", key)))
+    })
+    .await;
+}
+
+/// Exercises every bucket against the live backend: reads always, writes
+/// when `SELFTEST_PREFIX` names a scratch folder.
+pub async fn run_selftest() -> Report {
+    let mut reports = Vec::new();
+    for bucket in buckets() {
+        let mut steps = Vec::new();
+        read_checks(&bucket, &mut steps).await;
+        if let Some(prefix) = &config().selftest_prefix {
+            write_checks(&bucket, prefix, &mut steps).await;
+        }
+        reports.push(BucketReport {
+            bucket: bucket.name,
+            passed: steps.iter().all(|step| step.passed),
+            steps,
+        });
+    }
+    Report {
+        passed: reports.iter().all(|report| report.passed),
+        buckets: reports,
+    }
+}
@@ -0,0 +1,154 @@
+use bytes::Bytes;
+use futures_util::Stream;
+use reqwest::{Client, Error, Method};
+use sha2::{Digest, Sha256};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::{debug, warn};
+
+use crate::config;
+
+/// What the adapter served for a read, compared against the shadow backend.
+pub struct ShadowRead {
+    pub method: Method,
+    pub key: String,
+    pub status_code: u16,
+    pub size: u64,
+    pub content_hash: Option<String>,
+}
+
+pub fn is_shadow_enabled() -> bool {
+    config().shadow_endpoint.is_some()
+}
+
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Hashes a streamed body as it passes through and mirrors the read once the
+/// last chunk was sent, so streaming does not need to buffer for the shadow.
+pub struct ShadowedStream<S> {
+    inner: S,
+    key: String,
+    hasher: Sha256,
+    size: u64,
+    done: bool,
+}
+
+impl<S> ShadowedStream<S> {
+    pub fn new(inner: S, key: String) -> Self {
+        ShadowedStream {
+            inner,
+            key,
+            hasher: Sha256::new(),
+            size: 0,
+            done: false,
+        }
+    }
+}
+
+impl<S> Stream for ShadowedStream<S>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Unpin,
+{
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.size += chunk.len() as u64;
+                self.hasher.update(chunk);
+            }
+            Poll::Ready(Some(Err(_))) => self.done = true,
+            Poll::Ready(None) if !self.done => {
+                self.done = true;
+                shadow_read(ShadowRead {
+                    method: Method::GET,
+                    key: self.key.clone(),
+                    status_code: 200,
+                    size: self.size,
+                    content_hash: Some(format!("{:x}", self.hasher.clone().finalize())),
+                });
+            }
+            _ => {}
+        }
+        poll
+    }
+}
+
+/// Mirrors the read to the shadow backend in the background so the client
+/// response is never delayed by the comparison.
+pub fn shadow_read(read: ShadowRead) {
+    if !is_shadow_enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        compare_shadow_read(read).await;
+    });
+}
+
+async fn compare_shadow_read(read: ShadowRead) {
+    let endpoint = config().shadow_endpoint.clone().unwrap_or_default();
+    let path = read
+        .key
+        .split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<String>>()
+        .join("/");
+    let url = format!("{}/{}", endpoint.trim_end_matches('/'), path);
+    let client = Client::new();
+    let mut request = client.request(read.method.clone(), url);
+    if let Some(authorization) = config().shadow_authorization.clone() {
+        request = request.header("Authorization", authorization);
+    }
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(err) => {
+            warn!("Shadow {} {} failed: {}", read.method, read.key, err);
+            return;
+        }
+    };
+    let status_code = response.status().as_u16();
+    let mut divergences = Vec::new();
+    if status_code != read.status_code {
+        divergences.push(format!("status {} != {}", read.status_code, status_code));
+    }
+    // The header rather than `content_length()`, which is the length of the
+    // body actually received and so 0 for a HEAD.
+    let size = response
+        .headers()
+        .get("Content-Length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let content_hash = match read.content_hash {
+        Some(_) => match response.bytes().await {
+            Ok(data) => Some(content_hash(&data)),
+            Err(err) => {
+                warn!("Shadow {} {} body failed: {}", read.method, read.key, err);
+                return;
+            }
+        },
+        None => None,
+    };
+    if let Some(size) = size.filter(|size| *size != read.size) {
+        divergences.push(format!("size {} != {}", read.size, size));
+    }
+    if content_hash != read.content_hash {
+        divergences.push(format!(
+            "sha256 {} != {}",
+            read.content_hash.unwrap_or_default(),
+            content_hash.unwrap_or_default()
+        ));
+    }
+    if divergences.is_empty() {
+        debug!("Shadow {} {} matches", read.method, read.key);
+    } else {
+        warn!(
+            "Shadow {} {} diverges: {}",
+            read.method,
+            read.key,
+            divergences.join(", ")
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use once_cell::sync::Lazy;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::info;
+
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// Resolves on the first SIGTERM or SIGINT and tells background tasks to
+/// stop.
+pub async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler installs");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("SIGINT handler installs");
+    let name = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    };
+    info!("Received {}, shutting down", name);
+    SHUTDOWN.send_replace(true);
+}
+
+/// Sleeps between rounds of a background task. Returns `false` once shutdown
+/// has begun, which ends the task.
+pub async fn sleep_until_shutdown(duration: Duration) -> bool {
+    let mut shutdown = SHUTDOWN.subscribe();
+    if *shutdown.borrow_and_update() {
+        return false;
+    }
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => true,
+        _ = shutdown.changed() => false,
+    }
+}
@@ -0,0 +1,275 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use salvo::http::HeaderMap;
+use sha2::{Digest, Sha256};
+use url::form_urlencoded;
+
+use crate::config;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const MAX_CLOCK_SKEW_MINUTES: i64 = 15;
+
+pub fn is_sigv4_authorization(authorization: &str) -> bool {
+    authorization.starts_with(ALGORITHM)
+}
+
+struct Credential {
+    access_key: String,
+    date: String,
+    region: String,
+    service: String,
+}
+
+struct Authorization {
+    credential: Credential,
+    signed_headers: Vec<String>,
+    signature: String,
+}
+
+fn parse_authorization(authorization: &str) -> Option<Authorization> {
+    let mut credential = None;
+    let mut signed_headers = None;
+    let mut signature = None;
+    for part in authorization.strip_prefix(ALGORITHM)?.split(',') {
+        match part.trim().split_once('=')? {
+            ("Credential", value) => {
+                let mut scope = value.split('/');
+                credential = Some(Credential {
+                    access_key: scope.next()?.to_string(),
+                    date: scope.next()?.to_string(),
+                    region: scope.next()?.to_string(),
+                    service: scope.next()?.to_string(),
+                });
+                if scope.next()? != "aws4_request" {
+                    return None;
+                }
+            }
+            ("SignedHeaders", value) => {
+                signed_headers = Some(value.split(';').map(str::to_string).collect());
+            }
+            ("Signature", value) => signature = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Some(Authorization {
+        credential: credential?,
+        signed_headers: signed_headers?,
+        signature: signature?,
+    })
+}
+
+/// URI-encodes a value the way AWS expects it: only unreserved characters
+/// stay as they are, slashes are kept when encoding paths.
+fn aws_uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn canonical_uri(path: &str) -> String {
+    let decoded = urlencoding::decode(path)
+        .map(|path| path.into_owned())
+        .unwrap_or_else(|_| path.to_string());
+    aws_uri_encode(&decoded, true)
+}
+
+fn canonical_query(query: &str) -> String {
+    let mut pairs = form_urlencoded::parse(query.as_bytes())
+        .map(|(name, value)| (aws_uri_encode(&name, false), aws_uri_encode(&value, false)))
+        .collect::<Vec<(String, String)>>();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+fn canonical_headers(headers: &HeaderMap, signed_headers: &[String]) -> Option<String> {
+    let mut canonical = String::new();
+    for name in signed_headers {
+        let values = headers
+            .get_all(name.as_str())
+            .iter()
+            .map(|value| {
+                value
+                    .to_str()
+                    .map(|value| value.split_whitespace().collect::<Vec<&str>>().join(" "))
+            })
+            .collect::<Result<Vec<String>, _>>()
+            .ok()?;
+        if values.is_empty() {
+            return None;
+        }
+        canonical.push_str(&format!("{}:{}\n", name, values.join(",")));
+    }
+    Some(canonical)
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn secret_for(access_key: &str) -> Option<String> {
+    config().access_keys.iter().find_map(|pair| {
+        pair.split_once(':')
+            .filter(|(id, _)| *id == access_key)
+            .map(|(_, secret)| secret.to_string())
+    })
+}
+
+/// Verifies an `AWS4-HMAC-SHA256` signed request against the configured
+/// access keys and returns the access key id of the caller.
+pub fn verify_sigv4(
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &HeaderMap,
+) -> Result<String, String> {
+    let authorization = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_authorization)
+        .ok_or("Malformed AWS4-HMAC-SHA256 authorization header")?;
+    let credential = &authorization.credential;
+    if credential.service != "s3" {
+        return Err(format!("Unsupported service {}", credential.service));
+    }
+    let secret = secret_for(&credential.access_key)
+        .ok_or(format!("Unknown access key {}", credential.access_key))?;
+
+    let amz_date = headers
+        .get("x-amz-date")
+        .and_then(|value| value.to_str().ok())
+        .ok_or("Missing x-amz-date header")?;
+    let request_time = NaiveDateTime::parse_from_str(amz_date, "%Y%m%dT%H%M%SZ")
+        .map_err(|_| format!("Invalid x-amz-date {}", amz_date))?
+        .and_utc();
+    if (Utc::now() - request_time).abs() > TimeDelta::minutes(MAX_CLOCK_SKEW_MINUTES) {
+        return Err(format!("Request time {} is too skewed", amz_date));
+    }
+    if !amz_date.starts_with(&credential.date) {
+        return Err("Credential date does not match x-amz-date".to_string());
+    }
+
+    let payload_hash = headers
+        .get("x-amz-content-sha256")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("UNSIGNED-PAYLOAD");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        canonical_uri(path),
+        canonical_query(query),
+        canonical_headers(headers, &authorization.signed_headers)
+            .ok_or("Signed header missing from request")?,
+        authorization.signed_headers.join(";"),
+        payload_hash
+    );
+    let scope = format!(
+        "{}/{}/{}/aws4_request",
+        credential.date, credential.region, credential.service
+    );
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{:x}",
+        ALGORITHM,
+        amz_date,
+        scope,
+        Sha256::digest(canonical_request.as_bytes())
+    );
+
+    let date_key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), &credential.date);
+    let region_key = hmac_sha256(&date_key, &credential.region);
+    let service_key = hmac_sha256(&region_key, &credential.service);
+    let signing_key = hmac_sha256(&service_key, "aws4_request");
+
+    let signature =
+        hex::decode(&authorization.signature).map_err(|_| "Malformed signature".to_string())?;
+    let mut mac = HmacSha256::new_from_slice(&signing_key).expect("HMAC accepts any key length");
+    mac.update(string_to_sign.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| "Signature does not match".to_string())?;
+
+    Ok(credential.access_key.clone())
+}
+
+/// Credentials the adapter signs its own calls to AWS services with.
+pub struct AwsCredentials {
+    pub access_key: String,
+    pub secret: String,
+    pub region: String,
+}
+
+/// Signs an outgoing request to an AWS service and returns the headers to
+/// send along: `x-amz-date`, `x-amz-content-sha256` and `Authorization`.
+pub fn sign_sigv4(
+    method: &str,
+    url: &url::Url,
+    content_type: &str,
+    body: &[u8],
+    service: &str,
+    credentials: &AwsCredentials,
+) -> Vec<(&'static str, String)> {
+    let (access_key, secret, region) = (
+        &credentials.access_key,
+        &credentials.secret,
+        credentials.region.as_str(),
+    );
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = format!("{:x}", Sha256::digest(body));
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let signed_headers = "content-type;host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "{}\n{}\n{}\ncontent-type:{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        method,
+        canonical_uri(url.path()),
+        canonical_query(url.query().unwrap_or_default()),
+        content_type,
+        host,
+        payload_hash,
+        amz_date,
+        signed_headers,
+        payload_hash
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{:x}",
+        ALGORITHM,
+        amz_date,
+        scope,
+        Sha256::digest(canonical_request.as_bytes())
+    );
+    let date_key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), &date);
+    let region_key = hmac_sha256(&date_key, region);
+    let service_key = hmac_sha256(&region_key, service);
+    let signing_key = hmac_sha256(&service_key, "aws4_request");
+    let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign));
+    vec![
+        ("x-amz-date", amz_date),
+        ("x-amz-content-sha256", payload_hash),
+        (
+            "Authorization",
+            format!(
+                "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+                ALGORITHM, access_key, scope, signed_headers, signature
+            ),
+        ),
+    ]
+}
@@ -0,0 +1,217 @@
+use chrono::{DateTime, Utc};
+use reqwest::{Error, StatusCode};
+use tracing::warn;
+
+use super::azure::{
+    get_azure_item, get_azure_object_data, get_azure_version_data, head_azure_object,
+    list_azure_versions, GetAzureObjectResponse, HeadAzureObjectResponse, Item, ItemVersion,
+};
+use super::buckets::{buckets, is_multi_bucket, Bucket};
+use super::conditional::Conditions;
+use super::naming::filename_regex;
+use crate::config;
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|value| value.with_timezone(&Utc))
+        .ok()
+}
+
+/// Parses a `SNAPSHOT_VIEWS` entry of the form `view=bucket@timestamp`, e.g.
+/// `documents-2026q2=documents@2026-06-30T23:59:59Z`.
+fn parse_snapshot_view(entry: &str) -> Result<Bucket, String> {
+    let (name, target) = entry
+        .split_once('=')
+        .ok_or("expected view=bucket@timestamp")?;
+    let (source, as_of) = target
+        .split_once('@')
+        .ok_or("expected view=bucket@timestamp")?;
+    let source = buckets()
+        .into_iter()
+        .find(|bucket| bucket.name == source.trim())
+        .ok_or(format!("unknown bucket {}", source.trim()))?;
+    let as_of = parse_time(as_of.trim()).ok_or("the timestamp must be RFC 3339")?;
+    Ok(Bucket {
+        name: name.trim().to_string(),
+        as_of: Some(as_of),
+        ..source
+    })
+}
+
+/// Validates `SNAPSHOT_VIEWS` at startup. Views are buckets of their own, so
+/// they need the path-style routing of `BUCKET_MAPPINGS`.
+pub fn check_snapshot_views() -> Result<(), String> {
+    if config().snapshot_views.is_empty() {
+        return Ok(());
+    }
+    if !is_multi_bucket() {
+        return Err("SNAPSHOT_VIEWS requires BUCKET_MAPPINGS".to_string());
+    }
+    for entry in &config().snapshot_views {
+        let view = parse_snapshot_view(entry)
+            .map_err(|err| format!("Invalid SNAPSHOT_VIEWS entry {}: {}", entry, err))?;
+        if buckets().iter().any(|bucket| bucket.name == view.name) {
+            return Err(format!(
+                "Snapshot view {} has the name of a bucket",
+                view.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Read-only views of buckets as they were at a point in time. They are
+/// kept apart from `buckets()` so that background tasks such as lifecycle
+/// rules never act on them.
+pub fn snapshot_views() -> Vec<Bucket> {
+    config()
+        .snapshot_views
+        .iter()
+        .filter_map(|entry| parse_snapshot_view(entry).ok())
+        .collect()
+}
+
+/// The state of an item at `as_of`: `None` when it did not exist yet, the
+/// item itself when it has not changed since, and otherwise the item with
+/// the size and timestamps of the version current at `as_of`, along with
+/// that version.
+///
+/// Items deleted since `as_of` are gone from the drive and Graph keeps no
+/// version history for them, so a view cannot show them. Renamed and moved
+/// files appear under their current key.
+async fn item_at(
+    drive: &str,
+    mut item: Item,
+    as_of: DateTime<Utc>,
+) -> Result<Option<(Item, Option<ItemVersion>)>, Error> {
+    if parse_time(&item.created_date_time).is_some_and(|created| created > as_of) {
+        return Ok(None);
+    }
+    let modified = item.last_modified_date_time.as_deref().and_then(parse_time);
+    if item.file.is_none() || modified.is_none_or(|modified| modified <= as_of) {
+        return Ok(Some((item, None)));
+    }
+    let version = list_azure_versions(drive.to_string(), item.id.clone())
+        .await?
+        .into_iter()
+        .filter_map(|version| {
+            let modified = parse_time(&version.last_modified_date_time)?;
+            (modified <= as_of).then_some((modified, version))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, version)| version);
+    let Some(version) = version else {
+        warn!(
+            "No version of {} as of {} is retained, leaving it out",
+            item.name, as_of
+        );
+        return Ok(None);
+    };
+    item.size = version.size;
+    item.last_modified_date_time = Some(version.last_modified_date_time.clone());
+    // Tagged by the version, which `get_azure_version_data` answers with too.
+    item.e_tag = Some(format!("{}/{}", item.id, version.id));
+    item.c_tag = None;
+    if let Some(file) = item.file.as_mut() {
+        file.hashes = None;
+    }
+    item.download_url = None;
+    Ok(Some((item, Some(version))))
+}
+
+/// Restates a listing page as of `as_of`.
+pub async fn snapshot_items(
+    drive: &str,
+    items: Vec<Item>,
+    as_of: DateTime<Utc>,
+) -> Result<Vec<Item>, Error> {
+    let mut snapshot = Vec::with_capacity(items.len());
+    for item in items {
+        if let Some((item, _)) = item_at(drive, item, as_of).await? {
+            snapshot.push(item);
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Looks up a key, answering `None` when it does not exist.
+async fn find_item(drive: &str, key: &str) -> Result<Option<Item>, Error> {
+    match get_azure_item(drive.to_string(), key.to_string()).await {
+        Ok(item) => Ok(Some(item)),
+        Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Downloads a file as it was at `as_of`.
+pub async fn get_snapshot_object_data(
+    drive: String,
+    key: String,
+    as_of: DateTime<Utc>,
+    range: Option<String>,
+    conditions: &Conditions,
+) -> Result<GetAzureObjectResponse, Error> {
+    let file_name = key.split('/').next_back().unwrap_or_default().to_string();
+    let Some(item) = find_item(&drive, &key).await? else {
+        return Ok(GetAzureObjectResponse::status(404, file_name));
+    };
+    match item_at(&drive, item, as_of).await? {
+        Some((item, None)) if item.file.is_some() => {
+            get_azure_object_data(drive, key, range, conditions).await
+        }
+        Some((item, Some(version))) => {
+            let e_tag = item.object_e_tag();
+            if conditions.is_not_modified(e_tag.as_deref(), item.last_modified_date_time.as_deref())
+            {
+                return Ok(GetAzureObjectResponse {
+                    e_tag,
+                    last_modified: item.last_modified_date_time,
+                    ..GetAzureObjectResponse::status(304, file_name)
+                });
+            }
+            get_azure_version_data(drive, item, version, range).await
+        }
+        _ => Ok(GetAzureObjectResponse::status(404, file_name)),
+    }
+}
+
+fn head_status(status_code: u16) -> HeadAzureObjectResponse {
+    HeadAzureObjectResponse {
+        content_type: "application/xml".to_string(),
+        status_code,
+        size: 0,
+        e_tag: None,
+        last_modified: None,
+    }
+}
+
+/// Heads a key as it was at `as_of`.
+pub async fn head_snapshot_object(
+    drive: String,
+    key: String,
+    as_of: DateTime<Utc>,
+) -> Result<HeadAzureObjectResponse, Error> {
+    let Some(item) = find_item(&drive, &key).await? else {
+        return Ok(head_status(404));
+    };
+    match item_at(&drive, item, as_of).await? {
+        None => Ok(head_status(404)),
+        Some((_, None)) => head_azure_object(drive, key).await,
+        Some((item, Some(_))) => {
+            let regex = filename_regex();
+            if !regex.is_match(&item.name) {
+                return Ok(head_status(403));
+            }
+            Ok(HeadAzureObjectResponse {
+                e_tag: item.object_e_tag(),
+                content_type: item
+                    .file
+                    .map(|file| file.mime_type)
+                    .unwrap_or("application/octet-stream".to_string()),
+                status_code: 200,
+                size: item.size.unwrap_or_default(),
+                last_modified: item.last_modified_date_time,
+            })
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use super::azure::{get_azure_object_data, GetAzureObjectResponse};
+use super::conditional::Conditions;
+use super::range::{parse_content_range, ByteRange};
+use super::readahead::RangeRead;
+use crate::config;
+
+/// The last bytes of a large object, where parquet footers and zip central
+/// directories live.
+struct Tail {
+    start: u64,
+    total: u64,
+    data: Bytes,
+    content_type: String,
+    file_name: String,
+    e_tag: Option<String>,
+    last_modified: Option<String>,
+    cached_at: Instant,
+}
+
+static TAILS: Lazy<Mutex<HashMap<String, Tail>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static IN_FLIGHT: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+pub fn is_tail_cache_enabled() -> bool {
+    config().tail_cache_bytes > 0
+}
+
+fn ttl() -> Duration {
+    Duration::from_secs(config().tail_cache_ttl_secs)
+}
+
+/// Serves a range from the cached tail of an object when it lies within it.
+pub fn cached_tail(object: &str, range: ByteRange) -> Option<GetAzureObjectResponse> {
+    let mut tails = TAILS.lock().unwrap();
+    if tails
+        .get(object)
+        .is_some_and(|tail| tail.cached_at.elapsed() > ttl())
+    {
+        tails.remove(object);
+    }
+    let tail = tails.get(object)?;
+    let (start, end) = range.resolve(tail.total)?;
+    // Anything outside the cached bytes is left to Graph.
+    if start < tail.start || end - tail.start >= tail.data.len() as u64 {
+        return None;
+    }
+    debug!("Serving {} bytes {}-{} from tail cache", object, start, end);
+    Some(GetAzureObjectResponse::partial(
+        tail.data
+            .slice((start - tail.start) as usize..=(end - tail.start) as usize),
+        start,
+        tail.total,
+        tail.content_type.clone(),
+        tail.file_name.clone(),
+        tail.e_tag.clone(),
+        tail.last_modified.clone(),
+    ))
+}
+
+/// Fetches and caches the tail of a large object in the background once a
+/// read touched it, so the next open of the same file skips Graph.
+pub fn record_tail_read(read: RangeRead) {
+    let tail_bytes = config().tail_cache_bytes;
+    let tail_start = read.total.saturating_sub(tail_bytes);
+    if read.total < config().tail_cache_min_size || read.end < tail_start {
+        return;
+    }
+    let object = format!("{}:{}", read.drive, read.key);
+    if TAILS
+        .lock()
+        .unwrap()
+        .get(&object)
+        .is_some_and(|tail| tail.cached_at.elapsed() <= ttl())
+        || !IN_FLIGHT.lock().unwrap().insert(object.clone())
+    {
+        return;
+    }
+    tokio::spawn(async move {
+        debug!("Caching last {} bytes of {}", tail_bytes, object);
+        let response = get_azure_object_data(
+            read.drive,
+            read.key,
+            Some(format!("bytes={}-{}", tail_start, read.total - 1)),
+            &Conditions::default(),
+        )
+        .await;
+        // The object may have changed since the read, so the bytes are
+        // placed by the Content-Range Graph answered with.
+        let tail = match response {
+            Ok(response) if response.status_code == 206 => {
+                let (e_tag, last_modified) =
+                    (response.e_tag.clone(), response.last_modified.clone());
+                let content_range = response
+                    .content_range
+                    .as_deref()
+                    .and_then(parse_content_range);
+                match (content_range, response.collect().await) {
+                    (Some((start, last, total)), Ok(data))
+                        if start <= last
+                            && last < total
+                            && data.len() as u64 == last - start + 1 =>
+                    {
+                        Some((start, total, data, e_tag, last_modified))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        IN_FLIGHT.lock().unwrap().remove(&object);
+        let Some((tail_start, total, data, e_tag, last_modified)) = tail else {
+            return;
+        };
+        let mut tails = TAILS.lock().unwrap();
+        tails.retain(|_, tail| tail.cached_at.elapsed() <= ttl());
+        if tails.len() >= config().tail_cache_max_objects {
+            let oldest = tails
+                .iter()
+                .min_by_key(|(_, tail)| tail.cached_at)
+                .map(|(object, _)| object.clone());
+            if let Some(oldest) = oldest {
+                tails.remove(&oldest);
+            }
+        }
+        tails.insert(
+            object,
+            Tail {
+                start: tail_start,
+                total,
+                data: Bytes::from(data),
+                content_type: read.content_type,
+                file_name: read.file_name,
+                e_tag,
+                last_modified,
+                cached_at: Instant::now(),
+            },
+        );
+    });
+}
+
+/// Drops the cached tail of an object after it was overwritten or deleted.
+pub fn invalidate_tail(drive: &str, key: &str) {
+    TAILS.lock().unwrap().remove(&format!("{}:{}", drive, key));
+}
@@ -0,0 +1,104 @@
+use opentelemetry::global;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use reqwest::RequestBuilder;
+use salvo::http::HeaderMap;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tracing::{field, info_span, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config;
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Whether spans are exported to `OTEL_EXPORTER_OTLP_ENDPOINT`.
+pub fn is_tracing_enabled() -> bool {
+    config()
+        .otel_exporter_otlp_endpoint
+        .as_deref()
+        .is_some_and(|endpoint| !endpoint.is_empty())
+}
+
+/// The layer exporting spans over OTLP/HTTP, when tracing is enabled. The
+/// W3C trace context propagator is installed along with it. An invalid
+/// endpoint is returned as an error, to be logged once logging is up.
+pub fn otel_layer<S>() -> Result<Option<OpenTelemetryLayer<S, SdkTracer>>, String>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let Some(endpoint) = config()
+        .otel_exporter_otlp_endpoint
+        .clone()
+        .filter(|_| is_tracing_enabled())
+    else {
+        return Ok(None);
+    };
+    let exporter = match SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(err) => {
+            return Err(format!(
+                "Invalid OTEL_EXPORTER_OTLP_ENDPOINT {}: {}",
+                endpoint, err
+            ))
+        }
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config().otel_service_name.clone())
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    let _ = PROVIDER.set(provider.clone());
+    global::set_tracer_provider(provider);
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Exports the spans still buffered, before the process exits.
+pub async fn flush_traces() {
+    if let Some(provider) = PROVIDER.get().cloned() {
+        let _ = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+    }
+}
+
+/// A server span for an incoming request, continuing the trace of its
+/// `traceparent` header.
+pub fn request_span(method: &str, path: &str, headers: &HeaderMap) -> Span {
+    let span = info_span!(
+        "request",
+        otel.kind = "server",
+        http.request.method = method,
+        url.path = path,
+        http.response.status_code = field::Empty
+    );
+    let carrier = headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect::<HashMap<String, String>>();
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&carrier));
+    let _ = span.set_parent(parent);
+    span
+}
+
+/// Adds the `traceparent` of a span to an outgoing request.
+pub fn inject_trace_context(request: RequestBuilder, span: &Span) -> RequestBuilder {
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut carrier)
+    });
+    carrier.into_iter().fold(request, |request, (name, value)| {
+        request.header(name, value)
+    })
+}
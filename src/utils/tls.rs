@@ -0,0 +1,77 @@
+use futures_util::stream::{self, Stream, StreamExt};
+use salvo::conn::rustls::{Keycert, RustlsConfig, ServerConfig};
+use std::io::Result as IoResult;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use super::shutdown::sleep_until_shutdown;
+use crate::config;
+
+/// How often the certificate files are checked for a rotation.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether the server terminates TLS itself.
+pub fn is_tls_enabled() -> bool {
+    config().tls_cert_path.is_some() && config().tls_key_path.is_some()
+}
+
+/// Rejects TLS settings that would otherwise be ignored: a certificate
+/// without its key or the other way round, and TLS on a Unix socket.
+pub fn check_tls_config() -> Result<(), String> {
+    match (&config().tls_cert_path, &config().tls_key_path) {
+        (Some(_), None) => Err("TLS_CERT_PATH is set without TLS_KEY_PATH".to_string()),
+        (None, Some(_)) => Err("TLS_KEY_PATH is set without TLS_CERT_PATH".to_string()),
+        (Some(_), Some(_)) if config().unix_socket_path.is_some() => {
+            Err("TLS cannot be combined with UNIX_SOCKET_PATH".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn load(cert_path: &str, key_path: &str) -> IoResult<ServerConfig> {
+    let keycert = Keycert::new()
+        .cert_from_path(cert_path)?
+        .key_from_path(key_path)?;
+    RustlsConfig::new(keycert).try_into()
+}
+
+/// The TLS configuration from `TLS_CERT_PATH` and `TLS_KEY_PATH`, followed by
+/// a new one whenever either file changes, so rotated certificates are
+/// served without a restart. A rotation that fails to load keeps the current
+/// certificate.
+pub fn tls_configs() -> Result<impl Stream<Item = ServerConfig>, String> {
+    let cert_path = config().tls_cert_path.clone().unwrap_or_default();
+    let key_path = config().tls_key_path.clone().unwrap_or_default();
+    let seen = (modified(&cert_path), modified(&key_path));
+    let initial = load(&cert_path, &key_path)
+        .map_err(|err| format!("Cannot load TLS certificate {}: {}", cert_path, err))?;
+    let reloads = stream::unfold(seen, move |mut seen| {
+        let (cert_path, key_path) = (cert_path.clone(), key_path.clone());
+        async move {
+            loop {
+                if !sleep_until_shutdown(RELOAD_INTERVAL).await {
+                    return None;
+                }
+                let current = (modified(&cert_path), modified(&key_path));
+                if current == seen {
+                    continue;
+                }
+                seen = current;
+                match load(&cert_path, &key_path) {
+                    Ok(server_config) => {
+                        info!("Reloaded TLS certificate {}", cert_path);
+                        return Some((server_config, seen));
+                    }
+                    Err(err) => warn!("Cannot reload TLS certificate {}: {}", cert_path, err),
+                }
+            }
+        }
+    });
+    Ok(stream::once(async { initial }).chain(reloads))
+}
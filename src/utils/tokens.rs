@@ -0,0 +1,170 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use std::sync::OnceLock;
+
+use crate::config;
+
+/// A scoped bearer token from `API_TOKENS`, written as
+/// `name|token|methods|prefixes[|expires]`, e.g.
+/// `reports|s3cr3t|GET,HEAD|reports/,shared/|2027-01-01`. `*` allows any
+/// method or key. A date expires the token at the start of that day (UTC).
+#[derive(Debug)]
+pub struct ApiToken {
+    pub name: String,
+    token: String,
+    methods: Option<Vec<String>>,
+    prefixes: Option<Vec<String>>,
+    expires: Option<DateTime<Utc>>,
+}
+
+static API_TOKENS: OnceLock<Vec<ApiToken>> = OnceLock::new();
+
+/// Splits a comma-separated list, `*` or nothing meaning no restriction.
+fn parse_list(list: &str, normalize: impl Fn(&str) -> String) -> Option<Vec<String>> {
+    let list = list.trim();
+    if list.is_empty() || list == "*" {
+        return None;
+    }
+    Some(
+        list.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(normalize)
+            .collect(),
+    )
+}
+
+fn parse_expiry(expires: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(expires)
+        .map(|expires| expires.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(expires, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|expires| expires.and_utc())
+        })
+}
+
+fn parse_api_token(entry: &str) -> Result<ApiToken, String> {
+    let parts = entry.split('|').map(str::trim).collect::<Vec<&str>>();
+    let (name, token, methods, prefixes) = match parts[..] {
+        [name, token, methods, prefixes] | [name, token, methods, prefixes, _] => {
+            (name, token, methods, prefixes)
+        }
+        _ => return Err("expected name|token|methods|prefixes[|expires]".to_string()),
+    };
+    if name.is_empty() || token.is_empty() {
+        return Err("name and token must not be empty".to_string());
+    }
+    // Names appear in logs, metric labels and `ON_BEHALF_OF_CALLERS`.
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        return Err("name may only contain letters, digits, '.', '_' and '-'".to_string());
+    }
+    let expires = match parts.get(4).filter(|expires| !expires.is_empty()) {
+        Some(expires) => Some(parse_expiry(expires).ok_or(format!("invalid expiry {}", expires))?),
+        None => None,
+    };
+    Ok(ApiToken {
+        name: name.to_string(),
+        token: token.to_string(),
+        methods: parse_list(methods, str::to_uppercase),
+        prefixes: parse_list(prefixes, |prefix| prefix.trim_matches('/').to_string()),
+        expires,
+    })
+}
+
+/// Parses a token table, `option` naming it in errors.
+pub fn parse_api_tokens<'a>(
+    option: &str,
+    entries: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<ApiToken>, String> {
+    entries
+        .into_iter()
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            parse_api_token(entry).map_err(|err| {
+                let name = entry.split('|').next().unwrap_or_default();
+                format!("Invalid {} entry {}: {}", option, name, err)
+            })
+        })
+        .collect()
+}
+
+/// Parses `API_TOKENS` at startup, rejecting malformed entries instead of
+/// silently dropping a team's access.
+pub fn load_api_tokens() -> Result<(), String> {
+    let tokens = parse_api_tokens("API_TOKENS", config().api_tokens.iter().map(String::as_str))?;
+    let _ = API_TOKENS.set(tokens);
+    Ok(())
+}
+
+/// Compares tokens in time independent of where they differ, so a caller
+/// cannot guess a token byte by byte.
+pub fn tokens_match(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub fn find_token<'a>(tokens: &'a [ApiToken], token: &str) -> Option<&'a ApiToken> {
+    tokens
+        .iter()
+        .find(|api_token| tokens_match(&api_token.token, token))
+}
+
+pub fn find_api_token(token: &str) -> Option<&'static ApiToken> {
+    find_token(API_TOKENS.get()?, token)
+}
+
+impl ApiToken {
+    /// The methods the token may use, `None` allowing any.
+    pub fn methods(&self) -> Option<&[String]> {
+        self.methods.as_deref()
+    }
+
+    /// The prefixes the token is confined to, `None` allowing any key.
+    pub fn prefixes(&self) -> Option<&[String]> {
+        self.prefixes.as_deref()
+    }
+
+    pub fn expires(&self) -> Option<DateTime<Utc>> {
+        self.expires
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= Utc::now())
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods
+            .as_ref()
+            .is_none_or(|methods| methods.iter().any(|allowed| allowed == method))
+    }
+
+    /// Whether the token may make a request at all: unexpired, with an
+    /// allowed method and within its prefixes.
+    pub fn allows(&self, method: &str, key: &str) -> bool {
+        !self.is_expired() && self.allows_method(method) && self.allows_key(key)
+    }
+
+    /// Whether a key, or the prefix of a listing, lies within the token's
+    /// prefixes.
+    pub fn allows_key(&self, key: &str) -> bool {
+        let key = key.trim_start_matches('/');
+        self.prefixes.as_ref().is_none_or(|prefixes| {
+            prefixes.iter().any(|prefix| {
+                prefix.is_empty()
+                    || key == prefix
+                    || key
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+        })
+    }
+}
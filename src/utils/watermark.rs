@@ -0,0 +1,44 @@
+use chrono::{DateTime, Utc};
+use reqwest::{Client, Error};
+use tracing::debug;
+
+use crate::config;
+
+/// Per-request details stamped onto watermarked documents.
+pub struct WatermarkContext {
+    pub subject: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+pub fn is_watermark_enabled(content_type: &str) -> bool {
+    config().pdf_watermark_url.is_some() && content_type.starts_with("application/pdf")
+}
+
+/// Pipes a PDF through the configured watermark service, which receives the
+/// document as body and the stamp details as headers and returns the new PDF.
+pub async fn apply_pdf_watermark(
+    data: Vec<u8>,
+    context: &WatermarkContext,
+) -> Result<Vec<u8>, Error> {
+    let url = config()
+        .pdf_watermark_url
+        .clone()
+        .expect("Watermark service not set");
+    debug!("Watermarking PDF for {}", context.subject);
+    let client = Client::new();
+    match client
+        .post(url)
+        .header("Content-Type", "application/pdf")
+        .header("X-Watermark-Subject", context.subject.clone())
+        .header("X-Watermark-Timestamp", context.timestamp.to_rfc3339())
+        .body(data)
+        .send()
+        .await
+    {
+        Ok(response) => match response.error_for_status() {
+            Ok(response) => Ok(response.bytes().await?.to_vec()),
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    }
+}